@@ -81,6 +81,20 @@ fn main() {
     bindings
         .write_to_file(out_path.join("libbpf_map_def.rs"))
         .expect("Couldn't write bindings!");
+    let bindings = bindgen::Builder::default()
+        .header("libbpf/src/xsk.h")
+        .clang_arg("-Ilibbpf/include/uapi")
+        .clang_arg("-Ilibbpf/include")
+        .clang_arg("-Ibcc")
+        .whitelist_function("xsk_.*")
+        .whitelist_type("xsk_.*")
+        .whitelist_type("xdp_desc")
+        .whitelist_var("XSK_.*")
+        .generate()
+        .expect("Unable to generate bindings");
+    bindings
+        .write_to_file(out_path.join("xsk_bindings.rs"))
+        .expect("Couldn't write bindings!");
     let bindings = bindgen::Builder::default()
         .header("bcc/perf_reader.h")
         .clang_arg("-Ilibbpf/include/uapi")
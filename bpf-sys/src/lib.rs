@@ -7,8 +7,34 @@ extern crate zero;
 pub mod headers;
 pub mod perf_reader;
 pub mod uname;
+pub mod xsk;
 
 include!(concat!(env!("OUT_DIR"), "/libbpf_bindings.rs"));
 include!(concat!(env!("OUT_DIR"), "/libbpf_map_def.rs"));
 unsafe impl ::zero::Pod for bpf_map_def {}
 unsafe impl ::zero::Pod for bpf_insn {}
+
+// Feature probes from libbpf_probes.c. The declarations aren't generated
+// from libbpf.h as that would duplicate the enums from libbpf_bindings.rs.
+extern "C" {
+    pub fn bpf_probe_prog_type(prog_type: bpf_prog_type, ifindex: u32) -> bool;
+    pub fn bpf_probe_map_type(map_type: bpf_map_type, ifindex: u32) -> bool;
+}
+
+// Diagnostics callback from libbpf.c, declared by hand for the same reason.
+// `ap` is the `va_list` of the message, which is passed by reference on the
+// supported architectures.
+pub type libbpf_print_level = u32;
+pub const libbpf_print_level_LIBBPF_WARN: libbpf_print_level = 0;
+pub const libbpf_print_level_LIBBPF_INFO: libbpf_print_level = 1;
+pub const libbpf_print_level_LIBBPF_DEBUG: libbpf_print_level = 2;
+pub type libbpf_print_fn_t = Option<
+    unsafe extern "C" fn(
+        level: libbpf_print_level,
+        format: *const ::libc::c_char,
+        ap: *mut ::libc::c_void,
+    ) -> ::libc::c_int,
+>;
+extern "C" {
+    pub fn libbpf_set_print(fn_: libbpf_print_fn_t) -> libbpf_print_fn_t;
+}
@@ -23,8 +23,17 @@ pub fn get_kernel_internal_version() -> Option<u32> {
         to_str(&uname().ok()?.release).into()
     };
 
-    parse_version(&version).map(|(major, minor, patch)| {
-        major << 16 | minor << 8 | patch
+    kernel_version_code(&version)
+}
+
+/// Returns the `LINUX_VERSION_CODE` of the kernel release `release`, eg.
+/// `5.4.0-42-generic`.
+///
+/// Like the kernel's `KERNEL_VERSION` macro, the patch level is capped to 255.
+#[inline]
+pub fn kernel_version_code(release: &str) -> Option<u32> {
+    parse_version(release).map(|(major, minor, patch)| {
+        major << 16 | minor << 8 | patch.min(255)
     })
 }
 
@@ -84,6 +93,13 @@ mod test {
         assert_eq!(parse_version(""), None);
     }
 
+    #[test]
+    fn test_kernel_version_code() {
+        assert_eq!(kernel_version_code("5.4.0-42-generic"), Some(0x050400));
+        assert_eq!(kernel_version_code("4.9.337"), Some(0x0409ff));
+        assert_eq!(kernel_version_code("foo"), None);
+    }
+
     #[test]
     fn test_parse_version_signature() {
         assert_eq!(parse_version_signature("Ubuntu 4.15.0-55.60-generic 4.15.18"), Some("4.15.18".into()));
@@ -1,22 +1,36 @@
+use goblin::elf::{section_header as hdr, Elf};
+use std::any::Any;
 use std::convert::From;
 use std::env;
 use std::fmt::{self, Display};
 use std::fs;
-use std::io;
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
 use toml_edit;
 
+use redbpf::build::Endian;
+
+use crate::manifest::{write_manifest, Manifest};
 use crate::CommandError;
 
 #[derive(Debug)]
 pub enum Error {
     MissingManifest(PathBuf),
+    MissingProbesFeature,
+    MissingFeatures(String, Vec<String>),
     NoPrograms,
     NoLLC,
     Compile(String),
     MissingBitcode(String),
     Link(String),
+    Sections(String, String),
+    Manifest(String),
+    Mirror(String),
+    BigEndianLlc,
+    LinkPanic(String),
     IOError(io::Error),
 }
 
@@ -40,11 +54,30 @@ impl Display for Error {
         use Error::*;
         match self {
             MissingManifest(p) => write!(f, "Could not find `Cargo.toml' in {:?}", p),
+            MissingProbesFeature => write!(
+                f,
+                "the package doesn't declare the `{}' feature eBPF programs are built with",
+                PROBES_FEATURE
+            ),
+            MissingFeatures(p, features) => write!(
+                f,
+                "the `{}' program requires features that `cargo bpf build' doesn't enable: {}",
+                p,
+                features.join(", ")
+            ),
             NoPrograms => write!(f, "the package doesn't contain any eBPF programs"),
             Compile(p) => write!(f, "failed to compile the `{}' program", p),
             MissingBitcode(p) => write!(f, "failed to generate bitcode for the `{}' program", p),
             Link(p) => write!(f, "failed to generate bitcode for the `{}' program", p),
+            Sections(p, e) => write!(f, "the `{}' program can't be loaded by redbpf: {}", p, e),
+            Manifest(e) => write!(f, "failed to generate manifest: {}", e),
+            Mirror(e) => write!(f, "failed to generate user space types: {}", e),
+            BigEndianLlc => write!(
+                f,
+                "big-endian programs can only be built with the rust backend (`--rust')"
+            ),
 	    NoLLC => write!(f, "no usable llc executable found, expecting version 9"),
+            LinkPanic(e) => write!(f, "a thread running llc panicked: {}", e),
             IOError(e) => write!(f, "{}", e),
         }
     }
@@ -56,13 +89,124 @@ impl From<Error> for CommandError {
     }
 }
 
+/// The feature enabled when building programs. Programs are declared with
+/// `required-features = ["probes"]` so that a plain `cargo build` of the
+/// package skips them.
+pub const PROBES_FEATURE: &str = "probes";
+
+/// The profile programs are built with.
+///
+/// eBPF programs must be optimized to be accepted by the verifier: at
+/// `opt-level=0`, rustc doesn't inline helper wrappers and spills to the stack
+/// in ways that the verifier rejects. The debug profile therefore still builds
+/// with `opt-level=1`, which is enough to troubleshoot problems introduced by
+/// the more aggressive optimizations of the release profile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profile {
+    Release,
+    Debug,
+}
+
+impl Profile {
+    /// The name of the profile, as used in the `target` directory.
+    pub fn name(self) -> &'static str {
+        match self {
+            Profile::Release => "release",
+            Profile::Debug => "debug",
+        }
+    }
+
+    fn cargo_args(self) -> &'static [&'static str] {
+        match self {
+            Profile::Release => &["rustc", "--release"],
+            Profile::Debug => &["rustc"],
+        }
+    }
+
+    fn rustc_args(self) -> &'static [&'static str] {
+        match self {
+            Profile::Release => &["-C", "opt-level=3"],
+            // overflow checks would pull in calls to the panic machinery
+            Profile::Debug => &["-C", "opt-level=1", "-C", "overflow-checks=off"],
+        }
+    }
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile::Release
+    }
+}
+
+/// How programs are compiled to eBPF.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// Emits LLVM bitcode with the host target and compiles it with `llc`.
+    ///
+    /// Only builds little-endian programs, as rustc lays out data with the
+    /// byte order of the host.
+    Llc,
+    /// Compiles with the `bpfel-unknown-none` rustc target, or
+    /// `bpfeb-unknown-none` for big-endian programs, and links with
+    /// `bpf-linker`.
+    ///
+    /// This is experimental: it requires a nightly toolchain, to build `core`
+    /// for the target, and `bpf-linker` in the `PATH`.
+    Rust,
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        Backend::Llc
+    }
+}
+
+// Only `Backend::Rust` builds for the target byte order.
+fn check_endian(backend: Backend, endian: Endian) -> Result<(), Error> {
+    match (backend, endian) {
+        (Backend::Llc, Endian::Big) => Err(Error::BigEndianLlc),
+        _ => Ok(()),
+    }
+}
+
+// The rustc target of `Backend::Rust`.
+fn rust_target(endian: Endian) -> &'static str {
+    match endian {
+        Endian::Little => "bpfel-unknown-none",
+        Endian::Big => "bpfeb-unknown-none",
+    }
+}
+
 pub fn build_program(
     cargo: &Path,
     package: &Path,
     out_dir: &Path,
     program: &str,
-) -> Result<(), Error> {
-    let llc_args = ["-march=bpf", "-filetype=obj", "-o"];
+    profile: Profile,
+    keep_intermediates: bool,
+    endian: Endian,
+) -> Result<PathBuf, Error> {
+    let bitcode = compile_bitcode(cargo, package, out_dir, program, profile)?;
+    let llc = get_llc_executable()?;
+    let (log, res) = link_bitcode(&llc, &bitcode, keep_intermediates, endian);
+    io::stderr().write_all(&log)?;
+    res
+}
+
+// A program compiled to LLVM bitcode, waiting to be compiled to eBPF by llc.
+struct Bitcode {
+    program: String,
+    bc_file: PathBuf,
+    elf_target: PathBuf,
+}
+
+fn compile_bitcode(
+    cargo: &Path,
+    package: &Path,
+    out_dir: &Path,
+    program: &str,
+    profile: Profile,
+) -> Result<Bitcode, Error> {
     let elf_target = out_dir.join(format!("{}.elf", program));
 
     let current_dir = env::current_dir().unwrap();
@@ -72,11 +216,13 @@ pub fn build_program(
 
     if !Command::new(cargo)
         .current_dir(package)
-        .args("rustc --release --features=probes".split(" "))
+        .args(profile.cargo_args())
+        .arg(format!("--features={}", PROBES_FEATURE))
         .arg("--bin")
         .arg(program)
         .arg("--")
-        .args("--emit=llvm-bc -C panic=abort -C link-arg=-nostartfiles -C opt-level=3".split(" "))
+        .args("--emit=llvm-bc -C panic=abort -C link-arg=-nostartfiles".split(" "))
+        .args(profile.rustc_args())
         .args(format!("-o {}/{}", out_dir.to_str().unwrap(), program).split(" "))
         .status()?
         .success()
@@ -84,7 +230,7 @@ pub fn build_program(
         return Err(Error::Compile(program.to_string()));
     }
 
-    let bc_files: Vec<PathBuf> = fs::read_dir(out_dir)?
+    let mut bc_files: Vec<PathBuf> = fs::read_dir(out_dir)?
         .filter(|e| {
             e.as_ref()
                 .unwrap()
@@ -99,18 +245,232 @@ pub fn build_program(
         return Err(Error::MissingBitcode(program.to_string()));
     }
 
-    let bc_file = &bc_files[0];
+    Ok(Bitcode {
+        program: program.to_string(),
+        bc_file: bc_files.remove(0),
+        elf_target,
+    })
+}
+
+// Compiles `bitcode` to eBPF with `llc`. The output of llc is returned
+// rather than printed, so that parallel builds can print it in order.
+fn link_bitcode(
+    llc: &str,
+    bitcode: &Bitcode,
+    keep_intermediates: bool,
+    endian: Endian,
+) -> (Vec<u8>, Result<PathBuf, Error>) {
+    let output = match Command::new(llc)
+        .arg(format!("-march={}", endian.llc_march()))
+        .args(&["-filetype=obj", "-o"])
+        .arg(&bitcode.elf_target)
+        .arg(bitcode.bc_file.to_str().unwrap())
+        .output()
+    {
+        Ok(output) => output,
+        Err(e) => return (Vec::new(), Err(e.into())),
+    };
+    let mut log = output.stdout;
+    log.extend_from_slice(&output.stderr);
+    if !output.status.success() {
+        return (log, Err(Error::Link(bitcode.program.clone())));
+    }
+    if !keep_intermediates {
+        if let Err(e) = fs::remove_file(&bitcode.bc_file) {
+            return (log, Err(e.into()));
+        }
+    }
+
+    (log, Ok(bitcode.elf_target.clone()))
+}
+
+// Runs llc on `bitcodes` with up to `jobs` threads. The ELF files, and the
+// output of llc, are in the order of `bitcodes` regardless of the order the
+// builds complete in, and the first error in that order is returned.
+fn link_all(
+    bitcodes: Vec<Bitcode>,
+    jobs: usize,
+    keep_intermediates: bool,
+    endian: Endian,
+) -> Result<Vec<PathBuf>, Error> {
     let llc = get_llc_executable()?;
-    if !Command::new(llc)
-        .args(&llc_args)
-        .arg(&elf_target)
-        .arg(bc_file.to_str().unwrap())
+    let count = bitcodes.len();
+    let queue = Arc::new(Mutex::new(bitcodes.into_iter().enumerate()));
+    let (tx, rx) = mpsc::channel();
+    let threads: Vec<_> = (0..jobs.max(1).min(count))
+        .map(|_| {
+            let queue = queue.clone();
+            let tx = tx.clone();
+            let llc = llc.clone();
+            thread::spawn(move || loop {
+                let next = queue.lock().unwrap().next();
+                let (index, bitcode) = match next {
+                    Some(next) => next,
+                    None => break,
+                };
+                let (log, res) = link_bitcode(&llc, &bitcode, keep_intermediates, endian);
+                if tx.send((index, log, res)).is_err() {
+                    break;
+                }
+            })
+        })
+        .collect();
+    drop(tx);
+
+    let mut results: Vec<_> = rx.iter().collect();
+    let mut panic = None;
+    for thread in threads {
+        if let Err(payload) = thread.join() {
+            panic.get_or_insert_with(|| panic_message(payload.as_ref()));
+        }
+    }
+    // the programs the panicked threads were linking have no result
+    if let Some(panic) = panic {
+        return Err(Error::LinkPanic(panic));
+    }
+    results.sort_by_key(|(index, _, _)| *index);
+
+    let mut elfs = Vec::with_capacity(count);
+    for (_, log, res) in results {
+        io::stderr().write_all(&log)?;
+        elfs.push(res?);
+    }
+    Ok(elfs)
+}
+
+// Returns the message of a panic payload, which is a `&str` or a `String`
+// unless the panic was raised with `panic_any`.
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+/// Returns the number of programs `cargo bpf build` compiles in parallel by
+/// default: `CARGO_BUILD_JOBS` if set, otherwise the number of online CPUs.
+pub fn default_jobs() -> usize {
+    env::var("CARGO_BUILD_JOBS")
+        .ok()
+        .and_then(|jobs| parse_jobs(&jobs))
+        .or_else(|| redbpf::cpus::get_online().ok().map(|cpus| cpus.len()))
+        .unwrap_or(1)
+}
+
+/// Parses a `--jobs` value, which must be a positive integer.
+pub fn parse_jobs(jobs: &str) -> Option<usize> {
+    match jobs.parse::<usize>() {
+        Ok(0) | Err(_) => None,
+        Ok(jobs) => Some(jobs),
+    }
+}
+
+/// Compiles `program` with the Rust BPF target of `endian`, see
+/// `Backend::Rust`.
+///
+/// With `keep_intermediates`, rustc keeps its temporary files, including the
+/// LLVM bitcode, in the `deps` directory of the target.
+pub fn build_program_rust(
+    cargo: &Path,
+    package: &Path,
+    out_dir: &Path,
+    program: &str,
+    profile: Profile,
+    keep_intermediates: bool,
+    endian: Endian,
+) -> Result<PathBuf, Error> {
+    let current_dir = env::current_dir().unwrap();
+    let out_dir = current_dir.join(out_dir);
+    let _ = fs::remove_dir_all(&out_dir); // ignore error
+    fs::create_dir_all(&out_dir)?;
+    let elf_target = out_dir.join(format!("{}.elf", program));
+
+    if !Command::new(cargo)
+        .current_dir(package)
+        .args(profile.cargo_args())
+        .arg(format!("--features={}", PROBES_FEATURE))
+        .arg("--bin")
+        .arg(program)
+        .args(&["--target", rust_target(endian), "-Z", "build-std=core"])
+        .arg("--")
+        .args(&["-C", "panic=abort"])
+        .args(profile.rustc_args())
+        .args(if keep_intermediates {
+            &["-C", "save-temps"][..]
+        } else {
+            &[]
+        })
         .status()?
         .success()
     {
-        return Err(Error::Link(program.to_string()));
+        return Err(Error::Compile(program.to_string()));
+    }
+
+    let target_dir = env::var("CARGO_TARGET_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| package.join("target"));
+    let object = target_dir
+        .join(rust_target(endian))
+        .join(profile.name())
+        .join(program);
+    let bytes = fs::read(&object)?;
+    check_sections(program, &bytes)?;
+    fs::write(&elf_target, &bytes)?;
+
+    Ok(elf_target)
+}
+
+// Checks that the object contains programs in sections `redbpf::Module`
+// understands, and a license.
+fn check_sections(program: &str, bytes: &[u8]) -> Result<(), Error> {
+    let object =
+        Elf::parse(bytes).map_err(|e| Error::Sections(program.to_string(), e.to_string()))?;
+    let names = object
+        .section_headers
+        .iter()
+        .filter(|shdr| shdr.sh_type == hdr::SHT_PROGBITS)
+        .filter_map(|shdr| object.shdr_strtab.get_unsafe(shdr.sh_name));
+    check_section_names(names).map_err(|e| Error::Sections(program.to_string(), e))
+}
+
+fn check_section_names<'a, I: Iterator<Item = &'a str>>(names: I) -> Result<(), String> {
+    let mut license = false;
+    let mut programs = 0;
+    for name in names {
+        if name == "license" {
+            license = true;
+            continue;
+        }
+        let mut parts = name.splitn(2, '/');
+        let kind = match (parts.next(), parts.next()) {
+            (Some(kind), Some(_)) => kind,
+            // unnamed program sections like `xdp', other sections are data
+            (Some(kind), None) => {
+                if redbpf::ProgramKind::from_section(kind).is_ok() {
+                    programs += 1;
+                }
+                continue;
+            }
+            _ => continue,
+        };
+        if kind == "maps" {
+            continue;
+        }
+        if redbpf::ProgramKind::from_section(kind).is_err() {
+            return Err(format!("unknown program section `{}'", name));
+        }
+        programs += 1;
     }
 
+    if !license {
+        return Err("missing `license' section, use the `program!' macro".to_string());
+    }
+    if programs == 0 {
+        return Err("no program sections".to_string());
+    }
     Ok(())
 }
 
@@ -129,24 +489,43 @@ fn get_llc_executable() -> Result<String, Error> {
     return Err(Error::NoLLC);
 }
 
+/// Builds `programs`, or all the programs of `package` if empty.
+///
+/// With the `Llc` backend, programs are compiled to bitcode one at a time, as
+/// cargo holds a lock on the package while building, then compiled to eBPF
+/// by up to `jobs` parallel llc processes.
+///
+/// `endian` must match the byte order of the kernel loading the programs.
+/// `Endian::Big` requires the `Rust` backend, as with the `Llc` backend rustc
+/// lays out data with the byte order of the host.
+#[allow(clippy::too_many_arguments)]
 pub fn build(
     cargo: &Path,
     package: &Path,
     out_dir: &Path,
     programs: Vec<String>,
-) -> Result<(), Error> {
+    profile: Profile,
+    backend: Backend,
+    keep_intermediates: bool,
+    jobs: usize,
+    endian: Endian,
+) -> Result<Vec<PathBuf>, Error> {
     use toml_edit::{Document, Item};
 
+    check_endian(backend, endian)?;
     let path = package.join("Cargo.toml");
     if !path.exists() {
         return Err(Error::MissingManifest(path.clone()));
     }
 
+    let data = fs::read_to_string(path).unwrap();
+    let config = data.parse::<Document>().unwrap();
+    if config["features"][PROBES_FEATURE].is_none() {
+        return Err(Error::MissingProbesFeature);
+    }
     let targets = if !programs.is_empty() {
         programs
     } else {
-        let data = fs::read_to_string(path).unwrap();
-        let config = data.parse::<Document>().unwrap();
         let targets: Vec<String> = match &config["bin"] {
             Item::ArrayOfTables(array) => array
                 .iter()
@@ -156,18 +535,266 @@ pub fn build(
         };
         targets
     };
+    for program in targets.iter() {
+        let missing = missing_features(&config, program);
+        if !missing.is_empty() {
+            return Err(Error::MissingFeatures(program.clone(), missing));
+        }
+    }
 
-    for program in targets {
-        build_program(cargo, package, &out_dir.join(program.clone()), &program)?;
+    match backend {
+        Backend::Llc => {
+            // cargo serializes builds of the same package on its lock, so
+            // only llc runs in parallel
+            let mut bitcodes = Vec::new();
+            for program in targets {
+                bitcodes.push(compile_bitcode(
+                    cargo,
+                    package,
+                    &out_dir.join(program.clone()),
+                    &program,
+                    profile,
+                )?);
+            }
+            link_all(bitcodes, jobs, keep_intermediates, endian)
+        }
+        Backend::Rust => {
+            let mut elfs = Vec::new();
+            for program in targets {
+                elfs.push(build_program_rust(
+                    cargo,
+                    package,
+                    &out_dir.join(program.clone()),
+                    &program,
+                    profile,
+                    keep_intermediates,
+                    endian,
+                )?);
+            }
+            Ok(elfs)
+        }
+    }
+}
+
+// Returns the required features of `program` other than `PROBES_FEATURE`,
+// which cargo would refuse to build the program without.
+fn missing_features(config: &toml_edit::Document, program: &str) -> Vec<String> {
+    let bins = match config["bin"].as_array_of_tables() {
+        Some(bins) => bins,
+        None => return Vec::new(),
+    };
+    bins.iter()
+        .filter(|t| t["name"].as_str() == Some(program))
+        .filter_map(|t| t["required-features"].as_array())
+        .flat_map(|features| features.iter())
+        .filter_map(|f| f.as_str())
+        .filter(|f| *f != PROBES_FEATURE)
+        .map(String::from)
+        .collect()
+}
+
+/// Maximum number of instructions of a program before Linux 5.2, and of
+/// unprivileged programs.
+pub const BPF_MAXINSNS: usize = 4096;
+/// Maximum number of instructions of privileged programs since Linux 5.2.
+pub const BPF_COMPLEXITY_LIMIT_INSNS: usize = 1_000_000;
+
+// Returns a warning if `insn_count` is close to or above an instruction limit.
+fn size_warning(insn_count: usize) -> Option<String> {
+    let near = |limit: usize| insn_count * 10 >= limit * 9;
+    if insn_count > BPF_COMPLEXITY_LIMIT_INSNS {
+        Some(format!(
+            "exceeds the {} instructions limit of all kernels",
+            BPF_COMPLEXITY_LIMIT_INSNS
+        ))
+    } else if near(BPF_COMPLEXITY_LIMIT_INSNS) {
+        Some(format!("is close to the {} instructions limit", BPF_COMPLEXITY_LIMIT_INSNS))
+    } else if insn_count > BPF_MAXINSNS {
+        Some(format!(
+            "exceeds the {} instructions limit of kernels older than 5.2 and of \
+             unprivileged programs",
+            BPF_MAXINSNS
+        ))
+    } else if near(BPF_MAXINSNS) {
+        Some(format!(
+            "is close to the {} instructions limit of kernels older than 5.2 and of \
+             unprivileged programs",
+            BPF_MAXINSNS
+        ))
+    } else {
+        None
+    }
+}
+
+/// Prints the number of instructions of each program in `elf`, warning about
+/// programs close to the kernel limits.
+///
+/// Note that the verifier also limits the number of instructions it
+/// processes, which grows with branches and loops, so programs under the
+/// limits can still be rejected.
+pub fn size_report(elf: &Path) -> Result<(), Error> {
+    let manifest = Manifest::parse(&fs::read(elf)?)?;
+    for (section, insn_count, warning) in program_sizes(&manifest) {
+        println!("{}: {} instructions", section, insn_count);
+        if let Some(warning) = warning {
+            eprintln!("warning: `{}' {}", section, warning);
+        }
     }
 
     Ok(())
 }
 
-pub fn cmd_build(programs: Vec<String>) -> Result<(), CommandError> {
+fn program_sizes(manifest: &Manifest) -> Vec<(&str, usize, Option<String>)> {
+    manifest
+        .programs
+        .iter()
+        .map(|program| {
+            (
+                program.section.as_str(),
+                program.insn_count,
+                size_warning(program.insn_count),
+            )
+        })
+        .collect()
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn cmd_build(
+    programs: Vec<String>,
+    manifest: bool,
+    profile: Profile,
+    backend: Backend,
+    keep_intermediates: bool,
+    report: bool,
+    jobs: usize,
+    endian: Endian,
+) -> Result<(), CommandError> {
     let current_dir = std::env::current_dir().unwrap();
     // FIXME: parse --target-dir etc
-    let out_dir = PathBuf::from("target/release/bpf-programs");
-    let ret = build(Path::new("cargo"), &current_dir, &out_dir, programs)?;
-    Ok(ret)
+    // keep in sync with probe_path_in
+    let out_dir = crate::probe_path::target_dir(Path::new(""))
+        .join(profile.name())
+        .join("bpf-programs");
+    let elfs = build(
+        Path::new("cargo"),
+        &current_dir,
+        &out_dir,
+        programs,
+        profile,
+        backend,
+        keep_intermediates,
+        jobs,
+        endian,
+    )?;
+    if manifest {
+        for elf in elfs.iter() {
+            write_manifest(elf)?;
+        }
+    }
+    if report {
+        for elf in elfs.iter() {
+            size_report(elf)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_missing_features() {
+        let config = r#"
+[features]
+probes = []
+
+[[bin]]
+name = "plain"
+required-features = ["probes"]
+
+[[bin]]
+name = "extra"
+required-features = ["probes", "tls"]
+"#
+        .parse::<toml_edit::Document>()
+        .unwrap();
+        assert!(missing_features(&config, "plain").is_empty());
+        assert_eq!(missing_features(&config, "extra"), vec!["tls".to_string()]);
+    }
+
+    #[test]
+    fn test_parse_jobs() {
+        assert_eq!(parse_jobs("4"), Some(4));
+        assert_eq!(parse_jobs("0"), None);
+        assert_eq!(parse_jobs("-1"), None);
+        assert_eq!(parse_jobs("many"), None);
+    }
+
+    #[test]
+    fn test_rust_target() {
+        assert_eq!(rust_target(Endian::default()), "bpfel-unknown-none");
+        assert_eq!(rust_target(Endian::Big), "bpfeb-unknown-none");
+    }
+
+    #[test]
+    fn test_check_endian() {
+        assert!(check_endian(Backend::Llc, Endian::Little).is_ok());
+        assert!(check_endian(Backend::Llc, Endian::Big).is_err());
+        assert!(check_endian(Backend::Rust, Endian::Big).is_ok());
+    }
+
+    #[test]
+    fn test_size_warning() {
+        assert!(size_warning(100).is_none());
+        assert!(size_warning(3800).unwrap().contains("close to the 4096"));
+        assert!(size_warning(5000).unwrap().contains("exceeds the 4096"));
+        assert!(size_warning(500_000).is_none());
+        assert!(size_warning(950_000).unwrap().contains("close to the 1000000"));
+        assert!(size_warning(2_000_000).unwrap().contains("exceeds the 1000000"));
+    }
+
+    #[test]
+    fn test_program_sizes_unnamed_section() {
+        let code = vec![0u8; 5000 * 8];
+        let sections = vec![("license", &b"GPL\0"[..]), ("xdp", &code[..])];
+        let manifest = Manifest::from_sections(sections.into_iter(), true).unwrap();
+        let sizes = program_sizes(&manifest);
+        assert_eq!(sizes.len(), 1);
+        let (section, insn_count, warning) = &sizes[0];
+        assert_eq!(*section, "xdp");
+        assert_eq!(*insn_count, 5000);
+        assert!(warning.as_ref().unwrap().contains("exceeds the 4096"));
+    }
+
+    #[test]
+    fn test_panic_message() {
+        let payload = thread::spawn(|| panic!("llc crashed")).join().unwrap_err();
+        assert_eq!(panic_message(payload.as_ref()), "llc crashed");
+        let payload = thread::spawn(|| panic!("{} crashed", "llc"))
+            .join()
+            .unwrap_err();
+        assert_eq!(panic_message(payload.as_ref()), "llc crashed");
+    }
+
+    #[test]
+    fn test_check_section_names() {
+        let names = ["license", "version", "maps/events", "kprobe/do_fork", ".text"];
+        assert!(check_section_names(names.iter().cloned()).is_ok());
+
+        let names = ["license", "flow_dissector/dissect"];
+        assert!(check_section_names(names.iter().cloned()).is_ok());
+
+        let names = ["license", "version", "xdp", ".text"];
+        assert!(check_section_names(names.iter().cloned()).is_ok());
+
+        let names = ["license", "kprobe/do_fork", "xdpp/filter"];
+        assert!(check_section_names(names.iter().cloned()).is_err());
+
+        let names = ["kprobe/do_fork"];
+        assert!(check_section_names(names.iter().cloned()).is_err());
+
+        let names = ["license", "maps/events"];
+        assert!(check_section_names(names.iter().cloned()).is_err());
+    }
 }
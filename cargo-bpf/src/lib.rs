@@ -1,9 +1,16 @@
+#[macro_use]
+extern crate serde_derive;
+
 mod bindgen;
 mod build;
 mod ebpf_io;
 mod load;
+mod manifest;
+mod mirror;
 mod new;
 mod new_program;
+mod probe_path;
+mod vmlinux;
 
 pub struct CommandError(pub String);
 
@@ -14,7 +21,12 @@ impl std::convert::From<std::io::Error> for CommandError {
 }
 
 pub use self::bindgen::cmd_bindgen as bindgen;
-pub use build::{build, cmd_build};
+pub use build::{build, cmd_build, default_jobs, parse_jobs, size_report, Backend, Profile};
+pub use redbpf::build::Endian;
 pub use load::load;
+pub use manifest::{write_manifest, Manifest, MapEntry, ProgramEntry};
+pub use mirror::{mirror, write_mirror};
 pub use new::new;
-pub use new_program::new_program;
+pub use new_program::{new_program, PROGRAM_TYPES};
+pub use probe_path::{emit_probes_dir, probe_path, probe_path_from_out_dir, probe_path_in};
+pub use vmlinux::vmlinux;
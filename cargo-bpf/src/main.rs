@@ -76,6 +76,15 @@ required-features = ["probes"]
 As you can see, running `cargo bpf add` added a new `[bin]` target to the
 crate. This new target will contain the eBPF program code.
 
+Programs require the `probes` feature so that a plain `cargo build` of the
+crate skips them. `cargo bpf build` always enables it, and fails before building
+programs that require other features, which cargo would refuse to build.
+
+The generated code is a template for a kprobe. Pass `--type xdp`, `--type uprobe`
+or `--type uretprobe` to start from another template instead, eg.
+`cargo bpf add --type xdp block_http`. Tracepoint templates are not available
+since there is no `#[tracepoint]` macro yet.
+
 # Building
 
 Say that you're building an XDP program to block all traffic directed to port 80, and have therefore modified
@@ -112,6 +121,76 @@ $ cargo bpf build block_http
 by `redbpf::Module` and will place it in
 `target/release/bpf-programs/http_block.elf`.
 
+Passing `--manifest` additionally writes a JSON file next to each program
+(eg. `target/release/bpf-programs/block_http/block_http.json`) listing the
+sections of the programs and the names, types and sizes of the maps it
+contains, so that user space loaders don't need to hardcode them.
+
+Passing `--debug` builds with the debug profile and places the programs in
+`target/debug/bpf-programs` instead. Note that the verifier only accepts
+optimized code, so debug builds still use `opt-level=1`: this is useful to
+find out whether a problem is caused by the more aggressive optimizations
+of release builds, not to step through the code.
+
+Passing `--report` prints the number of instructions of each program, and
+warns about programs getting close to the 4096 instructions limit of older
+kernels and unprivileged programs, or the 1 million instructions limit of
+newer ones.
+
+Passing `--keep` keeps the LLVM bitcode each program is compiled from next to
+the ELF object, so that it can be inspected with `llvm-dis` when debugging
+code generation problems.
+
+When building several programs, `llc` compiles them in parallel, using
+`CARGO_BUILD_JOBS` or the number of CPUs jobs unless `--jobs` is given. The
+output of each `llc` process is printed in the order of the programs.
+
+eBPF objects are specific to the byte order of the kernel. Programs are
+built for little-endian kernels by default, pass `--endian big` together with
+`--rust` to build them for big-endian ones, eg. s390x. The default backend
+can't build big-endian programs, as rustc lays out their data with the byte
+order of the host.
+
+Passing `--rust` compiles the programs with the `bpfel-unknown-none` rustc
+target instead of going through `llc`. This is experimental: it needs a
+nightly toolchain to build `core` for the target, eg. `cargo +nightly bpf
+build --rust`, and [`bpf-linker`](https://github.com/alessandrod/bpf-linker)
+in the `PATH`. The sections of the resulting objects are checked to be
+loadable by `redbpf::Module`.
+
+# Generating vmlinux.h
+
+C programs using CO-RE include `vmlinux.h`, which declares all the types of
+the kernel. `cargo bpf vmlinux` generates it from the BTF of the running
+kernel, `/sys/kernel/btf/vmlinux`, without requiring `bpftool`:
+
+```
+$ cargo bpf vmlinux -o include
+generated include/vmlinux.h
+```
+
+The header is cached per kernel release in `target/bpf/vmlinux`. Pass
+`--btf` to generate it from the BTF of another kernel instead. Build scripts
+can use `redbpf::build::vmlinux::vmlinux_header` directly.
+
+# Sharing event types with user space
+
+The structs sent through perf maps have to be defined again in user space,
+with the same layout. `cargo bpf mirror` generates these definitions from
+the `#[map("name")] static NAME: PerfMap<Event>` declarations of a program,
+along with an `Event::from_bytes` decoder and a `NAME` constant holding the
+name of the map:
+
+```
+$ cargo bpf mirror -o ../agent/src/events.rs src/trace_conns/main.rs
+```
+
+Build scripts can call `cargo_bpf_lib::write_mirror` instead, and `include!`
+the generated file. Only the structs defined in the program source are
+mirrored. They must be `#[repr(C)]` or `#[repr(transparent)]` and only
+contain integers, floats, arrays and other mirrored structs, for which the
+generated code implements `redbpf::Pod`.
+
 # Loading a program during development
 
 `cargo bpf` includes a simple `load` subcommand that can be used during
@@ -155,6 +234,9 @@ fn main() {
                             .arg(Arg::with_name("name").long("name").value_name("NAME").help(
                                 "Set the resulting package name, defaults to the directory name",
                             ))
+                            .arg(Arg::with_name("FORCE").long("force").help(
+                                "Creates the package even if <PATH> already exists. Existing files are never overwritten",
+                            ))
                             .arg(Arg::with_name("PATH").required(true)),
                     )
                     .subcommand(
@@ -163,6 +245,9 @@ fn main() {
                             .arg(Arg::with_name("NAME").required(true).help(
                                 "The name of the eBPF program. The code will be created under src/<NAME>",
                             ))
+                            .arg(Arg::with_name("TYPE").value_name("TYPE").long("type")
+                                .default_value("kprobe")
+                                .help("The type of the program to generate: kprobe, xdp, uprobe or uretprobe"))
                     )
                     .subcommand(
                         SubCommand::with_name("bindgen")
@@ -174,9 +259,51 @@ fn main() {
                                 "Extra arguments passed to bindgen",
                             ))
                     )
+                    .subcommand(
+                        SubCommand::with_name("vmlinux")
+                            .about("Generates vmlinux.h from the BTF of the running kernel, for C programs using CO-RE")
+                            .arg(Arg::with_name("OUTPUT").short("o").long("output").value_name("DIR")
+                                .default_value("include")
+                                .help("The directory to write vmlinux.h to"))
+                            .arg(Arg::with_name("BTF").long("btf").value_name("FILE").help(
+                                "Generates the header from FILE instead of /sys/kernel/btf/vmlinux",
+                            ))
+                    )
+                    .subcommand(
+                        SubCommand::with_name("mirror")
+                            .about("Generates user space definitions of the events of the perf maps declared in a probe source")
+                            .arg(Arg::with_name("OUTPUT").short("o").long("output").value_name("FILE").help(
+                                "Writes the definitions to FILE instead of stdout",
+                            ))
+                            .arg(Arg::with_name("SOURCE").required(true).help(
+                                "The source file declaring the maps, eg. src/trace_conns/main.rs",
+                            ))
+                    )
                     .subcommand(
                         SubCommand::with_name("build")
                             .about("Compiles the eBPF programs in the package")
+                            .arg(Arg::with_name("MANIFEST").long("manifest").help(
+                                "Writes a JSON manifest listing the programs and maps next to each compiled program",
+                            ))
+                            .arg(Arg::with_name("DEBUG").long("debug").help(
+                                "Builds with the debug profile and less optimizations, placing the programs in target/debug/bpf-programs",
+                            ))
+                            .arg(Arg::with_name("REPORT").long("report").help(
+                                "Prints the number of instructions of each program, warning about programs close to the kernel limits",
+                            ))
+                            .arg(Arg::with_name("KEEP").long("keep").help(
+                                "Keeps the LLVM bitcode the programs are compiled from, for inspection with llvm-dis",
+                            ))
+                            .arg(Arg::with_name("RUST").long("rust").help(
+                                "Experimental: compiles with the bpfel-unknown-none rustc target. Requires nightly and bpf-linker",
+                            ))
+                            .arg(Arg::with_name("ENDIAN").long("endian").value_name("ENDIAN")
+                                .possible_values(&["little", "big"])
+                                .default_value("little")
+                                .help("The byte order of the kernel the programs are built for, big requires --rust"))
+                            .arg(Arg::with_name("JOBS").short("j").long("jobs").value_name("N").help(
+                                "Number of programs to compile to eBPF in parallel, defaults to CARGO_BUILD_JOBS or the number of CPUs",
+                            ))
                             .arg(Arg::with_name("NAME").required(false).multiple(true).help(
                                 "The names of the programs to compile. When no names are specified, all the programs are built",
                             ))
@@ -197,12 +324,14 @@ fn main() {
     if let Some(m) = matches.subcommand_matches("new") {
         let path = m.value_of("PATH").map(PathBuf::from).unwrap();
 
-        if let Err(e) = cargo_bpf::new(&path, m.value_of("NAME")) {
+        if let Err(e) = cargo_bpf::new(&path, m.value_of("NAME"), m.is_present("FORCE")) {
             clap::Error::with_description(&e.0, clap::ErrorKind::InvalidValue).exit()
         }
     }
     if let Some(m) = matches.subcommand_matches("add") {
-        if let Err(e) = cargo_bpf::new_program(m.value_of("NAME").unwrap()) {
+        if let Err(e) =
+            cargo_bpf::new_program(m.value_of("NAME").unwrap(), m.value_of("TYPE").unwrap())
+        {
             clap::Error::with_description(&e.0, clap::ErrorKind::InvalidValue).exit()
         }
     }
@@ -216,12 +345,68 @@ fn main() {
             clap::Error::with_description(&e.0, clap::ErrorKind::InvalidValue).exit()
         }
     }
+    if let Some(m) = matches.subcommand_matches("vmlinux") {
+        let output = m.value_of("OUTPUT").map(PathBuf::from).unwrap();
+        let btf = m.value_of("BTF").map(PathBuf::from);
+        match cargo_bpf::vmlinux(&output, btf.as_ref().map(PathBuf::as_path)) {
+            Ok(header) => println!("generated {}", header.display()),
+            Err(e) => clap::Error::with_description(&e.0, clap::ErrorKind::InvalidValue).exit(),
+        }
+    }
+    if let Some(m) = matches.subcommand_matches("mirror") {
+        let source = m.value_of("SOURCE").map(PathBuf::from).unwrap();
+        let result = match m.value_of("OUTPUT") {
+            Some(output) => {
+                cargo_bpf::write_mirror(&source, &PathBuf::from(output)).map_err(|e| e.to_string())
+            }
+            None => std::fs::read_to_string(&source)
+                .map_err(|e| e.to_string())
+                .and_then(|code| cargo_bpf::mirror(&code).map_err(|e| e.to_string()))
+                .map(|code| print!("{}", code)),
+        };
+        if let Err(e) = result {
+            clap::Error::with_description(&e, clap::ErrorKind::InvalidValue).exit()
+        }
+    }
     if let Some(m) = matches.subcommand_matches("build") {
         let programs = m
             .values_of("NAME")
             .map(|i| i.map(|s| String::from(s)).collect())
             .unwrap_or_else(Vec::new);
-        if let Err(e) = cargo_bpf::cmd_build(programs) {
+        let profile = if m.is_present("DEBUG") {
+            cargo_bpf::Profile::Debug
+        } else {
+            cargo_bpf::Profile::Release
+        };
+        let backend = if m.is_present("RUST") {
+            cargo_bpf::Backend::Rust
+        } else {
+            cargo_bpf::Backend::Llc
+        };
+        let jobs = match m.value_of("JOBS") {
+            Some(jobs) => cargo_bpf::parse_jobs(jobs).unwrap_or_else(|| {
+                clap::Error::with_description(
+                    "--jobs must be a positive integer",
+                    clap::ErrorKind::InvalidValue,
+                )
+                .exit()
+            }),
+            None => cargo_bpf::default_jobs(),
+        };
+        let endian = match m.value_of("ENDIAN") {
+            Some("big") => cargo_bpf::Endian::Big,
+            _ => cargo_bpf::Endian::Little,
+        };
+        if let Err(e) = cargo_bpf::cmd_build(
+            programs,
+            m.is_present("MANIFEST"),
+            profile,
+            backend,
+            m.is_present("KEEP"),
+            m.is_present("REPORT"),
+            jobs,
+            endian,
+        ) {
             clap::Error::with_description(&e.0, clap::ErrorKind::InvalidValue).exit()
         }
     }
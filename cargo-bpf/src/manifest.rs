@@ -0,0 +1,180 @@
+use goblin::elf::{section_header as hdr, Elf};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+use crate::build::Error;
+
+const INSN_SIZE: usize = 8;
+
+/// Description of the programs and maps contained in a compiled eBPF object.
+///
+/// The manifest is written next to the ELF object by `cargo bpf build
+/// --manifest`, so that user space loaders can find programs and maps without
+/// hardcoding section names.
+#[derive(Debug, Serialize)]
+pub struct Manifest {
+    pub programs: Vec<ProgramEntry>,
+    pub maps: Vec<MapEntry>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProgramEntry {
+    pub section: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub name: String,
+    /// The number of eBPF instructions of the program.
+    pub insn_count: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MapEntry {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: u32,
+    pub key_size: u32,
+    pub value_size: u32,
+    pub max_entries: u32,
+}
+
+impl Manifest {
+    /// Parses the program and map sections of an eBPF ELF object.
+    pub fn parse(bytes: &[u8]) -> Result<Manifest, Error> {
+        let object = Elf::parse(bytes).map_err(|e| Error::Manifest(e.to_string()))?;
+        let sections = object
+            .section_headers
+            .iter()
+            .filter(|shdr| shdr.sh_type == hdr::SHT_PROGBITS)
+            .filter_map(|shdr| {
+                let section = object.shdr_strtab.get_unsafe(shdr.sh_name)?;
+                let start = shdr.sh_offset as usize;
+                let end = (shdr.sh_offset + shdr.sh_size) as usize;
+                Some((section, &bytes[start..end]))
+            });
+
+        Manifest::from_sections(sections, object.little_endian)
+    }
+
+    pub(crate) fn from_sections<'a, I>(sections: I, little_endian: bool) -> Result<Manifest, Error>
+    where
+        I: Iterator<Item = (&'a str, &'a [u8])>,
+    {
+        let mut programs = Vec::new();
+        let mut maps = Vec::new();
+
+        for (section, data) in sections {
+            let mut names = section.splitn(2, '/');
+            let (kind, name) = match (names.next(), names.next()) {
+                (Some(kind), name) => (kind, name),
+                _ => continue,
+            };
+
+            match (kind, name) {
+                ("maps", Some(name)) => maps.push(MapEntry::parse(name, data, little_endian)?),
+                ("maps", None) => {}
+                (kind, name) => {
+                    if redbpf::ProgramKind::from_section(kind).is_ok() {
+                        programs.push(ProgramEntry {
+                            section: section.to_string(),
+                            kind: kind.to_string(),
+                            // unnamed sections are named after their kind,
+                            // like the loader does
+                            name: name.unwrap_or(kind).to_string(),
+                            insn_count: data.len() / INSN_SIZE,
+                        });
+                    }
+                }
+            }
+        }
+
+        Ok(Manifest { programs, maps })
+    }
+}
+
+impl MapEntry {
+    fn parse(name: &str, data: &[u8], little_endian: bool) -> Result<MapEntry, Error> {
+        // struct bpf_map_def is five consecutive u32s
+        if data.len() < 5 * 4 {
+            return Err(Error::Manifest(format!("invalid map definition `{}'", name)));
+        }
+        let field = |i: usize| {
+            let mut buf = [0u8; 4];
+            buf.copy_from_slice(&data[i * 4..(i + 1) * 4]);
+            // the object may target another endianness than the host
+            if little_endian {
+                u32::from_le_bytes(buf)
+            } else {
+                u32::from_be_bytes(buf)
+            }
+        };
+
+        Ok(MapEntry {
+            name: name.to_string(),
+            kind: field(0),
+            key_size: field(1),
+            value_size: field(2),
+            max_entries: field(3),
+        })
+    }
+}
+
+/// Writes the manifest for the given ELF object to a `.json` file next to it.
+pub fn write_manifest(elf: &Path) -> Result<PathBuf, Error> {
+    let data = fs::read(elf)?;
+    let manifest = Manifest::parse(&data)?;
+    let path = elf.with_extension("json");
+    serde_json::to_writer_pretty(File::create(&path)?, &manifest)
+        .map_err(|e| Error::Manifest(e.to_string()))?;
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_map_entry() {
+        let mut def = Vec::new();
+        for field in &[4u32, 4, 4, 128, 0] {
+            def.extend_from_slice(&field.to_le_bytes());
+        }
+        let map = MapEntry::parse("events", &def, true).unwrap();
+        assert_eq!(map.name, "events");
+        assert_eq!(map.kind, 4);
+        assert_eq!(map.key_size, 4);
+        assert_eq!(map.value_size, 4);
+        assert_eq!(map.max_entries, 128);
+
+        assert!(MapEntry::parse("events", &def[..8], true).is_err());
+    }
+
+    #[test]
+    fn test_parse_big_endian_map_entry() {
+        let mut def = Vec::new();
+        for field in &[1u32, 4, 8, 1024, 0] {
+            def.extend_from_slice(&field.to_be_bytes());
+        }
+        let map = MapEntry::parse("counts", &def, false).unwrap();
+        assert_eq!(map.kind, 1);
+        assert_eq!(map.key_size, 4);
+        assert_eq!(map.value_size, 8);
+        assert_eq!(map.max_entries, 1024);
+    }
+
+    #[test]
+    fn test_parse_unnamed_program() {
+        let code = [0u8; 2 * INSN_SIZE];
+        let sections = vec![
+            ("license", &b"GPL\0"[..]),
+            ("version", &[0u8; 4][..]),
+            ("xdp", &code[..]),
+        ];
+        let manifest = Manifest::from_sections(sections.into_iter(), true).unwrap();
+        assert_eq!(manifest.programs.len(), 1);
+        assert_eq!(manifest.programs[0].section, "xdp");
+        assert_eq!(manifest.programs[0].kind, "xdp");
+        assert_eq!(manifest.programs[0].name, "xdp");
+        assert_eq!(manifest.programs[0].insn_count, 2);
+    }
+}
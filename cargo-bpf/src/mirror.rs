@@ -0,0 +1,504 @@
+use proc_macro2::TokenStream;
+use quote::quote;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use syn::{
+    parse_quote, Attribute, GenericArgument, Ident, Item, ItemStruct, LitStr, Meta, NestedMeta,
+    PathArguments, Type, Visibility,
+};
+
+use crate::build::Error;
+
+/// A perf event array declared with `#[map("name")]`.
+struct PerfMapDecl {
+    ident: Ident,
+    name: String,
+    module: String,
+    event: syn::Path,
+}
+
+fn map_name(attrs: &[Attribute]) -> Option<String> {
+    attrs
+        .iter()
+        .find(|attr| attr.path.is_ident("map"))
+        .and_then(|attr| attr.parse_args::<LitStr>().ok())
+        .map(|name| name.value())
+}
+
+// Returns `Event` for `PerfMap<Event>`.
+fn perf_map_event(ty: &Type) -> Option<syn::Path> {
+    let segment = match ty {
+        Type::Path(path) => path.path.segments.last()?,
+        _ => return None,
+    };
+    if segment.ident != "PerfMap" {
+        return None;
+    }
+    match &segment.arguments {
+        PathArguments::AngleBracketed(args) => match args.args.first()? {
+            GenericArgument::Type(Type::Path(path)) => Some(path.path.clone()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn join(module: &str, ident: &str) -> String {
+    if module.is_empty() {
+        ident.to_string()
+    } else {
+        format!("{}::{}", module, ident)
+    }
+}
+
+// Collects the perf maps and structs declared in `items`, including in
+// inline modules. Structs are keyed by their path in the source, eg.
+// `events::Connection`, as modules may define structs with the same name.
+fn collect<'a>(
+    items: &'a [Item],
+    module: &str,
+    maps: &mut Vec<PerfMapDecl>,
+    structs: &mut HashMap<String, &'a ItemStruct>,
+) {
+    for item in items {
+        match item {
+            Item::Static(item) => {
+                if let (Some(name), Some(event)) = (map_name(&item.attrs), perf_map_event(&item.ty))
+                {
+                    maps.push(PerfMapDecl {
+                        ident: item.ident.clone(),
+                        name,
+                        module: module.to_string(),
+                        event,
+                    });
+                }
+            }
+            Item::Struct(item) => {
+                structs.insert(join(module, &item.ident.to_string()), item);
+            }
+            Item::Mod(item) => {
+                if let Some((_, items)) = &item.content {
+                    let module = join(module, &item.ident.to_string());
+                    collect(items, &module, maps, structs);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// Returns the key of the struct `path` refers to from `module`. `use`
+// declarations aren't followed, so paths that don't resolve from `module`
+// or from the root fall back to the only struct with that name, if any.
+fn resolve(
+    module: &str,
+    path: &syn::Path,
+    structs: &HashMap<String, &ItemStruct>,
+) -> Option<String> {
+    let mut scope: Vec<&str> = module.split("::").filter(|m| !m.is_empty()).collect();
+    let mut segments = Vec::new();
+    for (i, segment) in path.segments.iter().enumerate() {
+        if !segment.arguments.is_empty() {
+            return None;
+        }
+        match segment.ident.to_string().as_str() {
+            "crate" if i == 0 => scope.clear(),
+            "self" if i == 0 => {}
+            "super" => {
+                scope.pop()?;
+            }
+            ident => segments.push(ident.to_string()),
+        }
+    }
+    let segments = segments.join("::");
+    let candidates = [join(&scope.join("::"), &segments), segments.clone()];
+    if let Some(key) = candidates.iter().find(|key| structs.contains_key(*key)) {
+        return Some(key.clone());
+    }
+    let mut same_name = structs
+        .keys()
+        .filter(|key| key.rsplit("::").next() == Some(segments.as_str()));
+    match (same_name.next(), same_name.next()) {
+        (Some(key), None) => Some(key.clone()),
+        _ => None,
+    }
+}
+
+// The primitive types that can be read from raw perf data, see
+// `redbpf::Pod`.
+const POD_TYPES: &[&str] = &[
+    "u8",
+    "u16",
+    "u32",
+    "u64",
+    "u128",
+    "usize",
+    "i8",
+    "i16",
+    "i32",
+    "i64",
+    "i128",
+    "isize",
+    "f32",
+    "f64",
+    "c_char",
+    "c_schar",
+    "c_uchar",
+    "c_short",
+    "c_ushort",
+    "c_int",
+    "c_uint",
+    "c_long",
+    "c_ulong",
+    "c_longlong",
+    "c_ulonglong",
+    "c_float",
+    "c_double",
+];
+
+// Checks that a field of type `ty`, defined in `module`, can be read from
+// raw perf data, returning the key of the struct it contains if any.
+// Mirrored field types are rewritten to the name of the struct, as the
+// generated code is flat.
+fn mirror_field(
+    ty: &mut Type,
+    module: &str,
+    structs: &HashMap<String, &ItemStruct>,
+) -> Result<Option<String>, String> {
+    match ty {
+        Type::Array(array) => mirror_field(&mut array.elem, module, structs),
+        Type::Path(path) if path.qself.is_none() => {
+            if let Some(key) = resolve(module, &path.path, structs) {
+                let ident = &structs[&key].ident;
+                *ty = parse_quote!(#ident);
+                return Ok(Some(key));
+            }
+            let pod = match path.path.segments.last() {
+                Some(segment) => {
+                    segment.arguments.is_empty()
+                        && POD_TYPES.contains(&segment.ident.to_string().as_str())
+                }
+                None => false,
+            };
+            if pod {
+                Ok(None)
+            } else {
+                Err(quote!(#ty).to_string())
+            }
+        }
+        ty => Err(quote!(#ty).to_string()),
+    }
+}
+
+// Returns true for `#[repr(C)]` and `#[repr(transparent)]`, which give the
+// struct the same layout in the probe and in user space.
+fn is_repr_c(attr: &Attribute) -> bool {
+    match attr.parse_meta() {
+        Ok(Meta::List(list)) if list.path.is_ident("repr") => list.nested.iter().any(|m| match m {
+            NestedMeta::Meta(Meta::Path(path)) => {
+                path.is_ident("C") || path.is_ident("transparent")
+            }
+            _ => false,
+        }),
+        _ => false,
+    }
+}
+
+// Returns the user space version of the probe struct `key`, public with
+// public fields, `Clone + Copy` and `redbpf::Pod`, along with the keys of
+// the structs it contains.
+fn mirror_struct(
+    key: &str,
+    structs: &HashMap<String, &ItemStruct>,
+) -> Result<(TokenStream, Vec<String>), Error> {
+    let mut item = structs[key].clone();
+    if !item.attrs.iter().any(is_repr_c) {
+        return Err(Error::Mirror(format!(
+            "`{}' must be #[repr(C)] or #[repr(transparent)] to have the same layout in user space",
+            key
+        )));
+    }
+    if !item.generics.params.is_empty() {
+        return Err(Error::Mirror(format!("`{}' can't be generic", key)));
+    }
+    let module = match key.rfind("::") {
+        Some(i) => &key[..i],
+        None => "",
+    };
+    item.vis = parse_quote!(pub);
+    item.attrs
+        .retain(|attr| attr.path.is_ident("repr") || attr.path.is_ident("doc"));
+    item.attrs.push(parse_quote!(#[derive(Clone, Copy)]));
+
+    let mut contained = Vec::new();
+    for (i, field) in item.fields.iter_mut().enumerate() {
+        field.vis = Visibility::Public(parse_quote!(pub));
+        match mirror_field(&mut field.ty, module, structs) {
+            Ok(Some(key)) => contained.push(key),
+            Ok(None) => {}
+            Err(ty) => {
+                let name = field
+                    .ident
+                    .as_ref()
+                    .map(|ident| ident.to_string())
+                    .unwrap_or_else(|| i.to_string());
+                return Err(Error::Mirror(format!(
+                    "field `{}' of `{}' has type `{}', which can't be read from raw perf data",
+                    name, key, ty
+                )));
+            }
+        }
+    }
+    let ident = &item.ident;
+    let code = quote! {
+        #item
+        unsafe impl ::redbpf::Pod for #ident {}
+    };
+
+    Ok((code, contained))
+}
+
+/// Generates user space definitions of the events of the perf maps declared
+/// in the probe source `source`.
+///
+/// Every `#[map("name")] static NAME: PerfMap<Event>` declaration yields
+/// a copy of `Event`, and of the structs it contains, along with a
+/// `from_bytes` decoder for the raw perf events, and a `NAME` constant
+/// holding the name of the map. The generated code is meant to be included
+/// in the user space crate with `include!`, and implements `redbpf::Pod` for
+/// the structs.
+///
+/// The structs must be `#[repr(C)]` or `#[repr(transparent)]`, and their
+/// fields integers, floats, arrays or other structs of the source. Structs
+/// of different modules are mirrored side by side, so their names must be
+/// distinct.
+pub fn mirror(source: &str) -> Result<String, Error> {
+    let file = syn::parse_file(source).map_err(|e| Error::Mirror(e.to_string()))?;
+    let mut maps = Vec::new();
+    let mut structs = HashMap::new();
+    collect(&file.items, "", &mut maps, &mut structs);
+
+    let mut mirrored: HashMap<String, String> = HashMap::new();
+    let mut items: Vec<TokenStream> = vec![quote! {
+        #[allow(unused_imports)]
+        use std::os::raw::*;
+        #[allow(dead_code)]
+        mod cty {
+            pub use std::os::raw::*;
+        }
+    }];
+    for map in maps.iter() {
+        let key = resolve(&map.module, &map.event, &structs).ok_or_else(|| {
+            let event = &map.event;
+            Error::Mirror(format!(
+                "the event type `{}' of map `{}' is not a struct defined in the source",
+                quote!(#event),
+                map.name
+            ))
+        })?;
+        let ident = &map.ident;
+        let name = &map.name;
+        let doc = format!("The name of the `{}` perf map.", name);
+        items.push(quote! {
+            #[doc = #doc]
+            pub const #ident: &str = #name;
+        });
+        if mirrored.values().any(|k| *k == key) {
+            continue;
+        }
+        let event = &structs[&key].ident;
+        items.push(quote! {
+            impl #event {
+                /// Reads the event from the data of a perf event, `None` if
+                /// the data is too short.
+                pub fn from_bytes(data: &[u8]) -> Option<#event> {
+                    <#event as ::redbpf::Pod>::from_bytes(data)
+                }
+            }
+        });
+
+        // mirror the structs the event contains too
+        let mut pending = vec![key];
+        while let Some(key) = pending.pop() {
+            let ident = structs[&key].ident.to_string();
+            match mirrored.get(&ident) {
+                Some(k) if *k == key => continue,
+                Some(k) => {
+                    return Err(Error::Mirror(format!(
+                        "`{}' and `{}' would both be mirrored as `{}'",
+                        k, key, ident
+                    )))
+                }
+                None => {}
+            }
+            let (code, contained) = mirror_struct(&key, &structs)?;
+            items.push(code);
+            mirrored.insert(ident, key);
+            pending.extend(contained);
+        }
+    }
+
+    let mut code = String::from("// Generated by `cargo bpf mirror`, do not edit.\n");
+    for item in items {
+        code.push_str(&item.to_string());
+        code.push('\n');
+    }
+
+    Ok(code)
+}
+
+/// Writes the user space definitions generated by `mirror` from the probe
+/// source at `source` to `out`, eg. from a build script.
+pub fn write_mirror(source: &Path, out: &Path) -> Result<(), Error> {
+    let code = mirror(&fs::read_to_string(source)?)?;
+    fs::write(out, code)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PROBE: &str = r#"
+        #[repr(C)]
+        #[derive(Debug)]
+        struct Addr {
+            ip: u32,
+            port: u16,
+        }
+
+        /// A connection.
+        #[repr(C)]
+        struct Connection {
+            pid: u64,
+            addrs: [Addr; 2],
+            comm: [cty::c_char; 16],
+        }
+
+        #[map("connections")]
+        static mut CONNECTIONS: PerfMap<Connection> = PerfMap::with_max_entries(1024);
+
+        #[map("counts")]
+        static mut COUNTS: HashMap<u32, u64> = HashMap::with_max_entries(1024);
+    "#;
+
+    #[test]
+    fn test_mirror() {
+        let code = mirror(PROBE).unwrap();
+        let file = syn::parse_file(&code).unwrap();
+        let mut maps = Vec::new();
+        let mut structs = HashMap::new();
+        collect(&file.items, "", &mut maps, &mut structs);
+        assert!(maps.is_empty());
+        let mut names: Vec<&String> = structs.keys().collect();
+        names.sort();
+        assert_eq!(names, vec!["Addr", "Connection"]);
+        assert!(file.items.iter().any(|item| match item {
+            Item::Const(item) => item.ident == "CONNECTIONS",
+            _ => false,
+        }));
+        assert!(code.contains("fn from_bytes"));
+        assert!(code.contains("unsafe impl :: redbpf :: Pod for Addr"));
+        assert!(!code.contains("COUNTS"));
+
+        let connection = structs["Connection"];
+        assert!(connection.attrs.iter().any(is_repr_c));
+        assert!(connection.fields.iter().all(|f| match f.vis {
+            Visibility::Public(_) => true,
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn test_mirror_undefined_event() {
+        let probe = r#"
+            #[map("events")]
+            static mut EVENTS: PerfMap<Missing> = PerfMap::with_max_entries(1024);
+        "#;
+        assert!(mirror(probe).is_err());
+    }
+
+    #[test]
+    fn test_mirror_requires_repr_c() {
+        let probe = r#"
+            struct Event {
+                pid: u32,
+            }
+
+            #[map("events")]
+            static mut EVENTS: PerfMap<Event> = PerfMap::with_max_entries(1024);
+        "#;
+        assert!(mirror(probe).is_err());
+        assert!(mirror(&probe.replace("struct", "#[repr(transparent)] struct")).is_ok());
+        assert!(mirror(&probe.replace("struct", "#[repr(C, packed)] struct")).is_ok());
+        assert!(mirror(&probe.replace("struct", "#[repr(packed)] struct")).is_err());
+    }
+
+    #[test]
+    fn test_mirror_field_types() {
+        let probe = r#"
+            #[repr(C)]
+            struct Event {
+                value: TYPE,
+            }
+
+            #[map("events")]
+            static mut EVENTS: PerfMap<Event> = PerfMap::with_max_entries(1024);
+        "#;
+        for ty in &["u64", "[u8; 4]", "cty::c_ulong", "[[i16; 2]; 2]", "f64"] {
+            assert!(mirror(&probe.replace("TYPE", ty)).is_ok(), "{}", ty);
+        }
+        for ty in &[
+            "bool",
+            "char",
+            "*const u8",
+            "&'static u8",
+            "Option<u32>",
+            "Missing",
+        ] {
+            assert!(mirror(&probe.replace("TYPE", ty)).is_err(), "{}", ty);
+        }
+    }
+
+    #[test]
+    fn test_mirror_modules() {
+        let probe = r#"
+            mod v4 {
+                #[repr(C)]
+                pub struct Addr {
+                    ip: u32,
+                }
+            }
+
+            mod v6 {
+                #[repr(C)]
+                pub struct Addr {
+                    ip: [u8; 16],
+                }
+
+                #[repr(C)]
+                pub struct Event {
+                    addr: Addr,
+                    old: super::v4::Addr,
+                }
+
+                #[map("events")]
+                static mut EVENTS: PerfMap<Event> = PerfMap::with_max_entries(1024);
+            }
+        "#;
+        // both structs would be mirrored as `Addr`
+        assert!(mirror(probe).is_err());
+
+        let probe = probe.replace("old: super::v4::Addr,", "");
+        let code = mirror(&probe).unwrap();
+        let file = syn::parse_file(&code).unwrap();
+        let mut structs = HashMap::new();
+        collect(&file.items, "", &mut Vec::new(), &mut structs);
+        let addr = structs["Addr"];
+        assert_eq!(addr.fields.len(), 1);
+        let ty = &addr.fields.iter().next().unwrap().ty;
+        assert_eq!(quote!(#ty).to_string(), "[u8 ; 16]");
+    }
+}
@@ -4,16 +4,34 @@ use std::path::PathBuf;
 
 use crate::CommandError;
 
-pub fn new(path: &PathBuf, name: Option<&str>) -> Result<(), CommandError> {
-    if path.exists() {
+/// Creates a new eBPF package at `path`.
+///
+/// Unless `force` is set, `path` must not exist. With `force`, the package is
+/// created in an existing directory, but existing files are never overwritten.
+pub fn new(path: &PathBuf, name: Option<&str>, force: bool) -> Result<(), CommandError> {
+    if path.exists() && !force {
         return Err(CommandError(format!(
             "destination `{}' already exists",
             path.to_str().unwrap()
         )));
     }
+    if path.exists() && !path.is_dir() {
+        return Err(CommandError(format!(
+            "destination `{}' is not a directory",
+            path.to_str().unwrap()
+        )));
+    }
+    let files = [path.join("Cargo.toml"), path.join("src").join("lib.rs")];
+    if let Some(file) = files.iter().find(|f| f.exists()) {
+        return Err(CommandError(format!(
+            "`{}' already exists, refusing to overwrite it",
+            file.to_str().unwrap()
+        )));
+    }
 
     fs::create_dir_all(path.join("src"))?;
-    let name = name.or_else(|| path.file_name()?.to_str()).unwrap();
+    let dir_name = path.canonicalize()?;
+    let name = name.or_else(|| dir_name.file_name()?.to_str()).unwrap();
     let mut file = File::create(path.join("Cargo.toml"))?;
     write!(
         &mut file,
@@ -1,13 +1,31 @@
 use std::fs::{self, File, OpenOptions};
-use std::io::{Seek, SeekFrom, Write};
+use std::io::{self, Seek, SeekFrom, Write};
 use std::path::Path;
 use toml_edit;
 
+use crate::build::PROBES_FEATURE;
 use crate::CommandError;
 
-pub fn new_program(name: &str) -> Result<(), CommandError> {
+/// The program types `new_program` can generate templates for.
+pub const PROGRAM_TYPES: [&str; 4] = ["kprobe", "xdp", "uprobe", "uretprobe"];
+
+pub fn new_program(name: &str, program_type: &str) -> Result<(), CommandError> {
     use toml_edit::{value, Array, ArrayOfTables, Document, Item, Table};
 
+    if program_type == "tracepoint" {
+        return Err(CommandError(
+            "there is no `#[tracepoint]' macro yet, tracepoint programs can't be generated"
+                .to_string(),
+        ));
+    }
+    if !PROGRAM_TYPES.contains(&program_type) {
+        return Err(CommandError(format!(
+            "unsupported program type `{}', expected one of: {}",
+            program_type,
+            PROGRAM_TYPES.join(", ")
+        )));
+    }
+
     let current_dir = std::env::current_dir().unwrap();
     let path = Path::new("Cargo.toml");
     if !path.exists() {
@@ -17,13 +35,11 @@ pub fn new_program(name: &str) -> Result<(), CommandError> {
         )));
     }
     let data = fs::read_to_string(path).unwrap();
-    let mut config = data.parse::<Document>().unwrap();
+    let mut config = data
+        .parse::<Document>()
+        .map_err(|e| CommandError(format!("failed to parse `Cargo.toml': {}", e)))?;
 
-    let crate_name = config["lib"]["name"]
-        .as_str()
-        .or(config["package"]["name"].as_str())
-        .ok_or(CommandError("invalid manifest syntax".to_string()))
-        .map(String::from)?;
+    let crate_name = crate_name(&config, &current_dir)?;
 
     let mut targets = match &config["bin"] {
         Item::None => ArrayOfTables::new(),
@@ -39,6 +55,22 @@ pub fn new_program(name: &str) -> Result<(), CommandError> {
             name
         )));
     }
+    if name.replace('-', "_") == crate_name.replace('-', "_") {
+        return Err(CommandError(format!(
+            "a program can't have the same name as the crate `{}'",
+            crate_name
+        )));
+    }
+    let probe_dir = Path::new("src").join(name);
+    if fs::read_dir(&probe_dir)
+        .map(|mut entries| entries.next().is_some())
+        .unwrap_or(false)
+    {
+        return Err(CommandError(format!(
+            "the directory `{}' already exists and is not empty",
+            probe_dir.display()
+        )));
+    }
 
     let mut target = Table::new();
     target.entry("name").or_insert(value(name));
@@ -46,7 +78,7 @@ pub fn new_program(name: &str) -> Result<(), CommandError> {
         .entry("path")
         .or_insert(value(format!("src/{}/main.rs", name)));
     let mut features = Array::default();
-    features.push("probes");
+    features.push(PROBES_FEATURE);
     target.entry("required-features").or_insert(value(features));
 
     targets.append(target);
@@ -82,8 +114,40 @@ use cty::*;
     )?;
     let main_rs = probe_dir.join("main.rs");
     let mut main_rs = File::create(main_rs)?;
+    match program_type {
+        "xdp" => write_xdp_template(&mut main_rs, &crate_name, name)?,
+        "uprobe" | "uretprobe" => {
+            write_uprobe_template(&mut main_rs, &crate_name, name, program_type)?
+        }
+        _ => write_kprobe_template(&mut main_rs, &crate_name, name)?,
+    }
+
+    Ok(())
+}
+
+/// Returns the name the probes crate is imported as.
+///
+/// The name is taken from `[lib]`, then `[package]`. Workspace members may
+/// inherit the package name from the workspace (`name = { workspace = true }`), in
+/// which case it's derived from the crate directory.
+fn crate_name(config: &toml_edit::Document, dir: &Path) -> Result<String, CommandError> {
+    let name = config["lib"]["name"]
+        .as_str()
+        .or_else(|| config["package"]["name"].as_str())
+        .map(String::from)
+        .or_else(|| {
+            dir.file_name()
+                .and_then(|name| name.to_str())
+                .map(String::from)
+        })
+        .ok_or_else(|| CommandError("invalid manifest syntax".to_string()))?;
+
+    Ok(name.replace('-', "_"))
+}
+
+fn write_kprobe_template(main_rs: &mut File, crate_name: &str, name: &str) -> io::Result<()> {
     write!(
-        &mut main_rs,
+        main_rs,
         r#"
 #![no_std]
 #![no_main]
@@ -113,14 +177,158 @@ program!(0xFFFFFFFE, "GPL");
 //     id: pid_tgid >> 32,
 //     ...
 //   }};
-//   unsafe {{ syscall_events.insert(ctx, event) }};
+//   unsafe {{ syscall_events.insert_unchecked(ctx, event) }};
 //
 //   return 0;
 // }}
 "#,
         lib = crate_name,
         name = name
-    )?;
+    )
+}
 
-    Ok(())
+fn write_xdp_template(main_rs: &mut File, crate_name: &str, name: &str) -> io::Result<()> {
+    write!(
+        main_rs,
+        r#"
+#![no_std]
+#![no_main]
+
+use cty::*;
+
+use redbpf_probes::bindings::*;
+use redbpf_probes::xdp::{{PerfMap, XdpAction, XdpContext}};
+use redbpf_macros::{{map, program, xdp}};
+
+// Use the types you're going to share with userspace, eg:
+// use {lib}::{name}::SomeEvent;
+
+program!(0xFFFFFFFE, "GPL");
+
+// The maps and probe functions go here, eg:
+//
+// #[map("packet_events")]
+// static mut packet_events: PerfMap<SomeEvent> = PerfMap::with_max_entries(1024);
+//
+// #[xdp]
+// pub extern "C" fn {name}(ctx: XdpContext) -> XdpAction {{
+//   if let Some(transport) = ctx.transport() {{
+//     let event = SomeEvent {{
+//       port: transport.dest(),
+//       ...
+//     }};
+//     // append the first 64 bytes of the packet to the event
+//     unsafe {{ packet_events.insert_unchecked(&ctx, event, 64) }};
+//   }}
+//
+//   XdpAction::Pass
+// }}
+"#,
+        lib = crate_name,
+        name = name
+    )
+}
+
+fn write_uprobe_template(
+    main_rs: &mut File,
+    crate_name: &str,
+    name: &str,
+    program_type: &str,
+) -> io::Result<()> {
+    write!(
+        main_rs,
+        r#"
+#![no_std]
+#![no_main]
+
+use cty::*;
+
+use redbpf_probes::bindings::*;
+use redbpf_probes::maps::*;
+use redbpf_macros::{{map, program, {kind}}};
+
+// Use the types you're going to share with userspace, eg:
+// use {lib}::{name}::SomeEvent;
+
+program!(0xFFFFFFFE, "GPL");
+
+// The maps and probe functions go here, eg:
+//
+// #[map("readline_events")]
+// static mut readline_events: PerfMap<SomeEvent> = PerfMap::new();
+//
+// #[{kind}("readline")]
+// pub extern "C" fn {name}(ctx: *mut pt_regs) -> i32 {{
+//   let pid_tgid = bpf_get_current_pid_tgid();
+//   ...
+//
+//   let event = SomeEvent {{
+//     id: pid_tgid >> 32,
+//     ...
+//   }};
+//   unsafe {{ readline_events.insert_unchecked(ctx, event) }};
+//
+//   return 0;
+// }}
+"#,
+        kind = program_type,
+        lib = crate_name,
+        name = name
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use toml_edit::Document;
+
+    #[test]
+    fn test_crate_name() {
+        let dir = Path::new("/src/my-probes");
+        let config = r#"
+[package]
+name = "my-probes"
+edition = "2021"
+
+[lib]
+name = "probes"
+"#
+        .parse::<Document>()
+        .unwrap();
+        assert_eq!(crate_name(&config, dir).unwrap(), "probes");
+
+        let config = r#"
+[package]
+name = "my-probes"
+"#
+        .parse::<Document>()
+        .unwrap();
+        assert_eq!(crate_name(&config, dir).unwrap(), "my_probes");
+    }
+
+    #[test]
+    fn test_crate_name_workspace_member() {
+        let dir = Path::new("/src/workspace/kernel-probes");
+        let config = r#"
+[package]
+name = { workspace = true }
+version = { workspace = true }
+edition = { workspace = true }
+
+[dependencies]
+redbpf-probes = { workspace = true }
+"#
+        .parse::<Document>()
+        .unwrap();
+        assert_eq!(crate_name(&config, dir).unwrap(), "kernel_probes");
+    }
+
+    #[test]
+    fn test_unsupported_program_type() {
+        let err = new_program("trace_open", "tracepoint").unwrap_err();
+        assert!(err.0.contains("no `#[tracepoint]' macro"));
+
+        let err = new_program("filter", "socket_filter").unwrap_err();
+        assert!(err.0.contains("unsupported program type"));
+    }
 }
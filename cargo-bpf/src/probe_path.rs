@@ -0,0 +1,281 @@
+use std::env;
+use std::ffi::OsStr;
+use std::path::{Component, Path, PathBuf};
+
+use crate::build::Profile;
+
+// The environment variable `emit_probes_dir` sets for
+// `include_probe_from_out_dir!`.
+const PROGRAMS_DIR_VAR: &str = "CARGO_BPF_PROGRAMS_DIR";
+
+/// Returns the cargo target directory of the package in `package_dir`:
+/// `CARGO_TARGET_DIR` if set, relative to the current directory like cargo
+/// does if it is a relative path, `package_dir/target` otherwise.
+pub(crate) fn target_dir(package_dir: &Path) -> PathBuf {
+    match env::var_os("CARGO_TARGET_DIR") {
+        Some(dir) => {
+            let cwd = env::current_dir().ok();
+            resolve_target_dir(Path::new(&dir), cwd.as_ref().map(PathBuf::as_path))
+        }
+        None => package_dir.join("target"),
+    }
+}
+
+// Resolves `CARGO_TARGET_DIR` against the current directory `cwd`.
+fn resolve_target_dir(dir: &Path, cwd: Option<&Path>) -> PathBuf {
+    match cwd {
+        Some(cwd) => cwd.join(dir),
+        None => dir.to_path_buf(),
+    }
+}
+
+/// Returns the path of the ELF object of the program `name` built by `cargo
+/// bpf build`, relative to the package directory.
+///
+/// The target directory can be overridden with `CARGO_TARGET_DIR`.
+pub fn probe_path(name: &str) -> PathBuf {
+    probe_path_in(&target_dir(Path::new("")), Profile::Release, name)
+}
+
+/// Returns the path of the ELF object of the program `name` built with
+/// `profile` in the cargo target directory `target_dir`.
+pub fn probe_path_in(target_dir: &Path, profile: Profile, name: &str) -> PathBuf {
+    programs_dir(target_dir, profile)
+        .join(name)
+        .join(format!("{}.elf", name))
+}
+
+fn programs_dir(target_dir: &Path, profile: Profile) -> PathBuf {
+    target_dir.join(profile.name()).join("bpf-programs")
+}
+
+// Returns the profile of the build script environment variable `PROFILE`.
+fn profile_from_name(name: &str) -> Profile {
+    match name {
+        "debug" => Profile::Debug,
+        _ => Profile::Release,
+    }
+}
+
+// Returns the target directory containing `out_dir`.
+//
+// `out_dir` is `<target>/<profile>/build/<package>-<hash>/out`, or
+// `<target>/<triple>/<profile>/build/<package>-<hash>/out` when cargo is
+// given `--target`, `triple` being the `TARGET` of the build script. `cargo
+// bpf build` doesn't take `--target`, so the programs are in
+// `<target>/<profile>` in both cases.
+fn target_dir_from_out_dir(out_dir: &Path, triple: Option<&str>) -> Option<PathBuf> {
+    let components: Vec<Component> = out_dir.components().collect();
+    let build = components
+        .iter()
+        .rposition(|c| *c == Component::Normal(OsStr::new("build")))?;
+    let mut target_dir: PathBuf = components[..build.checked_sub(1)?].iter().collect();
+    if triple.is_some() && target_dir.file_name().and_then(OsStr::to_str) == triple {
+        target_dir.pop();
+    }
+
+    Some(target_dir)
+}
+
+// Returns the directory the programs are built to, from the environment
+// variables cargo sets for build scripts. `CARGO_TARGET_DIR` takes precedence
+// over the target directory containing `out_dir`, and is relative to the
+// current directory `cwd` if it is a relative path.
+fn programs_dir_from_env(
+    cargo_target_dir: Option<&Path>,
+    cwd: Option<&Path>,
+    out_dir: &Path,
+    triple: Option<&str>,
+    profile: &str,
+) -> Option<PathBuf> {
+    let target_dir = match cargo_target_dir {
+        Some(dir) => resolve_target_dir(dir, cwd),
+        None => target_dir_from_out_dir(out_dir, triple)?,
+    };
+    Some(programs_dir(&target_dir, profile_from_name(profile)))
+}
+
+fn programs_dir_from_build_env() -> Option<PathBuf> {
+    let cargo_target_dir = env::var_os("CARGO_TARGET_DIR").map(PathBuf::from);
+    let cwd = env::current_dir().ok();
+    let triple = env::var("TARGET").ok();
+    programs_dir_from_env(
+        cargo_target_dir.as_ref().map(PathBuf::as_path),
+        cwd.as_ref().map(PathBuf::as_path),
+        Path::new(&env::var_os("OUT_DIR")?),
+        triple.as_ref().map(String::as_str),
+        &env::var("PROFILE").ok()?,
+    )
+}
+
+/// Returns the path of the ELF object of the program `name` from a build
+/// script.
+///
+/// The target directory is `CARGO_TARGET_DIR` if set, relative to the
+/// current directory if it is a relative path, or found from `OUT_DIR`
+/// otherwise, including when cargo is given `--target`. Programs are looked up in the profile of the build, so `cargo
+/// build --release` uses the programs built by `cargo bpf build --release`.
+pub fn probe_path_from_out_dir(name: &str) -> Option<PathBuf> {
+    Some(
+        programs_dir_from_build_env()?
+            .join(name)
+            .join(format!("{}.elf", name)),
+    )
+}
+
+/// Tells `include_probe_from_out_dir!` where the programs built by `cargo bpf
+/// build` are.
+///
+/// Call it from the build script of the crate using
+/// `include_probe_from_out_dir!`. The directory is resolved like
+/// `probe_path_from_out_dir` does.
+///
+/// # Panics
+///
+/// Panics if it is not called from a build script.
+pub fn emit_probes_dir() {
+    let dir =
+        programs_dir_from_build_env().expect("emit_probes_dir must be called from a build script");
+    println!("cargo:rustc-env={}={}", PROGRAMS_DIR_VAR, dir.display());
+    println!("cargo:rerun-if-env-changed=CARGO_TARGET_DIR");
+}
+
+/// Embeds the ELF object of a program built by `cargo bpf build --release`
+/// in the binary.
+///
+/// `include_probe!("block_http")` evaluates to the `&'static [u8; N]`
+/// contents of `target/release/bpf-programs/block_http/block_http.elf`,
+/// relative to the directory of the crate being compiled. Programs must be
+/// built before the crate that includes them. Use
+/// `include_probe_from_out_dir!` to honor `CARGO_TARGET_DIR` and the build
+/// profile.
+///
+/// ```ignore
+/// use redbpf::Module;
+///
+/// let module = Module::parse(cargo_bpf::include_probe!("block_http")).unwrap();
+/// ```
+#[macro_export]
+macro_rules! include_probe {
+    ($name:literal) => {
+        include_bytes!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/target/release/bpf-programs/",
+            $name,
+            "/",
+            $name,
+            ".elf"
+        ))
+    };
+}
+
+/// Same as `include_probe!`, with the programs of the target directory and
+/// profile of the build.
+///
+/// `include_probe_from_out_dir!("block_http")` evaluates to the contents of
+/// `<target>/<profile>/bpf-programs/block_http/block_http.elf`. The directory
+/// is set by `emit_probes_dir`, which the build script of the crate must
+/// call.
+///
+/// ```ignore
+/// // build.rs
+/// fn main() {
+///     cargo_bpf::emit_probes_dir();
+/// }
+///
+/// // main.rs
+/// use redbpf::Module;
+///
+/// let module = Module::parse(cargo_bpf::include_probe_from_out_dir!("block_http")).unwrap();
+/// ```
+#[macro_export]
+macro_rules! include_probe_from_out_dir {
+    ($name:literal) => {
+        include_bytes!(concat!(
+            env!("CARGO_BPF_PROGRAMS_DIR"),
+            "/",
+            $name,
+            "/",
+            $name,
+            ".elf"
+        ))
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_probe_path_in() {
+        assert_eq!(
+            probe_path_in(Path::new("target"), Profile::Release, "block_http"),
+            PathBuf::from("target/release/bpf-programs/block_http/block_http.elf")
+        );
+        assert_eq!(
+            probe_path_in(Path::new("/tmp/target"), Profile::Debug, "trace"),
+            PathBuf::from("/tmp/target/debug/bpf-programs/trace/trace.elf")
+        );
+    }
+
+    #[test]
+    fn test_programs_dir_from_env() {
+        let cwd = Some(Path::new("/src/app"));
+        let out_dir = Path::new("/src/app/target/debug/build/app-0123/out");
+        let triple = Some("x86_64-unknown-linux-gnu");
+        assert_eq!(
+            programs_dir_from_env(None, cwd, out_dir, triple, "debug"),
+            Some(PathBuf::from("/src/app/target/debug/bpf-programs"))
+        );
+        assert_eq!(
+            programs_dir_from_env(
+                Some(Path::new("/tmp/target")),
+                cwd,
+                out_dir,
+                triple,
+                "release"
+            ),
+            Some(PathBuf::from("/tmp/target/release/bpf-programs"))
+        );
+        assert_eq!(
+            programs_dir_from_env(Some(Path::new("../target")), cwd, out_dir, triple, "debug"),
+            Some(PathBuf::from("/src/app/../target/debug/bpf-programs"))
+        );
+    }
+
+    #[test]
+    fn test_target_dir_from_out_dir() {
+        let triple = Some("x86_64-unknown-linux-gnu");
+        assert_eq!(
+            target_dir_from_out_dir(Path::new("/src/target/debug/build/app-0123/out"), triple),
+            Some(PathBuf::from("/src/target"))
+        );
+        // cargo build --target
+        assert_eq!(
+            target_dir_from_out_dir(
+                Path::new("/src/target/x86_64-unknown-linux-gnu/release/build/app-0123/out"),
+                triple
+            ),
+            Some(PathBuf::from("/src/target"))
+        );
+        // a `build` directory above the target directory
+        assert_eq!(
+            target_dir_from_out_dir(Path::new("/build/target/debug/build/app-0123/out"), triple),
+            Some(PathBuf::from("/build/target"))
+        );
+        assert_eq!(target_dir_from_out_dir(Path::new("/out"), triple), None);
+    }
+
+    #[test]
+    fn test_resolve_target_dir() {
+        let cwd = Some(Path::new("/src/workspace"));
+        assert_eq!(
+            resolve_target_dir(Path::new("target"), cwd),
+            PathBuf::from("/src/workspace/target")
+        );
+        assert_eq!(
+            resolve_target_dir(Path::new("/tmp/target"), cwd),
+            PathBuf::from("/tmp/target")
+        );
+    }
+}
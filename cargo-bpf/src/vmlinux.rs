@@ -0,0 +1,31 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use redbpf::build::vmlinux::{vmlinux_header, write_vmlinux_header, VMLINUX_H};
+
+use crate::CommandError;
+
+/// The directory, relative to the package, `vmlinux.h` is cached in per
+/// kernel release.
+const CACHE_DIR: &str = "target/bpf/vmlinux";
+
+/// Generates `vmlinux.h` in `output_dir` and returns its path.
+///
+/// The header is generated from the BTF file `btf` if given, otherwise from
+/// the BTF of the running kernel, in which case it is cached per kernel
+/// release in `target/bpf/vmlinux` and only copied to `output_dir`.
+pub fn vmlinux(output_dir: &Path, btf: Option<&Path>) -> Result<PathBuf, CommandError> {
+    let header = output_dir.join(VMLINUX_H);
+    match btf {
+        Some(btf) => write_vmlinux_header(btf, &header).map_err(|e| CommandError(e.to_string()))?,
+        None => {
+            let cached = vmlinux_header(Path::new(CACHE_DIR))
+                .map_err(|e| CommandError(e.to_string()))?
+                .join(VMLINUX_H);
+            fs::create_dir_all(output_dir)?;
+            fs::copy(&cached, &header)?;
+        }
+    }
+
+    Ok(header)
+}
@@ -0,0 +1,24 @@
+/* SPDX-License-Identifier: (LGPL-2.1 OR BSD-2-Clause) */
+#ifndef __BPF_CORE_READ_H__
+#define __BPF_CORE_READ_H__
+
+/*
+ * Reads the field pointed to by src into dst, recording a CO-RE relocation
+ * for the field access. When the program is loaded, the field offset is
+ * adjusted to match the layout of the running kernel.
+ *
+ * Requires compiling with -g so that clang emits BTF.
+ */
+#define BPF_CORE_READ(dst, src)						\
+	bpf_probe_read((dst), sizeof(*(src)),				\
+		       __builtin_preserve_access_index(src))
+
+/* Evaluates to 1 if the field exists in the running kernel, 0 otherwise. */
+#define bpf_core_field_exists(field)					\
+	__builtin_preserve_field_info(field, 2)
+
+/* Evaluates to the size of the field in the running kernel. */
+#define bpf_core_field_size(field)					\
+	__builtin_preserve_field_info(field, 1)
+
+#endif
@@ -0,0 +1,55 @@
+/* SPDX-License-Identifier: (LGPL-2.1 OR BSD-2-Clause) */
+#ifndef __BPF_TASK_H__
+#define __BPF_TASK_H__
+
+#include <linux/sched.h>
+#include <linux/nsproxy.h>
+#include <net/net_namespace.h>
+#include "bpf_core_read.h"
+
+/*
+ * Accessors for common task_struct fields. Reads go through
+ * BPF_CORE_READ() so that the field offsets are relocated against the
+ * running kernel when compiled with -g.
+ */
+
+static __always_inline struct task_struct *bpf_task_current(void)
+{
+	return (struct task_struct *)bpf_get_current_task();
+}
+
+static __always_inline __u32 bpf_task_tgid(struct task_struct *task)
+{
+	__u32 tgid = 0;
+
+	BPF_CORE_READ(&tgid, &task->tgid);
+	return tgid;
+}
+
+static __always_inline __u32 bpf_task_parent_tgid(struct task_struct *task)
+{
+	struct task_struct *parent = NULL;
+
+	BPF_CORE_READ(&parent, &task->real_parent);
+	if (!parent)
+		return 0;
+	return bpf_task_tgid(parent);
+}
+
+static __always_inline __u32 bpf_task_netns_inum(struct task_struct *task)
+{
+	struct nsproxy *nsproxy = NULL;
+	struct net *net = NULL;
+	__u32 inum = 0;
+
+	BPF_CORE_READ(&nsproxy, &task->nsproxy);
+	if (!nsproxy)
+		return 0;
+	BPF_CORE_READ(&net, &nsproxy->net_ns);
+	if (!net)
+		return 0;
+	BPF_CORE_READ(&inum, &net->ns.inum);
+	return inum;
+}
+
+#endif
@@ -47,8 +47,9 @@ use syn::parse::{Parse, ParseStream};
 use syn::punctuated::Punctuated;
 use syn::token::Comma;
 use syn::{
-    parse_macro_input, parse_quote, parse_str, Block, Expr, ExprLit, FnArg, ItemFn, Lit, Pat,
-    PatIdent, PatType, Result, Stmt,
+    parse_macro_input, parse_quote, parse_str, Abi, Block, Error, Expr, ExprLit, FnArg, ItemFn,
+    ItemStruct, Lit, Member, Pat, PatIdent, PatType, Result, ReturnType, Type, TypePath,
+    Visibility,
 };
 
 fn inline_string_literal(e: &Expr) -> (TokenStream2, TokenStream2) {
@@ -143,6 +144,68 @@ pub fn map(attrs: TokenStream, item: TokenStream) -> TokenStream {
     tokens.into()
 }
 
+/// Declares that the program `program` must be stored at `index` of the
+/// `ProgramArray` map `map`, so that probes can tail call into it.
+///
+/// The declarations are stored in the `tail_calls` section of the ELF object,
+/// and `redbpf::Module::load` sets the entries after loading all the
+/// programs.
+///
+/// # Example
+/// ```
+/// #[map("dispatch")]
+/// static mut DISPATCH: ProgramArray = ProgramArray::with_max_entries(8);
+///
+/// tail_call!("dispatch", 0, "parse_ipv4");
+/// tail_call!("dispatch", 1, "parse_ipv6");
+/// ```
+#[proc_macro]
+pub fn tail_call(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as Args);
+    let args: Vec<&Expr> = input.0.iter().collect();
+    if args.len() != 3 {
+        return Error::new_spanned(&input.0, "expected `map, index, program`")
+            .to_compile_error()
+            .into();
+    }
+    let string = |e: &Expr| match e {
+        Expr::Lit(ExprLit {
+            lit: Lit::Str(s), ..
+        }) => Ok(s.value()),
+        e => Err(Error::new_spanned(e, "expected string literal")),
+    };
+    let index = match args[1] {
+        Expr::Lit(ExprLit {
+            lit: Lit::Int(i), ..
+        }) => i.base10_parse::<u32>(),
+        e => Err(Error::new_spanned(e, "expected integer literal")),
+    };
+    let (map, index, program) = match (string(args[0]), index, string(args[2])) {
+        (Ok(map), Ok(index), Ok(program)) => (map, index, program),
+        (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => return e.to_compile_error().into(),
+    };
+
+    // entries are `map/index/program` strings, concatenated by the linker.
+    // Declaring the same index twice fails to link.
+    let entry = format!("{}/{}/{}", map, index, program);
+    let (entry_ty, entry) = inline_string_literal(&parse_quote!(#entry));
+    let ident = Ident::new(
+        &format!(
+            "_tail_call_{}_{}",
+            map.replace(|c: char| !c.is_alphanumeric(), "_"),
+            index
+        ),
+        Span::call_site(),
+    );
+    let tokens = quote! {
+        #[no_mangle]
+        #[link_section = "tail_calls"]
+        pub static #ident: #entry_ty = #entry;
+    };
+
+    tokens.into()
+}
+
 fn bpf_helpers(prefix: Option<&str>) -> Block {
     let mut funcs = String::from(include!(concat!(env!("OUT_DIR"), "/gen_helper_funcs.rs")));
     if let Some(prefix) = prefix {
@@ -229,6 +292,9 @@ fn probe_impl(ty: &str, attrs: TokenStream, mut item: ItemFn) -> TokenStream {
 
 /// Attribute macro that must be used to define [`kprobes`](https://www.kernel.org/doc/Documentation/kprobes.txt).
 ///
+/// The probe can be placed at an offset within the function using the
+/// `function+offset` syntax, eg. `#[kprobe("do_sys_open+0x10")]`.
+///
 /// # Example
 /// ```
 /// #[kprobe("__x64_sys_clone")]
@@ -242,8 +308,228 @@ pub fn kprobe(attrs: TokenStream, item: TokenStream) -> TokenStream {
     probe_impl("kprobe", attrs, item).into()
 }
 
+fn uprobe_impl(ty: &str, attrs: TokenStream, item: ItemFn) -> TokenStream {
+    let args = parse_macro_input!(attrs as Args);
+    let mut args = args.0.into_iter();
+    let name = args.next().expect("no symbol name");
+    let sleepable = match args.next() {
+        None => false,
+        Some(Expr::Path(ref p)) if p.path.is_ident("sleepable") => true,
+        Some(e) => return Error::new_spanned(e, "expected `sleepable`").to_compile_error().into(),
+    };
+    if !sleepable {
+        return probe_impl(ty, quote!(#name).into(), item);
+    }
+
+    // The user function is wrapped so that it gets the `Sleepable` token,
+    // which only exists in programs loaded with BPF_F_SLEEPABLE
+    let ident = item.sig.ident.clone();
+    let vis = item.vis.clone();
+    let output = item.sig.output.clone();
+    let inner_ident = Ident::new(&format!("_sleepable_{}", ident), Span::call_site());
+    let mut inner = item;
+    inner.sig.ident = inner_ident.clone();
+    inner.sig.abi = None;
+    inner.vis = Visibility::Inherited;
+    inject_bpf_helpers(&mut inner, None);
+    let wrapper: ItemFn = parse_quote! {
+        #vis extern "C" fn #ident(ctx: *mut pt_regs) #output {
+            #[inline(always)]
+            #inner
+
+            let sleepable = unsafe { ::redbpf_probes::helpers::Sleepable::new_unchecked() };
+            #inner_ident(ctx, &sleepable)
+        }
+    };
+    probe_impl(&format!("{}.s", ty), quote!(#name).into(), wrapper)
+}
+
+/// Attribute macro that must be used to define uprobes.
+///
+/// Takes the name of the user space function to probe. With the `sleepable`
+/// option the program is loaded as sleepable (Linux 6.3+), and the function
+/// takes a second `&Sleepable` argument that gives access to helpers like
+/// `copy_from_user`. Kprobes can't be sleepable.
+///
+/// # Example
+/// ```
+/// #[uprobe("readline")]
+/// pub extern "C" fn enter_readline(ctx: *mut pt_regs) {
+///     ...
+/// }
+///
+/// #[uretprobe("readline", sleepable)]
+/// pub fn exit_readline(ctx: *mut pt_regs, sleepable: &Sleepable) {
+///     let line = unsafe { (*ctx).rax } as *const [u8; 64];
+///     if let Some(line) = unsafe { copy_from_user(sleepable, line) } {
+///         ...
+///     }
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn uprobe(attrs: TokenStream, item: TokenStream) -> TokenStream {
+    let item = parse_macro_input!(item as ItemFn);
+    uprobe_impl("uprobe", attrs, item)
+}
+
+/// Attribute macro that must be used to define uretprobes. See `uprobe`.
+#[proc_macro_attribute]
+pub fn uretprobe(attrs: TokenStream, item: TokenStream) -> TokenStream {
+    let item = parse_macro_input!(item as ItemFn);
+    uprobe_impl("uretprobe", attrs, item)
+}
+
+/// Derives `redbpf_probes::maps::MapKey` for structs without padding.
+///
+/// Compilation fails with an array length mismatch if the size of the struct
+/// is larger than the sum of the sizes of its fields, or if a field is not a
+/// `MapKey` itself.
+#[proc_macro_derive(MapKey)]
+pub fn derive_map_key(item: TokenStream) -> TokenStream {
+    let item = parse_macro_input!(item as ItemStruct);
+    if !item.generics.params.is_empty() {
+        return Error::new_spanned(&item.generics, "MapKey can't be derived for generic structs")
+            .to_compile_error()
+            .into();
+    }
+
+    let ident = &item.ident;
+    let tys: Vec<&Type> = item.fields.iter().map(|f| &f.ty).collect();
+    let tokens = quote! {
+        unsafe impl ::redbpf_probes::maps::MapKey for #ident {}
+
+        // the struct has padding if it's larger than its fields
+        const _: [(); 0] = [(); ::core::mem::size_of::<#ident>()
+            - (0 #(+ ::core::mem::size_of::<#tys>())*)];
+
+        const _: fn() = || {
+            fn assert_map_key<T: ::redbpf_probes::maps::MapKey>() {}
+            #(assert_map_key::<#tys>();)*
+        };
+    };
+
+    tokens.into()
+}
+
+// Returns whether `ty` is a path to the type named `name`, eg. `SpinLock` or
+// `maps::SpinLock`.
+fn is_type_named(ty: &Type, name: &str) -> bool {
+    match ty {
+        Type::Path(TypePath { path, .. }) => path
+            .segments
+            .last()
+            .map(|segment| segment.ident == name)
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+// Returns the top level field of type `name` of `item`, `None` if there's
+// no such field.
+fn find_field(item: &ItemStruct, name: &str) -> Result<Option<Member>> {
+    let mut members = item
+        .fields
+        .iter()
+        .enumerate()
+        .filter(|(_, field)| is_type_named(&field.ty, name))
+        .map(|(index, field)| match &field.ident {
+            Some(ident) => Member::Named(ident.clone()),
+            None => Member::Unnamed(index.into()),
+        });
+    let member = members.next();
+    if members.next().is_some() {
+        return Err(Error::new_spanned(
+            &item.ident,
+            format!("map values can only hold one {}", name),
+        ));
+    }
+
+    Ok(member)
+}
+
+// Expands to the offset of `member` in `ident`, as an `Option<usize>`.
+fn field_offset(ident: &Ident, member: Option<&Member>) -> TokenStream2 {
+    match member {
+        Some(member) => quote! {
+            Some({
+                let value = ::core::mem::MaybeUninit::<#ident>::uninit();
+                let base = value.as_ptr();
+                unsafe {
+                    (::core::ptr::addr_of!((*base).#member) as *const u8)
+                        .offset_from(base as *const u8) as usize
+                }
+            })
+        },
+        None => quote!(None),
+    }
+}
+
+/// Derives `redbpf_probes::maps::MapValue` for `#[repr(C)]` structs, and
+/// `Lockable` if the struct has a `SpinLock` field.
+///
+/// The offsets of the `SpinLock` and `Timer` fields are recorded so that the
+/// loader can describe them with BTF.
+///
+/// The fields are found by type name, and must be top level fields of the
+/// struct.
+#[proc_macro_derive(MapValue)]
+pub fn derive_map_value(item: TokenStream) -> TokenStream {
+    let item = parse_macro_input!(item as ItemStruct);
+    if !item.generics.params.is_empty() {
+        return Error::new_spanned(&item.generics, "MapValue can't be derived for generic structs")
+            .to_compile_error()
+            .into();
+    }
+    let repr_c = item.attrs.iter().any(|attr| {
+        attr.path.is_ident("repr")
+            && attr
+                .tokens
+                .to_string()
+                .split(|c: char| !c.is_alphanumeric())
+                .any(|word| word == "C")
+    });
+    if !repr_c {
+        return Error::new_spanned(&item.ident, "MapValue can only be derived for #[repr(C)] structs")
+            .to_compile_error()
+            .into();
+    }
+    let (spin_lock, timer) = match (find_field(&item, "SpinLock"), find_field(&item, "Timer")) {
+        (Ok(spin_lock), Ok(timer)) => (spin_lock, timer),
+        (Err(e), _) | (_, Err(e)) => return e.to_compile_error().into(),
+    };
+
+    let ident = &item.ident;
+    let spin_lock_offset = field_offset(ident, spin_lock.as_ref());
+    let timer_offset = field_offset(ident, timer.as_ref());
+    let lockable = spin_lock.map(|member| {
+        quote! {
+            unsafe impl ::redbpf_probes::maps::Lockable for #ident {
+                #[inline]
+                fn spin_lock(&mut self) -> &mut ::redbpf_probes::maps::SpinLock {
+                    &mut self.#member
+                }
+            }
+        }
+    });
+    let tokens = quote! {
+        unsafe impl ::redbpf_probes::maps::MapValue for #ident {
+            const SPIN_LOCK_OFFSET: Option<usize> = #spin_lock_offset;
+            const TIMER_OFFSET: Option<usize> = #timer_offset;
+        }
+
+        #lockable
+    };
+
+    tokens.into()
+}
+
 /// Attribute macro that must be used to define [`XDP` probes](https://www.iovisor.org/technology/xdp).
 ///
+/// Probes must be declared as `extern "C"`, take a single `XdpContext`
+/// argument and return an `XdpAction`, otherwise compilation fails. The
+/// section is named after the function, unless a name is given, eg.
+/// `#[xdp("block_http")]`.
+///
 /// See also the [`XDP` API provided by
 /// `redbpf-probes`](https://redsift.github.io/rust/redbpf/doc/redbpf_probes/xdp/index.html).
 ///
@@ -257,21 +543,92 @@ pub fn kprobe(attrs: TokenStream, item: TokenStream) -> TokenStream {
 /// ```
 #[proc_macro_attribute]
 pub fn xdp(attrs: TokenStream, item: TokenStream) -> TokenStream {
-    let mut item = parse_macro_input!(item as ItemFn);
-    let arg = item.sig.inputs.pop().unwrap();
-    let pat = match arg.value() {
-        FnArg::Typed(PatType { pat, .. }) => pat,
-        _ => panic!("unexpected xdp probe signature"),
+    let item = parse_macro_input!(item as ItemFn);
+    let ctx = match check_xdp_signature(&item) {
+        Ok(ctx) => ctx.clone(),
+        Err(e) => return e.to_compile_error().into(),
     };
-    let ident = if let Pat::Ident(PatIdent { ident, .. }) = &**pat {
-        ident
+
+    // The user function is wrapped so that the program always returns a
+    // valid XdpAction discriminant
+    let ident = item.sig.ident.clone();
+    let vis = item.vis.clone();
+    let inner_ident = Ident::new(&format!("_xdp_{}", ident), Span::call_site());
+    let mut inner = item;
+    inner.sig.ident = inner_ident.clone();
+    inner.sig.abi = None;
+    inner.vis = Visibility::Inherited;
+    inject_bpf_helpers(&mut inner, None);
+    let raw_ctx = Ident::new(&format!("_raw_{}", ctx), Span::call_site());
+    let wrapper: ItemFn = parse_quote! {
+        #vis extern "C" fn #ident(#raw_ctx: *mut xdp_md) -> u32 {
+            #[inline(always)]
+            #inner
+
+            let action: XdpAction = #inner_ident(XdpContext { ctx: #raw_ctx });
+            action as u32
+        }
+    };
+
+    let attrs = if attrs.is_empty() {
+        let name = ident.to_string();
+        quote!(#name).into()
     } else {
-        panic!("unexpected xdp probe signature")
+        attrs
     };
-    let raw_ctx = Ident::new(&format!("_raw_{}", ident), Span::call_site());
-    let arg: FnArg = parse_quote! { #raw_ctx: *mut xdp_md };
-    item.sig.inputs.push(arg);
-    let ctx: Stmt = parse_quote! { let #ident = XdpContext { ctx: #raw_ctx }; };
-    item.block.stmts.insert(0, ctx);
-    probe_impl("xdp", attrs, item).into()
+    probe_impl("xdp", attrs, wrapper)
+}
+
+fn type_name(ty: &Type) -> Option<&Ident> {
+    match ty {
+        Type::Path(TypePath { path, .. }) => path.segments.last().map(|s| &s.ident),
+        _ => None,
+    }
+}
+
+// Checks that the probe is declared as
+// `extern "C" fn name(ctx: XdpContext) -> XdpAction`, and returns the name of
+// the context argument.
+fn check_xdp_signature(item: &ItemFn) -> Result<&Ident> {
+    let sig = &item.sig;
+    match &sig.abi {
+        Some(Abi {
+            name: Some(name), ..
+        }) if name.value() == "C" => {}
+        _ => {
+            return Err(Error::new_spanned(
+                &sig.fn_token,
+                "XDP probes must be declared `extern \"C\"`",
+            ))
+        }
+    }
+
+    let ctx_error = || {
+        Error::new_spanned(
+            &sig.inputs,
+            "XDP probes must take a single `XdpContext` argument",
+        )
+    };
+    if sig.inputs.len() != 1 {
+        return Err(ctx_error());
+    }
+    let ident = match sig.inputs.first() {
+        Some(FnArg::Typed(PatType { pat, ty, .. })) => match (&**pat, type_name(ty)) {
+            (Pat::Ident(PatIdent { ident, .. }), Some(ty)) if ty == "XdpContext" => ident,
+            _ => return Err(ctx_error()),
+        },
+        _ => return Err(ctx_error()),
+    };
+
+    match &sig.output {
+        ReturnType::Type(_, ty) if type_name(ty).map_or(false, |t| t == "XdpAction") => {}
+        output => {
+            return Err(Error::new_spanned(
+                output,
+                "XDP probes must return `XdpAction`",
+            ))
+        }
+    }
+
+    Ok(ident)
 }
@@ -0,0 +1,248 @@
+/*!
+Safe wrappers around common BPF helpers.
+ */
+use core::mem::{self, MaybeUninit};
+use cty::*;
+
+use crate::bindings::*;
+
+use redbpf_macros::internal_helpers as helpers;
+
+/// Reads a value of type `T` from kernel memory pointed to by `src`.
+///
+/// Returns `None` if the memory can't be read.
+///
+/// # Safety
+///
+/// The bytes read are not checked, so any bit pattern must be a valid `T`,
+/// eg. `T` can't be a `bool`, an enum or a reference.
+#[inline]
+#[helpers]
+pub unsafe fn probe_read<T>(src: *const T) -> Option<T> {
+    let mut value = MaybeUninit::<T>::uninit();
+    let ret = bpf_probe_read(
+        value.as_mut_ptr() as *mut c_void,
+        mem::size_of::<T>() as u32,
+        src as *const c_void,
+    );
+    if ret < 0 {
+        None
+    } else {
+        Some(value.assume_init())
+    }
+}
+
+// Newer than the bundled helper definitions, so it is called by id like the
+// generated helpers.
+const BPF_FUNC_COPY_FROM_USER: usize = 148;
+
+/// Proof that the running program is sleepable.
+///
+/// Only sleepable programs may fault in user memory, so the helpers doing it,
+/// like `copy_from_user`, take a `&Sleepable`. It can't be created by probes:
+/// the `#[uprobe("...", sleepable)]` macro passes it as the second argument of
+/// the probe function, and puts the program in a `.s` section so the loader
+/// sets `BPF_F_SLEEPABLE`.
+///
+/// Kprobes run in atomic context and can never be sleepable.
+pub struct Sleepable {
+    _private: (),
+}
+
+impl Sleepable {
+    #[doc(hidden)]
+    #[inline]
+    pub unsafe fn new_unchecked() -> Sleepable {
+        Sleepable { _private: () }
+    }
+}
+
+/// Reads a value of type `T` from user memory pointed to by `src`, faulting
+/// the page in if needed.
+///
+/// Unlike `probe_read`, this succeeds on memory that was swapped out. Returns
+/// `None` if the memory can't be read. Requires Linux 5.10.
+///
+/// # Safety
+///
+/// Like `probe_read`, the bytes read are user controlled, so any bit pattern
+/// must be a valid `T`, eg. `T` can't be a `bool`, an enum or a reference.
+#[inline]
+pub unsafe fn copy_from_user<T>(_sleepable: &Sleepable, src: *const T) -> Option<T> {
+    let mut value = MaybeUninit::<T>::uninit();
+    let bpf_copy_from_user: unsafe extern "C" fn(*mut c_void, u32, *const c_void) -> c_long =
+        mem::transmute(BPF_FUNC_COPY_FROM_USER);
+    let ret = bpf_copy_from_user(
+        value.as_mut_ptr() as *mut c_void,
+        mem::size_of::<T>() as u32,
+        src as *const c_void,
+    );
+    if ret < 0 {
+        None
+    } else {
+        Some(value.assume_init())
+    }
+}
+
+/// Returns the id of the cgroup v2 the current task belongs to.
+#[inline]
+#[helpers]
+pub fn current_cgroup_id() -> u64 {
+    unsafe { bpf_get_current_cgroup_id() }
+}
+
+/// Returns the thread group id (the user space pid) of the current task.
+#[inline]
+#[helpers]
+pub fn current_tgid() -> u32 {
+    unsafe { (bpf_get_current_pid_tgid() >> 32) as u32 }
+}
+
+/// Returns the id (the user space thread id) of the current task.
+#[inline]
+#[helpers]
+pub fn current_pid() -> u32 {
+    unsafe { bpf_get_current_pid_tgid() as u32 }
+}
+
+/// Returns the id of the CPU the program is running on.
+#[inline]
+#[helpers]
+pub fn smp_processor_id() -> u32 {
+    unsafe { bpf_get_smp_processor_id() }
+}
+
+/// Returns the id of the NUMA node the program is running on.
+#[inline]
+#[helpers]
+pub fn numa_node_id() -> u32 {
+    unsafe { bpf_get_numa_node_id() as u32 }
+}
+
+/// Mask of the number of frames to skip in the flags of `get_stack`.
+pub const BPF_F_SKIP_FIELD_MASK: u64 = 0xff;
+/// Captures the user space stack with `get_stack` instead of the kernel
+/// stack.
+pub const BPF_F_USER_STACK: u64 = 1 << 8;
+
+/// Writes the stack of the current task into `buf`, innermost frame first.
+///
+/// `ctx` is the context of the program, eg. the `*mut pt_regs` of a probe.
+/// `flags` is the number of frames to skip, up to `BPF_F_SKIP_FIELD_MASK`,
+/// optionally combined with `BPF_F_USER_STACK`. Unlike with
+/// `bpf_get_stackid`, the stack is not stored in a stack trace map, so it can
+/// be sent along with an event.
+///
+/// Returns the number of frames written, the deeper frames being dropped if
+/// `buf` is too small and the remaining entries left untouched, or a
+/// negative error.
+#[inline]
+#[helpers]
+pub fn get_stack<C>(ctx: *mut C, buf: &mut [u64], flags: u64) -> i64 {
+    if buf.is_empty() {
+        return 0;
+    }
+    let size = (buf.len() * mem::size_of::<u64>()) as u32;
+    let ret = unsafe {
+        bpf_get_stack(
+            ctx as *mut c_void,
+            buf.as_mut_ptr() as *mut c_void,
+            size,
+            flags,
+        )
+    };
+    if ret < 0 {
+        ret as i64
+    } else {
+        ret as i64 / mem::size_of::<u64>() as i64
+    }
+}
+
+/// Offsets of the `task_struct` fields read by `Task`.
+///
+/// The layout of `task_struct` depends on the kernel version and
+/// configuration, so the offsets must match the kernel the probe runs on.
+/// They can be found with `pahole task_struct`, or from the kernel BTF with
+/// `redbpf::btf::Btf`. C probes can use the relocatable accessors in
+/// `bpf_task.h` instead.
+#[derive(Debug, Clone, Copy)]
+pub struct TaskOffsets {
+    pub pid: usize,
+    pub tgid: usize,
+    pub real_parent: usize,
+}
+
+/// A pointer to a kernel `task_struct`.
+#[derive(Debug, Clone, Copy)]
+pub struct Task {
+    ptr: *const u8,
+}
+
+impl Task {
+    /// Returns the task the probe is running in.
+    #[inline]
+    #[helpers]
+    pub fn current() -> Task {
+        Task {
+            ptr: unsafe { bpf_get_current_task() } as *const u8,
+        }
+    }
+
+    /// Reads the value of type `T` at `offset` bytes into the `task_struct`.
+    ///
+    /// # Safety
+    ///
+    /// `offset` must be the offset of a field of type `T`.
+    #[inline]
+    pub unsafe fn read<T>(&self, offset: usize) -> Option<T> {
+        probe_read(self.ptr.add(offset) as *const T)
+    }
+
+    /// Returns the thread id of the task.
+    #[inline]
+    pub fn pid(&self, offsets: &TaskOffsets) -> Option<u32> {
+        unsafe { self.read(offsets.pid) }
+    }
+
+    /// Returns the thread group id of the task.
+    #[inline]
+    pub fn tgid(&self, offsets: &TaskOffsets) -> Option<u32> {
+        unsafe { self.read(offsets.tgid) }
+    }
+
+    /// Returns the parent of the task.
+    #[inline]
+    pub fn parent(&self, offsets: &TaskOffsets) -> Option<Task> {
+        let ptr: *const u8 = unsafe { self.read(offsets.real_parent)? };
+        if ptr.is_null() {
+            None
+        } else {
+            Some(Task { ptr })
+        }
+    }
+}
+
+/// Reads a field through a pointer to a kernel struct.
+///
+/// `probe_read_field!(task, real_parent)` reads `(*task).real_parent` using
+/// `bpf_probe_read`, and evaluates to an `Option` containing the value of the
+/// field. The field address is computed with `core::ptr::addr_of!`, so no
+/// reference to the kernel struct is created.
+///
+/// This is not a CO-RE read: unlike `BPF_CORE_READ()` in C, which records a
+/// relocation that `redbpf::Module` resolves against the BTF of the running
+/// kernel, no relocation is emitted and the offset of the field is the one of
+/// the bindings the probe was compiled against. The read returns garbage on
+/// kernels where the layout of the struct differs.
+///
+/// # Safety
+///
+/// Must be used in an `unsafe` block: `$ptr` is a raw pointer dereferenced
+/// to compute the field address, and `probe_read` requires any bit pattern
+/// to be a valid value of the field type.
+#[macro_export]
+macro_rules! probe_read_field {
+    ($ptr:expr, $($field:ident).+) => {
+        $crate::helpers::probe_read(::core::ptr::addr_of!((*$ptr)$(.$field)+))
+    };
+}
@@ -46,5 +46,7 @@ pub extern "C" fn block_port_80(ctx: XdpContext) -> XdpAction {
 #![deny(clippy::all)]
 #![no_std]
 pub mod bindings;
+pub mod helpers;
 pub mod maps;
+pub mod tc;
 pub mod xdp;
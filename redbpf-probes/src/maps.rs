@@ -8,18 +8,128 @@ kernel and user-space code.
 use core::default::Default;
 use core::marker::PhantomData;
 use core::mem;
+use core::sync::atomic::{AtomicU64, Ordering};
 use cty::*;
 
 use crate::bindings::*;
 
 use redbpf_macros::internal_helpers as helpers;
 
+/// Types that can be used as `HashMap` keys.
+///
+/// The kernel hashes and compares keys byte by byte, so padding bytes, which
+/// are left uninitialized, make lookups of otherwise equal keys fail at
+/// random. Structs can implement the trait with `#[derive(MapKey)]`, which
+/// fails to compile if the struct contains padding. Make the padding explicit
+/// with a zeroed field to fix it:
+///
+/// ```
+/// use redbpf_macros::MapKey;
+///
+/// #[repr(C)]
+/// #[derive(MapKey)]
+/// struct Key {
+///     pid: u32,
+///     fd: u16,
+///     _pad: [u8; 2],
+/// }
+/// ```
+///
+/// # Safety
+///
+/// The type must not contain any padding bytes.
+pub unsafe trait MapKey {}
+
+macro_rules! impl_map_key {
+    ($($t:ty),*) => {
+        $(unsafe impl MapKey for $t {})*
+    };
+}
+
+macro_rules! impl_map_key_array {
+    ($($n:expr),*) => {
+        $(unsafe impl<T: MapKey> MapKey for [T; $n] {})*
+    };
+}
+
+impl_map_key!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize);
+impl_map_key_array!(
+    1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 20, 24, 32, 64, 128, 256
+);
+
+/// Map values that can hold a `SpinLock` or a `Timer`.
+///
+/// The verifier only accepts locks and timers in the values of maps created
+/// with BTF type information describing where they are. Probes compiled from
+/// Rust don't carry BTF, so their offsets are recorded by this trait instead,
+/// and maps created with `HashMap::with_value_btf` pass them on to the
+/// loader, which generates the BTF.
+///
+/// Implement the trait with `#[derive(MapValue)]`, which finds the top level
+/// `SpinLock` and `Timer` fields of a `#[repr(C)]` struct:
+///
+/// ```
+/// use redbpf_macros::MapValue;
+/// use redbpf_probes::maps::SpinLock;
+///
+/// #[repr(C)]
+/// #[derive(MapValue)]
+/// pub struct Account {
+///     lock: SpinLock,
+///     balance: u64,
+/// }
+/// ```
+///
+/// # Safety
+///
+/// `SPIN_LOCK_OFFSET` and `TIMER_OFFSET` must be the offsets of top level
+/// `SpinLock` and `Timer` fields.
+pub unsafe trait MapValue {
+    /// The offset of the `SpinLock` field of the value, if any.
+    const SPIN_LOCK_OFFSET: Option<usize> = None;
+    /// The offset of the `Timer` field of the value, if any.
+    const TIMER_OFFSET: Option<usize> = None;
+}
+
+// The offsets of the fields the loader describes with BTF when creating the
+// map, `NO_FIELD` if the value doesn't have the field. Stored right after the
+// map definition in the `maps/name` section.
+#[repr(C)]
+struct ValueBtf {
+    spin_lock_offset: u32,
+    timer_offset: u32,
+}
+
+const NO_FIELD: u32 = u32::MAX;
+
+impl ValueBtf {
+    const NONE: ValueBtf = ValueBtf {
+        spin_lock_offset: NO_FIELD,
+        timer_offset: NO_FIELD,
+    };
+
+    const fn of<V: MapValue>() -> ValueBtf {
+        ValueBtf {
+            spin_lock_offset: field_offset(V::SPIN_LOCK_OFFSET),
+            timer_offset: field_offset(V::TIMER_OFFSET),
+        }
+    }
+}
+
+const fn field_offset(offset: Option<usize>) -> u32 {
+    match offset {
+        Some(offset) => offset as u32,
+        None => NO_FIELD,
+    }
+}
+
 /// Hash table map.
 ///
-/// High level API for BPF_MAP_TYPE_HASH maps.
-#[repr(transparent)]
+/// High level API for BPF_MAP_TYPE_HASH maps. Keys must implement `MapKey`.
+#[repr(C)]
 pub struct HashMap<K, V> {
     def: bpf_map_def,
+    value_btf: ValueBtf,
     _k: PhantomData<K>,
     _v: PhantomData<V>,
 }
@@ -27,6 +137,10 @@ pub struct HashMap<K, V> {
 impl<K, V> HashMap<K, V> {
     /// Creates a map with the specified maximum number of elements.
     pub const fn with_max_entries(max_entries: u32) -> Self {
+        Self::with_value_fields(max_entries, ValueBtf::NONE)
+    }
+
+    const fn with_value_fields(max_entries: u32, value_btf: ValueBtf) -> Self {
         Self {
             def: bpf_map_def {
                 type_: bpf_map_type_BPF_MAP_TYPE_HASH,
@@ -35,11 +149,23 @@ impl<K, V> HashMap<K, V> {
                 max_entries,
                 map_flags: 0,
             },
+            value_btf,
             _k: PhantomData,
             _v: PhantomData,
         }
     }
+}
 
+impl<K, V: MapValue> HashMap<K, V> {
+    /// Creates a map with the specified maximum number of elements, which
+    /// the loader describes with BTF so that its values can hold a
+    /// `SpinLock` or a `Timer`, see `MapValue`.
+    pub const fn with_value_btf(max_entries: u32) -> Self {
+        Self::with_value_fields(max_entries, ValueBtf::of::<V>())
+    }
+}
+
+impl<K: MapKey, V> HashMap<K, V> {
     /// Returns a reference to the value corresponding to the key.
     #[inline]
     #[helpers]
@@ -56,6 +182,652 @@ impl<K, V> HashMap<K, V> {
             }
         }
     }
+
+    /// Returns a mutable reference to the value corresponding to the key,
+    /// inserting `init` first if the key is not present.
+    ///
+    /// If another CPU inserts the key concurrently, its value is kept and
+    /// `init` is discarded. Returns `None` if the key could not be inserted,
+    /// eg. because the map is full.
+    #[inline]
+    #[helpers]
+    pub fn get_or_init(&mut self, mut key: K, mut init: V) -> Option<&mut V> {
+        unsafe {
+            let map = &mut self.def as *mut _ as *mut c_void;
+            let key = &mut key as *mut _ as *mut c_void;
+            let mut value = bpf_map_lookup_elem(map, key);
+            if value.is_null() {
+                // fails with -EEXIST if we lost a race, in which case the
+                // lookup below returns the winning value
+                bpf_map_update_elem(
+                    map,
+                    key,
+                    &mut init as *mut _ as *mut c_void,
+                    BPF_NOEXIST as u64,
+                );
+                value = bpf_map_lookup_elem(map, key);
+            }
+            if value.is_null() {
+                None
+            } else {
+                Some(&mut *(value as *mut V))
+            }
+        }
+    }
+
+    /// Sets the value for the given key.
+    ///
+    /// Returns the error code returned by the kernel if the update failed,
+    /// eg. because the map is full.
+    #[inline]
+    #[helpers]
+    pub fn set(&mut self, mut key: K, mut value: V) -> Result<(), i32> {
+        let ret = unsafe {
+            bpf_map_update_elem(
+                &mut self.def as *mut _ as *mut c_void,
+                &mut key as *mut _ as *mut c_void,
+                &mut value as *mut _ as *mut c_void,
+                BPF_ANY as u64,
+            )
+        };
+        if ret < 0 {
+            Err(ret)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Sets the value for the given key, ignoring errors.
+    #[inline]
+    pub fn set_unchecked(&mut self, key: K, value: V) {
+        let _ = self.set(key, value);
+    }
+
+    /// Deletes the entry indexed by `key`.
+    ///
+    /// Returns the error code returned by the kernel if the key could not be
+    /// deleted, eg. because it doesn't exist.
+    #[inline]
+    #[helpers]
+    pub fn delete(&mut self, mut key: K) -> Result<(), i32> {
+        let ret = unsafe {
+            bpf_map_delete_elem(
+                &mut self.def as *mut _ as *mut c_void,
+                &mut key as *mut _ as *mut c_void,
+            )
+        };
+        if ret < 0 {
+            Err(ret)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<K: MapKey, V: Lockable> HashMap<K, V> {
+    /// Looks up the value corresponding to the key and calls `f` on it while
+    /// holding the value's `SpinLock`.
+    ///
+    /// The lock is always released before returning, so it's not possible to
+    /// return with the lock held. The map must be created with
+    /// `with_value_btf`.
+    ///
+    /// Returns `None` if the key is not present in the map.
+    ///
+    /// The verifier rejects programs that call BPF helpers, or take another
+    /// lock, while a lock is held, so `f` should only read and update the
+    /// fields of the value:
+    ///
+    /// ```
+    /// let balance = unsafe {
+    ///     ACCOUNTS.with_locked(id, |account| {
+    ///         account.balance += amount;
+    ///         account.balance
+    ///     })
+    /// };
+    /// // helpers can be called again once the lock is released
+    /// ```
+    #[inline]
+    #[helpers]
+    pub fn with_locked<R, F: FnOnce(&mut V) -> R>(&mut self, mut key: K, f: F) -> Option<R> {
+        unsafe {
+            let value = bpf_map_lookup_elem(
+                &mut self.def as *mut _ as *mut c_void,
+                &mut key as *mut _ as *mut c_void,
+            ) as *mut V;
+            if value.is_null() {
+                return None;
+            }
+            // the lock is reborrowed from the value pointer each time, so
+            // that `f` is free to borrow the whole value in between
+            (*value).spin_lock().lock();
+            let ret = f(&mut *value);
+            (*value).spin_lock().unlock();
+            Some(ret)
+        }
+    }
+}
+
+const NSEC_PER_SEC: u64 = 1_000_000_000;
+
+/// The state of a `RateLimiter` bucket.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct TokenBucket {
+    /// The available tokens, in billionths of a token so that refills are
+    /// exact.
+    pub tokens: u64,
+    /// The time of the last refill, in nanoseconds.
+    pub last_ns: u64,
+    /// The number of tokens added per second.
+    pub rate: u64,
+    /// The maximum number of tokens. Buckets with no burst are uninitialized.
+    pub burst: u64,
+}
+
+impl TokenBucket {
+    // Refills the bucket for the time elapsed since the last refill, and
+    // takes a token if there's one.
+    #[inline]
+    fn take(&mut self, now_ns: u64) -> bool {
+        let max = self.burst * NSEC_PER_SEC;
+        let elapsed = now_ns.saturating_sub(self.last_ns);
+        self.last_ns = now_ns;
+        if self.rate > 0 {
+            // once the bucket is full, elapsed * rate can overflow
+            if elapsed >= max / self.rate {
+                self.tokens = max;
+            } else {
+                self.tokens = (self.tokens + elapsed * self.rate).min(max);
+            }
+        }
+        if self.tokens >= NSEC_PER_SEC {
+            self.tokens -= NSEC_PER_SEC;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Token bucket rate limiter.
+///
+/// Holds a `TokenBucket` per key, eg. per flow, in a
+/// BPF_MAP_TYPE_LRU_PERCPU_HASH map, so that the least recently seen keys
+/// are evicted when the map is full. `allow` takes a token from the bucket
+/// of the key, refilled at `rate` tokens per second up to `burst` tokens.
+///
+/// The buckets are per-CPU: each CPU enforces the rate on its own, with no
+/// atomic operations. This is exact when a key is always handled by the same
+/// CPU, like flows spread across receive queues by RSS in XDP programs.
+/// Otherwise, a key can go through up to `rate` times the number of CPUs,
+/// and the rate should be divided accordingly.
+///
+/// ```
+/// #[map("limits")]
+/// static mut LIMITS: RateLimiter<u32> = RateLimiter::with_max_entries(10240);
+///
+/// #[xdp]
+/// pub extern "C" fn limit(ctx: XdpContext) -> XdpAction {
+///     let ip = ...;
+///     let now = bpf_ktime_get_ns();
+///     if unsafe { LIMITS.allow(ip, now, 1000, 100) } {
+///         XdpAction::Pass
+///     } else {
+///         XdpAction::Drop
+///     }
+/// }
+/// ```
+///
+/// User space can read the buckets and adjust the rate and burst of each
+/// key with `redbpf::RateLimiter`.
+#[repr(transparent)]
+pub struct RateLimiter<K> {
+    def: bpf_map_def,
+    _k: PhantomData<K>,
+}
+
+impl<K> RateLimiter<K> {
+    /// Creates a map with the specified maximum number of keys.
+    pub const fn with_max_entries(max_entries: u32) -> Self {
+        Self {
+            def: bpf_map_def {
+                type_: bpf_map_type_BPF_MAP_TYPE_LRU_PERCPU_HASH,
+                key_size: mem::size_of::<K>() as u32,
+                value_size: mem::size_of::<TokenBucket>() as u32,
+                max_entries,
+                map_flags: 0,
+            },
+            _k: PhantomData,
+        }
+    }
+}
+
+impl<K: MapKey> RateLimiter<K> {
+    /// Takes a token from the bucket of `key` at time `now_ns`, usually
+    /// `bpf_ktime_get_ns()`, and returns whether there was one.
+    ///
+    /// New buckets start full, with `rate` tokens per second and `burst`
+    /// tokens at most. Existing buckets keep their own rate and burst, which
+    /// user space can change. Returns `true` if the bucket can't be created.
+    #[inline]
+    #[helpers]
+    pub fn allow(&mut self, mut key: K, now_ns: u64, rate: u64, burst: u64) -> bool {
+        unsafe {
+            let map = &mut self.def as *mut _ as *mut c_void;
+            let key = &mut key as *mut _ as *mut c_void;
+            let mut value = bpf_map_lookup_elem(map, key);
+            if value.is_null() {
+                // the other CPUs get zeroed, uninitialized buckets
+                let mut empty = TokenBucket {
+                    tokens: 0,
+                    last_ns: 0,
+                    rate: 0,
+                    burst: 0,
+                };
+                bpf_map_update_elem(
+                    map,
+                    key,
+                    &mut empty as *mut _ as *mut c_void,
+                    BPF_NOEXIST as u64,
+                );
+                value = bpf_map_lookup_elem(map, key);
+            }
+            if value.is_null() {
+                return true;
+            }
+
+            let bucket = &mut *(value as *mut TokenBucket);
+            if bucket.burst == 0 {
+                *bucket = TokenBucket {
+                    tokens: burst * NSEC_PER_SEC,
+                    last_ns: now_ns,
+                    rate,
+                    burst,
+                };
+            }
+            bucket.take(now_ns)
+        }
+    }
+}
+
+/// Per-CPU array map.
+///
+/// High level API for BPF_MAP_TYPE_PERCPU_ARRAY maps. Each CPU has its own
+/// copy of every element, and lookups return the copy of the current CPU.
+///
+/// Since programs can't be preempted, a single entry array is the standard
+/// way to get scratch space larger than the 512 bytes BPF stack:
+///
+/// ```
+/// #![no_std]
+/// #![no_main]
+/// use redbpf_probes::maps::PerCpuArray;
+/// use redbpf_probes::xdp::{XdpAction, XdpContext};
+/// use redbpf_macros::{map, program, xdp};
+///
+/// program!(0xFFFFFFFE, "GPL");
+///
+/// pub struct Scratch {
+///     buf: [u8; 4096],
+/// }
+///
+/// #[map("scratch")]
+/// static mut SCRATCH: PerCpuArray<Scratch> = PerCpuArray::with_max_entries(1);
+///
+/// #[xdp]
+/// pub extern "C" fn use_scratch(ctx: XdpContext) -> XdpAction {
+///     let scratch = match unsafe { SCRATCH.get_mut(0) } {
+///         Some(scratch) => scratch,
+///         None => return XdpAction::Aborted,
+///     };
+///     scratch.buf[0] = 1;
+///
+///     XdpAction::Pass
+/// }
+/// ```
+#[repr(transparent)]
+pub struct PerCpuArray<T> {
+    def: bpf_map_def,
+    _t: PhantomData<T>,
+}
+
+impl<T> PerCpuArray<T> {
+    /// Creates a map with the specified number of elements.
+    pub const fn with_max_entries(max_entries: u32) -> Self {
+        Self {
+            def: bpf_map_def {
+                type_: bpf_map_type_BPF_MAP_TYPE_PERCPU_ARRAY,
+                key_size: mem::size_of::<u32>() as u32,
+                value_size: mem::size_of::<T>() as u32,
+                max_entries,
+                map_flags: 0,
+            },
+            _t: PhantomData,
+        }
+    }
+
+    /// Returns a reference to the current CPU's element at `index`.
+    #[inline]
+    pub fn get(&mut self, index: u32) -> Option<&T> {
+        self.get_mut(index).map(|value| &*value)
+    }
+
+    /// Returns a mutable reference to the current CPU's element at `index`.
+    #[inline]
+    #[helpers]
+    pub fn get_mut(&mut self, mut index: u32) -> Option<&mut T> {
+        unsafe {
+            let value = bpf_map_lookup_elem(
+                &mut self.def as *mut _ as *mut c_void,
+                &mut index as *mut _ as *mut c_void,
+            );
+            if value.is_null() {
+                None
+            } else {
+                Some(&mut *(value as *mut T))
+            }
+        }
+    }
+}
+
+const BPF_F_MMAPABLE: u32 = 1 << 10;
+
+const ENOENT: i32 = 2;
+
+/// Array of `N` `u64` counters that user space can read without syscalls.
+///
+/// The counters are stored in a `BPF_MAP_TYPE_ARRAY` created with
+/// `BPF_F_MMAPABLE`, and are incremented atomically so that they can be
+/// shared by all CPUs. In user space, `redbpf::Counters<N>` reads them
+/// through a memory mapping of the map.
+///
+/// ```
+/// #[map("counters")]
+/// static mut COUNTERS: Counters<2> = Counters::new();
+///
+/// unsafe { COUNTERS.incr(0) };
+/// ```
+#[repr(transparent)]
+pub struct Counters<const N: usize> {
+    def: bpf_map_def,
+}
+
+impl<const N: usize> Counters<N> {
+    /// Creates an array of `N` counters.
+    pub const fn new() -> Self {
+        Self {
+            def: bpf_map_def {
+                type_: bpf_map_type_BPF_MAP_TYPE_ARRAY,
+                key_size: mem::size_of::<u32>() as u32,
+                value_size: mem::size_of::<u64>() as u32,
+                max_entries: N as u32,
+                map_flags: BPF_F_MMAPABLE,
+            },
+        }
+    }
+
+    /// Increments the counter at `index`.
+    #[inline]
+    pub fn incr(&mut self, index: u32) -> Result<(), i32> {
+        self.add(index, 1)
+    }
+
+    /// Adds `value` to the counter at `index`.
+    ///
+    /// Returns `Err(-ENOENT)` if `index` is not smaller than `N`.
+    #[inline]
+    #[helpers]
+    pub fn add(&mut self, mut index: u32, value: u64) -> Result<(), i32> {
+        if index as usize >= N {
+            return Err(-ENOENT);
+        }
+        unsafe {
+            let counter = bpf_map_lookup_elem(
+                &mut self.def as *mut _ as *mut c_void,
+                &mut index as *mut _ as *mut c_void,
+            );
+            if counter.is_null() {
+                return Err(-ENOENT);
+            }
+            (*(counter as *const AtomicU64)).fetch_add(value, Ordering::Relaxed);
+        }
+
+        Ok(())
+    }
+}
+
+/// Spin lock that can be embedded in map values.
+///
+/// This is a wrapper for `struct bpf_spin_lock`. In order to be usable, the
+/// lock must be a top level field of a `#[repr(C)]` map value deriving
+/// `MapValue`. Only one lock is allowed per value.
+///
+/// Locks can only be taken on values stored in maps described with BTF type
+/// information, created with `HashMap::with_value_btf`.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct SpinLock {
+    val: u32,
+}
+
+impl SpinLock {
+    /// Creates a new, unlocked lock.
+    pub const fn new() -> Self {
+        SpinLock { val: 0 }
+    }
+
+    /// Acquires the lock. Prefer `HashMap::with_locked` which guarantees that
+    /// the lock is released.
+    ///
+    /// # Safety
+    ///
+    /// The lock must be part of a value stored in a map. The verifier
+    /// rejects programs that return with the lock held, take a second lock,
+    /// or call helpers while holding it, so the caller must release it with
+    /// `SpinLock::unlock` before doing any of these.
+    #[inline]
+    #[helpers]
+    pub unsafe fn lock(&mut self) {
+        bpf_spin_lock(self as *mut _ as *mut bpf_spin_lock);
+    }
+
+    /// Releases the lock.
+    ///
+    /// # Safety
+    ///
+    /// The lock must have been acquired with `SpinLock::lock` by the running
+    /// program.
+    #[inline]
+    #[helpers]
+    pub unsafe fn unlock(&mut self) {
+        bpf_spin_unlock(self as *mut _ as *mut bpf_spin_lock);
+    }
+}
+
+/// Map values that contain a `SpinLock`, implemented by
+/// `#[derive(MapValue)]` for structs with a `SpinLock` field.
+///
+/// # Safety
+///
+/// `spin_lock` must always return the field at `SPIN_LOCK_OFFSET`.
+pub unsafe trait Lockable: MapValue {
+    /// Returns the lock protecting the value.
+    fn spin_lock(&mut self) -> &mut SpinLock;
+}
+
+// The timer helpers are newer than the bundled helper definitions, so they
+// are called by id like the generated helpers.
+const BPF_FUNC_TIMER_INIT: usize = 169;
+const BPF_FUNC_TIMER_SET_CALLBACK: usize = 170;
+const BPF_FUNC_TIMER_START: usize = 171;
+const BPF_FUNC_TIMER_CANCEL: usize = 172;
+
+/// Clock used by timers, see `Timer::init`.
+pub const CLOCK_MONOTONIC: u64 = 1;
+
+/// Callback called when a `Timer` fires, declared with `timer_callback!`.
+///
+/// The callback is passed the map, key and value that hold the timer.
+pub type TimerCallback<K, V> = extern "C" fn(*mut c_void, *mut K, *mut V) -> c_int;
+
+/// Timer that can be embedded in map values.
+///
+/// This is a wrapper for `struct bpf_timer`, available since Linux 5.15. The
+/// verifier enforces a number of rules, which this type can't all check:
+///
+/// * the timer must be a top level field of a `#[repr(C)]` value deriving
+///   `MapValue`, stored in a `HashMap` created with `with_value_btf` so that
+///   the map is described with BTF type information. Only one timer is
+///   allowed per value.
+/// * `init` must be called before the other methods, with the map holding
+///   the value.
+/// * the callback must be declared with `timer_callback!` and use the key
+///   and value types of the map.
+///
+/// Timers are cancelled when their value is deleted from the map, which
+/// makes them a good fit to expire entries:
+///
+/// ```
+/// #[repr(C)]
+/// #[derive(MapValue)]
+/// pub struct Conn {
+///     timer: Timer,
+///     packets: u64,
+/// }
+///
+/// #[map("conns")]
+/// static mut CONNS: HashMap<FlowKey, Conn> = HashMap::with_value_btf(1024);
+///
+/// timer_callback! {
+///     fn expire(key: &mut FlowKey, _conn: &mut Conn) {
+///         let _ = unsafe { CONNS.delete(*key) };
+///     }
+/// }
+///
+/// let init = Conn { timer: Timer::new(), packets: 0 };
+/// // the value is used as a pointer as `init` needs to borrow the map again
+/// if let Some(conn) = unsafe { CONNS.get_or_init(key, init).map(|c| c as *mut Conn) } {
+///     unsafe {
+///         (*conn).packets += 1;
+///         // fails with -EBUSY if the timer was already initialized
+///         if (*conn).timer.init(&mut CONNS, CLOCK_MONOTONIC).is_ok() {
+///             let _ = (*conn).timer.set_callback(expire);
+///         }
+///         // restart the timer on every packet
+///         let _ = (*conn).timer.start(30_000_000_000);
+///     }
+/// }
+/// ```
+#[repr(C, align(8))]
+#[derive(Debug, Default)]
+pub struct Timer {
+    _opaque: [u64; 2],
+}
+
+#[inline]
+fn timer_result(ret: c_long) -> Result<(), i32> {
+    if ret < 0 {
+        Err(ret as i32)
+    } else {
+        Ok(())
+    }
+}
+
+impl Timer {
+    /// Creates an uninitialized timer.
+    pub const fn new() -> Self {
+        Timer { _opaque: [0; 2] }
+    }
+
+    /// Initializes the timer. `map` must be the map holding the value the
+    /// timer is part of, and `clock_id` is usually `CLOCK_MONOTONIC`.
+    ///
+    /// Returns `-EBUSY` if the timer was already initialized.
+    ///
+    /// # Safety
+    ///
+    /// The timer must be part of a value stored in `map`, not a copy of it
+    /// on the stack.
+    #[inline]
+    pub unsafe fn init<K, V: MapValue>(
+        &mut self,
+        map: &mut HashMap<K, V>,
+        clock_id: u64,
+    ) -> Result<(), i32> {
+        let bpf_timer_init: unsafe extern "C" fn(*mut Timer, *mut c_void, u64) -> c_long =
+            mem::transmute(BPF_FUNC_TIMER_INIT);
+        timer_result(bpf_timer_init(
+            self,
+            &mut map.def as *mut _ as *mut c_void,
+            clock_id,
+        ))
+    }
+
+    /// Sets the function called when the timer fires.
+    ///
+    /// # Safety
+    ///
+    /// The timer must have been initialized with `Timer::init`, and
+    /// `callback` must be declared for the map the timer was initialized
+    /// with.
+    #[inline]
+    pub unsafe fn set_callback<K, V>(&mut self, callback: TimerCallback<K, V>) -> Result<(), i32> {
+        let bpf_timer_set_callback: unsafe extern "C" fn(*mut Timer, *mut c_void) -> c_long =
+            mem::transmute(BPF_FUNC_TIMER_SET_CALLBACK);
+        timer_result(bpf_timer_set_callback(self, callback as *mut c_void))
+    }
+
+    /// Starts the timer, which fires after `nsecs` nanoseconds. Starting a
+    /// running timer restarts it.
+    ///
+    /// # Safety
+    ///
+    /// The timer must have been initialized with `Timer::init` and given a
+    /// callback with `Timer::set_callback`.
+    #[inline]
+    pub unsafe fn start(&mut self, nsecs: u64) -> Result<(), i32> {
+        let bpf_timer_start: unsafe extern "C" fn(*mut Timer, u64, u64) -> c_long =
+            mem::transmute(BPF_FUNC_TIMER_START);
+        timer_result(bpf_timer_start(self, nsecs, 0))
+    }
+
+    /// Cancels the timer if it's running.
+    ///
+    /// # Safety
+    ///
+    /// The timer must be part of a value stored in a map, not a copy of it
+    /// on the stack.
+    #[inline]
+    pub unsafe fn cancel(&mut self) -> Result<(), i32> {
+        let bpf_timer_cancel: unsafe extern "C" fn(*mut Timer) -> c_long =
+            mem::transmute(BPF_FUNC_TIMER_CANCEL);
+        timer_result(bpf_timer_cancel(self))
+    }
+}
+
+/// Declares a function that can be passed to `Timer::set_callback`.
+///
+/// The function is given mutable references to the key and the value
+/// holding the timer. The verifier requires timer callbacks to return 0,
+/// which the generated function does. The function is placed in `.text`,
+/// which the loader appends to the programs referencing it.
+#[macro_export]
+macro_rules! timer_callback {
+    (fn $name:ident($key:ident: &mut $k:ty, $value:ident: &mut $v:ty) $body:block) => {
+        #[inline(never)]
+        #[link_section = ".text"]
+        extern "C" fn $name(
+            _map: *mut ::cty::c_void,
+            key: *mut $k,
+            value: *mut $v,
+        ) -> ::cty::c_int {
+            let $key: &mut $k = unsafe { &mut *key };
+            let $value: &mut $v = unsafe { &mut *value };
+            $body
+            0
+        }
+    };
 }
 
 /// Flags that can be passed to `PerfMap::insert_with_flags`.
@@ -113,6 +885,42 @@ impl From<PerfMapFlags> for u64 {
     }
 }
 
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// Raw context of the programs that can write to perf maps.
+///
+/// `bpf_perf_event_output` must be given the context the program was called
+/// with, otherwise the verifier rejects the program. This trait is sealed so
+/// that passing a pointer to anything else fails at compile time instead.
+///
+/// Tracepoint programs are called with a struct specific to each
+/// tracepoint, pass it to `PerfMap::insert_raw` instead.
+pub trait PerfContext: sealed::Sealed {}
+
+macro_rules! impl_perf_context {
+    ($($ty:ty),*) => {
+        $(
+            impl sealed::Sealed for $ty {}
+            impl PerfContext for $ty {}
+        )*
+    };
+}
+
+// kprobes, XDP, socket, tc and cgroup skb programs, perf event programs,
+// raw tracepoints, sock_ops, sk_msg and cgroup sock_addr programs
+impl_perf_context!(
+    pt_regs,
+    xdp_md,
+    __sk_buff,
+    bpf_perf_event_data,
+    bpf_raw_tracepoint_args,
+    bpf_sock_ops,
+    sk_msg_md,
+    bpf_sock_addr
+);
+
 /// Perf events map.
 ///
 /// Perf events map that allows eBPF programs to store data in mmap()ed shared
@@ -147,25 +955,332 @@ impl<T> PerfMap<T> {
     /// Each array can hold up to `max_entries` events, see `with_max_entries`.
     /// If you want to use a key other than the current CPU, see
     /// `insert_with_flags`.
+    ///
+    /// Returns the error code returned by the kernel if the event could not be
+    /// written, eg. because the perf buffer is full.
     #[inline]
-    #[helpers]
-    pub fn insert<C>(&mut self, ctx: *mut C, data: T) {
+    pub fn insert<C: PerfContext>(&mut self, ctx: *mut C, data: T) -> Result<(), i32> {
         self.insert_with_flags(ctx, data, PerfMapFlags::default())
     }
 
+    /// Insert a new event in the perf events array keyed by the current CPU
+    /// number, ignoring errors.
+    #[inline]
+    pub fn insert_unchecked<C: PerfContext>(&mut self, ctx: *mut C, data: T) {
+        let _ = self.insert(ctx, data);
+    }
+
     /// Insert a new event in the perf events array keyed by the index and with
     /// the additional xdp payload data specified in the given `PerfMapFlags`.
     #[inline]
+    pub fn insert_with_flags<C: PerfContext>(
+        &mut self,
+        ctx: *mut C,
+        data: T,
+        flags: PerfMapFlags,
+    ) -> Result<(), i32> {
+        unsafe { self.insert_raw(ctx as *mut c_void, data, flags) }
+    }
+
+    /// Insert a new event in the perf events array like `insert_with_flags`,
+    /// with a context of any type, eg. the struct of a tracepoint.
+    ///
+    /// # Safety
+    ///
+    /// `ctx` must be the context the program was called with.
+    #[inline]
     #[helpers]
-    pub fn insert_with_flags<C>(&mut self, ctx: *mut C, mut data: T, flags: PerfMapFlags) {
+    pub unsafe fn insert_raw(
+        &mut self,
+        ctx: *mut c_void,
+        mut data: T,
+        flags: PerfMapFlags,
+    ) -> Result<(), i32> {
+        let ret = bpf_perf_event_output(
+            ctx,
+            &mut self.def as *mut _ as *mut c_void,
+            flags.into(),
+            &mut data as *mut _ as *mut c_void,
+            mem::size_of::<T>() as u64,
+        );
+        if ret < 0 {
+            Err(ret)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Perf event array used to read hardware and software counters.
+///
+/// This is a `BPF_MAP_TYPE_PERF_EVENT_ARRAY` populated from user space with
+/// file descriptors returned by `perf_event_open(2)`, eg. for
+/// `PERF_COUNT_HW_INSTRUCTIONS` or `PERF_COUNT_HW_CACHE_MISSES`, usually one
+/// per CPU. Probes can then read the counters to correlate them with the
+/// events they trace.
+#[repr(transparent)]
+pub struct PerfEventArray {
+    def: bpf_map_def,
+}
+
+impl PerfEventArray {
+    /// Creates a map with the specified maximum number of elements.
+    pub const fn with_max_entries(max_entries: u32) -> Self {
+        Self {
+            def: bpf_map_def {
+                type_: bpf_map_type_BPF_MAP_TYPE_PERF_EVENT_ARRAY,
+                key_size: mem::size_of::<u32>() as u32,
+                value_size: mem::size_of::<u32>() as u32,
+                max_entries,
+                map_flags: 0,
+            },
+        }
+    }
+
+    /// Reads the counter stored at `index`.
+    ///
+    /// The returned value includes the time the counter was enabled and
+    /// running, which can be used to scale multiplexed counters. Returns
+    /// `None` if the counter can't be read, eg. because `index` is not set.
+    #[inline]
+    #[helpers]
+    pub fn read_value(&mut self, index: u32) -> Option<bpf_perf_event_value> {
         unsafe {
-            bpf_perf_event_output(
-                ctx as *mut _ as *mut c_void,
+            let mut value = mem::MaybeUninit::<bpf_perf_event_value>::uninit();
+            let ret = bpf_perf_event_read_value(
                 &mut self.def as *mut _ as *mut c_void,
-                flags.into(),
-                &mut data as *mut _ as *mut c_void,
-                mem::size_of::<T>() as u64,
+                index as u64,
+                value.as_mut_ptr(),
+                mem::size_of::<bpf_perf_event_value>() as u32,
             );
-        };
+            if ret < 0 {
+                None
+            } else {
+                Some(value.assume_init())
+            }
+        }
+    }
+
+    /// Reads the counter of the current CPU.
+    #[inline]
+    pub fn read_current_cpu(&mut self) -> Option<bpf_perf_event_value> {
+        self.read_value(BPF_F_CURRENT_CPU)
     }
 }
+
+/// Program array used for tail calls.
+///
+/// High level API for BPF_MAP_TYPE_PROG_ARRAY maps. The map holds programs
+/// of the same type as the caller, which `tail_call` jumps to without
+/// returning. The entries are set from user space, see the `tail_call!`
+/// macro of `redbpf-macros` to have the loader set them.
+///
+/// ```
+/// #[map("dispatch")]
+/// static mut DISPATCH: ProgramArray = ProgramArray::with_max_entries(8);
+///
+/// tail_call!("dispatch", 0, "parse_ipv4");
+///
+/// #[xdp]
+/// pub extern "C" fn entry(ctx: XdpContext) -> XdpAction {
+///     unsafe { DISPATCH.tail_call(ctx.ctx, 0) };
+///     // only reached if there's no program at index 0
+///     XdpAction::Pass
+/// }
+/// ```
+#[repr(transparent)]
+pub struct ProgramArray {
+    def: bpf_map_def,
+}
+
+impl ProgramArray {
+    /// Creates a map with the specified maximum number of programs.
+    pub const fn with_max_entries(max_entries: u32) -> Self {
+        Self {
+            def: bpf_map_def {
+                type_: bpf_map_type_BPF_MAP_TYPE_PROG_ARRAY,
+                key_size: mem::size_of::<u32>() as u32,
+                value_size: mem::size_of::<u32>() as u32,
+                max_entries,
+                map_flags: 0,
+            },
+        }
+    }
+
+    /// Jumps to the program at `index`, passing it `ctx`.
+    ///
+    /// Only returns if the jump fails, eg. because there's no program at
+    /// `index` or the maximum number of chained tail calls is reached, with
+    /// the error code returned by the kernel.
+    ///
+    /// # Safety
+    ///
+    /// `ctx` must be the context pointer the running program was called
+    /// with, and the programs stored in the array must take the same context
+    /// type.
+    #[inline]
+    #[helpers]
+    pub unsafe fn tail_call<C>(&mut self, ctx: *mut C, index: u32) -> i32 {
+        bpf_tail_call(
+            ctx as *mut c_void,
+            &mut self.def as *mut _ as *mut c_void,
+            index,
+        ) as i32
+    }
+}
+
+// Local storage maps are newer than the kernel headers the bindings may be
+// generated from, and so are their helpers.
+const BPF_MAP_TYPE_SK_STORAGE: u32 = 24;
+const BPF_MAP_TYPE_INODE_STORAGE: u32 = 28;
+const BPF_MAP_TYPE_TASK_STORAGE: u32 = 29;
+const BPF_F_NO_PREALLOC: u32 = 1 << 0;
+const BPF_LOCAL_STORAGE_GET_F_CREATE: u64 = 1 << 0;
+const BPF_FUNC_SK_STORAGE_GET: usize = 107;
+const BPF_FUNC_SK_STORAGE_DELETE: usize = 108;
+const BPF_FUNC_INODE_STORAGE_GET: usize = 145;
+const BPF_FUNC_INODE_STORAGE_DELETE: usize = 146;
+const BPF_FUNC_TASK_STORAGE_GET: usize = 156;
+const BPF_FUNC_TASK_STORAGE_DELETE: usize = 157;
+
+macro_rules! local_storage {
+    ($(#[$meta:meta])* $name:ident, $map_type:expr, $get:expr, $delete:expr) => {
+        $(#[$meta])*
+        #[repr(transparent)]
+        pub struct $name<T> {
+            def: bpf_map_def,
+            _v: PhantomData<T>,
+        }
+
+        impl<T> $name<T> {
+            /// Creates the map. Local storage maps have no maximum number of
+            /// entries, since there's one per kernel object.
+            pub const fn new() -> Self {
+                Self {
+                    def: bpf_map_def {
+                        type_: $map_type,
+                        key_size: mem::size_of::<i32>() as u32,
+                        value_size: mem::size_of::<T>() as u32,
+                        max_entries: 0,
+                        map_flags: BPF_F_NO_PREALLOC,
+                    },
+                    _v: PhantomData,
+                }
+            }
+
+            /// Returns the value stored for `owner`, if any.
+            #[inline]
+            pub fn get<O>(&mut self, owner: *mut O) -> Option<&mut T> {
+                self.storage_get(owner, core::ptr::null_mut(), 0)
+            }
+
+            /// Returns the value stored for `owner`, creating it from `init`
+            /// if it doesn't exist.
+            ///
+            /// Returns `None` if the value can't be created, eg. because the
+            /// object is being destroyed.
+            #[inline]
+            pub fn get_or_create<O>(&mut self, owner: *mut O, mut init: T) -> Option<&mut T> {
+                self.storage_get(owner, &mut init, BPF_LOCAL_STORAGE_GET_F_CREATE)
+            }
+
+            /// Deletes the value stored for `owner`.
+            ///
+            /// Returns the error code returned by the kernel, eg. `-ENOENT`
+            /// if there's no value.
+            #[inline]
+            pub fn delete<O>(&mut self, owner: *mut O) -> Result<(), i32> {
+                let ret = unsafe {
+                    let storage_delete: unsafe extern "C" fn(
+                        *mut c_void,
+                        *mut c_void,
+                    ) -> c_long = mem::transmute($delete);
+                    storage_delete(&mut self.def as *mut _ as *mut c_void, owner as *mut c_void)
+                };
+                if ret < 0 {
+                    Err(ret as i32)
+                } else {
+                    Ok(())
+                }
+            }
+
+            #[inline]
+            fn storage_get<O>(
+                &mut self,
+                owner: *mut O,
+                value: *mut T,
+                flags: u64,
+            ) -> Option<&mut T> {
+                unsafe {
+                    let storage_get: unsafe extern "C" fn(
+                        *mut c_void,
+                        *mut c_void,
+                        *mut c_void,
+                        u64,
+                    ) -> *mut c_void = mem::transmute($get);
+                    let value = storage_get(
+                        &mut self.def as *mut _ as *mut c_void,
+                        owner as *mut c_void,
+                        value as *mut c_void,
+                        flags,
+                    );
+                    if value.is_null() {
+                        None
+                    } else {
+                        Some(&mut *(value as *mut T))
+                    }
+                }
+            }
+        }
+    };
+}
+
+local_storage!(
+    /// Socket local storage.
+    ///
+    /// High level API for BPF_MAP_TYPE_SK_STORAGE maps. Each socket gets its
+    /// own value, which the kernel frees along with the socket, so there's no
+    /// need to expire entries keyed by socket cookie. The owner is a socket
+    /// pointer, eg. `bpf_sock_ops::sk` or a `bpf_sock` returned by
+    /// `bpf_sk_lookup_tcp`.
+    ///
+    /// ```
+    /// #[map("conn_state")]
+    /// static mut CONN_STATE: SkStorage<ConnState> = SkStorage::new();
+    ///
+    /// if let Some(state) = unsafe { CONN_STATE.get_or_create(sk, ConnState::default()) } {
+    ///     state.packets += 1;
+    /// }
+    /// ```
+    ///
+    /// Requires Linux 5.2. The map is created with BTF describing its key and
+    /// value, which the kernel requires for local storage maps.
+    SkStorage,
+    BPF_MAP_TYPE_SK_STORAGE,
+    BPF_FUNC_SK_STORAGE_GET,
+    BPF_FUNC_SK_STORAGE_DELETE
+);
+
+local_storage!(
+    /// Inode local storage, see `SkStorage`.
+    ///
+    /// High level API for BPF_MAP_TYPE_INODE_STORAGE maps, only usable from
+    /// LSM programs. The owner is a `struct inode` pointer. Requires Linux
+    /// 5.10.
+    InodeStorage,
+    BPF_MAP_TYPE_INODE_STORAGE,
+    BPF_FUNC_INODE_STORAGE_GET,
+    BPF_FUNC_INODE_STORAGE_DELETE
+);
+
+local_storage!(
+    /// Task local storage, see `SkStorage`.
+    ///
+    /// High level API for BPF_MAP_TYPE_TASK_STORAGE maps. The owner is a
+    /// `struct task_struct` pointer, eg. from `bpf_get_current_task`.
+    /// Requires Linux 5.11.
+    TaskStorage,
+    BPF_MAP_TYPE_TASK_STORAGE,
+    BPF_FUNC_TASK_STORAGE_GET,
+    BPF_FUNC_TASK_STORAGE_DELETE
+);
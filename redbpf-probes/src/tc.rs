@@ -0,0 +1,114 @@
+/*!
+Traffic control (`tc`) classifiers.
+
+Classifier programs are attached to the ingress or egress path of an
+interface with `tc` and see packets as `__sk_buff`s. The action the program
+returns tells the kernel what to do with the packet.
+
+# Example
+
+Send all the packets out of the interface with index 4:
+
+```
+#![no_std]
+#![no_main]
+use redbpf_probes::bindings::*;
+use redbpf_probes::tc::{TcAction, TcContext};
+use redbpf_macros::program;
+
+program!(0xFFFFFFFE, "GPL");
+
+#[no_mangle]
+#[link_section = "classifier/forward"]
+pub extern "C" fn forward(skb: *mut __sk_buff) -> i32 {
+    let ctx = TcContext::new(skb);
+    ctx.redirect(4, 0) as i32
+}
+```
+ */
+use cty::*;
+
+use crate::bindings::*;
+
+use redbpf_macros::internal_helpers as helpers;
+
+/// Redirect to the ingress path of the target interface instead of its
+/// egress path.
+pub const BPF_F_INGRESS: u64 = 1;
+
+/// The return type of classifiers, the `TC_ACT_*` values.
+#[repr(i32)]
+pub enum TcAction {
+    /// Use the default action configured in `tc`.
+    Unspec = -1,
+    /// Let the packet through.
+    Ok = 0,
+    /// Restart the classification from the start.
+    Reclassify = 1,
+    /// Drop the packet.
+    Shot = 2,
+    /// Continue with the next action.
+    Pipe = 3,
+    /// Consume the packet, which is not dropped but stops being processed.
+    Stolen = 4,
+    /// Queue the packet for later processing.
+    Queued = 5,
+    /// Run the action again.
+    Repeat = 6,
+    /// Redirect the packet, see `TcContext::redirect`.
+    Redirect = 7,
+}
+
+/// Context object provided to classifiers.
+pub struct TcContext {
+    pub skb: *mut __sk_buff,
+}
+
+impl TcContext {
+    /// Wraps the `__sk_buff` the classifier is called with.
+    #[inline]
+    pub fn new(skb: *mut __sk_buff) -> TcContext {
+        TcContext { skb }
+    }
+
+    /// Returns the raw `__sk_buff` context.
+    #[inline]
+    pub fn inner(&self) -> *mut __sk_buff {
+        self.skb
+    }
+
+    /// Returns a pointer to the metadata area written by an XDP program as a
+    /// `T`, if the area is large enough to hold one.
+    ///
+    /// See `xdp::XdpContext::adjust_meta`.
+    #[inline]
+    pub fn meta<T>(&self) -> Option<*const T> {
+        unsafe {
+            let meta = (*self.skb).data_meta as *const T;
+            if meta.add(1) as *const c_void > (*self.skb).data as *const c_void {
+                return None;
+            }
+            Some(meta)
+        }
+    }
+
+    /// Redirects the packet to the interface with index `ifindex`.
+    ///
+    /// The packet leaves through the egress path of the interface, unless
+    /// `flags` is `BPF_F_INGRESS`, in which case it is received on its ingress
+    /// path as if it came from the wire. Both paths are available to
+    /// classifiers attached to either ingress or egress, whereas XDP programs
+    /// can only redirect to the egress path.
+    ///
+    /// Returns `TcAction::Redirect` on success, `TcAction::Shot` otherwise.
+    /// The packet is only redirected once the program returns the action.
+    #[inline]
+    #[helpers]
+    pub fn redirect(&self, ifindex: u32, flags: u64) -> TcAction {
+        if unsafe { bpf_redirect(ifindex, flags) } == TcAction::Redirect as i32 {
+            TcAction::Redirect
+        } else {
+            TcAction::Shot
+        }
+    }
+}
@@ -35,7 +35,8 @@ use core::slice;
 use cty::*;
 
 use crate::bindings::*;
-use crate::maps::{PerfMap as PerfMapBase, PerfMapFlags};
+use crate::maps::{MapKey, PerfMap as PerfMapBase, PerfMapFlags};
+
 use redbpf_macros::internal_helpers as helpers;
 
 /// The return type of XDP probes.
@@ -114,6 +115,62 @@ impl XdpContext {
         }
     }
 
+    /// Returns the index of the interface the packet was received on.
+    ///
+    /// This is useful for programs attached to several interfaces.
+    #[inline]
+    pub fn ingress_ifindex(&self) -> u32 {
+        unsafe { (*self.ctx).ingress_ifindex }
+    }
+
+    /// Returns the index of the RX queue the packet was received on.
+    ///
+    /// With receive side scaling, the NIC spreads flows over its queues,
+    /// which each have their own interrupt and CPU. The queue index can be
+    /// used to distribute work per queue, eg. as the key of an `XskMap`.
+    #[inline]
+    pub fn rx_queue_index(&self) -> u32 {
+        unsafe { (*self.ctx).rx_queue_index }
+    }
+
+    /// Moves the start of the metadata area in front of the packet by
+    /// `delta` bytes: a negative `delta` grows the area, a positive one
+    /// shrinks it.
+    ///
+    /// The metadata can be used to pass information to a tc classifier that
+    /// processes the packet next, see `tc::TcContext::meta`. Its size must be
+    /// a multiple of 4 bytes and it can't exceed 32 bytes. Pointers
+    /// previously obtained from the context are invalidated and must be
+    /// requested again.
+    #[inline]
+    #[helpers]
+    pub fn adjust_meta(&mut self, delta: i32) -> Result<(), i32> {
+        match unsafe { bpf_xdp_adjust_meta(self.ctx, delta) } {
+            0 => Ok(()),
+            e => Err(e),
+        }
+    }
+
+    /// Returns a pointer to the metadata area as a `T`, if the area is large
+    /// enough to hold one.
+    #[inline]
+    pub fn meta<T>(&self) -> Option<*const T> {
+        self.meta_mut().map(|meta| meta as *const T)
+    }
+
+    /// Same as `meta`, returning a mutable pointer to write the metadata.
+    #[inline]
+    pub fn meta_mut<T>(&self) -> Option<*mut T> {
+        unsafe {
+            let meta = (*self.ctx).data_meta as *mut T;
+            // checked against `data`, where the metadata area ends
+            if meta.add(1) as *const c_void > (*self.ctx).data as *const c_void {
+                return None;
+            }
+            Some(meta)
+        }
+    }
+
     /// Returns the packet's `Ethernet` header if present.
     #[inline]
     pub fn eth(&self) -> Option<*const ethhdr> {
@@ -191,6 +248,25 @@ impl XdpContext {
             })
         }
     }
+
+    /// Redirects the packet out of the interface with index `ifindex`.
+    ///
+    /// This is the simplest way to send every packet to a single interface,
+    /// without setting up a `DevMap`. XDP only supports redirecting to the
+    /// egress path of the interface, so `flags` must be `0`: unlike with
+    /// `tc::TcContext::redirect`, `BPF_F_INGRESS` is rejected.
+    ///
+    /// Returns `XdpAction::Redirect` on success, `XdpAction::Aborted`
+    /// otherwise. The packet is only redirected once the program returns the
+    /// action.
+    #[inline]
+    #[helpers]
+    pub fn redirect(&self, ifindex: u32, flags: u64) -> XdpAction {
+        match unsafe { bpf_redirect(ifindex, flags) } as u32 {
+            xdp_action_XDP_REDIRECT => XdpAction::Redirect,
+            _ => XdpAction::Aborted,
+        }
+    }
 }
 
 /// Data type returned by calling `XdpContext::data()`
@@ -238,6 +314,151 @@ impl Data {
     }
 }
 
+/// IPv4 5-tuple identifying a TCP or UDP flow.
+///
+/// The layout has no implicit padding so that it can be used as a map key.
+/// Addresses are in network byte order, ports in host byte order.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FlowKey {
+    pub src_addr: u32,
+    pub dst_addr: u32,
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub proto: u8,
+    _pad: [u8; 3],
+}
+
+impl FlowKey {
+    /// Creates a key for the flow of an IPv4 packet.
+    ///
+    /// Returns `None` if the packet is not TCP or UDP over IPv4, or if it's
+    /// truncated.
+    #[inline]
+    pub fn from_ctx(ctx: &XdpContext) -> Option<FlowKey> {
+        let ip = ctx.ip()?;
+        let transport = ctx.transport()?;
+        unsafe {
+            Some(FlowKey {
+                src_addr: (*ip).saddr,
+                dst_addr: (*ip).daddr,
+                src_port: transport.source(),
+                dst_port: transport.dest(),
+                proto: (*ip).protocol,
+                _pad: [0; 3],
+            })
+        }
+    }
+
+    /// Returns the key of the flow in the opposite direction.
+    #[inline]
+    pub fn reversed(&self) -> FlowKey {
+        FlowKey {
+            src_addr: self.dst_addr,
+            dst_addr: self.src_addr,
+            src_port: self.dst_port,
+            dst_port: self.src_port,
+            ..*self
+        }
+    }
+
+    /// Returns a key that is the same for both directions of the flow.
+    ///
+    /// The endpoint with the lowest address, then port, is used as source.
+    #[inline]
+    pub fn normalized(&self) -> FlowKey {
+        if (self.src_addr, self.src_port) <= (self.dst_addr, self.dst_port) {
+            *self
+        } else {
+            self.reversed()
+        }
+    }
+}
+
+/// IPv6 5-tuple identifying a TCP or UDP flow.
+///
+/// Same as `FlowKey`, with 16 byte addresses.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FlowKeyV6 {
+    pub src_addr: [u8; 16],
+    pub dst_addr: [u8; 16],
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub proto: u8,
+    _pad: [u8; 3],
+}
+
+unsafe impl MapKey for FlowKey {}
+unsafe impl MapKey for FlowKeyV6 {}
+
+// fixed part of the IPv6 header
+const IPV6_HDR_LEN: usize = 40;
+const IPV6_NEXTHDR_OFFSET: usize = 6;
+const IPV6_SADDR_OFFSET: usize = 8;
+const IPV6_DADDR_OFFSET: usize = 24;
+
+impl FlowKeyV6 {
+    /// Creates a key for the flow of an IPv6 packet.
+    ///
+    /// Returns `None` if the packet is not TCP or UDP over IPv6, or if it's
+    /// truncated. Packets with extension headers are not supported.
+    #[inline]
+    pub fn from_ctx(ctx: &XdpContext) -> Option<FlowKeyV6> {
+        let eth = ctx.eth()?;
+        unsafe {
+            if (*eth).h_proto != u16::from_be(ETH_P_IPV6 as u16) {
+                return None;
+            }
+            let ip = eth.add(1) as *const u8;
+            let transport = ip.add(IPV6_HDR_LEN);
+            // the ports are the first 4 bytes of both TCP and UDP headers
+            if transport.add(4) > (*ctx.ctx).data_end as *const u8 {
+                return None;
+            }
+            let proto = *ip.add(IPV6_NEXTHDR_OFFSET);
+            match proto as u32 {
+                IPPROTO_TCP | IPPROTO_UDP => (),
+                _ => return None,
+            }
+
+            let mut key = FlowKeyV6 {
+                proto,
+                src_port: u16::from_be((transport as *const u16).read_unaligned()),
+                dst_port: u16::from_be((transport.add(2) as *const u16).read_unaligned()),
+                ..Default::default()
+            };
+            key.src_addr = (ip.add(IPV6_SADDR_OFFSET) as *const [u8; 16]).read_unaligned();
+            key.dst_addr = (ip.add(IPV6_DADDR_OFFSET) as *const [u8; 16]).read_unaligned();
+            Some(key)
+        }
+    }
+
+    /// Returns the key of the flow in the opposite direction.
+    #[inline]
+    pub fn reversed(&self) -> FlowKeyV6 {
+        FlowKeyV6 {
+            src_addr: self.dst_addr,
+            dst_addr: self.src_addr,
+            src_port: self.dst_port,
+            dst_port: self.src_port,
+            ..*self
+        }
+    }
+
+    /// Returns a key that is the same for both directions of the flow.
+    ///
+    /// The endpoint with the lowest address, then port, is used as source.
+    #[inline]
+    pub fn normalized(&self) -> FlowKeyV6 {
+        if (self.src_addr, self.src_port) <= (self.dst_addr, self.dst_port) {
+            *self
+        } else {
+            self.reversed()
+        }
+    }
+}
+
 /// Perf events map.
 ///
 /// Similar to `PerfMap`, with additional XDP-only API.
@@ -258,18 +479,125 @@ impl<T> PerfMap<T> {
     ///
     /// `packet_size` specifies the number of bytes from the current packet that
     /// the kernel should append to the event data.
+    ///
+    /// Returns the error code returned by the kernel if the event could not be
+    /// written.
     #[inline]
-    #[helpers]
-    pub fn insert(&mut self, ctx: &XdpContext, data: T, packet_size: u32) {
+    pub fn insert(&mut self, ctx: &XdpContext, data: T, packet_size: u32) -> Result<(), i32> {
         self.0
             .insert_with_flags(ctx.inner(), data, PerfMapFlags::with_xdp_size(packet_size))
     }
 
+    /// Insert a new event in the perf events array keyed by the current CPU
+    /// number, ignoring errors.
+    #[inline]
+    pub fn insert_unchecked(&mut self, ctx: &XdpContext, data: T, packet_size: u32) {
+        let _ = self.insert(ctx, data, packet_size);
+    }
+
     /// Insert a new event in the perf events array keyed by the index and with
     /// the additional xdp payload data specified in the given `PerfMapFlags`.
     #[inline]
-    #[helpers]
-    pub fn insert_with_flags(&mut self, ctx: &XdpContext, data: T, flags: PerfMapFlags) {
+    pub fn insert_with_flags(
+        &mut self,
+        ctx: &XdpContext,
+        data: T,
+        flags: PerfMapFlags,
+    ) -> Result<(), i32> {
         self.0.insert_with_flags(ctx.inner(), data, flags)
     }
 }
+
+/// Device map keyed by arbitrary `u32` keys.
+///
+/// High level API for `BPF_MAP_TYPE_DEVMAP_HASH` maps, which hold network
+/// devices that packets can be redirected to. Unlike index keyed device maps,
+/// entries can be keyed directly by `ifindex` without packing them into a
+/// contiguous range.
+///
+/// Entries are populated from user space, see `redbpf::Map::set_ifindex`.
+#[repr(transparent)]
+pub struct DevMapHash {
+    def: bpf_map_def,
+}
+
+impl DevMapHash {
+    /// Creates a map with the specified maximum number of elements.
+    pub const fn with_max_entries(max_entries: u32) -> Self {
+        Self {
+            def: bpf_map_def {
+                type_: bpf_map_type_BPF_MAP_TYPE_DEVMAP_HASH,
+                key_size: mem::size_of::<u32>() as u32,
+                value_size: mem::size_of::<u32>() as u32,
+                max_entries,
+                map_flags: 0,
+            },
+        }
+    }
+
+    /// Redirects the packet to the device stored under `key`.
+    ///
+    /// Returns `XdpAction::Redirect` on success. If `key` is not in the map,
+    /// the action in the lower bits of `flags` is returned instead, which is
+    /// `XdpAction::Aborted` when `flags` is `0`.
+    #[inline]
+    #[helpers]
+    pub fn redirect(&mut self, key: u32, flags: u64) -> XdpAction {
+        let action = unsafe {
+            bpf_redirect_map(&mut self.def as *mut _ as *mut c_void, key, flags) as u32
+        };
+        match action {
+            xdp_action_XDP_DROP => XdpAction::Drop,
+            xdp_action_XDP_PASS => XdpAction::Pass,
+            xdp_action_XDP_TX => XdpAction::Tx,
+            xdp_action_XDP_REDIRECT => XdpAction::Redirect,
+            _ => XdpAction::Aborted,
+        }
+    }
+}
+
+/// Map of AF_XDP sockets keyed by queue id.
+///
+/// High level API for `BPF_MAP_TYPE_XSKMAP` maps, used to redirect packets
+/// to AF_XDP sockets created in user space with `redbpf::xsk::XskSocket`.
+#[repr(transparent)]
+pub struct XskMap {
+    def: bpf_map_def,
+}
+
+impl XskMap {
+    /// Creates a map with the specified maximum number of elements, usually
+    /// the number of queues of the interface.
+    pub const fn with_max_entries(max_entries: u32) -> Self {
+        Self {
+            def: bpf_map_def {
+                type_: bpf_map_type_BPF_MAP_TYPE_XSKMAP,
+                key_size: mem::size_of::<u32>() as u32,
+                value_size: mem::size_of::<u32>() as u32,
+                max_entries,
+                map_flags: 0,
+            },
+        }
+    }
+
+    /// Redirects the packet to the socket bound to `queue_id`, usually the
+    /// `rx_queue_index` of the packet.
+    ///
+    /// Returns `XdpAction::Redirect` on success. If no socket is bound to the
+    /// queue, the action in the lower bits of `flags` is returned instead,
+    /// which is `XdpAction::Aborted` when `flags` is `0`.
+    #[inline]
+    #[helpers]
+    pub fn redirect(&mut self, queue_id: u32, flags: u64) -> XdpAction {
+        let action = unsafe {
+            bpf_redirect_map(&mut self.def as *mut _ as *mut c_void, queue_id, flags) as u32
+        };
+        match action {
+            xdp_action_XDP_DROP => XdpAction::Drop,
+            xdp_action_XDP_PASS => XdpAction::Pass,
+            xdp_action_XDP_TX => XdpAction::Tx,
+            xdp_action_XDP_REDIRECT => XdpAction::Redirect,
+            _ => XdpAction::Aborted,
+        }
+    }
+}
@@ -0,0 +1,291 @@
+//! Batched map operations.
+//!
+//! Linux 5.6 added `BPF_MAP_*_BATCH` commands that process many map entries
+//! with a single syscall. The functions in this module use them when
+//! available, and fall back to one syscall per entry on older kernels.
+use std::io;
+use std::mem;
+use std::ptr;
+
+use crate::dump::is_percpu;
+use crate::{sys, LoadError, Map, Pod, Result, VoidPtr};
+
+const BPF_MAP_LOOKUP_BATCH: u32 = 24;
+const BPF_MAP_UPDATE_BATCH: u32 = 26;
+const BPF_MAP_DELETE_BATCH: u32 = 27;
+
+const ENOTSUPP: i32 = 524;
+
+// Batch part of `union bpf_attr`.
+#[repr(C)]
+#[derive(Default)]
+struct BatchAttr {
+    in_batch: u64,
+    out_batch: u64,
+    keys: u64,
+    values: u64,
+    count: u32,
+    map_fd: u32,
+    elem_flags: u64,
+    flags: u64,
+}
+
+unsafe fn batch_syscall(cmd: u32, attr: &mut BatchAttr) -> io::Result<()> {
+    sys::bpf(cmd, attr).map(|_| ())
+}
+
+// Maps that don't implement batching return ENOTSUPP. Kernels older than 5.6
+// reject the unknown command `cmd` with EINVAL, which is also the error for
+// invalid arguments, so EINVAL only means unsupported if `cmd` is unknown.
+fn batch_unsupported(cmd: u32, e: &io::Error) -> bool {
+    match e.raw_os_error() {
+        Some(libc::EOPNOTSUPP) | Some(ENOTSUPP) => true,
+        Some(libc::EINVAL) => batch_command_unknown(cmd),
+        _ => false,
+    }
+}
+
+// Kernels knowing `cmd` fail on the invalid map fd with EBADF, older ones
+// reject the command itself with EINVAL.
+fn batch_command_unknown(cmd: u32) -> bool {
+    let mut attr = BatchAttr {
+        map_fd: std::u32::MAX,
+        ..Default::default()
+    };
+    match unsafe { batch_syscall(cmd, &mut attr) } {
+        Err(ref e) => e.raw_os_error() == Some(libc::EINVAL),
+        Ok(()) => false,
+    }
+}
+
+impl Map {
+    fn check_not_percpu(&self) -> Result<()> {
+        // per-CPU values are larger than value_size
+        if is_percpu(self.config.type_) {
+            return Err(LoadError::NotSupported(format!(
+                "batch operations on per-CPU map `{}'",
+                self.name
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Returns all the entries of the map.
+    ///
+    /// `K` and `V` must have the same size as the keys and values of the map.
+    /// Entries are read `max_entries` at a time with `BPF_MAP_LOOKUP_BATCH`
+    /// when supported. Per-CPU maps are not supported.
+    pub fn lookup_batch<K: Pod, V: Pod>(&self) -> Result<Vec<(K, V)>> {
+        self.check_not_percpu()?;
+        self.assert_layout::<K, V>()?;
+        match self.lookup_batch_syscall() {
+            Err(LoadError::IO(ref e)) if batch_unsupported(BPF_MAP_LOOKUP_BATCH, e) => {
+                self.lookup_each()
+            }
+            res => res,
+        }
+    }
+
+    fn lookup_batch_syscall<K: Pod, V: Pod>(&self) -> Result<Vec<(K, V)>> {
+        let batch_size = self.config.max_entries.max(1);
+        let mut keys = Vec::<K>::with_capacity(batch_size as usize);
+        let mut values = Vec::<V>::with_capacity(batch_size as usize);
+        // the cursor is opaque, and at most as large as a key
+        let cursor_size = mem::size_of::<K>().max(mem::size_of::<u64>());
+        let mut in_batch = vec![0u8; cursor_size];
+        let mut out_batch = vec![0u8; cursor_size];
+        let mut entries = Vec::new();
+        let mut first = true;
+
+        loop {
+            let mut attr = BatchAttr {
+                in_batch: if first { 0 } else { in_batch.as_ptr() as u64 },
+                out_batch: out_batch.as_mut_ptr() as u64,
+                keys: keys.as_mut_ptr() as u64,
+                values: values.as_mut_ptr() as u64,
+                count: batch_size,
+                map_fd: self.fd as u32,
+                ..Default::default()
+            };
+            let res = unsafe { batch_syscall(BPF_MAP_LOOKUP_BATCH, &mut attr) };
+            // ENOENT signals the end of the map, the entries copied by the last
+            // call are still valid
+            let done = match res {
+                Ok(()) => false,
+                Err(ref e) if e.raw_os_error() == Some(libc::ENOENT) => true,
+                Err(e) => return Err(LoadError::IO(e)),
+            };
+
+            unsafe {
+                keys.set_len(attr.count as usize);
+                values.set_len(attr.count as usize);
+            }
+            entries.extend(keys.drain(..).zip(values.drain(..)));
+            if done {
+                return Ok(entries);
+            }
+            in_batch.copy_from_slice(&out_batch);
+            first = false;
+        }
+    }
+
+    fn lookup_each<K: Pod, V: Pod>(&self) -> Result<Vec<(K, V)>> {
+        let mut entries = Vec::new();
+        // `lookup_batch` checked that `K` and `V` have the size of the keys
+        // and values
+        self.for_each_entry_bytes(|key, value| unsafe {
+            entries.push((
+                ptr::read_unaligned(key.as_ptr() as *const K),
+                ptr::read_unaligned(value.as_ptr() as *const V),
+            ))
+        });
+
+        Ok(entries)
+    }
+
+    /// Calls `f` with the key and value bytes of every entry of the map, read
+    /// one entry at a time with `bpf_get_next_key` and `bpf_lookup_elem`.
+    ///
+    /// Entries deleted between finding their key and looking them up are
+    /// skipped.
+    pub(crate) fn for_each_entry_bytes<F: FnMut(&[u8], &[u8])>(&self, mut f: F) {
+        let key_size = self.config.key_size as usize;
+        let mut key = vec![0u8; key_size];
+        let mut next_key = vec![0u8; key_size];
+        let mut value = vec![0u8; self.config.value_size as usize];
+
+        let mut ret =
+            unsafe { bpf_sys::bpf_get_first_key(self.fd, key.as_mut_ptr() as VoidPtr, key_size) };
+        while ret == 0 {
+            unsafe {
+                if bpf_sys::bpf_lookup_elem(
+                    self.fd,
+                    key.as_mut_ptr() as VoidPtr,
+                    value.as_mut_ptr() as VoidPtr,
+                ) == 0
+                {
+                    f(&key, &value);
+                }
+                ret = bpf_sys::bpf_get_next_key(
+                    self.fd,
+                    key.as_mut_ptr() as VoidPtr,
+                    next_key.as_mut_ptr() as VoidPtr,
+                );
+            }
+            mem::swap(&mut key, &mut next_key);
+        }
+    }
+
+    /// Sets the values of several keys at once.
+    ///
+    /// `K` and `V` must have the same size as the keys and values of the map.
+    /// `flags` are the same as for single updates, eg. `BPF_NOEXIST`.
+    /// Per-CPU maps are not supported.
+    pub fn update_batch<K: Pod, V: Pod>(
+        &self,
+        keys: &[K],
+        values: &[V],
+        flags: u64,
+    ) -> Result<()> {
+        self.check_not_percpu()?;
+        self.assert_layout::<K, V>()?;
+        if keys.len() != values.len() {
+            return Err(LoadError::InvalidMap(
+                "keys and values have different lengths".to_string(),
+            ));
+        }
+
+        let mut attr = BatchAttr {
+            keys: keys.as_ptr() as u64,
+            values: values.as_ptr() as u64,
+            count: keys.len() as u32,
+            map_fd: self.fd as u32,
+            elem_flags: flags,
+            ..Default::default()
+        };
+        match unsafe { batch_syscall(BPF_MAP_UPDATE_BATCH, &mut attr) } {
+            Ok(()) => Ok(()),
+            Err(ref e) if batch_unsupported(BPF_MAP_UPDATE_BATCH, e) => {
+                for (key, value) in keys.iter().zip(values.iter()) {
+                    let ret = unsafe {
+                        bpf_sys::bpf_update_elem(
+                            self.fd,
+                            key as *const K as VoidPtr,
+                            value as *const V as VoidPtr,
+                            flags,
+                        )
+                    };
+                    if ret < 0 {
+                        return Err(LoadError::IO(io::Error::last_os_error()));
+                    }
+                }
+                Ok(())
+            }
+            Err(e) => Err(LoadError::IO(e)),
+        }
+    }
+
+    /// Deletes several keys at once.
+    ///
+    /// `K` must have the same size as the keys of the map.
+    pub fn delete_batch<K: Pod>(&self, keys: &[K]) -> Result<()> {
+        self.assert_key_layout::<K>()?;
+
+        let mut attr = BatchAttr {
+            keys: keys.as_ptr() as u64,
+            count: keys.len() as u32,
+            map_fd: self.fd as u32,
+            ..Default::default()
+        };
+        match unsafe { batch_syscall(BPF_MAP_DELETE_BATCH, &mut attr) } {
+            Ok(()) => Ok(()),
+            Err(ref e) if batch_unsupported(BPF_MAP_DELETE_BATCH, e) => {
+                for key in keys.iter() {
+                    let ret =
+                        unsafe { bpf_sys::bpf_delete_elem(self.fd, key as *const K as VoidPtr) };
+                    if ret < 0 {
+                        return Err(LoadError::IO(io::Error::last_os_error()));
+                    }
+                }
+                Ok(())
+            }
+            Err(e) => Err(LoadError::IO(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bpf_sys::bpf_map_def;
+
+    #[test]
+    fn test_percpu_rejected() {
+        let kind = bpf_sys::bpf_map_type_BPF_MAP_TYPE_PERCPU_HASH;
+        let mut config: bpf_map_def = unsafe { mem::zeroed() };
+        config.type_ = kind;
+        config.key_size = 4;
+        config.value_size = 8;
+        let map = Map {
+            name: "counts".to_string(),
+            kind,
+            fd: -1,
+            config,
+            numa_node: None,
+            value_btf: None,
+        };
+        match map.lookup_batch::<u32, u64>() {
+            Err(LoadError::NotSupported(_)) => (),
+            _ => panic!("per-CPU map accepted"),
+        }
+        match map.update_batch(&[1u32], &[1u64], 0) {
+            Err(LoadError::NotSupported(_)) => (),
+            _ => panic!("per-CPU map accepted"),
+        }
+        match map.delete_batch(&[1u64]) {
+            Err(LoadError::InvalidMap(_)) => (),
+            _ => panic!("wrong key size accepted"),
+        }
+    }
+}
@@ -0,0 +1,737 @@
+//! Generation of C headers from BTF, eg. `vmlinux.h` from the BTF of the
+//! running kernel, the same way `bpftool btf dump file ... format c` does.
+//!
+//! Types are emitted so that every type used by value, eg. the struct of a
+//! member, is defined before it is used, while types only used through
+//! pointers don't need to be. Types with the same name, which BTF allows,
+//! are renamed with a `___N` suffix so that the header compiles, and CO-RE
+//! relocations still match them thanks to the suffix.
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+use super::{Btf, Member, Type};
+use crate::{LoadError, Result};
+
+const HEADER: &str = "\
+#ifndef __VMLINUX_H__
+#define __VMLINUX_H__
+
+#ifndef BPF_NO_PRESERVE_ACCESS_INDEX
+#pragma clang attribute push (__attribute__((preserve_access_index)), apply_to = record)
+#endif
+
+";
+
+const FOOTER: &str = "
+#ifndef BPF_NO_PRESERVE_ACCESS_INDEX
+#pragma clang attribute pop
+#endif
+
+#endif /* __VMLINUX_H__ */
+";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    NotEmitted,
+    Emitting,
+    Emitted,
+}
+
+struct Emitter<'a> {
+    btf: &'a Btf,
+    states: Vec<State>,
+    // the names types are emitted with, after renaming duplicates
+    names: Vec<Option<String>>,
+    enum_values: HashMap<u32, Vec<String>>,
+    // the structs and unions declared before their definition
+    declared: HashSet<u32>,
+    out: String,
+}
+
+fn invalid(id: u32) -> LoadError {
+    LoadError::BTF(format!("invalid type id {}", id))
+}
+
+fn unique(seen: &mut HashMap<String, usize>, name: &str) -> String {
+    let count = seen.entry(name.to_string()).or_insert(0);
+    *count += 1;
+    if *count == 1 {
+        name.to_string()
+    } else {
+        format!("{}___{}", name, count)
+    }
+}
+
+// Assigns unique names within the namespaces of C: struct, union and enum
+// tags share one, typedefs and enum values another.
+fn unique_names(btf: &Btf) -> (Vec<Option<String>>, HashMap<u32, Vec<String>>) {
+    let mut tags: HashMap<String, usize> = HashMap::new();
+    let mut idents: HashMap<String, usize> = HashMap::new();
+
+    let mut names = Vec::with_capacity(btf.types.len());
+    let mut enum_values = HashMap::new();
+    for (id, ty) in btf.types.iter().enumerate() {
+        let name = match ty {
+            Type::Struct { name, .. } | Type::Union { name, .. } if !name.is_empty() => {
+                Some(unique(&mut tags, name))
+            }
+            Type::Enum { name, values, .. } => {
+                let values = values
+                    .iter()
+                    .map(|(value, _)| unique(&mut idents, value))
+                    .collect();
+                enum_values.insert(id as u32, values);
+                if name.is_empty() {
+                    None
+                } else {
+                    Some(unique(&mut tags, name))
+                }
+            }
+            Type::Typedef { name, .. } => Some(unique(&mut idents, name)),
+            _ => None,
+        };
+        names.push(name);
+    }
+
+    (names, enum_values)
+}
+
+impl<'a> Emitter<'a> {
+    fn new(btf: &'a Btf) -> Emitter<'a> {
+        let (names, enum_values) = unique_names(btf);
+        Emitter {
+            btf,
+            states: vec![State::NotEmitted; btf.types.len()],
+            names,
+            enum_values,
+            declared: HashSet::new(),
+            out: String::new(),
+        }
+    }
+
+    fn ty(&self, id: u32) -> Result<&'a Type> {
+        self.btf.type_by_id(id).ok_or_else(|| invalid(id))
+    }
+
+    fn name(&self, id: u32) -> &str {
+        self.names[id as usize].as_ref().map_or("", String::as_str)
+    }
+
+    // Returns the alignment of a type, in bytes.
+    fn alignment(&self, id: u32) -> Result<u32> {
+        let (id, ty) = self.btf.resolve(id)?;
+        Ok(match ty {
+            Type::Int { size, .. } | Type::Float { size, .. } | Type::Enum { size, .. } => {
+                (*size).min(8).max(1)
+            }
+            Type::Ptr(_) => 8,
+            Type::Array { type_id, .. } => self.alignment(*type_id)?,
+            Type::Struct { members, .. } | Type::Union { members, .. } => {
+                if self.is_packed(id)? {
+                    1
+                } else {
+                    let mut align = 1;
+                    for member in members {
+                        align = align.max(self.alignment(member.type_id)?);
+                    }
+                    align
+                }
+            }
+            _ => 1,
+        })
+    }
+
+    // Whether the layout of a struct can only be reproduced with
+    // `__attribute__((packed))`.
+    fn is_packed(&self, id: u32) -> Result<bool> {
+        let (size, members) = match self.ty(id)? {
+            Type::Struct { size, members, .. } | Type::Union { size, members, .. } => {
+                (*size, members)
+            }
+            _ => return Ok(false),
+        };
+        let mut align = 1;
+        for member in members {
+            let member_align = self.alignment(member.type_id)?;
+            if member.bitfield_size == 0 && (member.offset / 8) % member_align != 0 {
+                return Ok(true);
+            }
+            align = align.max(member_align);
+        }
+
+        Ok(size % align != 0)
+    }
+
+    // Emits the types `id` depends on. Types used by value have to be
+    // defined first, types behind pointers only need to be declared.
+    fn emit_deps(&mut self, id: u32, by_value: bool) -> Result<()> {
+        match self.ty(id)? {
+            Type::Ptr(type_id) => self.emit_deps(*type_id, false),
+            Type::Array { type_id, .. }
+            | Type::Const(type_id)
+            | Type::Volatile(type_id)
+            | Type::Restrict(type_id)
+            | Type::TypeTag { type_id, .. } => self.emit_deps(*type_id, by_value),
+            Type::FuncProto {
+                return_type,
+                params,
+            } => {
+                self.emit_deps(*return_type, false)?;
+                self.declare(*return_type)?;
+                for (_, type_id) in params.iter() {
+                    self.emit_deps(*type_id, false)?;
+                    self.declare(*type_id)?;
+                }
+                Ok(())
+            }
+            Type::Struct { name, members, .. } | Type::Union { name, members, .. } => {
+                if name.is_empty() {
+                    // defined inline where it is used
+                    for member in members.iter() {
+                        self.emit_deps(member.type_id, true)?;
+                    }
+                    Ok(())
+                } else if by_value {
+                    self.emit(id)
+                } else {
+                    Ok(())
+                }
+            }
+            Type::Typedef { type_id, .. } => {
+                self.emit_deps(*type_id, by_value)?;
+                self.emit(id)
+            }
+            Type::Enum { name, .. } if !name.is_empty() => self.emit(id),
+            _ => Ok(()),
+        }
+    }
+
+    // Declares the struct or union a function parameter or return type
+    // points to, if it's not defined yet: a struct first seen in a
+    // parameter list would only be declared within the function prototype.
+    fn declare(&mut self, id: u32) -> Result<()> {
+        let (keyword, name) = match self.ty(id)? {
+            Type::Ptr(type_id)
+            | Type::Array { type_id, .. }
+            | Type::Const(type_id)
+            | Type::Volatile(type_id)
+            | Type::Restrict(type_id)
+            | Type::TypeTag { type_id, .. } => return self.declare(*type_id),
+            Type::Struct { name, .. } if !name.is_empty() => ("struct", self.name(id)),
+            Type::Union { name, .. } if !name.is_empty() => ("union", self.name(id)),
+            Type::Fwd(name) => (self.fwd_keyword(name), name.as_str()),
+            _ => return Ok(()),
+        };
+        if self.states[id as usize] == State::NotEmitted && !self.declared.contains(&id) {
+            let declaration = format!("{} {};\n\n", keyword, name);
+            self.out.push_str(&declaration);
+            self.declared.insert(id);
+        }
+
+        Ok(())
+    }
+
+    fn fwd_keyword(&self, name: &str) -> &'static str {
+        let is_union = self
+            .btf
+            .type_ids_by_name(name)
+            .iter()
+            .any(|id| matches_union(self.btf.type_by_id(*id)));
+        if is_union {
+            "union"
+        } else {
+            "struct"
+        }
+    }
+
+    // Emits the definition of the named type `id`.
+    fn emit(&mut self, id: u32) -> Result<()> {
+        if self.states[id as usize] != State::NotEmitted {
+            return Ok(());
+        }
+        self.states[id as usize] = State::Emitting;
+        let definition = match self.ty(id)? {
+            Type::Struct { members, .. } | Type::Union { members, .. } => {
+                for member in members.iter() {
+                    self.emit_deps(member.type_id, true)?;
+                }
+                format!("{};\n\n", self.composite(id, 0)?)
+            }
+            Type::Enum { .. } => format!("{};\n\n", self.enumeration(id, 0)?),
+            Type::Typedef { type_id, .. } => {
+                self.emit_deps(*type_id, false)?;
+                let name = self.name(id).to_string();
+                format!("typedef {};\n\n", self.declaration(*type_id, &name, 0)?)
+            }
+            _ => return Ok(()),
+        };
+        self.out.push_str(&definition);
+        self.states[id as usize] = State::Emitted;
+
+        Ok(())
+    }
+
+    fn enumeration(&self, id: u32, indent: usize) -> Result<String> {
+        let (size, signed, values) = match self.ty(id)? {
+            Type::Enum {
+                size,
+                signed,
+                values,
+                ..
+            } => (*size, *signed, values),
+            _ => return Err(invalid(id)),
+        };
+        let names = &self.enum_values[&id];
+        let mut out = format!("enum {}", self.name(id));
+        if !self.name(id).is_empty() {
+            out.push(' ');
+        }
+        out.push_str("{\n");
+        for ((_, value), name) in values.iter().zip(names.iter()) {
+            let _ = if size == 8 && !signed {
+                writeln!(out, "{}{} = {}ULL,", tabs(indent + 1), name, *value as u64)
+            } else {
+                writeln!(out, "{}{} = {},", tabs(indent + 1), name, value)
+            };
+        }
+        out.push_str(&tabs(indent));
+        out.push('}');
+
+        Ok(out)
+    }
+
+    fn composite(&self, id: u32, indent: usize) -> Result<String> {
+        let (keyword, size, members) = match self.ty(id)? {
+            Type::Struct { size, members, .. } => ("struct", *size, members),
+            Type::Union { size, members, .. } => ("union", *size, members),
+            _ => return Err(invalid(id)),
+        };
+        let packed = self.is_packed(id)?;
+        let mut out = format!("{} {}", keyword, self.name(id));
+        if !self.name(id).is_empty() {
+            out.push(' ');
+        }
+        out.push_str("{\n");
+
+        // offset of the end of the previous member, in bits
+        let mut end = 0;
+        for (i, member) in members.iter().enumerate() {
+            if keyword == "struct" {
+                self.padding(&mut out, indent + 1, i, end, member, packed)?;
+            }
+            let mut decl = self.declaration(member.type_id, &member.name, indent + 1)?;
+            if member.bitfield_size > 0 {
+                let _ = write!(decl, ": {}", member.bitfield_size);
+                end = member.offset + member.bitfield_size;
+            } else {
+                end = member.offset + self.btf.type_size(member.type_id)? * 8;
+            }
+            let _ = writeln!(out, "{}{};", tabs(indent + 1), decl);
+        }
+        if keyword == "struct" {
+            let align = if packed { 1 } else { self.alignment(id)? };
+            let natural = round_up((end + 7) / 8, align);
+            if size > natural {
+                let _ = writeln!(
+                    out,
+                    "{}char __pad_end[{}];",
+                    tabs(indent + 1),
+                    size - (end + 7) / 8
+                );
+            }
+        }
+        out.push_str(&tabs(indent));
+        out.push('}');
+        if packed {
+            out.push_str(" __attribute__((packed))");
+        }
+
+        Ok(out)
+    }
+
+    // Pads the gaps the natural alignment of a member doesn't explain, eg.
+    // from `__aligned` attributes or removed members.
+    fn padding(
+        &self,
+        out: &mut String,
+        indent: usize,
+        index: usize,
+        end: u32,
+        member: &Member,
+        packed: bool,
+    ) -> Result<()> {
+        if member.bitfield_size > 0 {
+            return Ok(());
+        }
+        let start = (end + 7) / 8;
+        let align = if packed {
+            1
+        } else {
+            self.alignment(member.type_id)?
+        };
+        let offset = member.offset / 8;
+        if offset > round_up(start, align) {
+            let _ = writeln!(
+                out,
+                "{}char __pad_{}[{}];",
+                tabs(indent),
+                index,
+                offset - start
+            );
+        }
+
+        Ok(())
+    }
+
+    // Returns the C declaration of `name` with the type `id`, eg.
+    // `int (*name)[4]`.
+    fn declaration(&self, id: u32, name: &str, indent: usize) -> Result<String> {
+        let ty = self.ty(id)?;
+        let (base, declarator) = match ty {
+            Type::Ptr(type_id) => {
+                let inner = match self.ty(*type_id)? {
+                    Type::Array { .. } | Type::FuncProto { .. } => format!("(*{})", name),
+                    _ => format!("*{}", name),
+                };
+                return self.declaration(*type_id, &inner, indent);
+            }
+            Type::Array { type_id, nelems } => {
+                return self.declaration(*type_id, &format!("{}[{}]", name, nelems), indent);
+            }
+            Type::FuncProto {
+                return_type,
+                params,
+            } => {
+                let mut args = Vec::with_capacity(params.len());
+                for (i, (param, type_id)) in params.iter().enumerate() {
+                    if *type_id == 0 && i == params.len() - 1 {
+                        args.push("...".to_string());
+                    } else {
+                        args.push(self.declaration(*type_id, param, indent)?);
+                    }
+                }
+                if args.is_empty() {
+                    args.push("void".to_string());
+                }
+                let inner = format!("{}({})", name, args.join(", "));
+                return self.declaration(*return_type, &inner, indent);
+            }
+            Type::Const(type_id) | Type::Volatile(type_id) | Type::Restrict(type_id) => {
+                let qualifier = match ty {
+                    Type::Const(_) => "const",
+                    Type::Volatile(_) => "volatile",
+                    _ => "restrict",
+                };
+                return match self.ty(*type_id)? {
+                    Type::Ptr(_) => {
+                        self.declaration(*type_id, &format!("{} {}", qualifier, name), indent)
+                    }
+                    _ => Ok(format!(
+                        "{} {}",
+                        qualifier,
+                        self.declaration(*type_id, name, indent)?
+                    )),
+                };
+            }
+            Type::TypeTag { type_id, .. } => return self.declaration(*type_id, name, indent),
+            Type::Void => ("void".to_string(), name),
+            Type::Int { name: ty, .. } | Type::Float { name: ty, .. } => (ty.clone(), name),
+            Type::Struct { name: ty, .. } | Type::Union { name: ty, .. } if ty.is_empty() => {
+                (self.composite(id, indent)?, name)
+            }
+            Type::Struct { .. } => (format!("struct {}", self.name(id)), name),
+            Type::Union { .. } => (format!("union {}", self.name(id)), name),
+            Type::Enum { name: ty, .. } if ty.is_empty() => (self.enumeration(id, indent)?, name),
+            Type::Enum { .. } => (format!("enum {}", self.name(id)), name),
+            Type::Typedef { .. } => (self.name(id).to_string(), name),
+            Type::Fwd(ty) => (format!("{} {}", self.fwd_keyword(ty), ty), name),
+            _ => return Err(invalid(id)),
+        };
+
+        Ok(if declarator.is_empty() {
+            base
+        } else {
+            format!("{} {}", base, declarator)
+        })
+    }
+}
+
+fn matches_union(ty: Option<&Type>) -> bool {
+    match ty {
+        Some(Type::Union { .. }) => true,
+        _ => false,
+    }
+}
+
+fn round_up(value: u32, align: u32) -> u32 {
+    (value + align - 1) / align * align
+}
+
+fn tabs(indent: usize) -> String {
+    "\t".repeat(indent)
+}
+
+// Returns the types referenced by other types, as opposed to the top level
+// ones.
+fn referenced_types(btf: &Btf) -> HashSet<u32> {
+    let mut referenced = HashSet::new();
+    for ty in btf.types.iter() {
+        match ty {
+            Type::Ptr(type_id)
+            | Type::Array { type_id, .. }
+            | Type::Typedef { type_id, .. }
+            | Type::Const(type_id)
+            | Type::Volatile(type_id)
+            | Type::Restrict(type_id)
+            | Type::TypeTag { type_id, .. }
+            | Type::Var { type_id, .. } => {
+                referenced.insert(*type_id);
+            }
+            Type::Struct { members, .. } | Type::Union { members, .. } => {
+                referenced.extend(members.iter().map(|member| member.type_id));
+            }
+            Type::FuncProto {
+                return_type,
+                params,
+            } => {
+                referenced.insert(*return_type);
+                referenced.extend(params.iter().map(|(_, type_id)| *type_id));
+            }
+            _ => {}
+        }
+    }
+    referenced
+}
+
+impl Btf {
+    /// Generates a C header declaring all the types, eg. `vmlinux.h` from
+    /// the BTF of the kernel.
+    ///
+    /// Structs and unions are declared with the `preserve_access_index`
+    /// attribute, so that the field accesses of programs compiled with the
+    /// header are relocated by CO-RE, unless `BPF_NO_PRESERVE_ACCESS_INDEX`
+    /// is defined.
+    pub fn to_c_header(&self) -> Result<String> {
+        let referenced = referenced_types(self);
+        let mut emitter = Emitter::new(self);
+        for id in 1..self.types.len() as u32 {
+            match &self.types[id as usize] {
+                Type::Struct { name, .. } | Type::Union { name, .. } | Type::Enum { name, .. }
+                    if !name.is_empty() =>
+                {
+                    emitter.emit_deps(id, true)?
+                }
+                Type::Typedef { .. } => emitter.emit_deps(id, true)?,
+                // anonymous enums only declaring constants
+                Type::Enum { .. } if !referenced.contains(&id) => {
+                    let definition = emitter.enumeration(id, 0)?;
+                    let _ = write!(emitter.out, "{};\n\n", definition);
+                }
+                _ => {}
+            }
+        }
+
+        Ok(format!("{}{}{}", HEADER, emitter.out, FOOTER))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::btf::consts::{BTF_INT_CHAR, BTF_INT_SIGNED};
+
+    fn member(name: &str, type_id: u32, offset: u32) -> Member {
+        Member {
+            name: name.to_string(),
+            type_id,
+            offset,
+            bitfield_size: 0,
+        }
+    }
+
+    fn btf(types: Vec<Type>) -> Btf {
+        let mut names: HashMap<String, Vec<u32>> = HashMap::new();
+        for (id, ty) in types.iter().enumerate() {
+            if let Some(name) = ty.name() {
+                names.entry(name.to_string()).or_default().push(id as u32);
+            }
+        }
+        Btf { types, names }
+    }
+
+    #[test]
+    fn test_to_c_header() {
+        let btf = btf(vec![
+            Type::Void,
+            // 1
+            Type::Int {
+                name: "int".to_string(),
+                size: 4,
+                encoding: (BTF_INT_SIGNED << 24) | 32,
+            },
+            // 2: typedef struct list list_t;
+            Type::Typedef {
+                name: "list_t".to_string(),
+                type_id: 3,
+            },
+            // 3: struct list { list_t *next; struct node node; int (*cb)(int); }
+            Type::Struct {
+                name: "list".to_string(),
+                size: 24,
+                members: vec![
+                    member("next", 4, 0),
+                    member("node", 5, 64),
+                    member("cb", 7, 128),
+                ],
+            },
+            Type::Ptr(2),
+            // 5: struct node { int id; int flags: 3; }
+            Type::Struct {
+                name: "node".to_string(),
+                size: 8,
+                members: vec![
+                    member("id", 1, 0),
+                    Member {
+                        name: "flags".to_string(),
+                        type_id: 1,
+                        offset: 32,
+                        bitfield_size: 3,
+                    },
+                ],
+            },
+            // 6
+            Type::FuncProto {
+                return_type: 1,
+                params: vec![("".to_string(), 1)],
+            },
+            Type::Ptr(6),
+            // 8: a duplicate struct node
+            Type::Struct {
+                name: "node".to_string(),
+                size: 4,
+                members: vec![member("id", 1, 0)],
+            },
+            // 9
+            Type::Enum {
+                name: "".to_string(),
+                size: 4,
+                signed: true,
+                values: vec![("NODE_A".to_string(), 0), ("NODE_B".to_string(), 1)],
+            },
+        ]);
+        let header = btf.to_c_header().unwrap();
+        let body = &header[HEADER.len()..header.len() - FOOTER.len()];
+        assert_eq!(
+            body,
+            "typedef struct list list_t;\n\n\
+             struct node {\n\tint id;\n\tint flags: 3;\n};\n\n\
+             struct list {\n\tlist_t *next;\n\tstruct node node;\n\tint (*cb)(int);\n};\n\n\
+             struct node___2 {\n\tint id;\n};\n\n\
+             enum {\n\tNODE_A = 0,\n\tNODE_B = 1,\n};\n\n"
+        );
+    }
+
+    #[test]
+    fn test_packed() {
+        let btf = btf(vec![
+            Type::Void,
+            Type::Int {
+                name: "int".to_string(),
+                size: 4,
+                encoding: (BTF_INT_SIGNED << 24) | 32,
+            },
+            Type::Int {
+                name: "char".to_string(),
+                size: 1,
+                encoding: (BTF_INT_CHAR << 24) | 8,
+            },
+            Type::Struct {
+                name: "hdr".to_string(),
+                size: 5,
+                members: vec![member("kind", 2, 0), member("len", 1, 8)],
+            },
+            Type::Struct {
+                name: "aligned".to_string(),
+                size: 64,
+                members: vec![member("a", 1, 0), member("b", 1, 256)],
+            },
+        ]);
+        let emitter = Emitter::new(&btf);
+        assert!(emitter.is_packed(3).unwrap());
+        assert!(!emitter.is_packed(4).unwrap());
+        assert_eq!(
+            emitter.composite(4, 0).unwrap(),
+            "struct aligned {\n\tint a;\n\tchar __pad_1[28];\n\tint b;\n\tchar __pad_end[28];\n}"
+        );
+    }
+
+    #[test]
+    fn test_enum64() {
+        let btf = btf(vec![
+            Type::Void,
+            Type::Enum {
+                name: "flags".to_string(),
+                size: 8,
+                signed: false,
+                values: vec![("FLAG_HIGH".to_string(), std::i64::MIN)],
+            },
+            Type::Enum {
+                name: "offsets".to_string(),
+                size: 8,
+                signed: true,
+                values: vec![("OFFSET_MIN".to_string(), -(1 << 40))],
+            },
+        ]);
+        let emitter = Emitter::new(&btf);
+        assert_eq!(
+            emitter.enumeration(1, 0).unwrap(),
+            "enum flags {\n\tFLAG_HIGH = 9223372036854775808ULL,\n}"
+        );
+        assert_eq!(
+            emitter.enumeration(2, 0).unwrap(),
+            "enum offsets {\n\tOFFSET_MIN = -1099511627776,\n}"
+        );
+    }
+
+    #[test]
+    fn test_forward_declarations() {
+        let btf = btf(vec![
+            Type::Void,
+            // 1
+            Type::Int {
+                name: "int".to_string(),
+                size: 4,
+                encoding: (BTF_INT_SIGNED << 24) | 32,
+            },
+            // 2: struct ops { int (*open)(struct file *); }
+            Type::Struct {
+                name: "ops".to_string(),
+                size: 8,
+                members: vec![member("open", 5, 0)],
+            },
+            // 3: struct file { struct ops *ops; }
+            Type::Struct {
+                name: "file".to_string(),
+                size: 8,
+                members: vec![member("ops", 6, 0)],
+            },
+            Type::Ptr(3),
+            // 5
+            Type::Ptr(7),
+            Type::Ptr(2),
+            // 7
+            Type::FuncProto {
+                return_type: 1,
+                params: vec![("".to_string(), 4)],
+            },
+        ]);
+        let header = btf.to_c_header().unwrap();
+        let body = &header[HEADER.len()..header.len() - FOOTER.len()];
+        assert_eq!(
+            body,
+            "struct file;\n\n\
+             struct ops {\n\tint (*open)(struct file *);\n};\n\n\
+             struct file {\n\tstruct ops *ops;\n};\n\n"
+        );
+    }
+}
@@ -0,0 +1,27 @@
+//! Constants of the BTF format, shared by the BTF parser and the minimal BTF
+//! blobs generated to create maps.
+pub(crate) const BTF_MAGIC: u16 = 0xeb9f;
+
+pub(crate) const BTF_KIND_INT: u32 = 1;
+pub(crate) const BTF_KIND_PTR: u32 = 2;
+pub(crate) const BTF_KIND_ARRAY: u32 = 3;
+pub(crate) const BTF_KIND_STRUCT: u32 = 4;
+pub(crate) const BTF_KIND_UNION: u32 = 5;
+pub(crate) const BTF_KIND_ENUM: u32 = 6;
+pub(crate) const BTF_KIND_FWD: u32 = 7;
+pub(crate) const BTF_KIND_TYPEDEF: u32 = 8;
+pub(crate) const BTF_KIND_VOLATILE: u32 = 9;
+pub(crate) const BTF_KIND_CONST: u32 = 10;
+pub(crate) const BTF_KIND_RESTRICT: u32 = 11;
+pub(crate) const BTF_KIND_FUNC: u32 = 12;
+pub(crate) const BTF_KIND_FUNC_PROTO: u32 = 13;
+pub(crate) const BTF_KIND_VAR: u32 = 14;
+pub(crate) const BTF_KIND_DATASEC: u32 = 15;
+pub(crate) const BTF_KIND_FLOAT: u32 = 16;
+pub(crate) const BTF_KIND_DECL_TAG: u32 = 17;
+pub(crate) const BTF_KIND_TYPE_TAG: u32 = 18;
+pub(crate) const BTF_KIND_ENUM64: u32 = 19;
+
+pub(crate) const BTF_INT_SIGNED: u32 = 1 << 0;
+pub(crate) const BTF_INT_CHAR: u32 = 1 << 1;
+pub(crate) const BTF_INT_BOOL: u32 = 1 << 2;
@@ -0,0 +1,1246 @@
+//! # BTF and CO-RE relocations
+//!
+//! BTF (BPF Type Format) describes the types used by eBPF programs and by the
+//! kernel itself. When a program is compiled with CO-RE (compile once, run
+//! everywhere) relocations, the compiler records every field access in the
+//! `.BTF.ext` section of the object instead of assuming a fixed struct layout.
+//! During loading, the accesses are matched against the BTF of the running
+//! kernel, exposed at `/sys/kernel/btf/vmlinux`, and the instructions are
+//! patched with the field offsets of the running kernel.
+//!
+//! Relocation records are emitted by clang for C programs using
+//! `BPF_CORE_READ()` from `bpf_core_read.h`, or
+//! `__builtin_preserve_access_index()` directly, eg. built with
+//! `redbpf::build` and debug info. rustc doesn't emit them, so Rust probes
+//! are not relocated and there is no relocation-aware `core_read!` macro:
+//! `redbpf_probes::probe_read_field!` reads fields at the offsets of the
+//! bindings the probe was compiled against.
+//!
+//! Only the relocations of the programs being loaded are resolved, so
+//! objects without any don't need the BTF of the running kernel.
+//!
+//! `Btf::to_c_header` generates C headers from BTF, eg. `vmlinux.h` for C
+//! programs using CO-RE.
+//!
+//! Parsing BTF and generating headers is always available, while
+//! `redbpf::Module` only applies CO-RE relocations with the `core` cargo
+//! feature.
+use std::cmp;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt::{self, Display};
+use std::fs;
+use std::mem;
+use std::path::Path;
+
+use bpf_sys::bpf_insn;
+
+use crate::prog_load::ExtInfo;
+use crate::{LoadError, Result};
+
+mod c_header;
+pub(crate) mod consts;
+
+use self::consts::*;
+
+pub const KERNEL_BTF: &str = "/sys/kernel/btf/vmlinux";
+
+const BPF_FIELD_BYTE_OFFSET: u32 = 0;
+const BPF_FIELD_BYTE_SIZE: u32 = 1;
+const BPF_FIELD_EXISTS: u32 = 2;
+
+// Same limit as libbpf, BTF loaded from a file could contain cycles.
+const MAX_RESOLVE_DEPTH: usize = 32;
+
+const BPF_LDX: u8 = 0x01;
+const BPF_ST: u8 = 0x02;
+const BPF_STX: u8 = 0x03;
+
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub name: String,
+    pub type_id: u32,
+    /// Offset of the member in bits.
+    pub offset: u32,
+    /// Size of the member in bits if it is a bitfield, 0 otherwise.
+    pub bitfield_size: u32,
+}
+
+#[derive(Debug, Clone)]
+pub enum Type {
+    Void,
+    Int { name: String, size: u32, encoding: u32 },
+    Ptr(u32),
+    Array { type_id: u32, nelems: u32 },
+    Struct { name: String, size: u32, members: Vec<Member> },
+    Union { name: String, size: u32, members: Vec<Member> },
+    /// Enums, including the 64 bits enums of newer kernels, whose values are
+    /// unsigned unless `signed`.
+    Enum { name: String, size: u32, signed: bool, values: Vec<(String, i64)> },
+    Fwd(String),
+    Typedef { name: String, type_id: u32 },
+    Volatile(u32),
+    Const(u32),
+    Restrict(u32),
+    Func { name: String, type_id: u32 },
+    FuncProto { return_type: u32, params: Vec<(String, u32)> },
+    Var { name: String, type_id: u32 },
+    Datasec { name: String, size: u32 },
+    Float { name: String, size: u32 },
+    DeclTag { name: String, type_id: u32 },
+    TypeTag { name: String, type_id: u32 },
+}
+
+impl Type {
+    pub fn name(&self) -> Option<&str> {
+        use Type::*;
+        match self {
+            Int { name, .. } | Struct { name, .. } | Union { name, .. } | Enum { name, .. }
+            | Fwd(name) | Typedef { name, .. } | Func { name, .. } | Var { name, .. }
+            | Datasec { name, .. } | Float { name, .. } | DeclTag { name, .. }
+            | TypeTag { name, .. } => Some(name),
+            _ => None,
+        }
+    }
+
+    fn kind(&self) -> u32 {
+        use Type::*;
+        match self {
+            Void => 0,
+            Int { .. } => BTF_KIND_INT,
+            Ptr(_) => BTF_KIND_PTR,
+            Array { .. } => BTF_KIND_ARRAY,
+            Struct { .. } => BTF_KIND_STRUCT,
+            Union { .. } => BTF_KIND_UNION,
+            Enum { .. } => BTF_KIND_ENUM,
+            Fwd(_) => BTF_KIND_FWD,
+            Typedef { .. } => BTF_KIND_TYPEDEF,
+            Volatile(_) => BTF_KIND_VOLATILE,
+            Const(_) => BTF_KIND_CONST,
+            Restrict(_) => BTF_KIND_RESTRICT,
+            Func { .. } => BTF_KIND_FUNC,
+            FuncProto { .. } => BTF_KIND_FUNC_PROTO,
+            Var { .. } => BTF_KIND_VAR,
+            Datasec { .. } => BTF_KIND_DATASEC,
+            Float { .. } => BTF_KIND_FLOAT,
+            DeclTag { .. } => BTF_KIND_DECL_TAG,
+            TypeTag { .. } => BTF_KIND_TYPE_TAG,
+        }
+    }
+}
+
+/// Parsed BTF type information.
+#[derive(Debug)]
+pub struct Btf {
+    types: Vec<Type>,
+    names: HashMap<String, Vec<u32>>,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data, pos: 0 }
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_ne_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.pos + len > self.data.len() {
+            return Err(LoadError::BTF("unexpected end of data".to_string()));
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+}
+
+fn btf_str(strings: &[u8], offset: u32) -> Result<String> {
+    let strings = strings
+        .get(offset as usize..)
+        .ok_or_else(|| LoadError::BTF(format!("invalid string offset {}", offset)))?;
+    let end = strings.iter().position(|c| *c == 0).unwrap_or(strings.len());
+    Ok(String::from_utf8_lossy(&strings[..end]).into_owned())
+}
+
+impl Btf {
+    /// Parses the BTF of the running kernel.
+    pub fn from_kernel() -> Result<Btf> {
+        Btf::from_file(KERNEL_BTF)
+    }
+
+    /// Parses raw BTF data from a file, eg. `/sys/kernel/btf/vmlinux`.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Btf> {
+        let path = path.as_ref();
+        let data = fs::read(path)
+            .map_err(|e| LoadError::BTF(format!("couldn't read {:?}: {}", path, e)))?;
+        Btf::parse(&data)
+    }
+
+    /// Parses raw BTF data, such as the content of the `.BTF` ELF section.
+    pub fn parse(data: &[u8]) -> Result<Btf> {
+        let mut hdr = Reader::new(data);
+        if hdr.u16()? != BTF_MAGIC {
+            return Err(LoadError::BTF("invalid magic".to_string()));
+        }
+        let _version = hdr.bytes(1)?;
+        let _flags = hdr.bytes(1)?;
+        let hdr_len = hdr.u32()? as usize;
+        let type_off = hdr.u32()? as usize;
+        let type_len = hdr.u32()? as usize;
+        let str_off = hdr.u32()? as usize;
+        let str_len = hdr.u32()? as usize;
+
+        let body = &data[hdr_len.min(data.len())..];
+        let invalid = || LoadError::BTF("invalid section offsets".to_string());
+        let type_data = body.get(type_off..type_off + type_len).ok_or_else(invalid)?;
+        let strings = body.get(str_off..str_off + str_len).ok_or_else(invalid)?;
+
+        let mut types = vec![Type::Void];
+        let mut r = Reader::new(type_data);
+        while r.pos < type_data.len() {
+            let name = btf_str(strings, r.u32()?)?;
+            let info = r.u32()?;
+            let size_or_type = r.u32()?;
+            let vlen = info & 0xffff;
+            let kind = (info >> 24) & 0x1f;
+
+            let members = |r: &mut Reader<'_>| -> Result<Vec<Member>> {
+                let mut members = Vec::new();
+                for _ in 0..vlen {
+                    let name = btf_str(strings, r.u32()?)?;
+                    let type_id = r.u32()?;
+                    let offset = r.u32()?;
+                    // with kind_flag set, the upper 8 bits hold the bitfield size
+                    let (offset, bitfield_size) = if info >> 31 == 1 {
+                        (offset & 0xff_ffff, offset >> 24)
+                    } else {
+                        (offset, 0)
+                    };
+                    members.push(Member {
+                        name,
+                        type_id,
+                        offset,
+                        bitfield_size,
+                    });
+                }
+                Ok(members)
+            };
+
+            let ty = match kind {
+                BTF_KIND_INT => Type::Int {
+                    name,
+                    size: size_or_type,
+                    encoding: r.u32()?,
+                },
+                BTF_KIND_PTR => Type::Ptr(size_or_type),
+                BTF_KIND_ARRAY => {
+                    let type_id = r.u32()?;
+                    let _index_type = r.u32()?;
+                    let nelems = r.u32()?;
+                    Type::Array { type_id, nelems }
+                }
+                BTF_KIND_STRUCT => Type::Struct {
+                    name,
+                    size: size_or_type,
+                    members: members(&mut r)?,
+                },
+                BTF_KIND_UNION => Type::Union {
+                    name,
+                    size: size_or_type,
+                    members: members(&mut r)?,
+                },
+                BTF_KIND_ENUM => {
+                    let mut values = Vec::new();
+                    for _ in 0..vlen {
+                        let name = btf_str(strings, r.u32()?)?;
+                        values.push((name, i64::from(r.u32()? as i32)));
+                    }
+                    Type::Enum {
+                        name,
+                        size: size_or_type,
+                        signed: true,
+                        values,
+                    }
+                }
+                BTF_KIND_ENUM64 => {
+                    let mut values = Vec::new();
+                    for _ in 0..vlen {
+                        let name = btf_str(strings, r.u32()?)?;
+                        let lo = u64::from(r.u32()?);
+                        let hi = u64::from(r.u32()?);
+                        values.push((name, (hi << 32 | lo) as i64));
+                    }
+                    // kind_flag is set for signed values
+                    Type::Enum {
+                        name,
+                        size: size_or_type,
+                        signed: info >> 31 == 1,
+                        values,
+                    }
+                }
+                BTF_KIND_FWD => Type::Fwd(name),
+                BTF_KIND_TYPEDEF => Type::Typedef {
+                    name,
+                    type_id: size_or_type,
+                },
+                BTF_KIND_VOLATILE => Type::Volatile(size_or_type),
+                BTF_KIND_CONST => Type::Const(size_or_type),
+                BTF_KIND_RESTRICT => Type::Restrict(size_or_type),
+                BTF_KIND_FUNC => Type::Func {
+                    name,
+                    type_id: size_or_type,
+                },
+                BTF_KIND_FUNC_PROTO => {
+                    let mut params = Vec::new();
+                    for _ in 0..vlen {
+                        let name = btf_str(strings, r.u32()?)?;
+                        params.push((name, r.u32()?));
+                    }
+                    Type::FuncProto {
+                        return_type: size_or_type,
+                        params,
+                    }
+                }
+                BTF_KIND_VAR => {
+                    let _linkage = r.u32()?;
+                    Type::Var {
+                        name,
+                        type_id: size_or_type,
+                    }
+                }
+                BTF_KIND_DATASEC => {
+                    r.bytes(vlen as usize * 12)?;
+                    Type::Datasec {
+                        name,
+                        size: size_or_type,
+                    }
+                }
+                BTF_KIND_FLOAT => Type::Float {
+                    name,
+                    size: size_or_type,
+                },
+                BTF_KIND_DECL_TAG => {
+                    let _component_idx = r.u32()?;
+                    Type::DeclTag {
+                        name,
+                        type_id: size_or_type,
+                    }
+                }
+                BTF_KIND_TYPE_TAG => Type::TypeTag {
+                    name,
+                    type_id: size_or_type,
+                },
+                kind => return Err(LoadError::BTF(format!("unsupported type kind {}", kind))),
+            };
+            types.push(ty);
+        }
+
+        let mut names: HashMap<String, Vec<u32>> = HashMap::new();
+        for (id, ty) in types.iter().enumerate() {
+            if let Some(name) = ty.name() {
+                if !name.is_empty() {
+                    names.entry(name.to_string()).or_default().push(id as u32);
+                }
+            }
+        }
+
+        Ok(Btf { types, names })
+    }
+
+    /// Returns the type with the given id.
+    pub fn type_by_id(&self, id: u32) -> Option<&Type> {
+        self.types.get(id as usize)
+    }
+
+    /// Returns the ids of all the types with the given name.
+    pub fn type_ids_by_name(&self, name: &str) -> &[u32] {
+        self.names.get(name).map(|ids| &ids[..]).unwrap_or(&[])
+    }
+
+    /// Skips typedefs and type qualifiers.
+    pub fn resolve(&self, mut id: u32) -> Result<(u32, &Type)> {
+        for _ in 0..MAX_RESOLVE_DEPTH {
+            let ty = self
+                .type_by_id(id)
+                .ok_or_else(|| LoadError::BTF(format!("invalid type id {}", id)))?;
+            match ty {
+                Type::Typedef { type_id, .. }
+                | Type::Volatile(type_id)
+                | Type::Const(type_id)
+                | Type::Restrict(type_id)
+                | Type::TypeTag { type_id, .. } => id = *type_id,
+                ty => return Ok((id, ty)),
+            }
+        }
+
+        Err(LoadError::BTF(format!("type {} is nested too deeply", id)))
+    }
+
+    /// Returns the size of the type in bytes.
+    pub fn type_size(&self, id: u32) -> Result<u32> {
+        self.type_size_at(id, 0)
+    }
+
+    fn type_size_at(&self, id: u32, depth: usize) -> Result<u32> {
+        if depth >= MAX_RESOLVE_DEPTH {
+            return Err(LoadError::BTF(format!("type {} is nested too deeply", id)));
+        }
+        let (_, ty) = self.resolve(id)?;
+        Ok(match ty {
+            Type::Int { size, .. }
+            | Type::Struct { size, .. }
+            | Type::Union { size, .. }
+            | Type::Enum { size, .. }
+            | Type::Float { size, .. } => *size,
+            Type::Ptr(_) => 8,
+            Type::Array { type_id, nelems } => self
+                .type_size_at(*type_id, depth + 1)?
+                .checked_mul(*nelems)
+                .ok_or_else(|| LoadError::BTF(format!("size of type {} overflows", id)))?,
+            _ => 0,
+        })
+    }
+}
+
+/// A value decoded using its BTF type, see `Btf::decode`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(i64),
+    UInt(u64),
+    Bool(bool),
+    Char(u8),
+    /// A NUL terminated `char` array.
+    Str(String),
+    Float(f64),
+    Ptr(u64),
+    /// An enum value, with the name of the matching enumerator if any.
+    Enum { name: Option<String>, value: i64 },
+    Array(Vec<Value>),
+    /// The members of a struct or union, in declaration order.
+    Struct(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// Returns the member `name` of a struct value.
+    pub fn field(&self, name: &str) -> Option<&Value> {
+        match self {
+            Value::Struct(members) => members.iter().find(|(n, _)| n == name).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+}
+
+/// Formats the value as JSON.
+impl Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Value::*;
+        match self {
+            Int(v) => write!(f, "{}", v),
+            UInt(v) | Ptr(v) => write!(f, "{}", v),
+            Bool(v) => write!(f, "{}", v),
+            Char(v) => write!(f, "{}", v),
+            Str(v) => write!(f, "{:?}", v),
+            // JSON has no NaN or infinity
+            Float(v) if !v.is_finite() => write!(f, "null"),
+            Float(v) => write!(f, "{}", v),
+            Enum { name: Some(name), .. } => write!(f, "{:?}", name),
+            Enum { name: None, value } => write!(f, "{}", value),
+            Array(values) => {
+                write!(f, "[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            }
+            Struct(members) => {
+                write!(f, "{{")?;
+                for (i, (name, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{:?}: {}", name, value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn read_uint(data: &[u8], size: u32) -> Result<u64> {
+    let bytes = data
+        .get(..size as usize)
+        .ok_or_else(|| LoadError::BTF("value too short for its type".to_string()))?;
+    let mut buf = [0u8; 8];
+    match size {
+        1 | 2 | 4 | 8 if cfg!(target_endian = "little") => buf[..bytes.len()].copy_from_slice(bytes),
+        1 | 2 | 4 | 8 => buf[8 - bytes.len()..].copy_from_slice(bytes),
+        size => return Err(LoadError::BTF(format!("unsupported integer size {}", size))),
+    }
+    Ok(u64::from_ne_bytes(buf))
+}
+
+// Sign extends the low `bits` bits of `value`.
+fn sign_extend(value: u64, bits: u32) -> i64 {
+    let shift = 64 - bits;
+    ((value << shift) as i64) >> shift
+}
+
+fn int_value(raw: u64, bits: u32, encoding: u32) -> Value {
+    let flags = (encoding >> 24) & 0xf;
+    if flags & BTF_INT_BOOL != 0 {
+        Value::Bool(raw != 0)
+    } else if flags & BTF_INT_CHAR != 0 && bits == 8 {
+        Value::Char(raw as u8)
+    } else if flags & BTF_INT_SIGNED != 0 {
+        Value::Int(sign_extend(raw, bits))
+    } else {
+        Value::UInt(raw)
+    }
+}
+
+impl Btf {
+    /// Decodes `data` as a value of the type `type_id`.
+    ///
+    /// This gives generic access to map values described by BTF, eg. to
+    /// print them with their field names, without having to know their Rust
+    /// type.
+    pub fn decode(&self, type_id: u32, data: &[u8]) -> Result<Value> {
+        let (_, ty) = self.resolve(type_id)?;
+        let value = match ty {
+            Type::Int { size, encoding, .. } => {
+                if (encoding >> 16) & 0xff != 0 || encoding & 0xff != size * 8 {
+                    // bitfield described by the int encoding
+                    return self.decode_bits(type_id, data, 0, 0);
+                }
+                int_value(read_uint(data, *size)?, size * 8, *encoding)
+            }
+            Type::Float { size, .. } => {
+                let raw = read_uint(data, *size)?;
+                match size {
+                    4 => Value::Float(f32::from_bits(raw as u32) as f64),
+                    8 => Value::Float(f64::from_bits(raw)),
+                    size => return Err(LoadError::BTF(format!("unsupported float size {}", size))),
+                }
+            }
+            Type::Ptr(_) => Value::Ptr(read_uint(data, 8)?),
+            Type::Enum { size, values, .. } => {
+                let value = sign_extend(read_uint(data, *size)?, size * 8);
+                let name = values
+                    .iter()
+                    .find(|(_, v)| *v == value)
+                    .map(|(n, _)| n.clone());
+                Value::Enum { name, value }
+            }
+            Type::Array { type_id, nelems } => {
+                let elem_size = self.type_size(*type_id)? as usize;
+                // `nelems` comes from the BTF, don't trust it for allocations
+                let mut values = Vec::with_capacity(cmp::min(*nelems as usize, data.len()));
+                for i in 0..*nelems as usize {
+                    let elem = data.get(i * elem_size..).unwrap_or(&[]);
+                    values.push(self.decode(*type_id, elem)?);
+                }
+                let chars: Option<Vec<u8>> = values
+                    .iter()
+                    .map(|v| match v {
+                        Value::Char(c) => Some(*c),
+                        _ => None,
+                    })
+                    .collect();
+                match chars {
+                    Some(chars) if !chars.is_empty() => {
+                        let len = chars.iter().position(|c| *c == 0).unwrap_or(chars.len());
+                        Value::Str(String::from_utf8_lossy(&chars[..len]).into_owned())
+                    }
+                    _ => Value::Array(values),
+                }
+            }
+            Type::Struct { members, .. } | Type::Union { members, .. } => {
+                let mut values = Vec::with_capacity(members.len());
+                for member in members {
+                    let value = if member.offset % 8 != 0 || member.bitfield_size != 0 {
+                        self.decode_bits(member.type_id, data, member.offset, member.bitfield_size)?
+                    } else {
+                        let data = data.get(member.offset as usize / 8..).unwrap_or(&[]);
+                        self.decode(member.type_id, data)?
+                    };
+                    values.push((member.name.clone(), value));
+                }
+                Value::Struct(values)
+            }
+            ty => {
+                return Err(LoadError::BTF(format!(
+                    "can't decode values of type {:?}",
+                    ty.name().unwrap_or("")
+                )))
+            }
+        };
+
+        Ok(value)
+    }
+
+    // Decodes the bitfield of `bits` bits at the bit offset `offset` in
+    // `data`. With `bits` 0, the offset and size come from the encoding of the
+    // int type, as in BTF without kind_flag.
+    fn decode_bits(&self, type_id: u32, data: &[u8], offset: u32, bits: u32) -> Result<Value> {
+        let (_, ty) = self.resolve(type_id)?;
+        let (size, offset, bits) = match ty {
+            Type::Int { size, encoding, .. } => {
+                let bits = if bits == 0 { encoding & 0xff } else { bits };
+                let int_offset = (encoding >> 16) & 0xff;
+                (*size, offset as usize + int_offset as usize, bits)
+            }
+            Type::Enum { size, .. } if bits != 0 => (*size, offset as usize, bits),
+            ty => {
+                return Err(LoadError::BTF(format!(
+                    "can't decode bitfields of type {:?}",
+                    ty.name().unwrap_or("")
+                )))
+            }
+        };
+        if bits == 0 || bits > size * 8 || (offset % 8) as u32 + bits > 64 {
+            return Err(LoadError::BTF(format!(
+                "invalid bitfield of {} bits in a {} bytes type",
+                bits, size
+            )));
+        }
+        // only read the bytes holding the bits, the bitfield may end in the
+        // last bytes of the value
+        let start = offset / 8;
+        let end = (offset + bits as usize + 7) / 8;
+        let bytes = data
+            .get(start..end)
+            .ok_or_else(|| LoadError::BTF("value too short for its type".to_string()))?;
+        // the bits are numbered from the least significant bit on little
+        // endian, from the most significant one on big endian
+        let offset = (offset % 8) as u32;
+        let raw = if cfg!(target_endian = "little") {
+            let raw = bytes
+                .iter()
+                .rev()
+                .fold(0u128, |v, b| v << 8 | u128::from(*b));
+            raw >> offset
+        } else {
+            let raw = bytes.iter().fold(0u128, |v, b| v << 8 | u128::from(*b));
+            raw >> (bytes.len() as u32 * 8 - offset - bits)
+        };
+        let raw = (raw & ((1u128 << bits) - 1)) as u64;
+
+        Ok(match ty {
+            Type::Int { encoding, .. } => int_value(raw, bits, *encoding),
+            Type::Enum { signed, values, .. } => {
+                let value = if *signed {
+                    sign_extend(raw, bits)
+                } else {
+                    raw as i64
+                };
+                let name = values
+                    .iter()
+                    .find(|(_, v)| *v == value)
+                    .map(|(n, _)| n.clone());
+                Value::Enum { name, value }
+            }
+            _ => unreachable!(),
+        })
+    }
+
+    /// Decodes `data` as a value of the type named `name`, eg. the struct
+    /// used as a map value.
+    pub fn decode_by_name(&self, name: &str, data: &[u8]) -> Result<Value> {
+        let id = *self
+            .type_ids_by_name(name)
+            .first()
+            .ok_or_else(|| LoadError::BTF(format!("type `{}' not found", name)))?;
+        self.decode(id, data)
+    }
+}
+
+/// A CO-RE relocation record from the `.BTF.ext` section.
+#[derive(Debug, Clone)]
+pub struct CoreRelocation {
+    /// Name of the program section the relocation applies to.
+    pub section: String,
+    /// Offset of the instruction to patch in bytes.
+    pub insn_off: u32,
+    /// Id of the root type in the local BTF.
+    pub type_id: u32,
+    /// Access string, eg. `0:1:2`.
+    pub access: String,
+    pub kind: u32,
+}
+
+// The strings referenced by .BTF.ext live in the .BTF string section.
+fn string_section(btf: &[u8]) -> Result<&[u8]> {
+    let mut hdr = Reader::new(btf);
+    hdr.bytes(8)?;
+    let btf_hdr_len = hdr.u32()? as usize;
+    hdr.bytes(8)?;
+    let str_off = hdr.u32()? as usize;
+    let str_len = hdr.u32()? as usize;
+    btf.get(btf_hdr_len + str_off..btf_hdr_len + str_off + str_len)
+        .ok_or_else(|| LoadError::BTF("invalid string section".to_string()))
+}
+
+/// Parses the func_info and line_info records from the `.BTF.ext` section,
+/// by program section.
+pub(crate) fn parse_func_and_line_info(
+    btf: &[u8],
+    btf_ext: &[u8],
+) -> Result<HashMap<String, (ExtInfo, ExtInfo)>> {
+    let strings = string_section(btf)?;
+
+    let mut hdr = Reader::new(btf_ext);
+    if hdr.u16()? != BTF_MAGIC {
+        return Err(LoadError::BTF("invalid .BTF.ext magic".to_string()));
+    }
+    hdr.bytes(2)?;
+    let hdr_len = hdr.u32()? as usize;
+    let func_info_off = hdr.u32()? as usize;
+    let func_info_len = hdr.u32()? as usize;
+    let line_info_off = hdr.u32()? as usize;
+    let line_info_len = hdr.u32()? as usize;
+
+    let mut infos: HashMap<String, (ExtInfo, ExtInfo)> = HashMap::new();
+    let subsection = |off: usize, len: usize| {
+        btf_ext
+            .get(hdr_len + off..hdr_len + off + len)
+            .ok_or_else(|| LoadError::BTF("invalid .BTF.ext section offsets".to_string()))
+    };
+    for (section, info) in parse_ext_info(subsection(func_info_off, func_info_len)?, strings)? {
+        infos.entry(section).or_default().0 = info;
+    }
+    for (section, info) in parse_ext_info(subsection(line_info_off, line_info_len)?, strings)? {
+        infos.entry(section).or_default().1 = info;
+    }
+
+    Ok(infos)
+}
+
+// Parses a func_info or line_info subsection of .BTF.ext, converting the
+// instruction offsets of the records from bytes to instructions.
+fn parse_ext_info(data: &[u8], strings: &[u8]) -> Result<Vec<(String, ExtInfo)>> {
+    let mut infos = Vec::new();
+    if data.is_empty() {
+        return Ok(infos);
+    }
+    let mut r = Reader::new(data);
+    let rec_size = r.u32()?;
+    if rec_size < 4 {
+        return Err(LoadError::BTF(format!("invalid record size {}", rec_size)));
+    }
+    while r.pos < data.len() {
+        let section = btf_str(strings, r.u32()?)?;
+        let num_info = r.u32()? as usize;
+        let mut records = r.bytes(num_info * rec_size as usize)?.to_vec();
+        for record in records.chunks_mut(rec_size as usize) {
+            let insn_off = u32::from_ne_bytes([record[0], record[1], record[2], record[3]]);
+            let insn_idx = insn_off / mem::size_of::<bpf_insn>() as u32;
+            record[..4].copy_from_slice(&insn_idx.to_ne_bytes());
+        }
+        infos.push((section, ExtInfo { rec_size, records }));
+    }
+
+    Ok(infos)
+}
+
+/// Parses the CO-RE relocation records from the `.BTF.ext` section.
+pub fn parse_core_relocations(btf: &[u8], btf_ext: &[u8]) -> Result<Vec<CoreRelocation>> {
+    let strings = string_section(btf)?;
+
+    let mut hdr = Reader::new(btf_ext);
+    if hdr.u16()? != BTF_MAGIC {
+        return Err(LoadError::BTF("invalid .BTF.ext magic".to_string()));
+    }
+    hdr.bytes(2)?;
+    let hdr_len = hdr.u32()? as usize;
+    if hdr_len < 32 {
+        // no CO-RE relocations
+        return Ok(Vec::new());
+    }
+    hdr.bytes(16)?; // func_info and line_info
+    let relo_off = hdr.u32()? as usize;
+    let relo_len = hdr.u32()? as usize;
+    let relo_data = btf_ext
+        .get(hdr_len + relo_off..hdr_len + relo_off + relo_len)
+        .ok_or_else(|| LoadError::BTF("invalid relocation section".to_string()))?;
+
+    let mut relocs = Vec::new();
+    if relo_data.is_empty() {
+        return Ok(relocs);
+    }
+    let mut r = Reader::new(relo_data);
+    let rec_size = r.u32()? as usize;
+    while r.pos < relo_data.len() {
+        let section = btf_str(strings, r.u32()?)?;
+        let num_info = r.u32()?;
+        for _ in 0..num_info {
+            let mut rec = Reader::new(r.bytes(rec_size)?);
+            relocs.push(CoreRelocation {
+                section: section.clone(),
+                insn_off: rec.u32()?,
+                type_id: rec.u32()?,
+                access: btf_str(strings, rec.u32()?)?,
+                kind: rec.u32()?,
+            });
+        }
+    }
+
+    Ok(relocs)
+}
+
+// libbpf ignores everything after a triple underscore when matching names, so
+// that different versions of a struct can coexist in the same program.
+fn essential_name(name: &str) -> &str {
+    match name.find("___") {
+        Some(pos) => &name[..pos],
+        None => name,
+    }
+}
+
+struct Spec {
+    /// Bit offset of the accessed field.
+    offset: u32,
+    /// Type id of the accessed field.
+    type_id: u32,
+}
+
+fn parse_access(access: &str) -> Result<Vec<usize>> {
+    access
+        .split(':')
+        .map(|i| {
+            i.parse()
+                .map_err(|_| LoadError::BTF(format!("invalid access string {}", access)))
+        })
+        .collect()
+}
+
+// Returns the names of the members accessed by the local access string, so
+// they can be looked up by name in the target BTF.
+fn local_access_names(btf: &Btf, type_id: u32, access: &[usize]) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    let mut id = type_id;
+    for index in &access[1..] {
+        let (_, ty) = btf.resolve(id)?;
+        match ty {
+            Type::Struct { members, .. } | Type::Union { members, .. } => {
+                let member = members
+                    .get(*index)
+                    .ok_or_else(|| LoadError::BTF(format!("invalid member index {}", index)))?;
+                id = member.type_id;
+                names.push(member.name.clone());
+            }
+            Type::Array { type_id, .. } => {
+                id = *type_id;
+                names.push(index.to_string());
+            }
+            _ => return Err(LoadError::BTF(format!("invalid access in type {}", type_id))),
+        }
+    }
+
+    Ok(names)
+}
+
+// Returns the offset in bits of the `index`th element of an array of `id`.
+fn element_offset(btf: &Btf, id: u32, index: u32) -> Result<u32> {
+    btf.type_size(id)?
+        .checked_mul(8)
+        .and_then(|bits| bits.checked_mul(index))
+        .ok_or_else(|| LoadError::BTF(format!("offset of element {} overflows", index)))
+}
+
+fn target_spec(btf: &Btf, type_id: u32, first: usize, names: &[String]) -> Result<Option<Spec>> {
+    let overflow = || LoadError::BTF(format!("field offset in type {} overflows", type_id));
+    let mut id = type_id;
+    let first = u32::try_from(first).map_err(|_| overflow())?;
+    let mut offset = element_offset(btf, type_id, first)?;
+    for name in names {
+        let (_, ty) = btf.resolve(id)?;
+        match ty {
+            Type::Struct { members, .. } | Type::Union { members, .. } => {
+                match find_member(btf, members, name)? {
+                    Some((member_offset, member_id)) => {
+                        offset = offset.checked_add(member_offset).ok_or_else(overflow)?;
+                        id = member_id;
+                    }
+                    None => return Ok(None),
+                }
+            }
+            Type::Array { type_id, .. } => {
+                let index: u32 = name
+                    .parse()
+                    .map_err(|_| LoadError::BTF(format!("invalid array index {}", name)))?;
+                offset = offset
+                    .checked_add(element_offset(btf, *type_id, index)?)
+                    .ok_or_else(overflow)?;
+                id = *type_id;
+            }
+            _ => return Ok(None),
+        }
+    }
+
+    Ok(Some(Spec { offset, type_id: id }))
+}
+
+// Finds a member by name, looking into anonymous structs and unions.
+fn find_member(btf: &Btf, members: &[Member], name: &str) -> Result<Option<(u32, u32)>> {
+    for member in members {
+        if member.name == name {
+            return Ok(Some((member.offset, member.type_id)));
+        }
+        if member.name.is_empty() {
+            if let (_, Type::Struct { members: inner, .. })
+            | (_, Type::Union { members: inner, .. }) = btf.resolve(member.type_id)?
+            {
+                if let Some((offset, id)) = find_member(btf, inner, name)? {
+                    return Ok(Some((member.offset + offset, id)));
+                }
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+impl CoreRelocation {
+    /// Computes the value the relocated instruction should use on the target
+    /// kernel.
+    pub fn resolve(&self, local: &Btf, target: &Btf) -> Result<u32> {
+        let access = parse_access(&self.access)?;
+        let (local_id, local_ty) = local.resolve(self.type_id)?;
+        let local_name = local_ty
+            .name()
+            .map(essential_name)
+            .filter(|n| !n.is_empty())
+            .ok_or_else(|| LoadError::BTF(format!("anonymous root type {}", local_id)))?;
+        let names = local_access_names(local, local_id, &access)?;
+
+        let mut target_spec_found = None;
+        for candidate in target.type_ids_by_name(local_name) {
+            let (_, ty) = target.resolve(*candidate)?;
+            if ty.kind() != local_ty.kind() {
+                continue;
+            }
+            if let Some(spec) = target_spec(target, *candidate, access[0], &names)? {
+                target_spec_found = Some(spec);
+                break;
+            }
+        }
+
+        match (self.kind, target_spec_found) {
+            (BPF_FIELD_EXISTS, spec) => Ok(spec.is_some() as u32),
+            (BPF_FIELD_BYTE_OFFSET, Some(spec)) => Ok(spec.offset / 8),
+            (BPF_FIELD_BYTE_SIZE, Some(spec)) => target.type_size(spec.type_id),
+            (BPF_FIELD_BYTE_OFFSET, None) | (BPF_FIELD_BYTE_SIZE, None) => {
+                Err(LoadError::BTF(format!(
+                    "field {}.{} not found in target kernel",
+                    local_name,
+                    names.join(".")
+                )))
+            }
+            (kind, _) => Err(LoadError::BTF(format!("unsupported relocation kind {}", kind))),
+        }
+    }
+
+    /// Patches the instruction targeted by the relocation.
+    pub fn apply(&self, code: &mut [bpf_insn], local: &Btf, target: &Btf) -> Result<()> {
+        let value = self.resolve(local, target)?;
+        let idx = self.insn_off as usize / std::mem::size_of::<bpf_insn>();
+        let insn = code
+            .get_mut(idx)
+            .ok_or_else(|| LoadError::BTF(format!("invalid instruction offset {}", self.insn_off)))?;
+        match insn.code & 0x07 {
+            BPF_LDX | BPF_ST | BPF_STX => insn.off = value as i16,
+            _ => insn.imm = value as i32,
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_essential_name() {
+        assert_eq!(essential_name("task_struct"), "task_struct");
+        assert_eq!(essential_name("task_struct___v54"), "task_struct");
+    }
+
+    #[test]
+    fn test_parse_ext_info() {
+        let strings = b"\0kprobe/foo\0";
+        let mut data = Vec::new();
+        // record size, then the section name, record count and the line_info
+        // records: insn_off, file_name_off, line_off, line_col
+        for v in &[16u32, 1, 2, 0, 0, 0, 7 << 10, 16, 0, 0, 8 << 10] {
+            data.extend_from_slice(&v.to_ne_bytes());
+        }
+        let infos = parse_ext_info(&data, strings).unwrap();
+        assert_eq!(infos.len(), 1);
+        let (section, info) = &infos[0];
+        assert_eq!(section, "kprobe/foo");
+        assert_eq!(info.rec_size, 16);
+        assert_eq!(info.records.len(), 32);
+        // instruction offsets are converted from bytes to instructions
+        assert_eq!(&info.records[16..20], &2u32.to_ne_bytes());
+        assert!(parse_ext_info(&data[..20], strings).is_err());
+    }
+
+    #[test]
+    fn test_parse_access() {
+        assert_eq!(parse_access("0:1:2").unwrap(), vec![0, 1, 2]);
+        assert!(parse_access("0:a").is_err());
+    }
+
+    #[test]
+    fn test_decode() {
+        // struct event { int pid; unsigned long ts; char comm[4]; _Bool ok; }
+        let types = vec![
+            Type::Void,
+            Type::Int {
+                name: "int".to_string(),
+                size: 4,
+                encoding: (BTF_INT_SIGNED << 24) | 32,
+            },
+            Type::Int {
+                name: "unsigned long".to_string(),
+                size: 8,
+                encoding: 64,
+            },
+            Type::Int {
+                name: "char".to_string(),
+                size: 1,
+                encoding: (BTF_INT_CHAR << 24) | 8,
+            },
+            Type::Array {
+                type_id: 3,
+                nelems: 4,
+            },
+            Type::Int {
+                name: "_Bool".to_string(),
+                size: 1,
+                encoding: (BTF_INT_BOOL << 24) | 8,
+            },
+            Type::Struct {
+                name: "event".to_string(),
+                size: 24,
+                members: vec![
+                    Member { name: "pid".to_string(), type_id: 1, offset: 0, bitfield_size: 0 },
+                    Member { name: "ts".to_string(), type_id: 2, offset: 64, bitfield_size: 0 },
+                    Member { name: "comm".to_string(), type_id: 4, offset: 128, bitfield_size: 0 },
+                    Member { name: "ok".to_string(), type_id: 5, offset: 160, bitfield_size: 0 },
+                ],
+            },
+        ];
+        let mut names = HashMap::new();
+        names.insert("event".to_string(), vec![6]);
+        let btf = Btf { types, names };
+
+        let mut data = Vec::new();
+        data.extend_from_slice(&(-1i32).to_ne_bytes());
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(&42u64.to_ne_bytes());
+        data.extend_from_slice(b"sh\0\0");
+        data.extend_from_slice(&[1, 0, 0, 0]);
+
+        let value = btf.decode_by_name("event", &data).unwrap();
+        assert_eq!(value.field("pid"), Some(&Value::Int(-1)));
+        assert_eq!(value.field("ts"), Some(&Value::UInt(42)));
+        assert_eq!(value.field("comm"), Some(&Value::Str("sh".to_string())));
+        assert_eq!(value.field("ok"), Some(&Value::Bool(true)));
+        assert_eq!(
+            value.to_string(),
+            r#"{"pid": -1, "ts": 42, "comm": "sh", "ok": true}"#
+        );
+        assert!(btf.decode_by_name("event", &data[..8]).is_err());
+    }
+
+    #[test]
+    #[cfg(target_endian = "little")]
+    fn test_decode_bitfields() {
+        // struct flags { unsigned int a: 3; int b: 5; enum e c: 4; } with kind_flag,
+        // and the same layout with the sizes in the int encodings
+        let member = |name: &str, type_id, offset, bitfield_size| Member {
+            name: name.to_string(),
+            type_id,
+            offset,
+            bitfield_size,
+        };
+        let types = vec![
+            Type::Void,
+            Type::Int {
+                name: "unsigned int".to_string(),
+                size: 4,
+                encoding: 32,
+            },
+            Type::Int {
+                name: "int".to_string(),
+                size: 4,
+                encoding: (BTF_INT_SIGNED << 24) | 32,
+            },
+            Type::Enum {
+                name: "e".to_string(),
+                size: 4,
+                signed: false,
+                values: vec![("E_NINE".to_string(), 9)],
+            },
+            Type::Struct {
+                name: "flags".to_string(),
+                size: 4,
+                members: vec![
+                    member("a", 1, 0, 3),
+                    member("b", 2, 3, 5),
+                    member("c", 3, 8, 4),
+                ],
+            },
+            Type::Int {
+                name: "unsigned int".to_string(),
+                size: 4,
+                encoding: 3,
+            },
+            Type::Int {
+                name: "int".to_string(),
+                size: 4,
+                encoding: (BTF_INT_SIGNED << 24) | 5,
+            },
+            Type::Struct {
+                name: "legacy_flags".to_string(),
+                size: 4,
+                members: vec![member("a", 5, 0, 0), member("b", 6, 3, 0)],
+            },
+        ];
+        let mut names = HashMap::new();
+        names.insert("flags".to_string(), vec![4]);
+        names.insert("legacy_flags".to_string(), vec![7]);
+        let btf = Btf { types, names };
+
+        // a = 5, b = -2, c = 9
+        let raw: u32 = 5 | (0b11110 << 3) | (9 << 8);
+        let data = raw.to_le_bytes();
+        let value = btf.decode_by_name("flags", &data).unwrap();
+        assert_eq!(value.field("a"), Some(&Value::UInt(5)));
+        assert_eq!(value.field("b"), Some(&Value::Int(-2)));
+        assert_eq!(
+            value.field("c"),
+            Some(&Value::Enum {
+                name: Some("E_NINE".to_string()),
+                value: 9
+            })
+        );
+
+        let value = btf.decode_by_name("legacy_flags", &data).unwrap();
+        assert_eq!(value.field("a"), Some(&Value::UInt(5)));
+        assert_eq!(value.field("b"), Some(&Value::Int(-2)));
+        // `c` is in the second byte
+        assert!(btf.decode_by_name("flags", &data[..1]).is_err());
+    }
+
+    #[test]
+    fn test_decode_array_nelems() {
+        // char a[0xffffffff] must not allocate from the element count
+        let types = vec![
+            Type::Void,
+            Type::Int {
+                name: "char".to_string(),
+                size: 1,
+                encoding: (BTF_INT_CHAR << 24) | 8,
+            },
+            Type::Array {
+                type_id: 1,
+                nelems: std::u32::MAX,
+            },
+        ];
+        let btf = Btf {
+            types,
+            names: HashMap::new(),
+        };
+        assert!(btf.decode(2, b"abc").is_err());
+    }
+
+    #[test]
+    fn test_target_spec_array_index() {
+        let types = vec![
+            Type::Void,
+            Type::Int {
+                name: "int".to_string(),
+                size: 4,
+                encoding: 32,
+            },
+            Type::Array {
+                type_id: 1,
+                nelems: 4,
+            },
+        ];
+        let btf = Btf {
+            types,
+            names: HashMap::new(),
+        };
+        let spec = target_spec(&btf, 2, 0, &["3".to_string()]).unwrap().unwrap();
+        assert_eq!(spec.offset, 3 * 32);
+        assert!(target_spec(&btf, 2, 0, &["x".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_type_cycle() {
+        let types = vec![
+            Type::Void,
+            Type::Typedef {
+                name: "a".to_string(),
+                type_id: 2,
+            },
+            Type::Const(1),
+        ];
+        let btf = Btf {
+            types,
+            names: HashMap::new(),
+        };
+        assert!(btf.resolve(1).is_err());
+        assert!(btf.type_size(2).is_err());
+    }
+
+    #[test]
+    fn test_type_size_overflow() {
+        let types = vec![
+            Type::Void,
+            Type::Int {
+                name: "int".to_string(),
+                size: 4,
+                encoding: 32,
+            },
+            Type::Array {
+                type_id: 1,
+                nelems: std::u32::MAX,
+            },
+        ];
+        let btf = Btf {
+            types,
+            names: HashMap::new(),
+        };
+        assert!(btf.type_size(2).is_err());
+        assert!(element_offset(&btf, 1, 1 << 30).is_err());
+    }
+
+    #[test]
+    fn test_float_json() {
+        assert_eq!(Value::Float(1.5).to_string(), "1.5");
+        assert_eq!(Value::Float(std::f64::NAN).to_string(), "null");
+        assert_eq!(Value::Float(std::f64::INFINITY).to_string(), "null");
+        let value = Value::Array(vec![Value::Float(std::f64::NEG_INFINITY)]);
+        assert_eq!(value.to_string(), "[null]");
+    }
+}
@@ -17,28 +17,28 @@
 //! A full working example of the build process might look like this:
 //!
 //! ```rust
-//! use redbpf::build::{build, generate_bindings, cache::BuildCache, headers::kernel_headers};
+//! use redbpf::build::{build, generate_bindings, BuildOptions, cache::BuildCache, headers::kernel_headers};
 //!
-//! fn main() -> Result<(), Error> {
+//! fn main() -> Result<(), Box<dyn std::error::Error>> {
 //!     let out_dir = PathBuf::from(env::var("OUT_DIR")?);
-//!     let kernel_headers = kernel_headers().expect("couldn't find kernel headers");
-//!     let mut bindgen_flags: Vec<String> = kernel_headers
-//!         .iter()
-//!         .map(|dir| format!("-I{}", dir))
-//!         .collect();
-//!     bindgen_flags.extend(redbpf::build::BUILD_FLAGS.iter().map(|f| f.to_string()));
+//!     let kernel_headers = kernel_headers()?;
+//!     let mut options = BuildOptions::new();
+//!     for dir in kernel_headers.iter() {
+//!         options.flag(format!("-I{}", dir));
+//!     }
+//!     options.werror(false);
+//!     let bindgen_flags = options.to_args();
 //!
 //!     let mut cache = BuildCache::new(&out_dir);
 //!
 //!     for file in source_files("./bpf", "c")? {
 //!         if cache.file_changed(&file) {
-//!             build(&bindgen_flags[..], &out_dir, &file).expect("Failed building BPF plugin!");
+//!             build(&options, &out_dir, &file)?;
 //!         }
 //!     }
 //!     for file in source_files("./bpf", "h")? {
 //!         if cache.file_changed(&file) {
-//!             generate_bindings(&bindgen_flags[..], &out_dir, &file)
-//!                 .expect("Failed generating data bindings!");
+//!             generate_bindings(&bindgen_flags[..], &out_dir, &file)?;
 //!         }
 //!     }
 //!
@@ -50,13 +50,17 @@
 
 use regex::Regex;
 
+use std::collections::HashMap;
+use std::env;
+use std::fmt::{self, Display};
 use std::io::{self, Write};
-use std::fs::File;
+use std::fs::{self, File};
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Command, Output};
 
 pub mod cache;
 pub mod headers;
+pub mod vmlinux;
 
 #[cfg(target_arch = "x86_64")]
 pub const BUILD_FLAGS: [&str; 19] = [
@@ -104,14 +108,263 @@ pub const BUILD_FLAGS: [&str; 20] = [
     "-c",
 ];
 
+/// The define holding the `LINUX_VERSION_CODE` of the kernel probes are built
+/// for, see `BuildOptions::detect_kernel_version`.
+pub const KERNEL_VERSION_CODE: &str = "KERNEL_VERSION_CODE";
+
+/// Returns the `LINUX_VERSION_CODE` of the kernel probes are built for.
+///
+/// This is the version of the kernel release in the `KERNEL_VERSION`
+/// environment variable, eg. `5.4.0-42-generic` when cross-building, and the
+/// version of the running kernel otherwise. `KERNEL_VERSION` also selects the
+/// kernel headers, see `headers::kernel_headers`.
+pub fn target_kernel_version_code() -> Result<u32, Error> {
+    match env::var("KERNEL_VERSION") {
+        Ok(release) => {
+            bpf_sys::uname::kernel_version_code(&release).ok_or(Error::KernelVersion(release))
+        }
+        Err(_) => bpf_sys::uname::get_kernel_internal_version()
+            .ok_or_else(|| Error::KernelVersion("the running kernel".to_string())),
+    }
+}
+
+/// The byte order of the generated eBPF code.
+///
+/// eBPF objects are specific to the byte order of the kernel that loads them,
+/// so probes for big-endian kernels, eg. s390x or some MIPS systems, must be
+/// built with `Endian::Big`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endian {
+    Little,
+    Big,
+}
+
+impl Endian {
+    /// The `llc` architecture generating code with this byte order.
+    pub fn llc_march(self) -> &'static str {
+        match self {
+            Endian::Little => "bpfel",
+            Endian::Big => "bpfeb",
+        }
+    }
+}
+
+impl Default for Endian {
+    fn default() -> Self {
+        Endian::Little
+    }
+}
+
+/// Compiler flags used to build eBPF modules.
+///
+/// The options start from `BUILD_FLAGS`, and individual flags can then be
+/// added or removed.
+///
+/// Flags that only apply to some sources can be added with `source_flags`,
+/// or listed in a file next to the source with the `.flags` extension, eg.
+/// `bpf/trace.flags` for `bpf/trace.c`. Those flags are appended to the
+/// global ones.
+///
+/// ```
+/// use redbpf::build::BuildOptions;
+///
+/// let mut options = BuildOptions::new();
+/// options
+///     .flag("-DDEBUG")
+///     .remove_flag("-Wunused")
+///     .opt_level("3")
+///     .werror(false)
+///     .source_flags("bpf/trace.c", &["-DTRACE_ALL"]);
+/// ```
+#[derive(Debug, Clone)]
+pub struct BuildOptions {
+    flags: Vec<String>,
+    source_flags: HashMap<PathBuf, Vec<String>>,
+    debug_info: bool,
+    keep_intermediates: bool,
+    endian: Endian,
+}
+
+impl Default for BuildOptions {
+    fn default() -> Self {
+        BuildOptions {
+            flags: BUILD_FLAGS.iter().map(|f| f.to_string()).collect(),
+            source_flags: HashMap::new(),
+            debug_info: false,
+            keep_intermediates: false,
+            endian: Endian::default(),
+        }
+    }
+}
+
+impl BuildOptions {
+    /// Creates options with the default `BUILD_FLAGS`.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Appends a flag to the compiler command line.
+    pub fn flag<S: Into<String>>(&mut self, flag: S) -> &mut Self {
+        self.flags.push(flag.into());
+        self
+    }
+
+    /// Appends several flags to the compiler command line.
+    pub fn flags<I, S>(&mut self, flags: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.flags
+            .extend(flags.into_iter().map(|s| s.as_ref().to_string()));
+        self
+    }
+
+    /// Appends flags to the compiler command line of `source` only.
+    pub fn source_flags<P, I, S>(&mut self, source: P, flags: I) -> &mut Self
+    where
+        P: Into<PathBuf>,
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.source_flags
+            .entry(source.into())
+            .or_insert_with(Vec::new)
+            .extend(flags.into_iter().map(|s| s.as_ref().to_string()));
+        self
+    }
+
+    /// Removes every occurrence of `flag`.
+    pub fn remove_flag(&mut self, flag: &str) -> &mut Self {
+        self.flags.retain(|f| f != flag);
+        self
+    }
+
+    /// Sets the optimization level, eg. `"2"` for `-O2`.
+    pub fn opt_level(&mut self, level: &str) -> &mut Self {
+        self.flags.retain(|f| !f.starts_with("-O"));
+        self.flags.push(format!("-O{}", level));
+        self
+    }
+
+    /// Turns warnings into errors. Enabled by default.
+    pub fn werror(&mut self, enabled: bool) -> &mut Self {
+        self.remove_flag("-Werror");
+        if enabled {
+            self.flags.push("-Werror".to_string());
+        }
+        self
+    }
+
+    /// Emits debug information in the compiled object.
+    ///
+    /// The object then contains DWARF and BTF line info (`.BTF.ext`).
+    /// `Program::load` passes the line info to the kernel, so the verifier
+    /// log returned in `LoadError::Verifier` references source lines instead
+    /// of raw instruction offsets. Disabled by default.
+    pub fn debug_info(&mut self, enabled: bool) -> &mut Self {
+        self.debug_info = enabled;
+        self
+    }
+
+    /// Keeps the LLVM bitcode `.obj` file `build` compiles the source to
+    /// before generating the ELF object, eg. to inspect it with `llvm-dis`.
+    ///
+    /// The `.obj` file is always kept when generating the ELF object fails.
+    /// Disabled by default.
+    pub fn keep_intermediates(&mut self, enabled: bool) -> &mut Self {
+        self.keep_intermediates = enabled;
+        self
+    }
+
+    /// Sets the byte order of the ELF object. Defaults to `Endian::Little`.
+    ///
+    /// This only selects the `llc` architecture: the C code is still compiled
+    /// with the byte order of the host target, so building big-endian probes
+    /// on a little-endian host also needs a big-endian `-target` flag.
+    pub fn endian(&mut self, endian: Endian) -> &mut Self {
+        self.endian = endian;
+        self
+    }
+
+    /// Defines `KERNEL_VERSION_CODE` to the `LINUX_VERSION_CODE` of the
+    /// kernel returned by `target_kernel_version_code`.
+    ///
+    /// Probes can then adapt to the kernel they're built for, eg. with
+    /// `#if KERNEL_VERSION_CODE >= KERNEL_VERSION(5, 8, 0)`.
+    pub fn detect_kernel_version(&mut self) -> Result<&mut Self, Error> {
+        let code = target_kernel_version_code()?;
+        Ok(self.kernel_version_code(code))
+    }
+
+    /// Defines `KERNEL_VERSION_CODE` to the version `major.minor.patch`,
+    /// overriding the detected version.
+    pub fn kernel_version(&mut self, major: u32, minor: u32, patch: u32) -> &mut Self {
+        self.kernel_version_code(major << 16 | minor << 8 | patch.min(255))
+    }
+
+    fn kernel_version_code(&mut self, code: u32) -> &mut Self {
+        let define = format!("-D{}=", KERNEL_VERSION_CODE);
+        self.flags.retain(|f| !f.starts_with(&define));
+        self.flags.push(format!("{}{}", define, code));
+        self
+    }
+
+    /// Returns the flags to pass to the compiler.
+    pub fn to_args(&self) -> Vec<String> {
+        let mut flags = self.flags.clone();
+        if self.debug_info {
+            flags.push("-g".to_string());
+        }
+        flags
+    }
+
+    /// Returns the flags to pass to the compiler when building `source`.
+    ///
+    /// These are the global flags, followed by the flags from the `.flags`
+    /// file of `source` if there is one, and the flags added with
+    /// `source_flags`.
+    pub fn args_for(&self, source: &Path) -> Result<Vec<String>, Error> {
+        let mut flags = self.to_args();
+        let flags_file = source.with_extension("flags");
+        if flags_file.is_file() {
+            flags.extend(parse_flags_file(&fs::read_to_string(&flags_file)?));
+        }
+        if let Some(extra) = self.source_flags.get(source) {
+            flags.extend(extra.iter().cloned());
+        }
+        Ok(flags)
+    }
+
+    fn llc_args(&self) -> Vec<String> {
+        let mut args = vec![
+            format!("-march={}", self.endian.llc_march()),
+            "-filetype=obj".to_string(),
+        ];
+        if self.debug_info {
+            // keep the debug sections relocatable so that BTF line info survives
+            args.push("-mattr=dwarfris".to_string());
+        }
+        args
+    }
+}
+
 #[derive(Debug)]
 pub enum Error {
     OSUnsupported,
     KernelHeadersNotFound,
     InvalidOutput,
-    Compile,
-    Link,
-    IO(io::Error)
+    /// Compiling the source file failed. Holds the source path and the
+    /// compiler output.
+    Compile(PathBuf, String),
+    /// Generating the ELF object failed. Holds the source path and the `llc`
+    /// output.
+    Link(PathBuf, String),
+    /// Parsing BTF or generating a header from it failed.
+    BTF(String),
+    /// The version of the kernel release isn't `major.minor.patch`.
+    KernelVersion(String),
+    IO(io::Error),
 }
 
 impl From<io::Error> for Error {
@@ -120,6 +373,42 @@ impl From<io::Error> for Error {
     }
 }
 
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Error::*;
+        match self {
+            OSUnsupported => write!(f, "the operating system is not supported"),
+            KernelHeadersNotFound => write!(f, "couldn't find the kernel headers"),
+            InvalidOutput => write!(f, "invalid output directory"),
+            Compile(p, out) => write!(f, "failed to compile {:?}:\n{}", p, out),
+            Link(p, out) => write!(f, "failed to generate the ELF object for {:?}:\n{}", p, out),
+            BTF(e) => write!(f, "failed to generate vmlinux.h: {}", e),
+            KernelVersion(r) => write!(f, "couldn't get the kernel version of {}", r),
+            IO(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::IO(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// Flags are separated by whitespace, and lines starting with `#` are ignored.
+fn parse_flags_file(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('#'))
+        .flat_map(str::split_whitespace)
+        .map(String::from)
+        .collect()
+}
+
 fn compile_target(out_dir: &Path, source: &Path) -> Option<PathBuf> {
     let basename = source.file_stem()?;
     let target_name = format!("{}.obj", basename.to_str()?);
@@ -132,39 +421,48 @@ fn link_target(out_dir: &Path, source: &Path) -> Option<PathBuf> {
     Some(out_dir.join(Path::new(&target_name)))
 }
 
-pub fn build(flags: &[String], out_dir: &Path, source: &Path) -> Result<PathBuf, Error> {
+pub fn build(options: &BuildOptions, out_dir: &Path, source: &Path) -> Result<PathBuf, Error> {
     println!("Building eBPF module: {:?} ", source);
 
-    let llc_args = ["-march=bpf", "-filetype=obj", "-o"];
+    let llc_args = options.llc_args();
     let cc_target = compile_target(out_dir, source).unwrap();
     let elf_target = link_target(out_dir, source).unwrap();
+    let flags = options.args_for(source)?;
 
     println!("Flags: {:?}", flags);
 
-    if !Command::new("clang")
-        .args(flags)
+    let output = Command::new("clang")
+        .args(&flags)
         .arg("-o")
         .arg(&cc_target)
         .arg(source)
-        .status()?
-        .success()
-    {
-        return Err(Error::Compile);
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::Compile(source.to_path_buf(), command_output(&output)));
     }
 
-    if !Command::new("llc")
+    let output = Command::new("llc")
         .args(&llc_args)
+        .arg("-o")
         .arg(&elf_target)
         .arg(&cc_target)
-        .status()?
-        .success()
-    {
-        return Err(Error::Link);
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::Link(source.to_path_buf(), command_output(&output)));
+    }
+    if !options.keep_intermediates {
+        let _ = fs::remove_file(&cc_target);
     }
 
     Ok(elf_target)
 }
 
+fn command_output(output: &Output) -> String {
+    let mut out = String::from_utf8_lossy(&output.stdout).into_owned();
+    out.push_str(&String::from_utf8_lossy(&output.stderr));
+    out
+}
+
 pub fn generate_bindings(flags: &[String], out_dir: &Path, source: &Path) -> Result<PathBuf, Error> {
     println!("Building eBPF module: {:?} ", source);
     println!("Flags: {:?}", &flags);
@@ -208,3 +506,69 @@ pub use bindings::*;
 ", code)?;
     Ok(filename)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_build_options() {
+        let mut options = BuildOptions::new();
+        assert_eq!(options.to_args().len(), BUILD_FLAGS.len());
+        assert!(options.to_args().contains(&"-Werror".to_string()));
+
+        options
+            .flag("-DFOO")
+            .remove_flag("-Wunused")
+            .opt_level("3")
+            .werror(false);
+        let args = options.to_args();
+        assert!(args.contains(&"-DFOO".to_string()));
+        assert!(args.contains(&"-O3".to_string()));
+        assert!(!args.contains(&"-O2".to_string()));
+        assert!(!args.contains(&"-Wunused".to_string()));
+        assert!(!args.contains(&"-Werror".to_string()));
+        assert!(!args.contains(&"-g".to_string()));
+
+        options.debug_info(true);
+        assert!(options.to_args().contains(&"-g".to_string()));
+        assert!(options.llc_args().contains(&"-mattr=dwarfris".to_string()));
+    }
+
+    #[test]
+    fn test_endian() {
+        let mut options = BuildOptions::new();
+        assert!(options.llc_args().contains(&"-march=bpfel".to_string()));
+        options.endian(Endian::Big);
+        assert!(options.llc_args().contains(&"-march=bpfeb".to_string()));
+    }
+
+    #[test]
+    fn test_kernel_version() {
+        let mut options = BuildOptions::new();
+        options.kernel_version(4, 19, 0).kernel_version(5, 8, 300);
+        let args = options.to_args();
+        assert_eq!(args.len(), BUILD_FLAGS.len() + 1);
+        assert_eq!(args.last().unwrap(), "-DKERNEL_VERSION_CODE=329983");
+    }
+
+    #[test]
+    fn test_source_flags() {
+        let mut options = BuildOptions::new();
+        options.source_flags("bpf/trace.c", &["-DTRACE_ALL", "-DDEBUG"]);
+        options.source_flags("bpf/trace.c", vec!["-DDEPTH=4".to_string()]);
+        let args = options.args_for(Path::new("bpf/trace.c")).unwrap();
+        assert_eq!(args.len(), BUILD_FLAGS.len() + 3);
+        assert_eq!(
+            &args[BUILD_FLAGS.len()..],
+            &["-DTRACE_ALL", "-DDEBUG", "-DDEPTH=4"]
+        );
+        let args = options.args_for(Path::new("bpf/other.c")).unwrap();
+        assert_eq!(args, options.to_args());
+
+        assert_eq!(
+            parse_flags_file("# tracing\n-DTRACE_ALL -DDEPTH=4\n\n  -Wno-unused\n"),
+            vec!["-DTRACE_ALL", "-DDEPTH=4", "-Wno-unused"]
+        );
+    }
+}
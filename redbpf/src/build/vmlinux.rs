@@ -0,0 +1,61 @@
+//! Generating `vmlinux.h` for C programs using CO-RE.
+//!
+//! CO-RE programs include `vmlinux.h`, which declares all the types of the
+//! kernel, instead of the kernel headers. The header is generated from the
+//! BTF of the running kernel without requiring `bpftool`, and cached per
+//! kernel release:
+//!
+//! ```rust
+//! use redbpf::build::{build, vmlinux::vmlinux_header, BuildOptions};
+//!
+//! fn main() -> Result<(), Box<dyn std::error::Error>> {
+//!     let out_dir = PathBuf::from(env::var("OUT_DIR")?);
+//!     let include_dir = vmlinux_header(&out_dir.join("vmlinux"))?;
+//!     let mut options = BuildOptions::new();
+//!     options.flag(format!("-I{}", include_dir.display()));
+//!     build(&options, &out_dir, Path::new("bpf/trace.c"))?;
+//!     Ok(())
+//! }
+//! ```
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use bpf_sys::uname::{to_str, uname};
+
+use crate::btf::{Btf, KERNEL_BTF};
+use crate::build::Error;
+
+/// The name of the generated header.
+pub const VMLINUX_H: &str = "vmlinux.h";
+
+/// Writes the C header declaring the types of the BTF file `btf`, eg.
+/// `/sys/kernel/btf/vmlinux`, to `out`.
+pub fn write_vmlinux_header(btf: &Path, out: &Path) -> Result<(), Error> {
+    let header = Btf::from_file(btf)
+        .and_then(|btf| btf.to_c_header())
+        .map_err(|e| Error::BTF(e.to_string()))?;
+    if let Some(dir) = out.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // write then rename, so that concurrent builds never see a partial header
+    let tmp = out.with_extension("h.tmp");
+    fs::write(&tmp, header)?;
+    fs::rename(&tmp, out)?;
+
+    Ok(())
+}
+
+/// Generates `vmlinux.h` from the BTF of the running kernel in a
+/// subdirectory of `cache_dir` named after the kernel release, unless it
+/// was already generated, and returns the directory to add to the include
+/// path.
+pub fn vmlinux_header(cache_dir: &Path) -> Result<PathBuf, Error> {
+    let uname = uname().map_err(|_| Error::OSUnsupported)?;
+    let dir = cache_dir.join(to_str(&uname.release));
+    let header = dir.join(VMLINUX_H);
+    if !header.exists() {
+        write_vmlinux_header(Path::new(KERNEL_BTF), &header)?;
+    }
+
+    Ok(dir)
+}
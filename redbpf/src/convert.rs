@@ -0,0 +1,185 @@
+//! Conversions from the raw fields of bindgen structs.
+//!
+//! Structs generated with `cargo bpf bindgen` mirror the C layout: addresses
+//! are integers in network byte order and strings are NUL terminated
+//! `c_char` arrays. The `event_accessors!` macro wraps such a struct and
+//! exposes its fields through these conversions, the struct implementing
+//! `redbpf::Pod`:
+//!
+//! ```rust
+//! # #[repr(C)]
+//! # #[derive(Clone, Copy)]
+//! # pub struct _data_connect { pub saddr: u32, pub dport: u16, pub comm: [i8; 16] }
+//! # unsafe impl redbpf::Pod for _data_connect {}
+//! use std::net::Ipv4Addr;
+//! use redbpf::event_accessors;
+//!
+//! event_accessors! {
+//!     /// A connection event.
+//!     pub struct Connect(_data_connect) {
+//!         saddr: Ipv4Addr = ipv4,
+//!         dport: u16 = be16,
+//!         comm: String = cstr_i8,
+//!     }
+//! }
+//!
+//! # let data = [0u8; std::mem::size_of::<_data_connect>()];
+//! let event = Connect::from_bytes(&data).unwrap();
+//! println!("{} {}:{}", event.comm(), event.saddr(), event.dport());
+//! ```
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// Returns a copy of the field.
+#[inline]
+pub fn copy<T: Copy>(field: &T) -> T {
+    *field
+}
+
+/// Converts an IPv4 address in network byte order.
+#[inline]
+pub fn ipv4(field: &u32) -> Ipv4Addr {
+    Ipv4Addr::from(u32::from_be(*field))
+}
+
+/// Converts a 16 bytes IPv6 address.
+#[inline]
+pub fn ipv6(field: &[u8; 16]) -> Ipv6Addr {
+    Ipv6Addr::from(*field)
+}
+
+/// Converts a 16 bit integer in network byte order, eg. a port.
+#[inline]
+pub fn be16(field: &u16) -> u16 {
+    u16::from_be(*field)
+}
+
+/// Converts a 32 bit integer in network byte order.
+#[inline]
+pub fn be32(field: &u32) -> u32 {
+    u32::from_be(*field)
+}
+
+/// Converts a NUL terminated byte array, eg. a `[u8; 16]` task name.
+///
+/// The string stops at the first NUL byte, or at the end of the array.
+/// Invalid UTF-8 is replaced.
+#[inline]
+pub fn cstr<const N: usize>(field: &[u8; N]) -> String {
+    let len = field.iter().position(|b| *b == 0).unwrap_or(N);
+    String::from_utf8_lossy(&field[..len]).into_owned()
+}
+
+/// Same as `cstr`, for the `[i8; N]` arrays bindgen generates for `c_char`
+/// arrays on architectures where `c_char` is signed.
+#[inline]
+pub fn cstr_i8<const N: usize>(field: &[i8; N]) -> String {
+    let mut bytes = [0u8; N];
+    for (byte, c) in bytes.iter_mut().zip(field.iter()) {
+        *byte = *c as u8;
+    }
+    cstr(&bytes)
+}
+
+/// Wraps a bindgen struct and generates accessors converting its fields.
+///
+/// Each field is declared as `name: Type = conversion`, where `conversion` is
+/// one of the functions of `redbpf::convert`. The raw struct must implement
+/// `redbpf::Pod`, which gives the wrapper a `from_bytes` constructor reading
+/// the struct from a perf event sample. The wrapper dereferences to the raw
+/// struct for the fields that need no conversion.
+/// See the [module documentation](convert/index.html) for an example.
+#[macro_export]
+macro_rules! event_accessors {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident($raw:ty) {
+            $($field:ident: $ty:ty = $conversion:ident),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy)]
+        $vis struct $name(pub $raw);
+
+        impl $name {
+            /// Reads the struct from the start of `data`, eg. the data of a
+            /// perf event sample.
+            ///
+            /// Returns `None` if `data` is too short.
+            pub fn from_bytes(data: &[u8]) -> Option<$name>
+            where
+                $raw: $crate::Pod,
+            {
+                <$raw as $crate::Pod>::from_bytes(data).map($name)
+            }
+
+            $(
+                pub fn $field(&self) -> $ty {
+                    $crate::convert::$conversion(&self.0.$field)
+                }
+            )*
+        }
+
+        impl ::std::ops::Deref for $name {
+            type Target = $raw;
+
+            fn deref(&self) -> &$raw {
+                &self.0
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+
+    #[allow(non_camel_case_types)]
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    pub struct _data_connect {
+        pub pid: u32,
+        pub saddr: u32,
+        pub dport: u16,
+        pub comm: [i8; 6],
+    }
+
+    unsafe impl crate::Pod for _data_connect {}
+
+    event_accessors! {
+        struct Connect(_data_connect) {
+            pid: u32 = copy,
+            saddr: Ipv4Addr = ipv4,
+            dport: u16 = be16,
+            comm: String = cstr_i8,
+        }
+    }
+
+    #[test]
+    fn test_event_accessors() {
+        let raw = _data_connect {
+            pid: 42,
+            saddr: u32::from(Ipv4Addr::new(10, 0, 0, 1)).to_be(),
+            dport: 443u16.to_be(),
+            comm: [b'c' as i8, b'u' as i8, b'r' as i8, b'l' as i8, 0, b'x' as i8],
+        };
+        let data = unsafe {
+            std::slice::from_raw_parts(
+                &raw as *const _ as *const u8,
+                std::mem::size_of::<_data_connect>(),
+            )
+        };
+        let event = Connect::from_bytes(data).unwrap();
+        assert_eq!(event.pid(), 42);
+        assert_eq!(event.saddr(), Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(event.dport(), 443);
+        assert_eq!(event.comm(), "curl");
+        assert!(Connect::from_bytes(&data[..4]).is_none());
+    }
+
+    #[test]
+    fn test_cstr() {
+        assert_eq!(super::cstr(b"bash\0\0"), "bash");
+        assert_eq!(super::cstr(b"bash"), "bash");
+        assert_eq!(super::cstr_i8(&[b'h' as i8, b'i' as i8, 0, -1]), "hi");
+    }
+}
@@ -0,0 +1,128 @@
+//! Reading counters maintained by eBPF programs.
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{LoadError, Map, MmapView, Result};
+
+const BPF_F_MMAPABLE: u32 = 1 << 10;
+
+/// User space side of `redbpf_probes::maps::Counters<N>`.
+///
+/// The counters are read through a memory mapping of the map, so reading
+/// them doesn't require any syscall.
+///
+/// ```rust
+/// use redbpf::{Counters, Module};
+///
+/// let code = std::fs::read("bpf.elf").unwrap();
+/// let module = Module::parse(&code).unwrap();
+/// let counters = Counters::<2>::new(module.map("counters").unwrap()).unwrap();
+/// println!("{:?}", counters.snapshot());
+/// ```
+pub struct Counters<const N: usize> {
+    view: MmapView,
+}
+
+impl<const N: usize> Counters<N> {
+    /// Maps the counters of `map`, which must be an mmapable array of `N`
+    /// `u64`.
+    pub fn new(map: &Map) -> Result<Counters<N>> {
+        check_config::<N>(map)?;
+
+        Ok(Counters { view: map.mmap()? })
+    }
+
+    fn counters(&self) -> &[AtomicU64] {
+        // the layout was checked in new(), and any bit pattern is valid
+        unsafe { self.view.as_slice().unwrap() }
+    }
+
+    /// Returns the number of counters.
+    pub fn len(&self) -> usize {
+        N
+    }
+
+    /// Returns `true` if the map holds no counters.
+    pub fn is_empty(&self) -> bool {
+        N == 0
+    }
+
+    /// Returns the value of the counter at `index`.
+    pub fn get(&self, index: usize) -> Option<u64> {
+        self.counters()
+            .get(index)
+            .map(|c| c.load(Ordering::Relaxed))
+    }
+
+    /// Returns the values of all the counters.
+    ///
+    /// The counters are read one by one while the programs keep updating
+    /// them, so the snapshot is not atomic as a whole.
+    pub fn snapshot(&self) -> [u64; N] {
+        let mut values = [0; N];
+        for (value, counter) in values.iter_mut().zip(self.counters()) {
+            *value = counter.load(Ordering::Relaxed);
+        }
+
+        values
+    }
+}
+
+// Checks that `map` was declared as `redbpf_probes::maps::Counters<N>`.
+fn check_config<const N: usize>(map: &Map) -> Result<()> {
+    let config = &map.config;
+    let invalid = |what: String| {
+        Err(LoadError::InvalidMap(format!(
+            "map `{}' {}",
+            map.name, what
+        )))
+    };
+    if config.type_ != bpf_sys::bpf_map_type_BPF_MAP_TYPE_ARRAY
+        || config.map_flags & BPF_F_MMAPABLE == 0
+    {
+        return invalid("is not an mmapable array".to_string());
+    }
+    if config.value_size as usize != std::mem::size_of::<u64>() {
+        return invalid("values are not u64 counters".to_string());
+    }
+    if config.max_entries as usize != N {
+        return invalid(format!(
+            "has {} counters, expected {}",
+            config.max_entries, N
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bpf_sys::bpf_map_def;
+
+    fn map(type_: u32, max_entries: u32, map_flags: u32) -> Map {
+        let mut config: bpf_map_def = unsafe { std::mem::zeroed() };
+        config.type_ = type_;
+        config.key_size = 4;
+        config.value_size = 8;
+        config.max_entries = max_entries;
+        config.map_flags = map_flags;
+        Map {
+            name: "counters".to_string(),
+            kind: type_,
+            fd: -1,
+            config,
+            numa_node: None,
+            value_btf: None,
+        }
+    }
+
+    #[test]
+    fn test_check_config() {
+        let array = bpf_sys::bpf_map_type_BPF_MAP_TYPE_ARRAY;
+        assert!(check_config::<2>(&map(array, 2, BPF_F_MMAPABLE)).is_ok());
+        assert!(check_config::<3>(&map(array, 2, BPF_F_MMAPABLE)).is_err());
+        assert!(check_config::<2>(&map(array, 2, 0)).is_err());
+        let hash = bpf_sys::bpf_map_type_BPF_MAP_TYPE_HASH;
+        assert!(check_config::<2>(&map(hash, 2, BPF_F_MMAPABLE)).is_err());
+    }
+}
@@ -1,8 +1,10 @@
 use std::fs::read;
 use std::io::Error;
+use std::mem;
 use std::str::FromStr;
 
 const SYS_CPU_ONLINE: &str = "/sys/devices/system/cpu/online";
+const SYS_CPU_POSSIBLE: &str = "/sys/devices/system/cpu/possible";
 
 pub type CpuId = i32;
 
@@ -20,6 +22,34 @@ pub fn get_online() -> Result<Vec<CpuId>, Error> {
     Ok(list_from_string(&cpus.trim()))
 }
 
+/// Returns a list of possible CPU IDs, which includes the CPUs that are
+/// offline or can be hotplugged.
+///
+/// Per-CPU maps hold a value for every possible CPU. Errors are handled like
+/// in `get_online`.
+pub fn get_possible() -> Result<Vec<CpuId>, Error> {
+    let cpus = unsafe { String::from_utf8_unchecked(read(SYS_CPU_POSSIBLE)?) };
+    Ok(list_from_string(&cpus.trim()))
+}
+
+/// Restricts the calling thread to run on `cpu` only.
+///
+/// This is useful for threads draining the perf buffer of a single CPU: the
+/// events are then read from the cache of the CPU, or at least from its NUMA
+/// node, that wrote them.
+pub fn pin_current_thread(cpu: CpuId) -> Result<(), Error> {
+    unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        libc::CPU_ZERO(&mut set);
+        libc::CPU_SET(cpu as usize, &mut set);
+        if libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
 fn list_from_string(cpus: &str) -> Vec<CpuId> {
     let cpu_list = cpus.split(',').flat_map(|group| {
         let mut split = group.split('-');
@@ -46,4 +76,24 @@ mod test {
         assert_eq!(list_from_string("0-4"), vec![0, 1, 2, 3, 4]);
         assert_eq!(list_from_string("0-2,5-6"), vec![0, 1, 2, 5, 6]);
     }
+
+    #[test]
+    fn test_pin_current_thread() {
+        use crate::cpus::{pin_current_thread, CpuId};
+        use std::mem;
+        // the test may be restricted to a subset of the online CPUs
+        let cpu = unsafe {
+            let mut set: libc::cpu_set_t = mem::zeroed();
+            assert_eq!(
+                libc::sched_getaffinity(0, mem::size_of::<libc::cpu_set_t>(), &mut set),
+                0
+            );
+            (0..libc::CPU_SETSIZE as usize)
+                .find(|cpu| libc::CPU_ISSET(*cpu, &set))
+                .unwrap() as CpuId
+        };
+        std::thread::spawn(move || pin_current_thread(cpu).unwrap())
+            .join()
+            .unwrap();
+    }
 }
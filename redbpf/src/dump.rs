@@ -0,0 +1,186 @@
+//! Saving maps to files and restoring them.
+//!
+//! This makes it possible for stateful agents to keep their maps across
+//! restarts:
+//!
+//! ```rust
+//! use redbpf::Module;
+//!
+//! let code = std::fs::read("bpf.elf").unwrap();
+//! let module = Module::parse(&code).unwrap();
+//! let conns = module.map("conns").unwrap();
+//! if std::path::Path::new("conns.dump").exists() {
+//!     conns.restore_from("conns.dump").unwrap();
+//! }
+//! // ...
+//! conns.dump_to("conns.dump").unwrap();
+//! ```
+//!
+//! Dumps start with a header recording the map type and the key and value
+//! sizes, followed by the raw key and value bytes of every entry. Restoring
+//! a dump into a map with a different layout fails.
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::{LoadError, Map, Result, VoidPtr};
+
+const DUMP_MAGIC: &[u8; 8] = b"RBPFMAP1";
+
+#[derive(Debug, PartialEq)]
+struct DumpHeader {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    count: u64,
+}
+
+impl DumpHeader {
+    const SIZE: usize = 8 + 4 * 3 + 8;
+
+    fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut buf = [0u8; Self::SIZE];
+        buf[..8].copy_from_slice(DUMP_MAGIC);
+        buf[8..12].copy_from_slice(&self.map_type.to_le_bytes());
+        buf[12..16].copy_from_slice(&self.key_size.to_le_bytes());
+        buf[16..20].copy_from_slice(&self.value_size.to_le_bytes());
+        buf[20..28].copy_from_slice(&self.count.to_le_bytes());
+        buf
+    }
+
+    fn from_bytes(buf: &[u8; Self::SIZE]) -> Result<DumpHeader> {
+        if &buf[..8] != DUMP_MAGIC {
+            return Err(LoadError::InvalidMap("not a map dump".to_string()));
+        }
+        let u32_at = |i: usize| u32::from_le_bytes(buf[i..i + 4].try_into().unwrap());
+        Ok(DumpHeader {
+            map_type: u32_at(8),
+            key_size: u32_at(12),
+            value_size: u32_at(16),
+            count: u64::from_le_bytes(buf[20..28].try_into().unwrap()),
+        })
+    }
+}
+
+pub(crate) fn is_percpu(map_type: u32) -> bool {
+    map_type == bpf_sys::bpf_map_type_BPF_MAP_TYPE_PERCPU_HASH
+        || map_type == bpf_sys::bpf_map_type_BPF_MAP_TYPE_PERCPU_ARRAY
+        || map_type == bpf_sys::bpf_map_type_BPF_MAP_TYPE_LRU_PERCPU_HASH
+}
+
+impl Map {
+    fn check_dumpable(&self) -> Result<()> {
+        // per-CPU values are larger than value_size
+        if is_percpu(self.config.type_) {
+            return Err(LoadError::NotSupported(format!(
+                "dumping per-CPU map `{}'",
+                self.name
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn entries_bytes(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut entries = Vec::new();
+        self.for_each_entry_bytes(|key, value| entries.push((key.to_vec(), value.to_vec())));
+        entries
+    }
+
+    /// Writes all the entries of the map to the file at `path`.
+    ///
+    /// Returns the number of entries written. The map is read entry by
+    /// entry while programs may keep updating it, so the dump is not an
+    /// atomic snapshot.
+    pub fn dump_to<P: AsRef<Path>>(&self, path: P) -> Result<usize> {
+        self.check_dumpable()?;
+        let entries = self.entries_bytes();
+        let header = DumpHeader {
+            map_type: self.config.type_,
+            key_size: self.config.key_size,
+            value_size: self.config.value_size,
+            count: entries.len() as u64,
+        };
+
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(&header.to_bytes())?;
+        for (key, value) in entries.iter() {
+            file.write_all(key)?;
+            file.write_all(value)?;
+        }
+        file.flush()?;
+
+        Ok(entries.len())
+    }
+
+    /// Inserts the entries dumped with `dump_to` in the file at `path`.
+    ///
+    /// Existing entries with the same keys are overwritten, and other
+    /// entries are kept. Returns the number of entries restored, or an error
+    /// if the dump was taken from a map with a different type or layout.
+    pub fn restore_from<P: AsRef<Path>>(&self, path: P) -> Result<usize> {
+        self.check_dumpable()?;
+        let mut file = BufReader::new(File::open(path)?);
+        let mut buf = [0u8; DumpHeader::SIZE];
+        file.read_exact(&mut buf)?;
+        let header = DumpHeader::from_bytes(&buf)?;
+        if header.map_type != self.config.type_
+            || header.key_size != self.config.key_size
+            || header.value_size != self.config.value_size
+        {
+            return Err(LoadError::InvalidMap(format!(
+                "dump of a map of type {} with {} bytes keys and {} bytes values \
+                 can't be restored into `{}'",
+                header.map_type, header.key_size, header.value_size, self.name
+            )));
+        }
+        if header.count > self.config.max_entries as u64 {
+            return Err(LoadError::InvalidMap(format!(
+                "dump has {} entries, `{}' can hold {}",
+                header.count, self.name, self.config.max_entries
+            )));
+        }
+
+        let mut key = vec![0u8; header.key_size as usize];
+        let mut value = vec![0u8; header.value_size as usize];
+        for _ in 0..header.count {
+            file.read_exact(&mut key)?;
+            file.read_exact(&mut value)?;
+            let ret = unsafe {
+                bpf_sys::bpf_update_elem(
+                    self.fd,
+                    key.as_mut_ptr() as VoidPtr,
+                    value.as_mut_ptr() as VoidPtr,
+                    0,
+                )
+            };
+            if ret < 0 {
+                return Err(LoadError::IO(io::Error::last_os_error()));
+            }
+        }
+
+        Ok(header.count as usize)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_dump_header() {
+        let header = DumpHeader {
+            map_type: bpf_sys::bpf_map_type_BPF_MAP_TYPE_HASH,
+            key_size: 4,
+            value_size: 16,
+            count: 3,
+        };
+        let bytes = header.to_bytes();
+        assert_eq!(DumpHeader::from_bytes(&bytes).unwrap(), header);
+
+        let mut bytes = bytes;
+        bytes[0] = b'X';
+        assert!(DumpHeader::from_bytes(&bytes).is_err());
+    }
+}
@@ -9,6 +9,37 @@ pub enum LoadError {
     IO(::std::io::Error),
     Uname,
     Reloc,
+    SymbolNotFound(String),
+    SymbolNotTraceable(String),
+    /// The symbol, named last, is not defined by the binary.
+    BinarySymbolNotFound(String, String),
+    /// The binary is not mapped by the process.
+    BinaryNotMapped(String, i32),
+    ProbeOffset(String, u64),
+    /// The offset of the probe named `function+offset` is not a decimal or
+    /// `0x` prefixed hexadecimal number.
+    ProbeOffsetParse(String),
+    BTF(String),
+    MapNotFound(String),
+    ProgramNotFound(String),
+    ProgramLoaded(String),
+    ProgramNotLoaded(String),
+    InvalidMap(String),
+    Interface(String, ::std::io::Error),
+    NotSupported(String),
+    /// `perf_event_open` was denied with `EPERM` or `EACCES`.
+    ///
+    /// This happens when the `kernel.perf_event_paranoid` sysctl is too
+    /// restrictive for the process, when the process lacks `CAP_PERFMON`
+    /// (or `CAP_SYS_ADMIN` before Linux 5.8), or when the syscall is blocked
+    /// by a seccomp profile or an LSM, which is common in containers.
+    PerfEventDenied(::std::io::Error),
+    /// The program, named first, calls a GPL-only helper but the license,
+    /// last, is not GPL compatible.
+    GplOnlyHelper(String, &'static str, String),
+    /// The verifier rejected the program named first, with the verifier log
+    /// last.
+    Verifier(String, String),
 }
 
 pub type Result<T> = ::std::result::Result<T, LoadError>;
@@ -30,3 +61,66 @@ impl From<::std::io::Error> for LoadError {
         LoadError::IO(e)
     }
 }
+
+impl ::std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        use LoadError::*;
+        match self {
+            StringConversion => write!(f, "invalid string"),
+            BPF => write!(f, "bpf syscall failed"),
+            Map => write!(f, "failed to create map"),
+            Section(name) => write!(f, "unknown section `{}'", name),
+            Parse(e) => write!(f, "failed to parse ELF: {}", e),
+            KernelRelease(release) => write!(f, "invalid kernel release `{}'", release),
+            IO(e) => write!(f, "{}", e),
+            Uname => write!(f, "uname failed"),
+            Reloc => write!(f, "failed to apply relocations"),
+            SymbolNotFound(name) => write!(f, "kernel symbol `{}' not found", name),
+            SymbolNotTraceable(name) => write!(f, "kernel symbol `{}' can't be traced", name),
+            BinarySymbolNotFound(binary, name) => {
+                write!(f, "symbol `{}' not found in `{}'", name, binary)
+            }
+            BinaryNotMapped(binary, pid) => {
+                write!(f, "`{}' is not mapped by process {}", binary, pid)
+            }
+            ProbeOffset(name, offset) => write!(f, "invalid probe offset {} in `{}'", offset, name),
+            ProbeOffsetParse(name) => write!(f, "can't parse the probe offset of `{}'", name),
+            BTF(msg) => write!(f, "BTF error: {}", msg),
+            MapNotFound(name) => write!(f, "map `{}' not found", name),
+            ProgramNotFound(name) => write!(f, "program `{}' not found", name),
+            ProgramLoaded(name) => write!(f, "program `{}' is already loaded", name),
+            ProgramNotLoaded(name) => write!(f, "program `{}' is not loaded", name),
+            InvalidMap(msg) => write!(f, "{}", msg),
+            Interface(name, e) => write!(f, "interface `{}': {}", name, e),
+            NotSupported(msg) => write!(f, "not supported: {}", msg),
+            PerfEventDenied(e) => write!(
+                f,
+                "perf_event_open denied ({}): lower the kernel.perf_event_paranoid sysctl, \
+                 grant CAP_PERFMON or CAP_SYS_ADMIN, or allow perf_event_open in the \
+                 seccomp profile",
+                e
+            ),
+            GplOnlyHelper(name, helper, license) => write!(
+                f,
+                "program `{}' calls the GPL-only helper `{}', but its license `{}' is not \
+                 GPL compatible",
+                name, helper, license
+            ),
+            Verifier(name, log) => {
+                write!(f, "program `{}' rejected by the verifier:\n{}", name, log)
+            }
+        }
+    }
+}
+
+impl ::std::error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn ::std::error::Error + 'static)> {
+        match self {
+            LoadError::Parse(e) => Some(e),
+            LoadError::IO(e) | LoadError::Interface(_, e) | LoadError::PerfEventDenied(e) => {
+                Some(e)
+            }
+            _ => None,
+        }
+    }
+}
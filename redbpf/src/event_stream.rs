@@ -0,0 +1,453 @@
+//! Consuming a perf event array on every CPU at once.
+//!
+//! `EventStream` binds a perf buffer for each online CPU, waits for events
+//! on all of them with `epoll` and yields them one by one along with the id
+//! of the CPU they come from:
+//!
+//! ```rust
+//! use redbpf::{EventStream, Module, OwnedEvent};
+//!
+//! let code = std::fs::read("bpf.elf").unwrap();
+//! let module = Module::parse(&code).unwrap();
+//! let map = module.maps.iter().find(|m| m.name == "events").unwrap();
+//!
+//! for (cpu, event) in EventStream::new(map, 16).unwrap() {
+//!     match event {
+//!         OwnedEvent::Sample(data) => println!("{} bytes from CPU {}", data.len(), cpu),
+//!         OwnedEvent::Lost(count) => println!("lost {} events on CPU {}", count, cpu),
+//!     }
+//! }
+//! ```
+//!
+//! With the `async` feature, `EventStream::into_stream` turns it into a
+//! `futures::Stream` driven by the tokio reactor. For high event rates,
+//! `EventStream::spawn_per_cpu` drains each CPU from its own thread instead,
+//! optionally pinned to that CPU.
+use std::collections::VecDeque;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::slice;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::cpus::{self, CpuId};
+use crate::perf::check_perf_event_array;
+use crate::{Event, LoadError, Map, PerfAttr, PerfMap, Result};
+
+// How often the online CPUs are checked for hotplug.
+const HOTPLUG_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_EPOLL_EVENTS: usize = 64;
+// How often per-CPU threads check if they have to stop, in milliseconds.
+const STOP_CHECK_INTERVAL: libc::c_int = 100;
+
+/// An event read from a perf buffer, copied out of the ring.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OwnedEvent {
+    /// The raw data written by the eBPF program.
+    Sample(Box<[u8]>),
+    /// The number of events that were dropped because the buffer was full.
+    Lost(u64),
+}
+
+impl<'a> From<Event<'a>> for OwnedEvent {
+    fn from(event: Event<'a>) -> OwnedEvent {
+        match event {
+            Event::Sample(sample) => {
+                let data =
+                    unsafe { slice::from_raw_parts(sample.data.as_ptr(), sample.size as usize) };
+                OwnedEvent::Sample(data.into())
+            }
+            Event::Lost(lost) => OwnedEvent::Lost(lost.count),
+        }
+    }
+}
+
+/// Iterator over the events of a perf event array, on all the online CPUs.
+///
+/// The iterator blocks until events are available. CPUs going online or
+/// offline are picked up within a second, CPUs that fail to bind are retried
+/// at the next check.
+pub struct EventStream {
+    map_fd: RawFd,
+    max_entries: u32,
+    page_cnt: usize,
+    epoll: RawFd,
+    buffers: Vec<(CpuId, PerfMap)>,
+    pending: VecDeque<(CpuId, OwnedEvent)>,
+    last_hotplug_check: Instant,
+}
+
+impl EventStream {
+    /// Binds a perf buffer of `page_cnt` pages, which must be a power of
+    /// two, to `map` for every online CPU.
+    ///
+    /// The stream keeps its own reference to the map, so it can outlive
+    /// `map`.
+    pub fn new(map: &Map, page_cnt: usize) -> Result<EventStream> {
+        let online = cpus::get_online()?;
+        for cpu in &online {
+            check_perf_event_array(map, *cpu)?;
+        }
+        let map_fd = unsafe { libc::fcntl(map.fd, libc::F_DUPFD_CLOEXEC, 0) };
+        if map_fd < 0 {
+            return Err(LoadError::IO(io::Error::last_os_error()));
+        }
+        let epoll = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if epoll < 0 {
+            let err = io::Error::last_os_error();
+            unsafe { libc::close(map_fd) };
+            return Err(LoadError::IO(err));
+        }
+
+        // from here on, dropping `stream` releases everything
+        let mut stream = EventStream {
+            map_fd,
+            max_entries: map.config.max_entries,
+            page_cnt,
+            epoll,
+            buffers: Vec::with_capacity(online.len()),
+            pending: VecDeque::new(),
+            last_hotplug_check: Instant::now(),
+        };
+        for cpu in online {
+            stream.bind(cpu)?;
+        }
+
+        Ok(stream)
+    }
+
+    /// Returns the CPUs that currently have a perf buffer bound.
+    pub fn cpus(&self) -> Vec<CpuId> {
+        self.buffers.iter().map(|(cpu, _)| *cpu).collect()
+    }
+
+    fn bind(&mut self, cpu: CpuId) -> Result<()> {
+        if cpu as u32 >= self.max_entries {
+            return Err(LoadError::InvalidMap(format!(
+                "perf event array has {} entries, can't bind CPU {}",
+                self.max_entries, cpu
+            )));
+        }
+        let perf_map = PerfMap::open(self.map_fd, -1, cpu, self.page_cnt, -1, 0, &PerfAttr::new())?;
+        let mut event = libc::epoll_event {
+            events: libc::EPOLLIN as u32,
+            u64: cpu as u64,
+        };
+        if unsafe { libc::epoll_ctl(self.epoll, libc::EPOLL_CTL_ADD, perf_map.fd, &mut event) } < 0
+        {
+            return Err(LoadError::IO(io::Error::last_os_error()));
+        }
+        self.buffers.push((cpu, perf_map));
+
+        Ok(())
+    }
+
+    // Binds the CPUs that went online and drops the buffers of the CPUs that
+    // went offline.
+    fn check_hotplug(&mut self) {
+        if self.last_hotplug_check.elapsed() < HOTPLUG_INTERVAL {
+            return;
+        }
+        self.last_hotplug_check = Instant::now();
+        let online = match cpus::get_online() {
+            Ok(online) => online,
+            Err(_) => return,
+        };
+
+        let epoll = self.epoll;
+        self.buffers.retain(|(cpu, perf_map)| {
+            if online.contains(cpu) {
+                return true;
+            }
+            unsafe {
+                libc::epoll_ctl(
+                    epoll,
+                    libc::EPOLL_CTL_DEL,
+                    perf_map.fd,
+                    std::ptr::null_mut(),
+                )
+            };
+            false
+        });
+        for cpu in online {
+            if !self.buffers.iter().any(|(bound, _)| *bound == cpu) {
+                let _ = self.bind(cpu);
+            }
+        }
+    }
+
+    fn drain(&mut self, cpu: CpuId) {
+        let pending = &mut self.pending;
+        if let Some((_, perf_map)) = self.buffers.iter().find(|(bound, _)| *bound == cpu) {
+            while let Some(event) = perf_map.read() {
+                pending.push_back((cpu, event.into()));
+            }
+        }
+    }
+
+    #[cfg(feature = "async")]
+    fn drain_all(&mut self) {
+        let cpus = self.cpus();
+        for cpu in cpus {
+            self.drain(cpu);
+        }
+    }
+}
+
+/// Consumer threads started by `EventStream::spawn_per_cpu`.
+///
+/// The threads are stopped and joined when this is dropped.
+pub struct PerCpuThreads {
+    stop: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl PerCpuThreads {
+    /// Stops the threads and waits for them to exit.
+    pub fn stop(mut self) {
+        self.stop_all();
+    }
+
+    fn stop_all(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for PerCpuThreads {
+    fn drop(&mut self) {
+        self.stop_all();
+    }
+}
+
+impl EventStream {
+    /// Drains the perf buffer of each online CPU from a dedicated thread,
+    /// instead of yielding the events of all the CPUs from a single
+    /// iterator.
+    ///
+    /// `handler` is called from the thread of each CPU with the events of
+    /// that CPU. If `pin` is true, each thread is pinned to the CPU it drains
+    /// with `cpus::pin_current_thread`, which keeps the reads local to the
+    /// CPU cache and NUMA node but takes time away from the CPU. Pinning is
+    /// best effort: threads that can't be pinned, eg. because of a
+    /// restrictive cpuset, run unpinned. CPU hotplug is not handled in this
+    /// mode.
+    pub fn spawn_per_cpu<F>(
+        map: &Map,
+        page_cnt: usize,
+        pin: bool,
+        handler: F,
+    ) -> Result<PerCpuThreads>
+    where
+        F: Fn(CpuId, OwnedEvent) + Send + Sync + 'static,
+    {
+        let mut buffers = Vec::new();
+        for cpu in cpus::get_online()? {
+            check_perf_event_array(map, cpu)?;
+            let attr = PerfAttr::new();
+            buffers.push((cpu, PerfMap::open(map.fd, -1, cpu, page_cnt, -1, 0, &attr)?));
+        }
+
+        let handler = Arc::new(handler);
+        let mut threads = PerCpuThreads {
+            stop: Arc::new(AtomicBool::new(false)),
+            threads: Vec::with_capacity(buffers.len()),
+        };
+        for (cpu, perf_map) in buffers {
+            let handler = handler.clone();
+            let stop = threads.stop.clone();
+            let thread = thread::Builder::new()
+                .name(format!("redbpf-cpu{}", cpu))
+                .spawn(move || {
+                    if pin {
+                        let _ = cpus::pin_current_thread(cpu);
+                    }
+                    let mut pollfd = libc::pollfd {
+                        fd: perf_map.fd,
+                        events: libc::POLLIN,
+                        revents: 0,
+                    };
+                    while !stop.load(Ordering::Relaxed) {
+                        if unsafe { libc::poll(&mut pollfd, 1, STOP_CHECK_INTERVAL) } <= 0 {
+                            continue;
+                        }
+                        while let Some(event) = perf_map.read() {
+                            handler(cpu, event.into());
+                        }
+                    }
+                })?;
+            threads.threads.push(thread);
+        }
+
+        Ok(threads)
+    }
+}
+
+impl EventStream {
+    // Waits up to `timeout` milliseconds, or forever if -1, for events and
+    // moves them to `pending`.
+    fn wait(&mut self, timeout: libc::c_int) -> io::Result<()> {
+        let mut events = [libc::epoll_event { events: 0, u64: 0 }; MAX_EPOLL_EVENTS];
+        self.check_hotplug();
+        let n = unsafe {
+            libc::epoll_wait(
+                self.epoll,
+                events.as_mut_ptr(),
+                MAX_EPOLL_EVENTS as i32,
+                timeout,
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for event in &events[..n as usize] {
+            self.drain(event.u64 as CpuId);
+        }
+
+        Ok(())
+    }
+
+    // Moves the events available without blocking to `pending` and returns
+    // them.
+    pub(crate) fn drain_ready(
+        &mut self,
+    ) -> io::Result<impl Iterator<Item = (CpuId, OwnedEvent)> + '_> {
+        self.wait(0)?;
+        Ok(self.pending.drain(..))
+    }
+
+    pub(crate) fn epoll_fd(&self) -> RawFd {
+        self.epoll
+    }
+}
+
+impl Iterator for EventStream {
+    type Item = (CpuId, OwnedEvent);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(item) = self.pending.pop_front() {
+                return Some(item);
+            }
+            let timeout = HOTPLUG_INTERVAL.as_millis() as i32;
+            if let Err(e) = self.wait(timeout) {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return None;
+            }
+        }
+    }
+}
+
+impl Drop for EventStream {
+    fn drop(&mut self) {
+        self.buffers.clear();
+        unsafe {
+            libc::close(self.epoll);
+            libc::close(self.map_fd);
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+mod stream {
+    use futures::{Async, Poll, Stream};
+    use mio::unix::EventedFd;
+    use mio::{Evented, PollOpt, Ready, Token};
+    use std::io;
+    use std::os::unix::io::RawFd;
+    use tokio::reactor::{Handle, PollEvented2};
+
+    use super::{EventStream, OwnedEvent};
+    use crate::cpus::CpuId;
+
+    struct EpollIo(RawFd);
+
+    impl Evented for EpollIo {
+        fn register(
+            &self,
+            poll: &mio::Poll,
+            token: Token,
+            interest: Ready,
+            opts: PollOpt,
+        ) -> io::Result<()> {
+            EventedFd(&self.0).register(poll, token, interest, opts)
+        }
+
+        fn reregister(
+            &self,
+            poll: &mio::Poll,
+            token: Token,
+            interest: Ready,
+            opts: PollOpt,
+        ) -> io::Result<()> {
+            EventedFd(&self.0).reregister(poll, token, interest, opts)
+        }
+
+        fn deregister(&self, poll: &mio::Poll) -> io::Result<()> {
+            EventedFd(&self.0).deregister(poll)
+        }
+    }
+
+    /// `Stream` over the events of an `EventStream`, see
+    /// `EventStream::into_stream`.
+    pub struct AsyncEventStream {
+        poll: PollEvented2<EpollIo>,
+        inner: EventStream,
+    }
+
+    impl EventStream {
+        /// Turns the iterator into a `Stream`, registering it with the
+        /// default tokio reactor.
+        ///
+        /// CPU hotplug is only checked when events arrive.
+        pub fn into_stream(self) -> io::Result<AsyncEventStream> {
+            let io = EpollIo(self.epoll);
+            let poll = PollEvented2::new_with_handle(io, &Handle::default())?;
+            Ok(AsyncEventStream { poll, inner: self })
+        }
+    }
+
+    impl Stream for AsyncEventStream {
+        type Item = (CpuId, OwnedEvent);
+        type Error = io::Error;
+
+        fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+            let ready = Ready::readable();
+            loop {
+                if let Some(item) = self.inner.pending.pop_front() {
+                    return Ok(Async::Ready(Some(item)));
+                }
+                if self.poll.poll_read_ready(ready)? == Async::NotReady {
+                    return Ok(Async::NotReady);
+                }
+                self.inner.check_hotplug();
+                self.inner.drain_all();
+                if self.inner.pending.is_empty() {
+                    self.poll.clear_read_ready(ready)?;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+pub use stream::AsyncEventStream;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::LostSamples;
+
+    #[test]
+    fn test_owned_lost_event() {
+        let mut lost: LostSamples = unsafe { std::mem::zeroed() };
+        lost.count = 3;
+        assert_eq!(OwnedEvent::from(Event::Lost(&lost)), OwnedEvent::Lost(3));
+    }
+}
@@ -0,0 +1,32 @@
+//! Detecting the program and map types supported by the running kernel.
+//!
+//! The probes load a minimal program or create a minimal map of the given
+//! type, so they need the same privileges as loading programs. This makes
+//! it possible to degrade gracefully on older kernels:
+//!
+//! ```rust
+//! use redbpf::probe_map_type;
+//!
+//! if !probe_map_type(bpf_sys::bpf_map_type_BPF_MAP_TYPE_ARRAY_OF_MAPS) {
+//!     println!("map in map not supported, falling back to a hash map");
+//! }
+//! ```
+use crate::ProgramKind;
+
+/// Returns `true` if the running kernel supports programs of type
+/// `prog_type`.
+pub fn probe_prog_type(prog_type: bpf_sys::bpf_prog_type) -> bool {
+    unsafe { bpf_sys::bpf_probe_prog_type(prog_type, 0) }
+}
+
+/// Returns `true` if the running kernel supports maps of type `map_type`.
+pub fn probe_map_type(map_type: bpf_sys::bpf_map_type) -> bool {
+    unsafe { bpf_sys::bpf_probe_map_type(map_type, 0) }
+}
+
+impl ProgramKind {
+    /// Returns `true` if the running kernel supports programs of this kind.
+    pub fn is_supported(&self) -> bool {
+        self.to_prog_type().map(probe_prog_type).unwrap_or(false)
+    }
+}
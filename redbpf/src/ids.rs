@@ -0,0 +1,207 @@
+//! Enumerating the programs and maps loaded in the kernel.
+//!
+//! Every program and map gets a system wide id when it's loaded. The ids can
+//! be used to get file descriptors for objects created by other processes,
+//! which is useful to build introspection and cleanup tooling:
+//!
+//! ```rust
+//! use redbpf::{iter_prog_ids, Program};
+//!
+//! for id in iter_prog_ids() {
+//!     let prog = Program::from_id(id.unwrap()).unwrap();
+//!     println!("{} {:?}", prog.name, prog.kind);
+//! }
+//! ```
+//!
+//! Enumerating and opening objects requires `CAP_SYS_ADMIN`.
+use std::ffi::CStr;
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+
+use crate::{sys, LoadError, Map, Program, ProgramKind, Result, VoidPtr};
+
+const BPF_PROG_GET_NEXT_ID: u32 = 11;
+const BPF_MAP_GET_NEXT_ID: u32 = 12;
+
+#[repr(C)]
+#[derive(Default)]
+struct NextIdAttr {
+    start_id: u32,
+    next_id: u32,
+    open_flags: u32,
+}
+
+/// Iterator over the ids of the programs or maps loaded in the kernel.
+///
+/// If the ids can't be read, eg. because of missing privileges, the error is
+/// returned once and the iteration ends.
+pub struct IdIter {
+    cmd: u32,
+    current: u32,
+    done: bool,
+}
+
+impl Iterator for IdIter {
+    type Item = Result<u32>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let mut attr = NextIdAttr {
+            start_id: self.current,
+            ..Default::default()
+        };
+        if let Err(e) = unsafe { sys::bpf(self.cmd, &mut attr) } {
+            self.done = true;
+            return match e.raw_os_error() {
+                Some(libc::ENOENT) => None,
+                _ => Some(Err(LoadError::IO(e))),
+            };
+        }
+
+        self.current = attr.next_id;
+        Some(Ok(attr.next_id))
+    }
+}
+
+/// Returns an iterator over the ids of the programs loaded in the kernel.
+pub fn iter_prog_ids() -> IdIter {
+    IdIter {
+        cmd: BPF_PROG_GET_NEXT_ID,
+        current: 0,
+        done: false,
+    }
+}
+
+/// Returns an iterator over the ids of the maps loaded in the kernel.
+pub fn iter_map_ids() -> IdIter {
+    IdIter {
+        cmd: BPF_MAP_GET_NEXT_ID,
+        current: 0,
+        done: false,
+    }
+}
+
+/// Fills `info` with the `BPF_OBJ_GET_INFO_BY_FD` info of `fd`.
+///
+/// `info` can hold buffers for the kernel to fill in, eg. the map ids of
+/// `bpf_prog_info`.
+pub(crate) unsafe fn obj_info_into<T>(fd: RawFd, info: &mut T) -> Result<()> {
+    let mut len = mem::size_of::<T>() as u32;
+    if bpf_sys::bpf_obj_get_info(fd, info as *mut T as VoidPtr, &mut len) < 0 {
+        return Err(LoadError::IO(io::Error::last_os_error()));
+    }
+
+    Ok(())
+}
+
+// Returns the info of the fd opened from an id, closing it on failure.
+unsafe fn obj_info<T>(fd: RawFd) -> Result<T> {
+    let mut info = mem::zeroed::<T>();
+    if let Err(e) = obj_info_into(fd, &mut info) {
+        libc::close(fd);
+        return Err(e);
+    }
+
+    Ok(info)
+}
+
+fn fd_by_id(fd: i32) -> Result<i32> {
+    if fd < 0 {
+        Err(LoadError::IO(io::Error::last_os_error()))
+    } else {
+        Ok(fd)
+    }
+}
+
+impl ProgramKind {
+    /// Returns the kind of the kernel program type `prog_type`.
+    ///
+    /// Kprobes and kretprobes share the same type, and are both returned as
+    /// `Kprobe`.
+    pub fn from_prog_type(prog_type: bpf_sys::bpf_prog_type) -> Option<ProgramKind> {
+        use crate::ProgramKind::*;
+        let kind = match prog_type {
+            bpf_sys::bpf_prog_type_BPF_PROG_TYPE_KPROBE => Kprobe,
+            bpf_sys::bpf_prog_type_BPF_PROG_TYPE_XDP => XDP,
+            bpf_sys::bpf_prog_type_BPF_PROG_TYPE_SOCKET_FILTER => SocketFilter,
+            bpf_sys::bpf_prog_type_BPF_PROG_TYPE_TRACEPOINT => Tracepoint,
+            bpf_sys::bpf_prog_type_BPF_PROG_TYPE_RAW_TRACEPOINT => RawTracepoint,
+            bpf_sys::bpf_prog_type_BPF_PROG_TYPE_SCHED_CLS => Classifier,
+            bpf_sys::bpf_prog_type_BPF_PROG_TYPE_SCHED_ACT => Action,
+            bpf_sys::bpf_prog_type_BPF_PROG_TYPE_CGROUP_SKB => CgroupSkb,
+            bpf_sys::bpf_prog_type_BPF_PROG_TYPE_CGROUP_SOCK => CgroupSock,
+            bpf_sys::bpf_prog_type_BPF_PROG_TYPE_SOCK_OPS => SockOps,
+            bpf_sys::bpf_prog_type_BPF_PROG_TYPE_SK_SKB => SkSkb,
+            bpf_sys::bpf_prog_type_BPF_PROG_TYPE_SK_MSG => SkMsg,
+            bpf_sys::bpf_prog_type_BPF_PROG_TYPE_PERF_EVENT => PerfEvent,
+            prog_type => {
+                return crate::program_types::find_by_prog_type(prog_type)
+                    .map(|t| Custom(t.section))
+            }
+        };
+
+        Some(kind)
+    }
+}
+
+impl Program {
+    /// Opens the loaded program with the given id.
+    ///
+    /// The returned program is loaded but has no code, and is closed, but
+    /// not detached, when dropped.
+    pub fn from_id(id: u32) -> Result<Program> {
+        let fd = fd_by_id(unsafe { bpf_sys::bpf_prog_get_fd_by_id(id) })?;
+        let info: bpf_sys::bpf_prog_info = unsafe { obj_info(fd)? };
+        let kind = match ProgramKind::from_prog_type(info.type_) {
+            Some(kind) => kind,
+            None => {
+                unsafe { libc::close(fd) };
+                return Err(LoadError::NotSupported(format!(
+                    "program type {}",
+                    info.type_
+                )));
+            }
+        };
+        let name = unsafe { CStr::from_ptr(info.name.as_ptr()) };
+
+        Ok(Program {
+            attachments: Vec::new(),
+            fd: Some(fd),
+            kind,
+            name: name.to_string_lossy().into_owned(),
+            code: Vec::new(),
+            code_bytes: 0,
+            expected_attach_type: 0,
+            sleepable: false,
+            btf: None,
+        })
+    }
+}
+
+impl Map {
+    /// Opens the map with the given id.
+    pub fn from_id(id: u32) -> Result<Map> {
+        let fd = fd_by_id(unsafe { bpf_sys::bpf_map_get_fd_by_id(id) })?;
+        let info: bpf_sys::bpf_map_info = unsafe { obj_info(fd)? };
+        let name = unsafe { CStr::from_ptr(info.name.as_ptr()) };
+        let mut config: bpf_sys::bpf_map_def = unsafe { mem::zeroed() };
+        config.type_ = info.type_;
+        config.key_size = info.key_size;
+        config.value_size = info.value_size;
+        config.max_entries = info.max_entries;
+        config.map_flags = info.map_flags;
+
+        Ok(Map {
+            name: name.to_string_lossy().into_owned(),
+            kind: info.type_,
+            fd,
+            config,
+            numa_node: None,
+            value_btf: None,
+        })
+    }
+}
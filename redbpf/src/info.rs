@@ -0,0 +1,100 @@
+//! Querying the metadata of loaded programs.
+use std::ffi::CStr;
+use std::fmt::Write;
+use std::mem;
+use std::os::unix::io::RawFd;
+
+use crate::ids::obj_info_into;
+use crate::{LoadError, Program, ProgramKind, Result};
+
+/// Metadata of a loaded program, as returned by `BPF_OBJ_GET_INFO_BY_FD`.
+#[derive(Debug, Clone)]
+pub struct ProgInfo {
+    /// The system wide id of the program.
+    pub id: u32,
+    /// The name of the program, truncated to 15 characters by the kernel.
+    pub name: String,
+    /// The hash of the program instructions.
+    pub tag: [u8; 8],
+    /// The kernel program type.
+    pub prog_type: bpf_sys::bpf_prog_type,
+    /// The program kind, if the type is known to RedBPF.
+    pub kind: Option<ProgramKind>,
+    /// The number of instructions after verification.
+    pub insn_cnt: usize,
+    /// The size of the JIT compiled program in bytes, 0 if it's not JITed.
+    pub jited_len: u32,
+    /// The ids of the maps used by the program.
+    pub map_ids: Vec<u32>,
+}
+
+impl ProgInfo {
+    /// Returns the tag as a hex string, the way `bpftool` prints it.
+    pub fn tag_hex(&self) -> String {
+        let mut hex = String::with_capacity(16);
+        for b in self.tag.iter() {
+            write!(hex, "{:02x}", b).unwrap();
+        }
+        hex
+    }
+}
+
+pub(crate) fn prog_info(fd: RawFd) -> Result<ProgInfo> {
+    let mut info = unsafe { mem::zeroed::<bpf_sys::bpf_prog_info>() };
+    unsafe { obj_info_into(fd, &mut info)? };
+
+    // the map ids are only filled in if a buffer is passed in a second call
+    let mut map_ids = vec![0u32; info.nr_map_ids as usize];
+    if !map_ids.is_empty() {
+        let nr_map_ids = info.nr_map_ids;
+        info = unsafe { mem::zeroed() };
+        info.nr_map_ids = nr_map_ids;
+        info.map_ids = map_ids.as_mut_ptr() as u64;
+        unsafe { obj_info_into(fd, &mut info)? };
+        // maps can't be added to a loaded program, but better safe than sorry
+        map_ids.truncate(info.nr_map_ids.min(nr_map_ids) as usize);
+    }
+
+    let name = unsafe { CStr::from_ptr(info.name.as_ptr()) };
+    Ok(ProgInfo {
+        id: info.id,
+        name: name.to_string_lossy().into_owned(),
+        tag: info.tag,
+        prog_type: info.type_,
+        kind: ProgramKind::from_prog_type(info.type_),
+        insn_cnt: info.xlated_prog_len as usize / mem::size_of::<bpf_sys::bpf_insn>(),
+        jited_len: info.jited_prog_len,
+        map_ids,
+    })
+}
+
+impl Program {
+    /// Returns the metadata of the loaded program.
+    ///
+    /// The tag can be compared with the one reported by `bpftool prog` to
+    /// check that the expected program is loaded.
+    pub fn info(&self) -> Result<ProgInfo> {
+        let fd = self.fd.ok_or(LoadError::BPF)?;
+        prog_info(fd)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_tag_hex() {
+        let info = ProgInfo {
+            id: 1,
+            name: "trace".to_string(),
+            tag: [0xde, 0xad, 0xbe, 0xef, 0x00, 0x01, 0x0a, 0xff],
+            prog_type: bpf_sys::bpf_prog_type_BPF_PROG_TYPE_KPROBE,
+            kind: Some(ProgramKind::Kprobe),
+            insn_cnt: 0,
+            jited_len: 0,
+            map_ids: Vec::new(),
+        };
+        assert_eq!(info.tag_hex(), "deadbeef00010aff");
+    }
+}
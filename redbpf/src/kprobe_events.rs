@@ -0,0 +1,191 @@
+//! Attaching kprobes through the legacy `kprobe_events` tracing interface.
+//!
+//! Kernels that can't create kprobes with `perf_event_open` need the probe to
+//! be registered as a trace event first, then a perf event is opened on the
+//! event id. bcc does the same, but only through debugfs, while tracefs can
+//! be mounted on its own at `/sys/kernel/tracing`.
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use libc::{ioctl, syscall, SYS_perf_event_open};
+
+use crate::sys::perf::*;
+use crate::{LoadError, ProgramKind, Result};
+
+// tracefs, and its legacy location in debugfs
+const TRACING_DIRS: &[&str] = &["/sys/kernel/tracing", "/sys/kernel/debug/tracing"];
+const GROUP: &str = "redbpf";
+
+/// A kprobe registered in `kprobe_events` with a perf event attached to it.
+pub(crate) struct KprobeEvent {
+    pub pfd: RawFd,
+    pub event: String,
+}
+
+// Builds the `kprobe_events` line registering the probe, and the name of the
+// event it creates.
+fn probe_definition(
+    kind: &ProgramKind,
+    fn_name: &str,
+    offset: u64,
+    pid: u32,
+) -> (String, String) {
+    let prefix = match kind {
+        ProgramKind::Kretprobe => 'r',
+        _ => 'p',
+    };
+    // event names can only contain alphanumeric characters and underscores
+    let sanitized: String = fn_name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    let event = format!("{}_{}_{}_{}", prefix, sanitized, offset, pid);
+    let line = if offset > 0 {
+        format!("{}:{}/{} {}+{}", prefix, GROUP, event, fn_name, offset)
+    } else {
+        format!("{}:{}/{} {}", prefix, GROUP, event, fn_name)
+    };
+
+    (line, event)
+}
+
+// Returns the first of `dirs` with a `kprobe_events` file.
+fn find_tracing_dir(dirs: &[&str]) -> io::Result<PathBuf> {
+    dirs.iter()
+        .map(Path::new)
+        .find(|dir| dir.join("kprobe_events").exists())
+        .map(Path::to_path_buf)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no kprobe_events in {}", dirs.join(" or ")),
+            )
+        })
+}
+
+fn write_kprobe_events(line: &str) -> io::Result<()> {
+    let path = find_tracing_dir(TRACING_DIRS)?.join("kprobe_events");
+    let mut file = OpenOptions::new().append(true).open(path)?;
+    file.write_all(line.as_bytes())
+}
+
+fn event_id(event: &str) -> io::Result<u64> {
+    let path = find_tracing_dir(TRACING_DIRS)?
+        .join("events")
+        .join(GROUP)
+        .join(event)
+        .join("id");
+    fs::read_to_string(path)?
+        .trim()
+        .parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+unsafe fn open_tracepoint_event(id: u64, prog_fd: RawFd) -> Result<RawFd> {
+    let mut attr = mem::zeroed::<perf_event_attr>();
+    attr.size = mem::size_of::<perf_event_attr>() as u32;
+    attr.type_ = perf_type_id_PERF_TYPE_TRACEPOINT;
+    attr.config = id;
+    attr.__bindgen_anon_1.sample_period = 1;
+    attr.__bindgen_anon_2.wakeup_events = 1;
+
+    let pfd = syscall(
+        SYS_perf_event_open,
+        &attr as *const perf_event_attr,
+        -1,
+        0,
+        -1,
+        PERF_FLAG_FD_CLOEXEC,
+    ) as RawFd;
+    if pfd < 0 {
+        return Err(LoadError::IO(io::Error::last_os_error()));
+    }
+    if ioctl(pfd, PERF_EVENT_IOC_SET_BPF, prog_fd) != 0
+        || ioctl(pfd, PERF_EVENT_IOC_ENABLE, 0) != 0
+    {
+        let err = io::Error::last_os_error();
+        libc::close(pfd);
+        return Err(LoadError::IO(err));
+    }
+
+    Ok(pfd)
+}
+
+/// Registers a kprobe on `fn_name` in `kprobe_events` and attaches `prog_fd`
+/// to it.
+pub(crate) fn attach(
+    prog_fd: RawFd,
+    kind: &ProgramKind,
+    fn_name: &str,
+    offset: u64,
+) -> Result<KprobeEvent> {
+    let (line, event) = probe_definition(kind, fn_name, offset, process::id());
+    write_kprobe_events(&line)?;
+    let pfd = event_id(&event)
+        .map_err(LoadError::IO)
+        .and_then(|id| unsafe { open_tracepoint_event(id, prog_fd) });
+    match pfd {
+        Ok(pfd) => Ok(KprobeEvent { pfd, event }),
+        Err(e) => {
+            let _ = remove(&event);
+            Err(e)
+        }
+    }
+}
+
+impl KprobeEvent {
+    /// Closes the perf event and removes the kprobe from `kprobe_events`.
+    pub fn detach(self) -> Result<()> {
+        unsafe {
+            ioctl(self.pfd, PERF_EVENT_IOC_DISABLE, 0);
+            libc::close(self.pfd);
+        }
+        remove(&self.event)
+    }
+}
+
+fn remove(event: &str) -> Result<()> {
+    write_kprobe_events(&format!("-:{}/{}", GROUP, event))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_probe_definition() {
+        let (line, event) = probe_definition(&ProgramKind::Kprobe, "do_sys_open", 0, 42);
+        assert_eq!(event, "p_do_sys_open_0_42");
+        assert_eq!(line, "p:redbpf/p_do_sys_open_0_42 do_sys_open");
+
+        let (line, event) = probe_definition(&ProgramKind::Kretprobe, "vfs.read", 0, 1);
+        assert_eq!(event, "r_vfs_read_0_1");
+        assert_eq!(line, "r:redbpf/r_vfs_read_0_1 vfs.read");
+
+        let (line, _) = probe_definition(&ProgramKind::Kprobe, "do_sys_open", 16, 1);
+        assert_eq!(line, "p:redbpf/p_do_sys_open_16_1 do_sys_open+16");
+    }
+
+    #[test]
+    fn test_find_tracing_dir() {
+        let dir = std::env::temp_dir().join(format!("redbpf-tracing-{}", process::id()));
+        let tracefs = dir.join("tracing");
+        let debugfs = dir.join("debug/tracing");
+        fs::create_dir_all(&tracefs).unwrap();
+        fs::create_dir_all(&debugfs).unwrap();
+        let dirs = [tracefs.to_str().unwrap(), debugfs.to_str().unwrap()];
+        assert!(find_tracing_dir(&dirs).is_err());
+
+        fs::write(debugfs.join("kprobe_events"), "").unwrap();
+        assert_eq!(find_tracing_dir(&dirs).unwrap(), debugfs);
+        fs::write(tracefs.join("kprobe_events"), "").unwrap();
+        assert_eq!(find_tracing_dir(&dirs).unwrap(), tracefs);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
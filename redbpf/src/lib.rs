@@ -23,6 +23,16 @@
 //!  * `kretprobe/function_name` for return probes for `function_name`
 //!  * `xdp/name` for XDP probes. Names can be anything.
 //!  * `socketfilter/name` for socket filters. Names can be anything.
+//!  * `tracepoint/name`, `raw_tracepoint/name`, `classifier/name`,
+//!    `action/name`, `cgroup_skb/name`, `cgroup_sock/name`, `sockops/name`,
+//!    `sk_skb/name`, `sk_msg/name` and `perf_event/name` for the
+//!    corresponding program types, see `ProgramKind::from_section`.
+//!
+//! The name can be omitted, eg. `xdp`, in which case the program is named
+//! after its type.
+//!
+//! The optional `tail_calls` section lists the programs to store in program
+//! arrays, see the `tail_call` module.
 //!
 //! Additionally, as per convention, the following sections should be present in
 //! the ELF object:
@@ -32,7 +42,8 @@
 //! char _license[] SEC("license") = "GPL";
 //! ```
 //!
-//! If the license is not GPL, some in-kernel functionality is not available for eBPF modules.
+//! If the license is not GPL compatible, some in-kernel functionality is not available for eBPF
+//! modules: programs calling GPL-only helpers fail to load with `LoadError::GplOnlyHelper`.
 //!
 //! The magic version number is compatible with GoBPF's convention: during
 //! loading it is replaced with the currently running kernel's internal version,
@@ -46,12 +57,47 @@ extern crate lazy_static;
 #[macro_use]
 extern crate serde_derive;
 
+mod batch;
+pub mod btf;
 #[cfg(feature = "build")]
 pub mod build;
+pub mod convert;
+mod counters;
 pub mod cpus;
+mod dump;
 mod error;
+mod event_stream;
+mod features;
+mod ids;
+mod info;
+mod kprobe_events;
+mod license;
+mod local_storage;
+mod mmap;
+mod net;
+mod netlink;
+mod numa;
 mod perf;
+mod perf_check;
+mod pod;
+mod poll;
+mod print;
+mod prog_load;
+pub mod program_types;
+mod rate_limiter;
+mod seed;
+mod stats;
+mod subprog;
+pub mod symbols;
 pub mod sys;
+pub mod tail_call;
+pub mod tc;
+mod test_run;
+pub mod uprobe;
+mod value_btf;
+mod watch;
+pub mod xdp;
+pub mod xsk;
 pub use bpf_sys::uname;
 
 use bpf_sys::{bpf_insn, bpf_map_def};
@@ -60,13 +106,44 @@ use goblin::elf::{section_header as hdr, Elf, SectionHeader, Sym,
 
 use std::collections::HashMap;
 use std::ffi::CString;
+use std::fs::File;
 use std::io;
 use std::mem;
-use std::os::unix::io::RawFd;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
 
+pub use crate::counters::Counters;
 pub use crate::error::{LoadError, Result};
+#[cfg(feature = "async")]
+pub use crate::event_stream::AsyncEventStream;
+pub use crate::event_stream::{EventStream, OwnedEvent, PerCpuThreads};
+pub use crate::features::{probe_map_type, probe_prog_type};
+pub use crate::ids::{iter_map_ids, iter_prog_ids, IdIter};
+pub use crate::info::ProgInfo;
+pub use crate::license::is_gpl_compatible;
+pub use crate::mmap::MmapView;
+pub use crate::net::{if_indextoname, if_nametoindex};
 pub use crate::perf::*;
+pub use crate::perf_check::PerfArraySizeMismatch;
+pub use crate::pod::Pod;
+#[cfg(feature = "log")]
+pub use crate::print::forward_to_log;
+pub use crate::print::{set_print_callback, PrintLevel};
+pub use crate::prog_load::{
+    BPF_F_ANY_ALIGNMENT, BPF_F_SLEEPABLE, BPF_F_STRICT_ALIGNMENT, BPF_F_TEST_RND_HI32,
+};
+pub use crate::rate_limiter::{RateLimiter, TokenBucket};
+pub use crate::stats::{enable_stats, ProgStats, StatsGuard};
+pub use crate::tail_call::TailCall;
+pub use crate::uprobe::resolve_symbol;
+pub use crate::test_run::{TestRun, XdpAction};
+pub use crate::watch::{MapChange, MapWatch};
+#[cfg(feature = "async")]
+pub use crate::watch::MapWatchStream;
+pub use crate::xdp::XdpMultiAttachment;
+use crate::prog_load::ProgramBtf;
 use crate::uname::get_kernel_internal_version;
+use crate::value_btf::ValueBtf;
 
 pub type VoidPtr = *mut std::os::raw::c_void;
 
@@ -81,11 +158,76 @@ pub type DataPtr = *const i8;
 #[cfg(target_arch = "x86_64")]
 pub type MutDataPtr = *mut i8;
 
+/// Options for parsing modules with `Module::parse_with_options`.
+///
+/// ```rust
+/// use redbpf::{LoadOptions, Module};
+///
+/// let code = std::fs::read("bpf.elf").unwrap();
+/// let mut options = LoadOptions::new();
+/// options.with_target_btf("/boot/vmlinux-5.4.0-42-generic.btf");
+/// let module = Module::parse_with_options(&code, &options).unwrap();
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct LoadOptions {
+    target_btf: Option<PathBuf>,
+    numa_nodes: HashMap<String, u32>,
+}
+
+impl LoadOptions {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Relocates CO-RE programs against the BTF at `path` instead of the BTF
+    /// of the running kernel, `/sys/kernel/btf/vmlinux`.
+    ///
+    /// This allows relocating programs for another kernel than the one
+    /// running, eg. in CI. The BTF of a kernel can be extracted from its
+    /// `vmlinux` with `pahole` or `bpftool`. Relocations require the `core`
+    /// cargo feature.
+    pub fn with_target_btf<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        self.target_btf = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Returns the BTF programs are relocated against, if not the BTF of the
+    /// running kernel.
+    pub fn target_btf(&self) -> Option<&Path> {
+        self.target_btf.as_ref().map(PathBuf::as_path)
+    }
+
+    /// Allocates the memory of the map `name` on the NUMA node `node`,
+    /// instead of the node of the CPU parsing the module.
+    ///
+    /// Placing a map on the node of the CPUs that access it most, eg. a
+    /// per-CPU map updated by a high rate probe, avoids cross-node memory
+    /// accesses. Parsing fails if the node doesn't exist.
+    pub fn with_map_numa_node(&mut self, name: &str, node: u32) -> &mut Self {
+        self.numa_nodes.insert(name.to_string(), node);
+        self
+    }
+
+    /// Returns the NUMA node the map `name` is allocated on, if set.
+    pub fn map_numa_node(&self, name: &str) -> Option<u32> {
+        self.numa_nodes.get(name).cloned()
+    }
+}
+
+/// The programs and maps of an ELF object.
+///
+/// Modules are created with `Module::parse`. They can't be built with a
+/// struct literal since they hold the handlers registered with `on_events`.
 pub struct Module {
     pub programs: Vec<Program>,
     pub maps: Vec<Map>,
     pub license: String,
     pub version: u32,
+    /// The programs to store in program arrays, declared with the
+    /// `tail_call!` macro or `add_tail_call`.
+    pub tail_calls: Vec<TailCall>,
+    // the event streams polled by `poll_all`
+    poller: poll::Poller,
 }
 
 /// You can load an eBPF module, and all the programs in it like so:
@@ -102,6 +244,20 @@ pub struct Module {
 /// Note that during the parsing the ELF file all BPF maps are automatically
 /// initialised.
 ///
+/// Parsing works on any byte slice and never touches the filesystem, so the
+/// ELF object can be embedded in the loader binary:
+///
+/// ```ignore
+/// use redbpf::Module;
+///
+/// static PROBE: &[u8] = include_bytes!("bpf.elf");
+///
+/// let mut module = Module::parse(PROBE).unwrap();
+/// for prog in module.programs.iter_mut() {
+///     prog.load(module.version, module.license.clone()).unwrap();
+/// }
+/// ```
+///
 /// You can attach kprobes like very easily:
 ///
 /// ```rust
@@ -129,30 +285,70 @@ pub struct Module {
 ///
 /// let code = std::fs::read("bpf.elf").unwrap();
 /// let mut module = Module::parse(&code).unwrap();
-/// for prog in module
-///     .programs
-///     .iter_mut()
-///     .filter(|p| p.kind == XDP)
-/// {
+/// for prog in module.programs_of_type_mut(XDP) {
 ///     prog.attach_xdp("eth0").unwrap();
 /// }
 /// ```
+///
+/// Programs are detached and unloaded when dropped. Use `detach()` and
+/// `close()` to handle errors explicitly.
 pub struct Program {
-    pfd: Option<RawFd>,
+    attachments: Vec<Attachment>,
     fd: Option<RawFd>,
     pub kind: ProgramKind,
     pub name: String,
     code: Vec<bpf_insn>,
     code_bytes: i32,
+    expected_attach_type: u32,
+    sleepable: bool,
+    btf: Option<ProgramBtf>,
 }
 
+enum Attachment {
+    Probe { pfd: RawFd, ev_name: String },
+    Uprobe { pfd: RawFd, ev_name: String },
+    KprobeEvent(kprobe_events::KprobeEvent),
+    Tracepoint(RawFd),
+    XDP {
+        iface: String,
+        netns: Option<File>,
+        /// The mode flags the program was attached with.
+        flags: u32,
+    },
+    SocketFilter(RawFd),
+    Custom {
+        prog_fd: RawFd,
+        target: String,
+        detach: Option<program_types::AttachFn>,
+    },
+}
+
+/// The kind of a program, detected from its ELF section name.
+///
+/// New kinds are added as the kernel grows program types, so the enum is
+/// `#[non_exhaustive]` and matches outside of this crate need a wildcard arm.
 #[derive(Debug, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum ProgramKind {
     Kprobe,
     Kretprobe,
+    Uprobe,
+    Uretprobe,
     XDP,
     SocketFilter,
     Tracepoint,
+    RawTracepoint,
+    Classifier,
+    Action,
+    CgroupSkb,
+    CgroupSock,
+    SockOps,
+    SkSkb,
+    SkMsg,
+    PerfEvent,
+    /// A type registered with `program_types::register_program_type`, holding
+    /// its section prefix.
+    Custom(&'static str),
 }
 
 /// Maps are loaded automatically, so you normally do not have to do anything to
@@ -167,10 +363,15 @@ pub enum ProgramKind {
 /// This design makes it easier to deal with maps, and keeps them versatile for
 /// sharing data between the kernel and userspace, however, it remains a foot
 /// cannon. In the future, this might need more work.
+///
+/// The map file descriptor is closed when the `Map` is dropped.
 pub struct Map {
     pub name: String,
     pub kind: u32,
     fd: RawFd,
+    config: bpf_map_def,
+    numa_node: Option<u32>,
+    value_btf: Option<ValueBtf>,
 }
 
 #[allow(dead_code)]
@@ -182,36 +383,77 @@ pub struct Rel {
 }
 
 impl ProgramKind {
-    pub fn to_prog_type(&self) -> bpf_sys::bpf_prog_type {
+    /// Returns the kernel program type of the kind.
+    ///
+    /// Fails with `LoadError::Section` if the kind is a custom one whose type
+    /// is not registered.
+    pub fn to_prog_type(&self) -> Result<bpf_sys::bpf_prog_type> {
         use crate::ProgramKind::*;
-        match self {
-            Kprobe | Kretprobe => bpf_sys::bpf_prog_type_BPF_PROG_TYPE_KPROBE,
+        let prog_type = match self {
+            Kprobe | Kretprobe | Uprobe | Uretprobe => {
+                bpf_sys::bpf_prog_type_BPF_PROG_TYPE_KPROBE
+            }
             XDP => bpf_sys::bpf_prog_type_BPF_PROG_TYPE_XDP,
             SocketFilter => bpf_sys::bpf_prog_type_BPF_PROG_TYPE_SOCKET_FILTER,
             Tracepoint => bpf_sys::bpf_prog_type_BPF_PROG_TYPE_TRACEPOINT,
-        }
+            RawTracepoint => bpf_sys::bpf_prog_type_BPF_PROG_TYPE_RAW_TRACEPOINT,
+            Classifier => bpf_sys::bpf_prog_type_BPF_PROG_TYPE_SCHED_CLS,
+            Action => bpf_sys::bpf_prog_type_BPF_PROG_TYPE_SCHED_ACT,
+            CgroupSkb => bpf_sys::bpf_prog_type_BPF_PROG_TYPE_CGROUP_SKB,
+            CgroupSock => bpf_sys::bpf_prog_type_BPF_PROG_TYPE_CGROUP_SOCK,
+            SockOps => bpf_sys::bpf_prog_type_BPF_PROG_TYPE_SOCK_OPS,
+            SkSkb => bpf_sys::bpf_prog_type_BPF_PROG_TYPE_SK_SKB,
+            SkMsg => bpf_sys::bpf_prog_type_BPF_PROG_TYPE_SK_MSG,
+            PerfEvent => bpf_sys::bpf_prog_type_BPF_PROG_TYPE_PERF_EVENT,
+            Custom(section) => {
+                program_types::find_by_section(section)
+                    .ok_or_else(|| LoadError::Section(section.to_string()))?
+                    .prog_type
+            }
+        };
+
+        Ok(prog_type)
     }
 
     pub fn to_attach_type(&self) -> bpf_sys::bpf_probe_attach_type {
         use crate::ProgramKind::*;
         match self {
-            Kprobe => bpf_sys::bpf_probe_attach_type_BPF_PROBE_ENTRY,
-            Kretprobe => bpf_sys::bpf_probe_attach_type_BPF_PROBE_RETURN,
-            a @ Tracepoint => panic!("Program type cannot be used with attach(): {:?}", a),
-            a @ SocketFilter => panic!("Program type cannot be used with attach(): {:?}", a),
-            a @ XDP => panic!("Program type cannot be used with attach(): {:?}", a),
+            Kprobe | Uprobe => bpf_sys::bpf_probe_attach_type_BPF_PROBE_ENTRY,
+            Kretprobe | Uretprobe => bpf_sys::bpf_probe_attach_type_BPF_PROBE_RETURN,
+            a => panic!("Program type cannot be used with attach(): {:?}", a),
         }
     }
 
+    /// Resolves the program type from the prefix of an ELF section name, eg.
+    /// `kprobe` for `kprobe/do_fork`.
+    ///
+    /// Prefixes that are not built in are looked up in the types registered
+    /// with `program_types::register_program_type`.
+    ///
+    /// The `.s` suffix of sleepable uprobes, `uprobe.s` and `uretprobe.s`, is
+    /// ignored.
     pub fn from_section(section: &str) -> Result<ProgramKind> {
         use crate::ProgramKind::*;
-        match section {
+        match strip_sleepable(section).0 {
             "kretprobe" => Ok(Kretprobe),
             "kprobe" => Ok(Kprobe),
+            "uretprobe" => Ok(Uretprobe),
+            "uprobe" => Ok(Uprobe),
             "xdp" => Ok(XDP),
             "socketfilter" => Ok(SocketFilter),
             "tracepoint" => Ok(Tracepoint),
-            sec => Err(LoadError::Section(sec.to_string())),
+            "raw_tracepoint" => Ok(RawTracepoint),
+            "classifier" => Ok(Classifier),
+            "action" => Ok(Action),
+            "cgroup_skb" => Ok(CgroupSkb),
+            "cgroup_sock" => Ok(CgroupSock),
+            "sockops" => Ok(SockOps),
+            "sk_skb" => Ok(SkSkb),
+            "sk_msg" => Ok(SkMsg),
+            "perf_event" => Ok(PerfEvent),
+            sec => program_types::find_by_section(sec)
+                .map(|t| Custom(t.section))
+                .ok_or_else(|| LoadError::Section(sec.to_string())),
         }
     }
 }
@@ -221,77 +463,153 @@ impl Program {
         let code_bytes = code.len() as i32;
         let code = zero::read_array(code).to_vec();
         let name = name.to_string();
+        let sleepable = strip_sleepable(kind).1;
         let kind = ProgramKind::from_section(kind)?;
 
         Ok(Program {
-            pfd: None,
+            attachments: Vec::new(),
             fd: None,
             kind,
             name,
             code,
             code_bytes,
+            expected_attach_type: 0,
+            sleepable,
+            btf: None,
         })
     }
 
+    /// Returns the kernel program type detected from the section name.
+    pub fn prog_type(&self) -> Result<bpf_sys::bpf_prog_type> {
+        self.kind.to_prog_type()
+    }
+
     pub fn is_loaded(&self) -> bool {
         self.fd.is_some()
     }
 
     pub fn is_attached(&self) -> bool {
-        self.pfd.is_some()
+        !self.attachments.is_empty()
+    }
+
+    /// Returns whether the program was declared sleepable, eg. with
+    /// `#[uprobe("...", sleepable)]`.
+    ///
+    /// Sleepable programs are loaded with `BPF_F_SLEEPABLE`.
+    pub fn is_sleepable(&self) -> bool {
+        self.sleepable
     }
 
     pub fn load(&mut self, kernel_version: u32, license: String) -> Result<RawFd> {
+        self.check_license(&license)?;
+        if self.sleepable {
+            return self.load_with_flags(kernel_version, license, BPF_F_SLEEPABLE);
+        }
+        if self.btf.is_some() {
+            // bcc can't pass the line info
+            return self.load_with_flags(kernel_version, license, 0);
+        }
+
         let clicense = CString::new(license)?;
         let cname = CString::new(self.name.clone())?;
-        let log_buffer: MutDataPtr =
-            unsafe { libc::malloc(mem::size_of::<i8>() * 16 * 65535) as MutDataPtr };
-        let buf_size = 64 * 65535 as u32;
+        let mut log_buf = vec![0u8; 64 * 65535];
 
+        // on failure, bcc loads the program again to fill the log buffer
         let fd = unsafe {
             bpf_sys::bcc_prog_load(
-                self.kind.to_prog_type(),
+                self.kind.to_prog_type()?,
                 cname.as_ptr() as DataPtr,
                 self.code.as_ptr(),
                 self.code_bytes,
                 clicense.as_ptr() as DataPtr,
                 kernel_version as u32,
                 0 as i32,
-                log_buffer,
-                buf_size,
+                log_buf.as_mut_ptr() as MutDataPtr,
+                log_buf.len() as u32,
             )
         };
 
         if fd < 0 {
-            Err(LoadError::BPF)
+            Err(prog_load::verifier_error(
+                &self.name,
+                prog_load::verifier_log(&log_buf),
+            ))
         } else {
             self.fd = Some(fd);
             Ok(fd)
         }
     }
 
+    /// Attaches the probe to the function named after the program.
+    ///
+    /// Programs named `function+offset`, eg. `#[kprobe("do_fork+0x10")]`, are
+    /// attached at the given offset within the function. See
+    /// `attach_kprobe_offset`.
     pub fn attach_probe(&mut self) -> Result<RawFd> {
-        self.attach_probe_to_name(&self.name.clone())
+        let name = self.name.clone();
+        let mut parts = name.splitn(2, '+');
+        let fn_name = parts.next().unwrap();
+        match parts.next() {
+            Some(offset) => {
+                let offset =
+                    parse_offset(offset).ok_or_else(|| LoadError::ProbeOffsetParse(name.clone()))?;
+                self.attach_kprobe_offset(fn_name, offset)
+            }
+            None => self.attach_probe_to_name(fn_name),
+        }
     }
 
     pub fn attach_probe_to_name(&mut self, name: &str) -> Result<RawFd> {
-        let ev_name = CString::new(format!("{}{}", name, self.kind.to_attach_type())).unwrap();
+        self.attach_probe_at(name, 0)
+    }
+
+    /// Attaches a kprobe to the instruction at `offset` bytes from the start
+    /// of `fn_name`.
+    ///
+    /// Offsets are only supported by entry probes. When the size of the
+    /// function can be determined from `/proc/kallsyms`, the offset is checked
+    /// to be within the function.
+    pub fn attach_kprobe_offset(&mut self, fn_name: &str, offset: u64) -> Result<RawFd> {
+        if self.kind != ProgramKind::Kprobe && offset > 0 {
+            return Err(LoadError::ProbeOffset(fn_name.to_string(), offset));
+        }
+        if let Some(size) = symbols::kernel_function_size(fn_name)? {
+            if offset >= size {
+                return Err(LoadError::ProbeOffset(fn_name.to_string(), offset));
+            }
+        }
+
+        self.attach_probe_at(fn_name, offset)
+    }
+
+    fn attach_probe_at(&mut self, name: &str, offset: u64) -> Result<RawFd> {
+        let ev_name = if offset > 0 {
+            format!("{}_{}{}", name, offset, self.kind.to_attach_type())
+        } else {
+            format!("{}{}", name, self.kind.to_attach_type())
+        };
+        let cev_name = CString::new(ev_name.clone()).unwrap();
         let cname = CString::new(name).unwrap();
         let pfd = unsafe {
             bpf_sys::bpf_attach_kprobe(
                 self.fd.unwrap(),
                 self.kind.to_attach_type(),
-                ev_name.as_ptr(),
+                cev_name.as_ptr(),
                 cname.as_ptr(),
-                0,
+                offset,
                 0
             )
         };
 
         if pfd < 0 {
-            Err(LoadError::BPF)
+            symbols::check_kprobe_symbol(name)?;
+            // older kernels can only create kprobes through kprobe_events
+            let event = kprobe_events::attach(self.fd.unwrap(), &self.kind, name, offset)?;
+            let pfd = event.pfd;
+            self.attachments.push(Attachment::KprobeEvent(event));
+            Ok(pfd)
         } else {
-            self.pfd = Some(pfd);
+            self.attachments.push(Attachment::Probe { pfd, ev_name });
             Ok(pfd)
         }
     }
@@ -310,19 +628,50 @@ impl Program {
         if res < 0 {
             Err(LoadError::BPF)
         } else {
+            self.attachments.push(Attachment::Tracepoint(res));
             Ok(res)
         }
     }
 
     pub fn attach_xdp(&mut self, iface: &str) -> Result<()> {
-        let ciface = CString::new(iface).unwrap();
-        let res = unsafe { bpf_sys::bpf_attach_xdp(ciface.as_ptr(), self.fd.unwrap(), 0) };
+        xdp::attach(iface, self.fd.ok_or(LoadError::BPF)?, 0)?;
+        self.attachments.push(Attachment::XDP {
+            iface: iface.to_string(),
+            netns: None,
+            flags: 0,
+        });
 
-        if res < 0 {
-            Err(LoadError::BPF)
-        } else {
-            Ok(())
+        Ok(())
+    }
+
+    /// Attaches the XDP program to the interface `iface` in the network
+    /// namespace at `netns`, eg. `/proc/<pid>/ns/net`.
+    ///
+    /// The namespace of the calling thread is restored after attaching.
+    pub fn attach_xdp_in_netns<P: AsRef<Path>>(&mut self, iface: &str, netns: P) -> Result<()> {
+        let netns = File::open(netns)?;
+        self.attach_xdp_in_netns_file(iface, netns)
+    }
+
+    /// Same as `attach_xdp_in_netns`, with the namespace given as an open
+    /// file descriptor. The descriptor is duplicated.
+    pub fn attach_xdp_in_netns_fd(&mut self, iface: &str, netns: RawFd) -> Result<()> {
+        let fd = unsafe { libc::dup(netns) };
+        if fd < 0 {
+            return Err(LoadError::IO(io::Error::last_os_error()));
         }
+        let netns = unsafe { File::from_raw_fd(fd) };
+        self.attach_xdp_in_netns_file(iface, netns)
+    }
+
+    fn attach_xdp_in_netns_file(&mut self, iface: &str, netns: File) -> Result<()> {
+        net::with_netns(&netns, || self.attach_xdp(iface))?;
+        // detaching has to happen in the same namespace
+        if let Some(Attachment::XDP { netns: ns, .. }) = self.attachments.last_mut() {
+            *ns = Some(netns);
+        }
+
+        Ok(())
     }
 
     pub fn attach_socketfilter(&mut self, iface: &str) -> Result<RawFd> {
@@ -335,16 +684,209 @@ impl Program {
 
         match unsafe { bpf_sys::bpf_attach_socket(sfd, self.fd.ok_or(LoadError::BPF)?) } {
             0 => {
-                self.pfd = Some(sfd);
+                self.attachments.push(Attachment::SocketFilter(sfd));
                 Ok(sfd)
             }
             _ => Err(LoadError::IO(io::Error::last_os_error())),
         }
     }
+
+    /// Runs the loaded program `repeat` times on `data` using
+    /// `BPF_PROG_TEST_RUN`.
+    ///
+    /// This is supported by XDP and socket filter programs, and is useful
+    /// to test packet processing logic without attaching to an interface.
+    pub fn test_run(&self, data: &[u8], repeat: u32) -> Result<TestRun> {
+        let fd = self.fd.ok_or(LoadError::BPF)?;
+        test_run::test_run(fd, data, repeat)
+    }
+
+    /// Detaches the program from everything it's been attached to.
+    ///
+    /// All the attachments are removed even if some of them fail, in which
+    /// case the first error is returned.
+    pub fn detach(&mut self) -> Result<()> {
+        let mut ret = Ok(());
+        for attachment in self.attachments.drain(..) {
+            let res = attachment.detach();
+            if ret.is_ok() {
+                ret = res;
+            }
+        }
+
+        ret
+    }
+
+    /// Detaches the program and unloads it from the kernel.
+    pub fn close(&mut self) -> Result<()> {
+        let ret = self.detach();
+        if let Some(fd) = self.fd.take() {
+            unsafe { libc::close(fd) };
+        }
+
+        ret
+    }
+}
+
+impl Drop for Program {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
+impl Attachment {
+    fn detach(self) -> Result<()> {
+        use crate::Attachment::*;
+        let res = match self {
+            Probe { pfd, ev_name } => {
+                let ev_name = CString::new(ev_name)?;
+                unsafe {
+                    bpf_sys::bpf_close_perf_event_fd(pfd);
+                    bpf_sys::bpf_detach_kprobe(ev_name.as_ptr())
+                }
+            }
+            Uprobe { pfd, ev_name } => {
+                let ev_name = CString::new(ev_name)?;
+                unsafe {
+                    bpf_sys::bpf_close_perf_event_fd(pfd);
+                    bpf_sys::bpf_detach_uprobe(ev_name.as_ptr())
+                }
+            }
+            KprobeEvent(event) => {
+                event.detach()?;
+                0
+            }
+            Tracepoint(pfd) => unsafe { bpf_sys::bpf_close_perf_event_fd(pfd) },
+            XDP {
+                iface,
+                netns,
+                flags,
+            } => {
+                // detaching has to use the same mode as attaching
+                let flags = flags & xdp::XDP_FLAGS_MODES;
+                let detach = || xdp::attach(&iface, -1, flags);
+                match netns {
+                    Some(netns) => net::with_netns(&netns, detach)?,
+                    None => detach()?,
+                }
+                0
+            }
+            SocketFilter(sfd) => unsafe { libc::close(sfd) },
+            Custom {
+                prog_fd,
+                target,
+                detach,
+            } => {
+                if let Some(detach) = detach {
+                    detach(prog_fd, &target)?;
+                }
+                0
+            }
+        };
+
+        if res < 0 {
+            Err(LoadError::BPF)
+        } else {
+            Ok(())
+        }
+    }
 }
 
 impl Module {
+    /// Returns the map loaded from the `maps/name` section.
+    ///
+    /// Maps are matched by section name, so lookups are not affected by the
+    /// order in which the maps are declared in the probe source.
+    pub fn map(&self, name: &str) -> Option<&Map> {
+        self.maps.iter().find(|m| m.name == name)
+    }
+
+    /// Returns a mutable reference to the map loaded from the `maps/name`
+    /// section.
+    pub fn map_mut(&mut self, name: &str) -> Option<&mut Map> {
+        self.maps.iter_mut().find(|m| m.name == name)
+    }
+
+    /// Returns the program with the given name.
+    pub fn program(&self, name: &str) -> Option<&Program> {
+        self.programs.iter().find(|p| p.name == name)
+    }
+
+    /// Returns a mutable reference to the program with the given name.
+    pub fn program_mut(&mut self, name: &str) -> Option<&mut Program> {
+        self.programs.iter_mut().find(|p| p.name == name)
+    }
+
+    /// Iterates over the programs of the given kind, as detected from their
+    /// section names.
+    pub fn programs_of_type(&self, kind: ProgramKind) -> impl Iterator<Item = &Program> {
+        self.programs.iter().filter(move |p| p.kind == kind)
+    }
+
+    /// Same as `programs_of_type`, returning mutable references, eg. to attach
+    /// all the programs of a kind.
+    pub fn programs_of_type_mut(
+        &mut self,
+        kind: ProgramKind,
+    ) -> impl Iterator<Item = &mut Program> {
+        self.programs.iter_mut().filter(move |p| p.kind == kind)
+    }
+
+    /// Overrides the maximum number of entries of the map `name`.
+    ///
+    /// The map is recreated with the new size and the programs that
+    /// reference it are updated, so this must be called before any program
+    /// is loaded.
+    pub fn set_map_max_entries(&mut self, name: &str, max_entries: u32) -> Result<()> {
+        if let Some(prog) = self.programs.iter().find(|p| p.is_loaded()) {
+            return Err(LoadError::ProgramLoaded(prog.name.clone()));
+        }
+        let map = self
+            .maps
+            .iter_mut()
+            .find(|m| m.name == name)
+            .ok_or_else(|| LoadError::MapNotFound(name.to_string()))?;
+
+        let mut config = map.config;
+        config.max_entries = max_entries;
+        let new_map = Map::create(name, config, map.numa_node, map.value_btf)?;
+        for prog in self.programs.iter_mut() {
+            for insn in prog.code.iter_mut() {
+                if insn.src_reg() == bpf_sys::BPF_PSEUDO_MAP_FD as u8 && insn.imm == map.fd {
+                    insn.imm = new_map.fd;
+                }
+            }
+        }
+        // drop closes the old map
+        *map = new_map;
+
+        Ok(())
+    }
+
+    /// Detaches and unloads all the programs, then closes all the maps.
+    ///
+    /// This is done automatically when the module is dropped.
+    pub fn close(&mut self) -> Result<()> {
+        let mut ret = Ok(());
+        for prog in self.programs.iter_mut() {
+            let res = prog.close();
+            if ret.is_ok() {
+                ret = res;
+            }
+        }
+        for map in self.maps.iter_mut() {
+            map.close();
+        }
+
+        ret
+    }
+
     pub fn parse(bytes: &[u8]) -> Result<Module> {
+        Module::parse_with_options(bytes, &LoadOptions::default())
+    }
+
+    /// Same as `parse`, with the given `options`.
+    pub fn parse_with_options(bytes: &[u8], options: &LoadOptions) -> Result<Module> {
         let object = Elf::parse(&bytes[..])?;
         let symtab = object.syms.to_vec();
         let shdr_relocs = &object.shdr_relocs;
@@ -355,6 +897,11 @@ impl Module {
 
         let mut license = String::new();
         let mut version = 0u32;
+        let mut tail_calls = Vec::new();
+        let mut sections = HashMap::new();
+        let mut btf = None;
+        let mut btf_ext = None;
+        let mut text = None;
 
         for (shndx, shdr) in object.section_headers.iter().enumerate() {
             let (kind, name) = get_split_section_name(&object, &shdr, shndx)?;
@@ -368,38 +915,188 @@ impl Module {
                 (hdr::SHT_PROGBITS, Some("license"), _) => {
                     license = zero::read_str(content).to_string()
                 }
+                (hdr::SHT_PROGBITS, Some("tail_calls"), None) => {
+                    tail_calls = tail_call::parse_tail_calls(content)?
+                }
                 (hdr::SHT_PROGBITS, Some("maps"), Some(name)) => {
                     // Maps are immediately bcc_create_map'd
-                    maps.insert(shndx, Map::load(name, &content)?);
+                    let config: &bpf_map_def = zero::read(content);
+                    let value_btf = ValueBtf::parse(content);
+                    let map = Map::create(name, *config, options.map_numa_node(name), value_btf)?;
+                    maps.insert(shndx, map);
                 }
-                (hdr::SHT_PROGBITS, Some(kind @ "kprobe"), Some(name))
-                | (hdr::SHT_PROGBITS, Some(kind @ "kretprobe"), Some(name))
-                | (hdr::SHT_PROGBITS, Some(kind @ "xdp"), Some(name))
-                | (hdr::SHT_PROGBITS, Some(kind @ "socketfilter"), Some(name)) => {
+                (hdr::SHT_PROGBITS, Some(kind), name)
+                    if ProgramKind::from_section(kind).is_ok() =>
+                {
+                    // .BTF.ext records reference programs by section name
+                    let section = match name {
+                        Some(name) => format!("{}/{}", kind, name),
+                        None => kind.to_string(),
+                    };
+                    let name = name.unwrap_or(kind);
                     programs.insert(shndx, Program::new(kind, name, &content)?);
+                    sections.insert(shndx, section);
                 }
+                (hdr::SHT_PROGBITS, Some(".text"), None) if !content.is_empty() => {
+                    text = Some((shndx, zero::read_array::<bpf_insn>(content).to_vec()))
+                }
+                (hdr::SHT_PROGBITS, Some(".BTF"), None) => btf = Some(content),
+                (hdr::SHT_PROGBITS, Some(".BTF.ext"), None) => btf_ext = Some(content),
                 _ => {}
             }
         }
 
         // Rewrite programs with relocation data
+        let text_shndx = text.as_ref().map(|(shndx, _)| *shndx);
+        let is_text_sym = |rel: &Rel| Some(symtab[rel.sym].st_shndx) == text_shndx;
         for rel in rels.iter() {
-            if programs.contains_key(&rel.target) {
+            if programs.contains_key(&rel.target) && !is_text_sym(rel) {
                 rel.apply(&mut programs, &maps, &symtab)?;
             }
         }
 
+        // Append .text to the programs referencing its functions, then
+        // relocate the appended copies
+        let mut text_starts = HashMap::new();
+        if let Some((text_shndx, text)) = text.as_ref() {
+            for rel in rels.iter() {
+                if let (Some(prog), true) = (programs.get_mut(&rel.target), is_text_sym(rel)) {
+                    let text_start = *text_starts
+                        .entry(rel.target)
+                        .or_insert_with(|| prog.append_text(text));
+                    let sym = &symtab[rel.sym];
+                    subprog::relocate(&mut prog.code, rel.insn_idx(), sym, text_start)?;
+                }
+            }
+            for rel in rels.iter().filter(|rel| rel.target == *text_shndx) {
+                for (shndx, text_start) in text_starts.iter() {
+                    let prog = programs.get_mut(shndx).ok_or(LoadError::Reloc)?;
+                    let insn_idx = text_start + rel.insn_idx();
+                    if is_text_sym(rel) {
+                        let sym = &symtab[rel.sym];
+                        subprog::relocate(&mut prog.code, insn_idx, sym, *text_start)?;
+                    } else {
+                        rel.apply_map(&mut prog.code, insn_idx, &maps, &symtab)?;
+                    }
+                }
+            }
+        }
+
+        if let (Some(btf), Some(btf_ext)) = (btf, btf_ext) {
+            #[cfg(feature = "core")]
+            apply_core_relocations(
+                &mut programs,
+                &sections,
+                &text_starts,
+                btf,
+                btf_ext,
+                options.target_btf(),
+            )?;
+            add_line_info(&mut programs, &sections, &text_starts, btf, btf_ext)?;
+        }
+
         let programs = programs.drain().map(|(_, v)| v).collect();
         let maps = maps.drain().map(|(_, v)| v).collect();
-        Ok(Module {
+        let module = Module {
             programs,
             maps,
             license,
             version,
-        })
+            tail_calls,
+            poller: Default::default(),
+        };
+        #[cfg(feature = "log")]
+        module.warn_perf_array_sizes();
+
+        Ok(module)
     }
 }
 
+#[cfg(feature = "core")]
+fn apply_core_relocations(
+    programs: &mut HashMap<usize, Program>,
+    sections: &HashMap<usize, String>,
+    text_starts: &HashMap<usize, usize>,
+    btf: &[u8],
+    btf_ext: &[u8],
+    target_btf: Option<&Path>,
+) -> Result<()> {
+    // only the relocations of the loaded code need the target BTF, so that
+    // objects without any load on kernels that don't expose their BTF
+    let mut relocs = crate::btf::parse_core_relocations(btf, btf_ext)?;
+    relocs.retain(|r| {
+        sections.values().any(|section| &r.section == section)
+            || (r.section == ".text" && !text_starts.is_empty())
+    });
+    if relocs.is_empty() {
+        return Ok(());
+    }
+
+    let local = crate::btf::Btf::parse(btf)?;
+    let target = match target_btf {
+        Some(path) => crate::btf::Btf::from_file(path)?,
+        None => crate::btf::Btf::from_kernel().map_err(|e| {
+            LoadError::BTF(format!(
+                "{} CO-RE relocations need the kernel BTF, see `LoadOptions::with_target_btf`: {}",
+                relocs.len(),
+                e
+            ))
+        })?,
+    };
+    for (shndx, section) in sections.iter() {
+        let prog = programs.get_mut(shndx).ok_or(LoadError::Reloc)?;
+        for reloc in relocs.iter().filter(|r| &r.section == section) {
+            reloc.apply(&mut prog.code, &local, &target)?;
+        }
+        if let Some(text_start) = text_starts.get(shndx) {
+            let text_off = (text_start * mem::size_of::<bpf_insn>()) as u32;
+            for reloc in relocs.iter().filter(|r| r.section == ".text") {
+                let mut reloc = reloc.clone();
+                reloc.insn_off += text_off;
+                reloc.apply(&mut prog.code, &local, &target)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Attaches the BTF func_info and line_info of the programs built with debug
+// info, which `Program::load` passes to the kernel.
+fn add_line_info(
+    programs: &mut HashMap<usize, Program>,
+    sections: &HashMap<usize, String>,
+    text_starts: &HashMap<usize, usize>,
+    btf: &[u8],
+    btf_ext: &[u8],
+) -> Result<()> {
+    let mut infos = crate::btf::parse_func_and_line_info(btf, btf_ext)?;
+    let text_infos = infos.remove(".text");
+    for (shndx, section) in sections.iter() {
+        let prog = programs.get_mut(shndx).ok_or(LoadError::Reloc)?;
+        if let Some((mut func_info, mut line_info)) = infos.remove(section) {
+            // the kernel only accepts line info along with the func info
+            if func_info.records.is_empty() || line_info.records.is_empty() {
+                continue;
+            }
+            // the kernel expects the func_info of every appended function
+            if let (Some(text_start), Some((text_func, text_line))) =
+                (text_starts.get(shndx), text_infos.as_ref())
+            {
+                func_info.append(text_func, *text_start as u32)?;
+                line_info.append(text_line, *text_start as u32)?;
+            }
+            prog.btf = Some(ProgramBtf {
+                btf: btf.to_vec(),
+                func_info,
+                line_info,
+            });
+        }
+    }
+
+    Ok(())
+}
+
 #[inline]
 fn get_split_section_name<'o>(
     object: &'o Elf<'_>,
@@ -431,13 +1128,29 @@ impl Rel {
         symtab: &[Sym],
     ) -> Result<()> {
         let prog = programs.get_mut(&self.target).ok_or(LoadError::Reloc)?;
+        self.apply_map(&mut prog.code, self.insn_idx(), maps, symtab)
+    }
+
+    fn insn_idx(&self) -> usize {
+        (self.offset / std::mem::size_of::<bpf_insn>() as u64) as usize
+    }
+
+    // Patches the map reference at `insn_idx` of `code`, which differs from
+    // the relocation offset for the copies of `.text` appended to programs.
+    fn apply_map(
+        &self,
+        code: &mut [bpf_insn],
+        insn_idx: usize,
+        maps: &HashMap<usize, Map>,
+        symtab: &[Sym],
+    ) -> Result<()> {
         let map = maps
             .get(&symtab[self.sym].st_shndx)
             .ok_or(LoadError::Reloc)?;
-        let insn_idx = (self.offset / std::mem::size_of::<bpf_insn>() as u64) as usize;
+        let insn = code.get_mut(insn_idx).ok_or(LoadError::Reloc)?;
 
-        prog.code[insn_idx].set_src_reg(bpf_sys::BPF_PSEUDO_MAP_FD as u8);
-        prog.code[insn_idx].imm = map.fd;
+        insn.set_src_reg(bpf_sys::BPF_PSEUDO_MAP_FD as u8);
+        insn.imm = map.fd;
 
         Ok(())
     }
@@ -446,27 +1159,105 @@ impl Rel {
 impl Map {
     pub fn load(name: &str, code: &[u8]) -> Result<Map> {
         let config: &bpf_map_def = zero::read(code);
-        let cname = CString::new(name.to_owned())?;
-        let fd = unsafe {
-            bpf_sys::bcc_create_map(
-                config.type_,
-                cname.as_ptr(),
-                config.key_size as i32,
-                config.value_size as i32,
-                config.max_entries as i32,
-                config.map_flags as i32
-            )
+        Map::create(name, *config, None, ValueBtf::parse(code))
+    }
+
+    fn create(
+        name: &str,
+        config: bpf_map_def,
+        numa_node: Option<u32>,
+        value_btf: Option<ValueBtf>,
+    ) -> Result<Map> {
+        let fd = if local_storage::is_local_storage(config.type_) {
+            if numa_node.is_some() {
+                return Err(LoadError::NotSupported(format!(
+                    "NUMA node of local storage map `{}'",
+                    name
+                )));
+            }
+            local_storage::create(name, &config)?
+        } else if let Some(fields) = value_btf {
+            crate::value_btf::create(name, &config, &fields, numa_node)?
+        } else if let Some(node) = numa_node {
+            numa::create(name, &config, node)?
+        } else {
+            let cname = CString::new(name.to_owned())?;
+            let fd = unsafe {
+                bpf_sys::bcc_create_map(
+                    config.type_,
+                    cname.as_ptr(),
+                    config.key_size as i32,
+                    config.value_size as i32,
+                    config.max_entries as i32,
+                    config.map_flags as i32
+                )
+            };
+            if fd < 0 {
+                return Err(LoadError::Map);
+            }
+            fd
         };
-        if fd < 0 {
-            return Err(LoadError::Map);
-        }
 
         Ok(Map {
             name: name.to_string(),
             kind: config.type_,
             fd,
+            config,
+            numa_node,
+            value_btf,
         })
     }
+
+    /// Returns the `BPF_MAP_TYPE_*` of the map.
+    pub fn map_type(&self) -> u32 {
+        self.config.type_
+    }
+
+    /// Returns the size of the keys of the map, in bytes.
+    pub fn key_size(&self) -> u32 {
+        self.config.key_size
+    }
+
+    /// Returns the size of the values of the map, in bytes.
+    pub fn value_size(&self) -> u32 {
+        self.config.value_size
+    }
+
+    /// Returns the maximum number of entries of the map.
+    pub fn max_entries(&self) -> u32 {
+        self.config.max_entries
+    }
+
+    /// Checks that `K` and `V` have the same size as the keys and values of
+    /// the map.
+    ///
+    /// Call it at startup to catch probe and user space types drifting apart,
+    /// before reading values through them.
+    pub fn assert_layout<K, V>(&self) -> Result<()> {
+        if mem::size_of::<K>() != self.config.key_size as usize
+            || mem::size_of::<V>() != self.config.value_size as usize
+        {
+            return Err(LoadError::InvalidMap(format!(
+                "map `{}' has {} bytes keys and {} bytes values",
+                self.name, self.config.key_size, self.config.value_size
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Checks that `K` has the same size as the keys of the map.
+    pub fn assert_key_layout<K>(&self) -> Result<()> {
+        if mem::size_of::<K>() != self.config.key_size as usize {
+            return Err(LoadError::InvalidMap(format!(
+                "map `{}' has {} bytes keys",
+                self.name, self.config.key_size
+            )));
+        }
+
+        Ok(())
+    }
+
     pub fn set(&self, key: VoidPtr, value: VoidPtr) {
         unsafe {
             bpf_sys::bpf_update_elem(self.fd, key, value, 0);
@@ -484,6 +1275,104 @@ impl Map {
             bpf_sys::bpf_delete_elem(self.fd, key);
         }
     }
+
+    /// Makes the map read-only from user space with `BPF_MAP_FREEZE`.
+    ///
+    /// Programs can still read and, unless the map was created with
+    /// `BPF_F_RDONLY_PROG`, update the map. The verifier treats frozen
+    /// read-only arrays as constants. Requires Linux 5.2.
+    pub fn freeze(&self) -> Result<()> {
+        const BPF_MAP_FREEZE: u32 = 22;
+        #[repr(C)]
+        struct FreezeAttr {
+            map_fd: u32,
+        }
+
+        let mut attr = FreezeAttr {
+            map_fd: self.fd as u32,
+        };
+        if let Err(e) = unsafe { sys::bpf(BPF_MAP_FREEZE, &mut attr) } {
+            // EINVAL is also the error for unknown commands, so only kernels
+            // that reject the command itself lack support. Kernels knowing
+            // it fail on an invalid map fd with EBADF.
+            let mut probe = FreezeAttr {
+                map_fd: std::u32::MAX,
+            };
+            let unknown = e.raw_os_error() == Some(libc::EINVAL)
+                && unsafe { sys::bpf(BPF_MAP_FREEZE, &mut probe) }
+                    .err()
+                    .and_then(|e| e.raw_os_error())
+                    == Some(libc::EINVAL);
+            if unknown {
+                return Err(LoadError::NotSupported(
+                    "BPF_MAP_FREEZE requires Linux 5.2".to_string(),
+                ));
+            }
+            return Err(LoadError::IO(e));
+        }
+
+        Ok(())
+    }
+
+    /// Maps the values of an array map created with `BPF_F_MMAPABLE` into
+    /// memory, so they can be read without syscalls.
+    pub fn mmap(&self) -> Result<MmapView> {
+        MmapView::new(self)
+    }
+
+    /// Sets the entry `key` of a `BPF_MAP_TYPE_DEVMAP_HASH` or
+    /// `BPF_MAP_TYPE_DEVMAP` map to the network interface `ifindex`.
+    ///
+    /// XDP programs can then redirect packets to the interface with
+    /// `DevMapHash::redirect(key)`. Hash maps are usually keyed by `ifindex`
+    /// itself.
+    pub fn set_ifindex(&self, mut key: u32, mut ifindex: u32) -> Result<()> {
+        if self.kind != bpf_sys::bpf_map_type_BPF_MAP_TYPE_DEVMAP_HASH
+            && self.kind != bpf_sys::bpf_map_type_BPF_MAP_TYPE_DEVMAP
+        {
+            return Err(LoadError::InvalidMap(format!(
+                "map `{}' is not a device map (type {})",
+                self.name, self.kind
+            )));
+        }
+        let ret = unsafe {
+            bpf_sys::bpf_update_elem(
+                self.fd,
+                &mut key as *mut u32 as VoidPtr,
+                &mut ifindex as *mut u32 as VoidPtr,
+                0,
+            )
+        };
+        if ret < 0 {
+            return Err(LoadError::IO(io::Error::last_os_error()));
+        }
+
+        Ok(())
+    }
+
+    /// Closes the map file descriptor.
+    ///
+    /// The kernel keeps the map alive for as long as loaded programs still
+    /// reference it.
+    pub fn close(&mut self) {
+        if self.fd >= 0 {
+            unsafe { libc::close(self.fd) };
+            self.fd = -1;
+        }
+    }
+}
+
+impl Drop for Map {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+impl Drop for Module {
+    fn drop(&mut self) {
+        // programs must be detached before the maps they use are closed
+        let _ = self.close();
+    }
 }
 #[inline]
 fn add_rel(
@@ -502,6 +1391,31 @@ fn add_rel(
     }));
 }
 
+#[inline]
+fn parse_offset(offset: &str) -> Option<u64> {
+    if offset.starts_with("0x") {
+        u64::from_str_radix(&offset[2..], 16).ok()
+    } else {
+        offset.parse().ok()
+    }
+}
+
+// The program sections the kernel allows to be sleepable. LSM and tracing
+// programs can be too, but redbpf doesn't load them.
+const SLEEPABLE_SECTIONS: &[&str] = &["uprobe", "uretprobe"];
+
+// Splits the `.s` suffix of sleepable program sections, eg. `uprobe.s`.
+#[inline]
+fn strip_sleepable(section: &str) -> (&str, bool) {
+    if section.ends_with(".s") {
+        let kind = &section[..section.len() - 2];
+        if SLEEPABLE_SECTIONS.contains(&kind) {
+            return (kind, true);
+        }
+    }
+    (section, false)
+}
+
 #[inline]
 fn get_version(bytes: &[u8]) -> u32 {
     let version = zero::read::<u32>(bytes);
@@ -518,3 +1432,456 @@ fn data<'d>(bytes: &'d [u8], shdr: &SectionHeader) -> &'d [u8] {
 
     &bytes[offset..end]
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // mov r0, -1; exit
+    const ACCEPT_ALL: [u8; 16] = [
+        0xb7, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, 0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00,
+    ];
+
+    struct TestSection {
+        name: &'static str,
+        kind: u32,
+        data: Vec<u8>,
+        link: u32,
+        info: u32,
+        entsize: u64,
+    }
+
+    impl TestSection {
+        fn progbits(name: &'static str, data: &[u8]) -> TestSection {
+            TestSection {
+                name,
+                kind: hdr::SHT_PROGBITS,
+                data: data.to_vec(),
+                link: 0,
+                info: 0,
+                entsize: 0,
+            }
+        }
+    }
+
+    fn push_section(shdrs: &mut Vec<u8>, name: u32, section: &TestSection, offset: usize) {
+        shdrs.extend_from_slice(&name.to_le_bytes());
+        shdrs.extend_from_slice(&section.kind.to_le_bytes());
+        shdrs.extend_from_slice(&[0u8; 16]); // sh_flags, sh_addr
+        shdrs.extend_from_slice(&(offset as u64).to_le_bytes());
+        shdrs.extend_from_slice(&(section.data.len() as u64).to_le_bytes());
+        shdrs.extend_from_slice(&section.link.to_le_bytes());
+        shdrs.extend_from_slice(&section.info.to_le_bytes());
+        shdrs.extend_from_slice(&8u64.to_le_bytes());
+        shdrs.extend_from_slice(&section.entsize.to_le_bytes());
+    }
+
+    /// Builds a relocatable BPF object. Section 0 is the null section and
+    /// section 1 the section header string table, so `sections` start at
+    /// index 2.
+    fn build_elf(sections: &[TestSection]) -> Vec<u8> {
+        let mut strtab = b"\0.shstrtab\0".to_vec();
+        let mut body = Vec::new();
+        let mut shdrs = Vec::new();
+        push_section(
+            &mut shdrs,
+            0,
+            &TestSection {
+                name: "",
+                kind: hdr::SHT_NULL,
+                data: vec![],
+                link: 0,
+                info: 0,
+                entsize: 0,
+            },
+            0,
+        );
+
+        let mut entries = Vec::new();
+        for section in sections {
+            let name = strtab.len() as u32;
+            strtab.extend_from_slice(section.name.as_bytes());
+            strtab.push(0);
+            let offset = 64 + body.len();
+            body.extend_from_slice(&section.data);
+            while body.len() % 8 != 0 {
+                body.push(0);
+            }
+            entries.push((name, offset));
+        }
+        let strtab_off = 64 + body.len();
+        body.extend_from_slice(&strtab);
+        while body.len() % 8 != 0 {
+            body.push(0);
+        }
+        let shoff = 64 + body.len();
+
+        let mut shstrtab = TestSection::progbits(".shstrtab", &strtab);
+        shstrtab.kind = hdr::SHT_STRTAB;
+        push_section(&mut shdrs, 1, &shstrtab, strtab_off);
+        for (section, (name, offset)) in sections.iter().zip(entries) {
+            push_section(&mut shdrs, name, section, offset);
+        }
+
+        let mut elf = vec![0x7f, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        elf.extend_from_slice(&1u16.to_le_bytes()); // ET_REL
+        elf.extend_from_slice(&247u16.to_le_bytes()); // EM_BPF
+        elf.extend_from_slice(&1u32.to_le_bytes());
+        elf.extend_from_slice(&0u64.to_le_bytes()); // e_entry
+        elf.extend_from_slice(&0u64.to_le_bytes()); // e_phoff
+        elf.extend_from_slice(&(shoff as u64).to_le_bytes());
+        elf.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+        elf.extend_from_slice(&64u16.to_le_bytes()); // e_ehsize
+        elf.extend_from_slice(&0u16.to_le_bytes()); // e_phentsize
+        elf.extend_from_slice(&0u16.to_le_bytes()); // e_phnum
+        elf.extend_from_slice(&64u16.to_le_bytes()); // e_shentsize
+        elf.extend_from_slice(&(sections.len() as u16 + 2).to_le_bytes()); // e_shnum
+        elf.extend_from_slice(&1u16.to_le_bytes()); // e_shstrndx
+        elf.extend_from_slice(&body);
+        elf.extend_from_slice(&shdrs);
+
+        elf
+    }
+
+    /// Builds a minimal relocatable BPF object with a single socket filter
+    /// program and no maps.
+    fn socketfilter_elf() -> Vec<u8> {
+        build_elf(&[
+            TestSection::progbits("socketfilter/accept_all", &ACCEPT_ALL),
+            TestSection::progbits("license", b"GPL\0"),
+        ])
+    }
+
+    /// Builds an object with two hash maps, declared in the given order, and
+    /// a socket filter that loads the `first` map then the `second` map.
+    fn maps_elf(order: [&'static str; 2]) -> Vec<u8> {
+        // ld_imm64 r1, map; ld_imm64 r2, map; mov r0, -1; exit
+        let mut code = vec![0x18, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        code.extend_from_slice(&[0x18, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        code.extend_from_slice(&ACCEPT_ALL);
+
+        let mut def = Vec::new();
+        for field in &[1u32, 4, 4, 16, 0] {
+            def.extend_from_slice(&field.to_le_bytes());
+        }
+        let map_shndx = |name: &str| order.iter().position(|n| *n == name).unwrap() as u16 + 3;
+
+        // null symbol, then one symbol per map
+        let mut symtab = vec![0u8; 24];
+        for name in &["first", "second"] {
+            symtab.extend_from_slice(&0u32.to_le_bytes()); // st_name
+            symtab.extend_from_slice(&[0x11, 0]); // STB_GLOBAL | STT_OBJECT
+            symtab.extend_from_slice(&map_shndx(name).to_le_bytes());
+            symtab.extend_from_slice(&[0u8; 16]); // st_value, st_size
+        }
+        let mut rels = Vec::new();
+        for (offset, sym) in &[(0u64, 1u64), (16, 2)] {
+            rels.extend_from_slice(&offset.to_le_bytes());
+            rels.extend_from_slice(&(sym << 32 | 1).to_le_bytes()); // R_BPF_64_64
+        }
+
+        let map_section = |name| match name {
+            "first" => TestSection::progbits("maps/first", &def),
+            _ => TestSection::progbits("maps/second", &def),
+        };
+        build_elf(&[
+            TestSection::progbits("socketfilter/load_maps", &code), // 2
+            map_section(order[0]),                                  // 3
+            map_section(order[1]),                                  // 4
+            TestSection {
+                name: ".symtab", // 5
+                kind: hdr::SHT_SYMTAB,
+                data: symtab,
+                link: 1,
+                info: 1,
+                entsize: 24,
+            },
+            TestSection {
+                name: ".relsocketfilter/load_maps",
+                kind: hdr::SHT_REL,
+                data: rels,
+                link: 5,
+                info: 2,
+                entsize: 16,
+            },
+            TestSection::progbits("license", b"GPL\0"),
+        ])
+    }
+
+    fn subprog_elf() -> Vec<u8> {
+        // ld_imm64 r2, callback; call helper; mov r0, -1; exit
+        let mut code = vec![0x18, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        code.extend_from_slice(&[0x85, 0x10, 0, 0, 0xff, 0xff, 0xff, 0xff]);
+        code.extend_from_slice(&ACCEPT_ALL);
+        // helper at 0 and callback at 16, both returning -1
+        let text = [ACCEPT_ALL, ACCEPT_ALL].concat();
+
+        // null symbol, then callback and helper
+        let mut symtab = vec![0u8; 24];
+        for st_value in &[16u64, 0] {
+            symtab.extend_from_slice(&0u32.to_le_bytes()); // st_name
+            symtab.extend_from_slice(&[0x12, 0]); // STB_GLOBAL | STT_FUNC
+            symtab.extend_from_slice(&3u16.to_le_bytes()); // .text
+            symtab.extend_from_slice(&st_value.to_le_bytes());
+            symtab.extend_from_slice(&0u64.to_le_bytes()); // st_size
+        }
+        let mut rels = Vec::new();
+        for (offset, sym, kind) in &[(0u64, 1u64, 1u64), (16, 2, 10)] {
+            rels.extend_from_slice(&offset.to_le_bytes());
+            rels.extend_from_slice(&(sym << 32 | kind).to_le_bytes()); // R_BPF_64_64, R_BPF_64_32
+        }
+
+        build_elf(&[
+            TestSection::progbits("socketfilter/timer", &code), // 2
+            TestSection::progbits(".text", &text),              // 3
+            TestSection {
+                name: ".symtab", // 4
+                kind: hdr::SHT_SYMTAB,
+                data: symtab,
+                link: 1,
+                info: 1,
+                entsize: 24,
+            },
+            TestSection {
+                name: ".relsocketfilter/timer",
+                kind: hdr::SHT_REL,
+                data: rels,
+                link: 4,
+                info: 2,
+                entsize: 16,
+            },
+            TestSection::progbits("license", b"GPL\0"),
+        ])
+    }
+
+    #[test]
+    fn test_parse_subprograms() {
+        let module = Module::parse(&subprog_elf()).unwrap();
+        let prog = module.program("timer").unwrap();
+        // .text is appended after the 5 instructions of the program
+        assert_eq!(prog.code.len(), 9);
+        assert_eq!(prog.code_bytes, 9 * 8);
+        assert_eq!(prog.code[5].imm, -1);
+        // the callback is at 5 + 2
+        assert_eq!(prog.code[0].src_reg(), 4); // BPF_PSEUDO_FUNC
+        assert_eq!(prog.code[0].imm, 6);
+        // the helper is at 5
+        assert_eq!(prog.code[2].src_reg(), 1); // BPF_PSEUDO_CALL
+        assert_eq!(prog.code[2].imm, 2);
+    }
+
+    #[test]
+    fn test_parse_offset() {
+        assert_eq!(parse_offset("16"), Some(16));
+        assert_eq!(parse_offset("0x10"), Some(16));
+        assert_eq!(parse_offset("foo"), None);
+        assert_eq!(parse_offset(""), None);
+    }
+
+    #[test]
+    fn test_attach_probe_invalid_offset() {
+        let mut prog = Program::new("kprobe", "do_fork+junk", &ACCEPT_ALL).unwrap();
+        match prog.attach_probe() {
+            Err(LoadError::ProbeOffsetParse(name)) => assert_eq!(name, "do_fork+junk"),
+            _ => panic!("expected a probe offset parse error"),
+        }
+    }
+
+    #[test]
+    fn test_program_kind_from_section() {
+        assert_eq!(ProgramKind::from_section("xdp").unwrap(), ProgramKind::XDP);
+        assert_eq!(
+            ProgramKind::from_section("tracepoint").unwrap(),
+            ProgramKind::Tracepoint
+        );
+        assert_eq!(
+            ProgramKind::from_section("classifier")
+                .unwrap()
+                .to_prog_type()
+                .unwrap(),
+            bpf_sys::bpf_prog_type_BPF_PROG_TYPE_SCHED_CLS
+        );
+        assert!(ProgramKind::from_section("maps").is_err());
+        assert!(ProgramKind::from_section(".text").is_err());
+    }
+
+    #[test]
+    fn test_parse_unnamed_section() {
+        let elf = build_elf(&[
+            TestSection::progbits("xdp", &ACCEPT_ALL),
+            TestSection::progbits("license", b"GPL\0"),
+        ]);
+        let module = Module::parse(&elf).unwrap();
+        let prog = module.program("xdp").unwrap();
+        assert_eq!(prog.kind, ProgramKind::XDP);
+        assert_eq!(prog.prog_type().unwrap(), bpf_sys::bpf_prog_type_BPF_PROG_TYPE_XDP);
+    }
+
+    // BTF with the section name as only string, and .BTF.ext with one
+    // line_info record and, if `with_func_info`, one func_info record for the
+    // section
+    fn btf_sections(section: &str, with_func_info: bool) -> (Vec<u8>, Vec<u8>) {
+        let strings = format!("\0{}\0", section);
+        let mut btf = vec![0x9f, 0xeb, 1, 0];
+        for field in &[24u32, 0, 0, 0, strings.len() as u32] {
+            btf.extend_from_slice(&field.to_le_bytes());
+        }
+        btf.extend_from_slice(strings.as_bytes());
+
+        let mut func_info = Vec::new();
+        if with_func_info {
+            for field in &[8u32, 1, 1, 0, 1] {
+                func_info.extend_from_slice(&field.to_le_bytes());
+            }
+        }
+        let mut line_info = Vec::new();
+        for field in &[16u32, 1, 1, 0, 0, 0, 0] {
+            line_info.extend_from_slice(&field.to_le_bytes());
+        }
+        let mut btf_ext = vec![0x9f, 0xeb, 1, 0];
+        let lens = [func_info.len() as u32, line_info.len() as u32];
+        for field in &[24u32, 0, lens[0], lens[0], lens[1]] {
+            btf_ext.extend_from_slice(&field.to_le_bytes());
+        }
+        btf_ext.extend_from_slice(&func_info);
+        btf_ext.extend_from_slice(&line_info);
+
+        (btf, btf_ext)
+    }
+
+    #[test]
+    fn test_line_info_unnamed_section() {
+        let (btf, btf_ext) = btf_sections("xdp", true);
+        let elf = build_elf(&[
+            TestSection::progbits("xdp", &ACCEPT_ALL),
+            TestSection::progbits("license", b"GPL\0"),
+            TestSection::progbits(".BTF", &btf),
+            TestSection::progbits(".BTF.ext", &btf_ext),
+        ]);
+        let module = Module::parse(&elf).unwrap();
+        let prog_btf = module.program("xdp").unwrap().btf.as_ref().unwrap();
+        assert_eq!(prog_btf.func_info.records.len(), 8);
+        assert_eq!(prog_btf.line_info.records.len(), 16);
+    }
+
+    #[test]
+    fn test_line_info_without_func_info() {
+        let (btf, btf_ext) = btf_sections("xdp", false);
+        let elf = build_elf(&[
+            TestSection::progbits("xdp", &ACCEPT_ALL),
+            TestSection::progbits("license", b"GPL\0"),
+            TestSection::progbits(".BTF", &btf),
+            TestSection::progbits(".BTF.ext", &btf_ext),
+        ]);
+        let module = Module::parse(&elf).unwrap();
+        assert!(module.program("xdp").unwrap().btf.is_none());
+    }
+
+    #[cfg(feature = "core")]
+    #[test]
+    fn test_no_core_relocations_without_target_btf() {
+        // .BTF.ext without CO-RE relocations doesn't need the target BTF
+        let (btf, btf_ext) = btf_sections("xdp", true);
+        let elf = build_elf(&[
+            TestSection::progbits("xdp", &ACCEPT_ALL),
+            TestSection::progbits("license", b"GPL\0"),
+            TestSection::progbits(".BTF", &btf),
+            TestSection::progbits(".BTF.ext", &btf_ext),
+        ]);
+        let mut options = LoadOptions::new();
+        options.with_target_btf("/nonexistent/vmlinux.btf");
+        assert!(Module::parse_with_options(&elf, &options).is_ok());
+    }
+
+    #[test]
+    fn test_parse_in_memory() {
+        let elf = socketfilter_elf();
+        let module = Module::parse(&elf).unwrap();
+        assert_eq!(module.license, "GPL");
+        assert_eq!(module.programs.len(), 1);
+        assert_eq!(module.programs[0].name, "accept_all");
+        assert_eq!(module.programs[0].kind, ProgramKind::SocketFilter);
+        assert_eq!(module.programs[0].code.len(), 2);
+        assert_eq!(module.programs_of_type(ProgramKind::SocketFilter).count(), 1);
+        assert_eq!(module.programs_of_type(ProgramKind::XDP).count(), 0);
+    }
+
+    #[test]
+    fn test_parse_with_target_btf() {
+        let mut options = LoadOptions::new();
+        assert_eq!(options.target_btf(), None);
+        options.with_target_btf("/tmp/vmlinux.btf");
+        assert_eq!(options.target_btf(), Some(Path::new("/tmp/vmlinux.btf")));
+        // the socket filter has no CO-RE relocations, so the BTF isn't read
+        let module = Module::parse_with_options(&socketfilter_elf(), &options).unwrap();
+        assert_eq!(module.programs.len(), 1);
+    }
+
+    #[test]
+    #[ignore] // requires CAP_SYS_ADMIN
+    fn test_load_and_attach_in_memory() {
+        let elf = socketfilter_elf();
+        let mut module = Module::parse(&elf).unwrap();
+        for prog in module.programs.iter_mut() {
+            prog.load(module.version, module.license.clone()).unwrap();
+            prog.attach_socketfilter("lo").unwrap();
+            assert!(prog.is_attached());
+        }
+    }
+
+    #[test]
+    #[ignore] // requires CAP_SYS_ADMIN
+    fn test_maps_resolved_by_name() {
+        for order in &[["first", "second"], ["second", "first"]] {
+            let elf = maps_elf(*order);
+            let module = Module::parse(&elf).unwrap();
+            let first = module.map("first").unwrap();
+            let second = module.map("second").unwrap();
+            assert_ne!(first.fd, second.fd);
+
+            let prog = module.program("load_maps").unwrap();
+            assert_eq!(prog.code[0].imm, first.fd);
+            assert_eq!(prog.code[2].imm, second.fd);
+        }
+    }
+
+    #[test]
+    fn test_assert_layout() {
+        let mut config: bpf_map_def = unsafe { mem::zeroed() };
+        config.type_ = bpf_sys::bpf_map_type_BPF_MAP_TYPE_HASH;
+        config.key_size = 4;
+        config.value_size = 8;
+        config.max_entries = 128;
+        let map = Map {
+            name: "counts".to_string(),
+            kind: config.type_,
+            fd: -1,
+            config,
+            numa_node: None,
+            value_btf: None,
+        };
+        assert_eq!(map.key_size(), 4);
+        assert_eq!(map.value_size(), 8);
+        assert_eq!(map.max_entries(), 128);
+        assert!(map.assert_layout::<u32, u64>().is_ok());
+        assert!(map.assert_layout::<u64, u64>().is_err());
+        assert!(map.assert_layout::<u32, u32>().is_err());
+    }
+
+    #[test]
+    fn test_sleepable_section() {
+        assert_eq!(ProgramKind::from_section("uprobe.s").unwrap(), ProgramKind::Uprobe);
+        let prog = Program::new("uprobe.s", "readline", &ACCEPT_ALL).unwrap();
+        assert!(prog.is_sleepable());
+        let prog = Program::new("uprobe", "readline", &ACCEPT_ALL).unwrap();
+        assert!(!prog.is_sleepable());
+        assert_eq!(
+            ProgramKind::from_section("uretprobe.s").unwrap(),
+            ProgramKind::Uretprobe
+        );
+        // only uprobes can sleep
+        assert!(ProgramKind::from_section("kprobe.s").is_err());
+        assert!(ProgramKind::from_section("xdp.s").is_err());
+    }
+}
@@ -0,0 +1,147 @@
+//! Checks for helpers that programs can only call under a GPL compatible
+//! license.
+//!
+//! The verifier rejects such calls with `cannot call GPL-restricted function
+//! from non-GPL compatible program`, which doesn't say which program or
+//! helper is at fault. Checking before loading gives a clearer error.
+use bpf_sys::bpf_insn;
+
+use crate::{LoadError, Program, Result};
+
+// Licenses accepted by the kernel's license_is_gpl_compatible().
+const GPL_COMPATIBLE: &[&str] = &[
+    "GPL",
+    "GPL v2",
+    "GPL and additional rights",
+    "Dual BSD/GPL",
+    "Dual MIT/GPL",
+    "Dual MPL/GPL",
+];
+
+// Helpers whose kernel prototype, their `bpf_*_proto` definition, has
+// `gpl_only = true`. Covers the helpers up to bpf_copy_from_user_task (191),
+// added in Linux 5.18.
+const GPL_ONLY_HELPERS: &[(i32, &str)] = &[
+    (4, "bpf_probe_read"),
+    (6, "bpf_trace_printk"),
+    (22, "bpf_perf_event_read"),
+    (25, "bpf_perf_event_output"),
+    (27, "bpf_get_stackid"),
+    (35, "bpf_get_current_task"),
+    (36, "bpf_probe_write_user"),
+    (45, "bpf_probe_read_str"),
+    (55, "bpf_perf_event_read_value"),
+    (56, "bpf_perf_prog_read_value"),
+    (58, "bpf_override_return"),
+    (67, "bpf_get_stack"),
+    (111, "bpf_skb_output"),
+    (112, "bpf_probe_read_user"),
+    (113, "bpf_probe_read_kernel"),
+    (114, "bpf_probe_read_user_str"),
+    (115, "bpf_probe_read_kernel_str"),
+    (119, "bpf_read_branch_records"),
+    (121, "bpf_xdp_output"),
+    (126, "bpf_seq_printf"),
+    (127, "bpf_seq_write"),
+    (150, "bpf_seq_printf_btf"),
+    (158, "bpf_get_current_task_btf"),
+    (165, "bpf_snprintf"),
+    (169, "bpf_timer_init"),
+    (170, "bpf_timer_set_callback"),
+    (171, "bpf_timer_start"),
+    (172, "bpf_timer_cancel"),
+    (173, "bpf_get_func_ip"),
+    (175, "bpf_task_pt_regs"),
+    (176, "bpf_get_branch_snapshot"),
+    (177, "bpf_trace_vprintk"),
+    (183, "bpf_get_func_arg"),
+    (184, "bpf_get_func_ret"),
+    (185, "bpf_get_func_arg_cnt"),
+    (191, "bpf_copy_from_user_task"),
+];
+
+// BPF_JMP | BPF_CALL
+const CALL: u8 = 0x85;
+
+/// Returns whether the kernel considers `license` GPL compatible.
+pub fn is_gpl_compatible(license: &str) -> bool {
+    let license = license.trim_end_matches('\0');
+    GPL_COMPATIBLE.contains(&license)
+}
+
+// Returns the first GPL-only helper called by `code`.
+fn gpl_only_helper(code: &[bpf_insn]) -> Option<&'static str> {
+    code.iter()
+        // src_reg is set for calls to other BPF functions
+        .filter(|insn| insn.code == CALL && insn.src_reg() == 0)
+        .filter_map(|insn| {
+            GPL_ONLY_HELPERS
+                .iter()
+                .find(|(id, _)| *id == insn.imm)
+                .map(|(_, name)| *name)
+        })
+        .next()
+}
+
+impl Program {
+    /// Checks that the program only calls GPL-only helpers, like
+    /// `bpf_probe_read` or `bpf_perf_event_output`, if `license` is GPL
+    /// compatible.
+    ///
+    /// This is done by `load`, so that using a GPL-only helper in a program
+    /// with another license fails with `LoadError::GplOnlyHelper`.
+    pub fn check_license(&self, license: &str) -> Result<()> {
+        if is_gpl_compatible(license) {
+            return Ok(());
+        }
+        match gpl_only_helper(&self.code) {
+            Some(helper) => Err(LoadError::GplOnlyHelper(
+                self.name.clone(),
+                helper,
+                license.trim_end_matches('\0').to_string(),
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // call bpf_get_current_pid_tgid; exit
+    const CALL_PID_TGID: [u8; 16] = [
+        0x85, 0x00, 0x00, 0x00, 0x0e, 0x00, 0x00, 0x00, 0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00,
+    ];
+
+    // call bpf_probe_read; exit
+    const CALL_PROBE_READ: [u8; 16] = [
+        0x85, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00,
+    ];
+
+    #[test]
+    fn test_gpl_compatible() {
+        assert!(is_gpl_compatible("GPL"));
+        assert!(is_gpl_compatible("Dual BSD/GPL\0"));
+        assert!(!is_gpl_compatible("MIT"));
+    }
+
+    #[test]
+    fn test_check_license() {
+        let prog = Program::new("kprobe", "do_fork", &CALL_PROBE_READ).unwrap();
+        assert!(prog.check_license("GPL").is_ok());
+        match prog.check_license("MIT") {
+            Err(LoadError::GplOnlyHelper(name, helper, license)) => {
+                assert_eq!(name, "do_fork");
+                assert_eq!(helper, "bpf_probe_read");
+                assert_eq!(license, "MIT");
+            }
+            _ => panic!("expected GplOnlyHelper"),
+        }
+
+        let prog = Program::new("kprobe", "do_fork", &CALL_PID_TGID).unwrap();
+        assert!(prog.check_license("MIT").is_ok());
+    }
+}
@@ -0,0 +1,183 @@
+//! Creation of local storage maps.
+//!
+//! The kernel only creates `BPF_MAP_TYPE_SK_STORAGE`, `INODE_STORAGE` and
+//! `TASK_STORAGE` maps when given the BTF ids of their key and value types.
+//! Probes compiled from Rust don't carry BTF, so a minimal BTF blob describing
+//! an `int` key and a byte array value of the right size is loaded instead.
+use std::ffi::CString;
+use std::os::unix::io::RawFd;
+
+use bpf_sys::bpf_map_def;
+
+use crate::btf::consts::{BTF_INT_SIGNED, BTF_KIND_ARRAY, BTF_KIND_INT, BTF_MAGIC};
+use crate::sys::bpf;
+use crate::{LoadError, Result};
+
+pub(crate) const BPF_MAP_CREATE: u32 = 0;
+const BPF_BTF_LOAD: u32 = 18;
+
+const BPF_MAP_TYPE_SK_STORAGE: u32 = 24;
+const BPF_MAP_TYPE_INODE_STORAGE: u32 = 28;
+const BPF_MAP_TYPE_TASK_STORAGE: u32 = 29;
+
+// ids of the types in the generated BTF, 0 being void
+const KEY_TYPE_ID: u32 = 1;
+const BYTE_TYPE_ID: u32 = 2;
+const VALUE_TYPE_ID: u32 = 3;
+
+/// Returns whether maps of type `map_type` need BTF to be created.
+pub(crate) fn is_local_storage(map_type: u32) -> bool {
+    match map_type {
+        BPF_MAP_TYPE_SK_STORAGE | BPF_MAP_TYPE_INODE_STORAGE | BPF_MAP_TYPE_TASK_STORAGE => true,
+        _ => false,
+    }
+}
+
+// Builds BTF with `int` (1), `unsigned char` (2) and `unsigned char[value_size]`
+// (3) types.
+fn storage_btf(value_size: u32) -> Vec<u8> {
+    let strings = b"\0int\0unsigned char\0";
+    let mut types = Vec::new();
+    // name_off, info, size, then the INT encoding
+    push_u32s(
+        &mut types,
+        &[1, BTF_KIND_INT << 24, 4, BTF_INT_SIGNED << 24 | 32],
+    );
+    push_u32s(&mut types, &[5, BTF_KIND_INT << 24, 1, 8]);
+    // name_off, info, unused, then the element type, index type and length
+    push_u32s(
+        &mut types,
+        &[
+            0,
+            BTF_KIND_ARRAY << 24,
+            0,
+            BYTE_TYPE_ID,
+            KEY_TYPE_ID,
+            value_size,
+        ],
+    );
+
+    btf_blob(&types, strings)
+}
+
+pub(crate) fn push_u32s(buf: &mut Vec<u8>, values: &[u32]) {
+    for v in values {
+        buf.extend_from_slice(&v.to_ne_bytes());
+    }
+}
+
+// Prepends the BTF header to the `types` and `strings` sections.
+pub(crate) fn btf_blob(types: &[u8], strings: &[u8]) -> Vec<u8> {
+    let hdr_len = 24u32;
+    let mut btf = Vec::new();
+    btf.extend_from_slice(&BTF_MAGIC.to_ne_bytes());
+    btf.push(1); // version
+    btf.push(0); // flags
+    btf.extend_from_slice(&hdr_len.to_ne_bytes());
+    btf.extend_from_slice(&0u32.to_ne_bytes()); // type_off
+    btf.extend_from_slice(&(types.len() as u32).to_ne_bytes());
+    btf.extend_from_slice(&(types.len() as u32).to_ne_bytes()); // str_off
+    btf.extend_from_slice(&(strings.len() as u32).to_ne_bytes());
+    btf.extend_from_slice(types);
+    btf.extend_from_slice(strings);
+    btf
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct BtfLoadAttr {
+    btf: u64,
+    btf_log_buf: u64,
+    btf_size: u32,
+    btf_log_size: u32,
+    btf_log_level: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+pub(crate) struct MapCreateAttr {
+    pub map_type: u32,
+    pub key_size: u32,
+    pub value_size: u32,
+    pub max_entries: u32,
+    pub map_flags: u32,
+    pub inner_map_fd: u32,
+    pub numa_node: u32,
+    pub map_name: [u8; 16],
+    pub map_ifindex: u32,
+    pub btf_fd: u32,
+    pub btf_key_type_id: u32,
+    pub btf_value_type_id: u32,
+}
+
+/// Loads the BTF blob `btf`, returning its fd.
+pub(crate) fn load_btf(btf: &[u8]) -> Result<RawFd> {
+    let mut attr = BtfLoadAttr {
+        btf: btf.as_ptr() as u64,
+        btf_size: btf.len() as u32,
+        ..Default::default()
+    };
+
+    unsafe { bpf(BPF_BTF_LOAD, &mut attr) }.map_err(LoadError::IO)
+}
+
+/// Returns `name` truncated to the size of `MapCreateAttr::map_name`.
+pub(crate) fn map_name(name: &str) -> Result<[u8; 16]> {
+    let cname = CString::new(name)?;
+    let mut map_name = [0u8; 16];
+    for (dst, src) in map_name[..15].iter_mut().zip(cname.as_bytes()) {
+        *dst = *src;
+    }
+
+    Ok(map_name)
+}
+
+/// Creates a map described by the BTF `btf_fd`, closing it.
+pub(crate) fn create_with_btf(mut attr: MapCreateAttr, btf_fd: RawFd) -> Result<RawFd> {
+    attr.btf_fd = btf_fd as u32;
+    let fd = unsafe { bpf(BPF_MAP_CREATE, &mut attr) };
+    // the map keeps a reference to the BTF
+    unsafe { libc::close(btf_fd) };
+
+    fd.map_err(|_| LoadError::Map)
+}
+
+/// Creates a local storage map, loading the BTF it requires.
+pub(crate) fn create(name: &str, config: &bpf_map_def) -> Result<RawFd> {
+    let btf_fd = load_btf(&storage_btf(config.value_size))?;
+    let attr = MapCreateAttr {
+        map_type: config.type_,
+        key_size: config.key_size,
+        value_size: config.value_size,
+        max_entries: config.max_entries,
+        map_flags: config.map_flags,
+        map_name: map_name(name)?,
+        btf_key_type_id: KEY_TYPE_ID,
+        btf_value_type_id: VALUE_TYPE_ID,
+        ..Default::default()
+    };
+
+    create_with_btf(attr, btf_fd)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_storage_btf() {
+        let btf = storage_btf(24);
+        let u32_at = |off: usize| {
+            let mut buf = [0u8; 4];
+            buf.copy_from_slice(&btf[off..off + 4]);
+            u32::from_ne_bytes(buf)
+        };
+        let type_len = u32_at(12) as usize;
+        let str_len = u32_at(20) as usize;
+        assert_eq!(btf.len(), 24 + type_len + str_len);
+        // the array length is the last field of the types
+        assert_eq!(u32_at(24 + type_len - 4), 24);
+        assert!(is_local_storage(BPF_MAP_TYPE_SK_STORAGE));
+        assert!(!is_local_storage(bpf_sys::bpf_map_type_BPF_MAP_TYPE_HASH));
+    }
+}
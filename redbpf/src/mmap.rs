@@ -0,0 +1,92 @@
+//! Memory mapped access to array maps.
+use std::io;
+use std::mem;
+use std::ptr::null_mut;
+use std::slice;
+
+use libc::{mmap, munmap, sysconf, MAP_FAILED, MAP_SHARED, PROT_READ, _SC_PAGESIZE};
+
+use crate::{LoadError, Map, Result};
+
+const BPF_F_MMAPABLE: u32 = 1 << 10;
+
+/// A read-only view of the values of an array map created with
+/// `BPF_F_MMAPABLE`.
+///
+/// Reading values through the view doesn't require a syscall per lookup,
+/// which makes it well suited for polling counters at high frequency. Values
+/// can be updated by eBPF programs concurrently, so they should be read with
+/// atomic or volatile accesses.
+///
+/// The memory is unmapped when the view is dropped.
+pub struct MmapView {
+    ptr: *mut u8,
+    len: usize,
+    value_size: usize,
+    max_entries: usize,
+}
+
+impl MmapView {
+    pub(crate) fn new(map: &Map) -> Result<MmapView> {
+        let config = &map.config;
+        if config.type_ != bpf_sys::bpf_map_type_BPF_MAP_TYPE_ARRAY
+            || config.map_flags & BPF_F_MMAPABLE == 0
+        {
+            return Err(LoadError::InvalidMap(format!(
+                "map `{}' is not an mmapable array",
+                map.name
+            )));
+        }
+
+        // array values are 8 byte aligned
+        let value_size = (config.value_size as usize + 7) & !7;
+        let max_entries = config.max_entries as usize;
+        let page_size = unsafe { sysconf(_SC_PAGESIZE) as usize };
+        let len = (value_size * max_entries + page_size - 1) / page_size * page_size;
+        let ptr = unsafe { mmap(null_mut(), len, PROT_READ, MAP_SHARED, map.fd, 0) };
+        if ptr == MAP_FAILED {
+            return Err(LoadError::IO(io::Error::last_os_error()));
+        }
+
+        Ok(MmapView {
+            ptr: ptr as *mut u8,
+            len,
+            value_size,
+            max_entries,
+        })
+    }
+
+    /// Returns the values of the map as a slice of `T`.
+    ///
+    /// Returns an error if the size of `T` doesn't match the aligned size of
+    /// the map values.
+    ///
+    /// # Safety
+    ///
+    /// `T` must have the same layout as the values stored by the eBPF
+    /// programs, and be valid for any bit pattern.
+    pub unsafe fn as_slice<T>(&self) -> Result<&[T]> {
+        if mem::size_of::<T>() != self.value_size {
+            return Err(LoadError::InvalidMap(format!(
+                "value size is {} bytes, not {}",
+                self.value_size,
+                mem::size_of::<T>()
+            )));
+        }
+
+        Ok(slice::from_raw_parts(self.ptr as *const T, self.max_entries))
+    }
+
+    /// Returns the raw bytes of the map values.
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr, self.value_size * self.max_entries) }
+    }
+}
+
+impl Drop for MmapView {
+    fn drop(&mut self) {
+        unsafe {
+            munmap(self.ptr as *mut _, self.len);
+        }
+    }
+}
@@ -0,0 +1,119 @@
+//! Network interface name resolution and network namespaces.
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+use crate::{LoadError, Result};
+
+/// Returns the index of the network interface `name`.
+pub fn if_nametoindex(name: &str) -> Result<u32> {
+    let cname = CString::new(name)?;
+    let index = unsafe { libc::if_nametoindex(cname.as_ptr()) };
+    if index == 0 {
+        return Err(LoadError::Interface(
+            name.to_string(),
+            io::Error::last_os_error(),
+        ));
+    }
+
+    Ok(index)
+}
+
+/// Returns the name of the network interface with index `index`.
+pub fn if_indextoname(index: u32) -> Result<String> {
+    let mut buf = [0 as libc::c_char; libc::IF_NAMESIZE];
+    let name = unsafe { libc::if_indextoname(index, buf.as_mut_ptr()) };
+    if name.is_null() {
+        return Err(LoadError::Interface(
+            index.to_string(),
+            io::Error::last_os_error(),
+        ));
+    }
+
+    let name = unsafe { CStr::from_ptr(name) };
+    Ok(name.to_string_lossy().into_owned())
+}
+
+/// Runs `f` in the network namespace `netns`.
+///
+/// The namespace of the calling thread is always restored, even if `f`
+/// fails or panics.
+pub(crate) fn with_netns<T, F>(netns: &File, f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T>,
+{
+    let guard = NetnsGuard {
+        original: Some(File::open("/proc/thread-self/ns/net")?),
+    };
+    setns(netns)?;
+    let ret = f();
+    guard.restore()?;
+
+    ret
+}
+
+// Restores the network namespace of the calling thread when dropped, eg.
+// when unwinding.
+struct NetnsGuard {
+    original: Option<File>,
+}
+
+impl NetnsGuard {
+    // Restores the namespace, returning the error dropping would ignore.
+    fn restore(mut self) -> Result<()> {
+        match self.original.take() {
+            Some(original) => setns(&original),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for NetnsGuard {
+    fn drop(&mut self) {
+        if let Some(original) = self.original.take() {
+            let _ = setns(&original);
+        }
+    }
+}
+
+fn setns(netns: &File) -> Result<()> {
+    if unsafe { libc::setns(netns.as_raw_fd(), libc::CLONE_NEWNET) } < 0 {
+        return Err(LoadError::IO(io::Error::last_os_error()));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_loopback() {
+        let index = if_nametoindex("lo").unwrap();
+        assert_eq!(if_indextoname(index).unwrap(), "lo");
+    }
+
+    #[test]
+    fn test_with_netns_panic() {
+        let ns = || std::fs::read_link("/proc/thread-self/ns/net").unwrap();
+        let current = ns();
+        let netns = File::open("/proc/self/ns/net").unwrap();
+        // entering the current namespace needs CAP_SYS_ADMIN
+        if setns(&netns).is_err() {
+            return;
+        }
+        let res = std::panic::catch_unwind(|| with_netns(&netns, || -> Result<()> { panic!() }));
+        assert!(res.is_err());
+        assert_eq!(ns(), current);
+    }
+
+    #[test]
+    fn test_missing_interface() {
+        match if_nametoindex("nonexistent0") {
+            Err(LoadError::Interface(name, _)) => assert_eq!(name, "nonexistent0"),
+            _ => panic!("expected an interface error"),
+        }
+    }
+}
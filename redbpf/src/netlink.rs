@@ -0,0 +1,504 @@
+//! Minimal rtnetlink client for the XDP link attributes that the bcc helpers
+//! don't expose, and for removing tc BPF filters.
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+
+const RTM_NEWLINK: u16 = 16;
+const RTM_GETLINK: u16 = 18;
+const RTM_SETLINK: u16 = 19;
+const RTM_NEWTFILTER: u16 = 44;
+const RTM_DELTFILTER: u16 = 45;
+const RTM_GETTFILTER: u16 = 46;
+const NLM_F_REQUEST: u16 = 1;
+const NLM_F_ACK: u16 = 4;
+const NLM_F_DUMP: u16 = 0x300;
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+const NLA_F_NESTED: u16 = 1 << 15;
+const NLA_F_NET_BYTEORDER: u16 = 1 << 14;
+
+const IFLA_XDP: u16 = 43;
+const IFLA_XDP_FD: u16 = 1;
+const IFLA_XDP_FLAGS: u16 = 3;
+const IFLA_XDP_DRV_PROG_ID: u16 = 5;
+const IFLA_XDP_SKB_PROG_ID: u16 = 6;
+const IFLA_XDP_HW_PROG_ID: u16 = 7;
+const IFLA_XDP_EXPECTED_FD: u16 = 8;
+
+/// The parent of the ingress filters of the clsact qdisc, `ffff:fff2`.
+pub(crate) const TC_H_INGRESS: u32 = 0xFFFF_FFF2;
+/// The parent of the egress filters of the clsact qdisc, `ffff:fff3`.
+pub(crate) const TC_H_EGRESS: u32 = 0xFFFF_FFF3;
+
+const TCA_KIND: u16 = 1;
+
+#[repr(C)]
+struct NlMsgHdr {
+    nlmsg_len: u32,
+    nlmsg_type: u16,
+    nlmsg_flags: u16,
+    nlmsg_seq: u32,
+    nlmsg_pid: u32,
+}
+
+#[repr(C)]
+struct IfInfoMsg {
+    ifi_family: u8,
+    _pad: u8,
+    ifi_type: u16,
+    ifi_index: i32,
+    ifi_flags: u32,
+    ifi_change: u32,
+}
+
+#[repr(C)]
+struct TcMsg {
+    tcm_family: u8,
+    _pad1: u8,
+    _pad2: u16,
+    tcm_ifindex: i32,
+    tcm_handle: u32,
+    tcm_parent: u32,
+    tcm_info: u32,
+}
+
+// Netlink attributes are 4 bytes aligned.
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn push_attr(buf: &mut Vec<u8>, kind: u16, payload: &[u8]) {
+    let len = 4 + payload.len();
+    buf.extend_from_slice(&(len as u16).to_ne_bytes());
+    buf.extend_from_slice(&kind.to_ne_bytes());
+    buf.extend_from_slice(payload);
+    buf.resize(buf.len() + align(len) - len, 0);
+}
+
+// Splits `buf` in its attributes, with the flags removed from the types.
+fn parse_attrs(mut buf: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut attrs = Vec::new();
+    while buf.len() >= 4 {
+        let len = u16::from_ne_bytes([buf[0], buf[1]]) as usize;
+        let kind = u16::from_ne_bytes([buf[2], buf[3]]) & !(NLA_F_NESTED | NLA_F_NET_BYTEORDER);
+        if len < 4 || len > buf.len() {
+            break;
+        }
+        attrs.push((kind, &buf[4..len]));
+        buf = &buf[align(len).min(buf.len())..];
+    }
+    attrs
+}
+
+fn as_bytes<T>(value: &T) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) }
+}
+
+fn ifinfo(ifindex: u32) -> IfInfoMsg {
+    IfInfoMsg {
+        ifi_family: libc::AF_UNSPEC as u8,
+        _pad: 0,
+        ifi_type: 0,
+        ifi_index: ifindex as i32,
+        ifi_flags: 0,
+        ifi_change: 0,
+    }
+}
+
+fn message(kind: u16, flags: u16, body: &[u8]) -> Vec<u8> {
+    let header = NlMsgHdr {
+        nlmsg_len: (mem::size_of::<NlMsgHdr>() + body.len()) as u32,
+        nlmsg_type: kind,
+        nlmsg_flags: flags,
+        nlmsg_seq: 1,
+        nlmsg_pid: 0,
+    };
+    let mut message = as_bytes(&header).to_vec();
+    message.extend_from_slice(body);
+    message
+}
+
+// Builds the RTM_SETLINK request setting the XDP program of `ifindex`.
+fn setlink_xdp_request(ifindex: u32, fd: RawFd, flags: u32, expected_fd: Option<RawFd>) -> Vec<u8> {
+    let mut xdp = Vec::new();
+    push_attr(&mut xdp, IFLA_XDP_FD, &fd.to_ne_bytes());
+    if flags != 0 {
+        push_attr(&mut xdp, IFLA_XDP_FLAGS, &flags.to_ne_bytes());
+    }
+    if let Some(expected_fd) = expected_fd {
+        push_attr(&mut xdp, IFLA_XDP_EXPECTED_FD, &expected_fd.to_ne_bytes());
+    }
+
+    let mut body = as_bytes(&ifinfo(ifindex)).to_vec();
+    push_attr(&mut body, IFLA_XDP | NLA_F_NESTED, &xdp);
+    message(RTM_SETLINK, NLM_F_REQUEST | NLM_F_ACK, &body)
+}
+
+/// Sends `request` on a new rtnetlink socket and waits for the kernel's ack.
+fn transact(request: &[u8]) -> io::Result<Vec<u8>> {
+    transact_with(request, |reply| Ok(Some(reply.to_vec())))
+}
+
+/// Sends `request` on a new rtnetlink socket and passes the replies to
+/// `handle` until it returns a value, eg. to read the replies of a dump.
+fn transact_with<T, F>(request: &[u8], mut handle: F) -> io::Result<T>
+where
+    F: FnMut(&[u8]) -> io::Result<Option<T>>,
+{
+    let sock = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_RAW, libc::NETLINK_ROUTE) };
+    if sock < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let res = (|| {
+        if unsafe { libc::send(sock, request.as_ptr() as *const _, request.len(), 0) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buf = vec![0u8; 8192];
+        loop {
+            let len = recv(sock, &mut buf)?;
+            if let Some(value) = handle(&buf[..len])? {
+                return Ok(value);
+            }
+        }
+    })();
+    unsafe { libc::close(sock) };
+    res
+}
+
+// Receives the next message of `sock` in `buf`, growing it to the size of
+// the message first so that it isn't truncated, eg. for the replies of links
+// with many attributes.
+fn recv(sock: RawFd, buf: &mut Vec<u8>) -> io::Result<usize> {
+    let flags = libc::MSG_PEEK | libc::MSG_TRUNC;
+    let len = unsafe { libc::recv(sock, buf.as_mut_ptr() as *mut _, 0, flags) };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if len as usize > buf.len() {
+        buf.resize(len as usize, 0);
+    }
+    let len = unsafe { libc::recv(sock, buf.as_mut_ptr() as *mut _, buf.len(), 0) };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(len as usize)
+}
+
+// Returns the error of an NLMSG_ERROR reply, which is an ack if 0.
+fn check_ack(reply: &[u8]) -> io::Result<()> {
+    let hdr_len = mem::size_of::<NlMsgHdr>();
+    if reply.len() < hdr_len + 4 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "short netlink reply",
+        ));
+    }
+    let kind = u16::from_ne_bytes([reply[4], reply[5]]);
+    if kind != NLMSG_ERROR {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unexpected netlink reply",
+        ));
+    }
+    let mut errno = [0u8; 4];
+    errno.copy_from_slice(&reply[hdr_len..hdr_len + 4]);
+    match i32::from_ne_bytes(errno) {
+        0 => Ok(()),
+        e => Err(io::Error::from_raw_os_error(-e)),
+    }
+}
+
+/// The ids of the XDP programs attached to an interface, in each mode.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct XdpProgIds {
+    pub skb: Option<u32>,
+    pub drv: Option<u32>,
+    pub hw: Option<u32>,
+}
+
+// Parses the RTM_NEWLINK reply to a RTM_GETLINK request.
+fn parse_xdp_prog_ids(reply: &[u8]) -> io::Result<XdpProgIds> {
+    let hdr_len = mem::size_of::<NlMsgHdr>();
+    let body_start = hdr_len + mem::size_of::<IfInfoMsg>();
+    if reply.len() >= hdr_len && u16::from_ne_bytes([reply[4], reply[5]]) == NLMSG_ERROR {
+        check_ack(reply)?;
+    }
+    if reply.len() < body_start || u16::from_ne_bytes([reply[4], reply[5]]) != RTM_NEWLINK {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unexpected netlink reply",
+        ));
+    }
+    let len = (u32::from_ne_bytes([reply[0], reply[1], reply[2], reply[3]]) as usize)
+        .min(reply.len())
+        .max(body_start);
+
+    let mut ids = XdpProgIds::default();
+    let xdp = parse_attrs(&reply[body_start..len])
+        .into_iter()
+        .find(|(kind, _)| *kind == IFLA_XDP);
+    if let Some((_, xdp)) = xdp {
+        for (kind, payload) in parse_attrs(xdp) {
+            if payload.len() < 4 {
+                continue;
+            }
+            let id = u32::from_ne_bytes([payload[0], payload[1], payload[2], payload[3]]);
+            match kind {
+                IFLA_XDP_SKB_PROG_ID => ids.skb = Some(id),
+                IFLA_XDP_DRV_PROG_ID => ids.drv = Some(id),
+                IFLA_XDP_HW_PROG_ID => ids.hw = Some(id),
+                _ => {}
+            }
+        }
+    }
+
+    Ok(ids)
+}
+
+/// Returns the ids of the XDP programs attached to `ifindex`.
+pub(crate) fn get_xdp(ifindex: u32) -> io::Result<XdpProgIds> {
+    let request = message(RTM_GETLINK, NLM_F_REQUEST, as_bytes(&ifinfo(ifindex)));
+    parse_xdp_prog_ids(&transact(&request)?)
+}
+
+/// Attaches the XDP program `fd` to `ifindex`, or detaches the current one if
+/// `fd` is -1.
+///
+/// With `XDP_FLAGS_REPLACE`, the change only happens if the program currently
+/// attached is `expected_fd`, and fails with `EEXIST` otherwise.
+pub(crate) fn set_xdp(
+    ifindex: u32,
+    fd: RawFd,
+    flags: u32,
+    expected_fd: Option<RawFd>,
+) -> io::Result<()> {
+    let request = setlink_xdp_request(ifindex, fd, flags, expected_fd);
+    check_ack(&transact(&request)?)
+}
+
+fn tcmsg(ifindex: u32, handle: u32, parent: u32, info: u32) -> TcMsg {
+    TcMsg {
+        tcm_family: libc::AF_UNSPEC as u8,
+        _pad1: 0,
+        _pad2: 0,
+        tcm_ifindex: ifindex as i32,
+        tcm_handle: handle,
+        tcm_parent: parent,
+        tcm_info: info,
+    }
+}
+
+/// The BPF filters found by a RTM_GETTFILTER dump, by their `tcm_info`, ie.
+/// priority and protocol.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct FilterDump {
+    infos: Vec<u32>,
+    done: bool,
+}
+
+impl FilterDump {
+    // Adds the BPF filters of `reply`, one of the replies of the dump.
+    fn add_reply(&mut self, mut reply: &[u8]) -> io::Result<()> {
+        let hdr_len = mem::size_of::<NlMsgHdr>();
+        let body_start = hdr_len + mem::size_of::<TcMsg>();
+        while reply.len() >= hdr_len {
+            let len = u32::from_ne_bytes([reply[0], reply[1], reply[2], reply[3]]) as usize;
+            if len < hdr_len || len > reply.len() {
+                break;
+            }
+            match u16::from_ne_bytes([reply[4], reply[5]]) {
+                RTM_NEWTFILTER if len >= body_start => {
+                    let info = &reply[hdr_len + 16..hdr_len + 20];
+                    let info = u32::from_ne_bytes([info[0], info[1], info[2], info[3]]);
+                    let is_bpf = parse_attrs(&reply[body_start..len])
+                        .into_iter()
+                        .any(|(kind, value)| kind == TCA_KIND && value == b"bpf\0");
+                    if is_bpf && !self.infos.contains(&info) {
+                        self.infos.push(info);
+                    }
+                }
+                NLMSG_DONE => {
+                    self.done = true;
+                    return Ok(());
+                }
+                NLMSG_ERROR => check_ack(&reply[..len])?,
+                _ => {}
+            }
+            reply = &reply[align(len).min(reply.len())..];
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the `tcm_info`, ie. priority and protocol, of the BPF filters of
+/// `parent` of `ifindex`.
+pub(crate) fn bpf_filters(ifindex: u32, parent: u32) -> io::Result<Vec<u32>> {
+    let request = message(
+        RTM_GETTFILTER,
+        NLM_F_REQUEST | NLM_F_DUMP,
+        as_bytes(&tcmsg(ifindex, 0, parent, 0)),
+    );
+    let mut dump = FilterDump::default();
+    // the dump can span several replies
+    transact_with(&request, |reply| {
+        dump.add_reply(reply)?;
+        Ok(if dump.done || reply.is_empty() {
+            Some(())
+        } else {
+            None
+        })
+    })?;
+
+    Ok(dump.infos)
+}
+
+// Builds the RTM_DELTFILTER request deleting the BPF filters with the
+// priority and protocol of `info` from `parent` of `ifindex`.
+fn deltfilter_request(ifindex: u32, parent: u32, info: u32) -> Vec<u8> {
+    let mut body = as_bytes(&tcmsg(ifindex, 0, parent, info)).to_vec();
+    push_attr(&mut body, TCA_KIND, b"bpf\0");
+    message(RTM_DELTFILTER, NLM_F_REQUEST | NLM_F_ACK, &body)
+}
+
+/// Deletes the BPF filters with the priority and protocol of `info` from
+/// `parent` of `ifindex`.
+pub(crate) fn del_bpf_filters(ifindex: u32, parent: u32, info: u32) -> io::Result<()> {
+    check_ack(&transact(&deltfilter_request(ifindex, parent, info))?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_setlink_xdp_request() {
+        let request = setlink_xdp_request(2, 5, 1 << 4, Some(4));
+        let hdr_len = mem::size_of::<NlMsgHdr>();
+        let ifinfo_len = mem::size_of::<IfInfoMsg>();
+        // nested IFLA_XDP with 3 attributes of 8 bytes
+        assert_eq!(request.len(), hdr_len + ifinfo_len + 4 + 3 * 8);
+        assert_eq!(
+            u32::from_ne_bytes([request[0], request[1], request[2], request[3]]) as usize,
+            request.len()
+        );
+        let xdp = &request[hdr_len + ifinfo_len..];
+        assert_eq!(
+            u16::from_ne_bytes([xdp[2], xdp[3]]),
+            IFLA_XDP | NLA_F_NESTED
+        );
+        assert_eq!(u16::from_ne_bytes([xdp[22], xdp[23]]), IFLA_XDP_EXPECTED_FD);
+    }
+
+    fn filter_reply(info: u32, kind: &[u8]) -> Vec<u8> {
+        let mut body = as_bytes(&tcmsg(3, 1, TC_H_INGRESS, info)).to_vec();
+        push_attr(&mut body, TCA_KIND, kind);
+        message(RTM_NEWTFILTER, 0, &body)
+    }
+
+    #[test]
+    fn test_filter_dump() {
+        let mut dump = FilterDump::default();
+        // the dump spans two replies, the second one ending with NLMSG_DONE
+        let mut reply = filter_reply(1 << 16 | 3, b"bpf\0");
+        reply.extend_from_slice(&filter_reply(2 << 16 | 3, b"u32\0"));
+        dump.add_reply(&reply).unwrap();
+        assert_eq!(dump.infos, vec![1 << 16 | 3]);
+        assert!(!dump.done);
+
+        let mut reply = filter_reply(1 << 16 | 3, b"bpf\0");
+        reply.extend_from_slice(&filter_reply(5 << 16 | 3, b"bpf\0"));
+        reply.extend_from_slice(&message(NLMSG_DONE, 0, &0i32.to_ne_bytes()));
+        dump.add_reply(&reply).unwrap();
+        assert_eq!(
+            dump,
+            FilterDump {
+                infos: vec![1 << 16 | 3, 5 << 16 | 3],
+                done: true,
+            }
+        );
+    }
+
+    #[test]
+    fn test_deltfilter_request() {
+        let request = deltfilter_request(3, TC_H_EGRESS, 1 << 16 | 3);
+        let hdr_len = mem::size_of::<NlMsgHdr>();
+        assert_eq!(u16::from_ne_bytes([request[4], request[5]]), RTM_DELTFILTER);
+        let tcmsg = &request[hdr_len..];
+        assert_eq!(
+            i32::from_ne_bytes([tcmsg[4], tcmsg[5], tcmsg[6], tcmsg[7]]),
+            3
+        );
+        assert_eq!(
+            u32::from_ne_bytes([tcmsg[12], tcmsg[13], tcmsg[14], tcmsg[15]]),
+            TC_H_EGRESS
+        );
+        assert_eq!(
+            u32::from_ne_bytes([tcmsg[16], tcmsg[17], tcmsg[18], tcmsg[19]]),
+            1 << 16 | 3
+        );
+    }
+
+    #[test]
+    fn test_parse_xdp_prog_ids() {
+        let mut xdp = Vec::new();
+        push_attr(&mut xdp, IFLA_XDP_SKB_PROG_ID, &42u32.to_ne_bytes());
+        push_attr(&mut xdp, IFLA_XDP_HW_PROG_ID, &7u32.to_ne_bytes());
+        let mut body = as_bytes(&ifinfo(2)).to_vec();
+        push_attr(&mut body, 3, b"eth0\0");
+        push_attr(&mut body, IFLA_XDP | NLA_F_NESTED, &xdp);
+        let reply = message(RTM_NEWLINK, 0, &body);
+        assert_eq!(
+            parse_xdp_prog_ids(&reply).unwrap(),
+            XdpProgIds {
+                skb: Some(42),
+                drv: None,
+                hw: Some(7),
+            }
+        );
+
+        let body = as_bytes(&ifinfo(2)).to_vec();
+        let reply = message(RTM_NEWLINK, 0, &body);
+        assert_eq!(parse_xdp_prog_ids(&reply).unwrap(), XdpProgIds::default());
+
+        let mut reply = message(NLMSG_ERROR, 0, &(-libc::ENODEV).to_ne_bytes());
+        reply.extend_from_slice(&[0; 16]);
+        assert_eq!(
+            parse_xdp_prog_ids(&reply).unwrap_err().raw_os_error(),
+            Some(libc::ENODEV)
+        );
+    }
+
+    #[test]
+    fn test_check_ack() {
+        let mut reply = vec![0u8; mem::size_of::<NlMsgHdr>() + 4];
+        reply[4..6].copy_from_slice(&NLMSG_ERROR.to_ne_bytes());
+        assert!(check_ack(&reply).is_ok());
+        let off = mem::size_of::<NlMsgHdr>();
+        reply[off..off + 4].copy_from_slice(&(-libc::EEXIST).to_ne_bytes());
+        assert_eq!(
+            check_ack(&reply).unwrap_err().raw_os_error(),
+            Some(libc::EEXIST)
+        );
+    }
+
+    #[test]
+    fn test_recv_long_message() {
+        let mut fds = [0; 2];
+        let ret = unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_DGRAM, 0, fds.as_mut_ptr()) };
+        assert_eq!(ret, 0);
+        let message = vec![7u8; 10000];
+        let sent = unsafe { libc::send(fds[0], message.as_ptr() as *const _, message.len(), 0) };
+        assert_eq!(sent, message.len() as isize);
+
+        let mut buf = vec![0u8; 8192];
+        let len = recv(fds[1], &mut buf).unwrap();
+        assert_eq!(&buf[..len], &message[..]);
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+}
@@ -0,0 +1,67 @@
+//! Creation of maps on a NUMA node.
+//!
+//! By default the memory of a map is allocated on the NUMA node of the CPU
+//! creating it. On large NUMA hosts, maps accessed at a high rate by
+//! programs running on the CPUs of another node are better allocated on
+//! that node, see `LoadOptions::with_map_numa_node`.
+use std::os::unix::io::RawFd;
+use std::path::Path;
+
+use bpf_sys::bpf_map_def;
+
+use crate::local_storage::{map_name, MapCreateAttr, BPF_MAP_CREATE};
+use crate::sys::bpf;
+use crate::{LoadError, Result};
+
+const BPF_F_NUMA_NODE: u32 = 1 << 2;
+
+// Checks that the NUMA node `node` exists, before the kernel rejects the map
+// with a bare `EINVAL`.
+pub(crate) fn check_node(name: &str, node: u32) -> Result<()> {
+    if !Path::new(&format!("/sys/devices/system/node/node{}", node)).exists() {
+        return Err(LoadError::InvalidMap(format!(
+            "NUMA node {} of map `{}' does not exist",
+            node, name
+        )));
+    }
+
+    Ok(())
+}
+
+pub(crate) fn create_attr(name: &str, config: &bpf_map_def, node: u32) -> Result<MapCreateAttr> {
+    Ok(MapCreateAttr {
+        map_type: config.type_,
+        key_size: config.key_size,
+        value_size: config.value_size,
+        max_entries: config.max_entries,
+        map_flags: config.map_flags | BPF_F_NUMA_NODE,
+        numa_node: node,
+        map_name: map_name(name)?,
+        ..Default::default()
+    })
+}
+
+/// Creates a map whose memory is allocated on the NUMA node `node`.
+pub(crate) fn create(name: &str, config: &bpf_map_def, node: u32) -> Result<RawFd> {
+    check_node(name, node)?;
+    let mut attr = create_attr(name, config, node)?;
+
+    unsafe { bpf(BPF_MAP_CREATE, &mut attr) }.map_err(|_| LoadError::Map)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::mem;
+
+    #[test]
+    fn test_create_attr() {
+        let mut config: bpf_map_def = unsafe { mem::zeroed() };
+        config.type_ = bpf_sys::bpf_map_type_BPF_MAP_TYPE_PERCPU_HASH;
+        config.map_flags = 1;
+        let attr = create_attr("a_long_map_name_truncated", &config, 1).unwrap();
+        assert_eq!(attr.map_flags, 1 | BPF_F_NUMA_NODE);
+        assert_eq!(attr.numa_node, 1);
+        assert_eq!(&attr.map_name, b"a_long_map_name\0");
+    }
+}
@@ -43,14 +43,15 @@
 #![allow(clippy::cast_lossless)]
 #![allow(clippy::cast_ptr_alignment)]
 
-use crate::{LoadError, Map, Result, VoidPtr};
+use crate::cpus::{self, CpuId};
+use crate::{Attachment, LoadError, Map, Program, Result, VoidPtr};
 use std::cell::RefCell;
 use std::io;
 use std::mem;
 use std::os::unix::io::RawFd;
-use std::ptr::null_mut;
+use std::ptr::{self, null_mut};
 use std::slice;
-use std::sync::atomic::{self, AtomicPtr, Ordering};
+use std::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
 
 use libc::{
     c_void, close, ioctl, mmap, munmap, syscall, sysconf, SYS_perf_event_open, MAP_FAILED,
@@ -59,16 +60,19 @@ use libc::{
 
 use crate::sys::perf::*;
 
-unsafe fn open_perf_buffer(pid: i32, cpu: i32, group: RawFd, flags: u32) -> Result<RawFd> {
-    let mut attr = mem::zeroed::<perf_event_attr>();
-
-    attr.config = perf_sw_ids_PERF_COUNT_SW_BPF_OUTPUT as u64;
-    attr.size = mem::size_of::<perf_event_attr>() as u32;
-    attr.type_ = perf_type_id_PERF_TYPE_SOFTWARE;
-    attr.sample_type = perf_event_sample_format_PERF_SAMPLE_RAW as u64;
-    attr.__bindgen_anon_1.sample_period = 1;
-    attr.__bindgen_anon_2.wakeup_events = 1;
+#[cfg(target_env = "gnu")]
+type RequestType = libc::c_ulong;
+#[cfg(target_env = "musl")]
+type RequestType = libc::c_int;
 
+unsafe fn open_perf_buffer(
+    pid: i32,
+    cpu: i32,
+    group: RawFd,
+    flags: u32,
+    perf_attr: &PerfAttr,
+) -> Result<RawFd> {
+    let attr = perf_attr.to_attr();
     let pfd = syscall(
         SYS_perf_event_open,
         &attr as *const perf_event_attr,
@@ -78,19 +82,663 @@ unsafe fn open_perf_buffer(pid: i32, cpu: i32, group: RawFd, flags: u32) -> Resu
         flags | PERF_FLAG_FD_CLOEXEC,
     );
     if pfd < 0 {
-        Err(LoadError::IO(io::Error::last_os_error()))
+        let e = io::Error::last_os_error();
+        match e.raw_os_error() {
+            Some(libc::EPERM) | Some(libc::EACCES) => Err(LoadError::PerfEventDenied(e)),
+            _ => Err(LoadError::IO(e)),
+        }
     } else {
         Ok(pfd as RawFd)
     }
 }
 
+pub(crate) fn check_perf_event_array(map: &Map, cpu: i32) -> Result<()> {
+    if map.kind != bpf_sys::bpf_map_type_BPF_MAP_TYPE_PERF_EVENT_ARRAY {
+        return Err(LoadError::InvalidMap(format!(
+            "map `{}' is not a perf event array (type {})",
+            map.name, map.kind
+        )));
+    }
+    let max_entries = map.config.max_entries;
+    if cpu < 0 || cpu as u32 >= max_entries {
+        return Err(LoadError::InvalidMap(format!(
+            "perf event array `{}' has {} entries, can't bind CPU {}",
+            map.name, max_entries, cpu
+        )));
+    }
+
+    Ok(())
+}
+
+// Checks that `group` is either -1 or a perf event fd. The kernel
+// additionally checks that the event is a group leader.
+fn check_group_leader(group: RawFd) -> Result<()> {
+    if group == -1 {
+        return Ok(());
+    }
+    let invalid = || {
+        LoadError::IO(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "group fd {} is not a perf event, use -1 for no group",
+                group
+            ),
+        ))
+    };
+    if group < 0 {
+        return Err(invalid());
+    }
+    match std::fs::read_link(format!("/proc/self/fd/{}", group)) {
+        Ok(target) if target.to_str() == Some("anon_inode:[perf_event]") => Ok(()),
+        _ => Err(invalid()),
+    }
+}
+
+// Checks that the buffer has a power of two number of pages, which the kernel
+// requires but reports with a bare `EINVAL` from `mmap`.
+fn check_page_cnt(page_cnt: usize) -> Result<()> {
+    if !page_cnt.is_power_of_two() {
+        return Err(LoadError::IO(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "perf buffers must have a power of two number of pages, got {}",
+                page_cnt
+            ),
+        )));
+    }
+
+    Ok(())
+}
+
+/// Perf events opened as a group.
+///
+/// The buffer bound with `PerfGroup::bind` becomes the group leader, and the
+/// events opened with `PerfGroup::join` follow it. The events of a group are
+/// scheduled on the CPU together, so their counters can be read atomically,
+/// which allows correlating them with the events of the leader. All the
+/// events of a group are opened on the CPU of the leader.
+///
+/// A perf event array holds a single buffer per CPU, so followers are plain
+/// perf events that are not stored in the map: programs keep writing to the
+/// leader's buffer.
+///
+/// The group only holds the fd of the leader: the leader `PerfMap` must not
+/// be dropped before the other members.
+///
+/// ```no_run
+/// use redbpf::{Map, PerfAttr, PerfGroup};
+///
+/// let mut map = Map::load("events", &vec![]).unwrap();
+/// let mut group = PerfGroup::new();
+/// let leader = group.bind(&mut map, -1, 0, 16, 0, &PerfAttr::new()).unwrap();
+/// let counter = group.join(-1, 0, &PerfAttr::new()).unwrap();
+/// assert_eq!(group.leader(), Some(leader.fd));
+/// ```
+#[derive(Debug, Default)]
+pub struct PerfGroup {
+    /// The fd and CPU of the leader.
+    leader: Option<(RawFd, i32)>,
+}
+
+impl PerfGroup {
+    pub fn new() -> PerfGroup {
+        PerfGroup::default()
+    }
+
+    /// Returns the fd of the group leader, once a buffer has been bound.
+    pub fn leader(&self) -> Option<RawFd> {
+        self.leader.map(|(fd, _)| fd)
+    }
+
+    /// Same as `PerfMap::bind_with_attr`, binding the leader of the group.
+    ///
+    /// Fails if the group already has a leader, use `join` to add events to
+    /// the group.
+    pub fn bind(
+        &mut self,
+        map: &mut Map,
+        pid: i32,
+        cpu: i32,
+        page_cnt: usize,
+        flags: u32,
+        attr: &PerfAttr,
+    ) -> Result<PerfMap> {
+        if let Some((leader, _)) = self.leader {
+            return Err(LoadError::InvalidMap(format!(
+                "the perf group already has a leader, fd {}",
+                leader
+            )));
+        }
+        let perf_map = PerfMap::bind_with_attr(map, pid, cpu, page_cnt, -1, flags, attr)?;
+        self.leader = Some((perf_map.fd, cpu));
+
+        Ok(perf_map)
+    }
+
+    /// Opens the perf event described by `attr` in the group, on the CPU of
+    /// the leader.
+    ///
+    /// The event is not stored in any map. Fails if no leader was bound.
+    pub fn join(&self, pid: i32, flags: u32, attr: &PerfAttr) -> Result<PerfEvent> {
+        let (leader, cpu) = self.leader.ok_or_else(|| {
+            LoadError::InvalidMap("the perf group has no leader, bind one first".to_string())
+        })?;
+        let fd = unsafe { open_perf_buffer(pid, cpu, leader, flags, attr)? };
+        let event = PerfEvent { fd };
+        if unsafe { ioctl(fd, PERF_EVENT_IOC_ENABLE, 0) } != 0 {
+            return Err(LoadError::IO(io::Error::last_os_error()));
+        }
+
+        Ok(event)
+    }
+}
+
+/// A perf event that is not stored in a map, eg. a member of a `PerfGroup`.
+///
+/// The event is disabled and closed when dropped.
+#[derive(Debug)]
+pub struct PerfEvent {
+    pub fd: RawFd,
+}
+
+impl Drop for PerfEvent {
+    fn drop(&mut self) {
+        unsafe {
+            ioctl(self.fd, PERF_EVENT_IOC_DISABLE, 0);
+            close(self.fd);
+        }
+    }
+}
+
+/// Builder for binding a `PerfMap` with named options.
+///
+/// By default the buffer has 16 pages, monitors all processes on CPU 0 and is
+/// not part of a group.
+///
+/// ```no_run
+/// use redbpf::{Map, PerfMapBuilder};
+///
+/// let mut map = Map::load("events", &vec![]).unwrap();
+/// let perf_map = PerfMapBuilder::new()
+///     .cpu(1)
+///     .pages(64)
+///     .sample_time()
+///     .bind(&mut map)
+///     .unwrap();
+/// ```
+#[derive(Debug, Copy, Clone)]
+pub struct PerfMapBuilder {
+    pid: i32,
+    cpu: i32,
+    page_cnt: usize,
+    group: RawFd,
+    flags: u32,
+    attr: PerfAttr,
+}
+
+impl Default for PerfMapBuilder {
+    fn default() -> PerfMapBuilder {
+        PerfMapBuilder {
+            pid: -1,
+            cpu: 0,
+            page_cnt: 16,
+            group: -1,
+            flags: 0,
+            attr: PerfAttr::new(),
+        }
+    }
+}
+
+impl PerfMapBuilder {
+    pub fn new() -> PerfMapBuilder {
+        PerfMapBuilder::default()
+    }
+
+    /// Only monitors the process `pid`, `-1` monitors all processes.
+    pub fn pid(mut self, pid: i32) -> PerfMapBuilder {
+        self.pid = pid;
+        self
+    }
+
+    /// Binds the buffer for `cpu`.
+    pub fn cpu(mut self, cpu: i32) -> PerfMapBuilder {
+        self.cpu = cpu;
+        self
+    }
+
+    /// Sets the number of pages of the buffer, which must be a power of two.
+    ///
+    /// Any other count makes `bind` fail with `ErrorKind::InvalidInput`.
+    pub fn pages(mut self, page_cnt: usize) -> PerfMapBuilder {
+        self.page_cnt = page_cnt;
+        self
+    }
+
+    /// Joins the group led by the fd `group`, see `PerfGroup`.
+    pub fn group(mut self, group: RawFd) -> PerfMapBuilder {
+        self.group = group;
+        self
+    }
+
+    /// Sets the `PERF_FLAG_*` flags passed to `perf_event_open`.
+    pub fn flags(mut self, flags: u32) -> PerfMapBuilder {
+        self.flags = flags;
+        self
+    }
+
+    /// Opens the perf event described by `attr`, see `PerfMap::bind_with_attr`.
+    pub fn attr(mut self, attr: PerfAttr) -> PerfMapBuilder {
+        self.attr = attr;
+        self
+    }
+
+    /// Records the timestamp of every sample, see `Sample::time()`.
+    pub fn sample_time(mut self) -> PerfMapBuilder {
+        self.attr = self.attr.time();
+        self
+    }
+
+    /// Wakes up readers once `bytes` bytes are available in the buffer, see
+    /// `PerfAttr::wakeup_watermark`.
+    pub fn watermark(mut self, bytes: u32) -> PerfMapBuilder {
+        self.attr = self.attr.wakeup_watermark(bytes);
+        self
+    }
+
+    /// Opens the perf buffer and stores it in the perf event array `map`.
+    pub fn bind(&self, map: &mut Map) -> Result<PerfMap> {
+        PerfMap::bind_with_attr(
+            map,
+            self.pid,
+            self.cpu,
+            self.page_cnt,
+            self.group,
+            self.flags,
+            &self.attr,
+        )
+    }
+}
+
+/// Extra fields to request in each sample.
+///
+/// By default samples only contain the raw data written by the eBPF program.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SampleOptions {
+    /// Record the id of the CPU that generated the sample, see `Sample::cpu()`.
+    pub cpu: bool,
+    /// Record the process and thread id, see `Sample::pid()` and `Sample::tid()`.
+    pub tid: bool,
+}
+
+impl From<SampleOptions> for PerfAttr {
+    fn from(options: SampleOptions) -> PerfAttr {
+        let mut attr = PerfAttr::new();
+        if options.cpu {
+            attr = attr.cpu();
+        }
+        if options.tid {
+            attr = attr.tid();
+        }
+        attr
+    }
+}
+
+/// Builder for the `perf_event_attr` a `PerfMap` is opened with.
+///
+/// The default opens a `PERF_COUNT_SW_BPF_OUTPUT` event which samples the raw
+/// data written by eBPF programs. Fields requested with `sample` are recorded
+/// in every sample, and `PerfMap::read()` parses records according to the
+/// resulting `sample_type`.
+///
+/// Other events, eg. hardware counters, can be sampled with `event`:
+///
+/// ```rust
+/// use redbpf::PerfAttr;
+/// use redbpf::sys::perf::*;
+///
+/// let attr = PerfAttr::new()
+///     .event(perf_type_id_PERF_TYPE_HARDWARE, perf_hw_id_PERF_COUNT_HW_CPU_CYCLES as u64)
+///     .sample_period(100_000)
+///     .no_raw()
+///     .ip()
+///     .tid();
+/// ```
+#[derive(Debug, Copy, Clone)]
+pub struct PerfAttr {
+    type_: u32,
+    config: u64,
+    sample_type: u64,
+    sample_period: u64,
+    wakeup_events: u32,
+    wakeup_watermark: u32,
+}
+
+impl Default for PerfAttr {
+    fn default() -> PerfAttr {
+        PerfAttr {
+            type_: perf_type_id_PERF_TYPE_SOFTWARE,
+            config: perf_sw_ids_PERF_COUNT_SW_BPF_OUTPUT as u64,
+            sample_type: perf_event_sample_format_PERF_SAMPLE_RAW as u64,
+            sample_period: 1,
+            wakeup_events: 1,
+            wakeup_watermark: 0,
+        }
+    }
+}
+
+impl PerfAttr {
+    pub fn new() -> PerfAttr {
+        PerfAttr::default()
+    }
+
+    /// Samples the hardware event `config`, one of the `PERF_COUNT_HW_*`
+    /// values, eg. `PERF_COUNT_HW_CACHE_MISSES`.
+    ///
+    /// Hardware events have no raw data, so it's not recorded.
+    pub fn hardware(config: u64) -> PerfAttr {
+        PerfAttr::new()
+            .event(perf_type_id_PERF_TYPE_HARDWARE, config)
+            .no_raw()
+    }
+
+    /// Sets the event type and config, eg. `PERF_TYPE_HARDWARE` and
+    /// `PERF_COUNT_HW_CPU_CYCLES`.
+    ///
+    /// Only `PERF_COUNT_SW_BPF_OUTPUT` events carry raw data, see `no_raw`.
+    pub fn event(mut self, type_: u32, config: u64) -> PerfAttr {
+        self.type_ = type_;
+        self.config = config;
+        self
+    }
+
+    /// Records the fields of `flag`, one of the `PERF_SAMPLE_*` values.
+    pub fn sample(mut self, flag: perf_event_sample_format) -> PerfAttr {
+        self.sample_type |= flag as u64;
+        self
+    }
+
+    /// Records the instruction pointer, see `Sample::ip()`.
+    pub fn ip(self) -> PerfAttr {
+        self.sample(perf_event_sample_format_PERF_SAMPLE_IP)
+    }
+
+    /// Records the process and thread id, see `Sample::pid()` and
+    /// `Sample::tid()`.
+    pub fn tid(self) -> PerfAttr {
+        self.sample(perf_event_sample_format_PERF_SAMPLE_TID)
+    }
+
+    /// Records the timestamp, see `Sample::time()`.
+    pub fn time(self) -> PerfAttr {
+        self.sample(perf_event_sample_format_PERF_SAMPLE_TIME)
+    }
+
+    /// Records the address of the sampled access, see `Sample::addr()`.
+    pub fn addr(self) -> PerfAttr {
+        self.sample(perf_event_sample_format_PERF_SAMPLE_ADDR)
+    }
+
+    /// Records the id of the CPU, see `Sample::cpu()`.
+    pub fn cpu(self) -> PerfAttr {
+        self.sample(perf_event_sample_format_PERF_SAMPLE_CPU)
+    }
+
+    /// Records the sampling period, see `Sample::period()`.
+    pub fn period(self) -> PerfAttr {
+        self.sample(perf_event_sample_format_PERF_SAMPLE_PERIOD)
+    }
+
+    /// Stops recording the raw data, which only BPF output events have.
+    pub fn no_raw(mut self) -> PerfAttr {
+        self.sample_type &= !(perf_event_sample_format_PERF_SAMPLE_RAW as u64);
+        self
+    }
+
+    /// Generates a sample every `period` events.
+    pub fn sample_period(mut self, period: u64) -> PerfAttr {
+        self.sample_period = period;
+        self
+    }
+
+    /// Wakes up readers every `count` samples.
+    pub fn wakeup_events(mut self, count: u32) -> PerfAttr {
+        self.wakeup_events = count;
+        self
+    }
+
+    /// Wakes up readers once `bytes` bytes are available in the buffer,
+    /// instead of every `wakeup_events` samples.
+    ///
+    /// `0` goes back to waking up readers every `wakeup_events` samples.
+    pub fn wakeup_watermark(mut self, bytes: u32) -> PerfAttr {
+        self.wakeup_watermark = bytes;
+        self
+    }
+
+    /// Returns the `PERF_SAMPLE_*` flags samples are recorded with.
+    pub fn sample_type(&self) -> u64 {
+        self.sample_type
+    }
+
+    fn to_attr(&self) -> perf_event_attr {
+        let mut attr = unsafe { mem::zeroed::<perf_event_attr>() };
+        attr.config = self.config;
+        attr.size = mem::size_of::<perf_event_attr>() as u32;
+        attr.type_ = self.type_;
+        attr.sample_type = self.sample_type;
+        attr.__bindgen_anon_1.sample_period = self.sample_period;
+        if self.wakeup_watermark > 0 {
+            attr.set_watermark(1);
+            attr.__bindgen_anon_2.wakeup_watermark = self.wakeup_watermark;
+        } else {
+            attr.__bindgen_anon_2.wakeup_events = self.wakeup_events;
+        }
+        attr
+    }
+}
+
+/// A sample produced by an eBPF program.
+///
+/// The record read from the ring buffer is normalized by `PerfMap::read()`,
+/// so the raw data is always available through `size` and `data` regardless
+/// of the other fields requested with `PerfAttr`. `size` is 0 for samples
+/// without raw data.
 #[repr(C)]
 pub struct Sample {
     header: perf_event_header,
+    sample_type: u64,
+    ip: u64,
+    time: u64,
+    addr: u64,
+    period: u64,
+    pid: u32,
+    tid: u32,
+    cpu: u32,
     pub size: u32,
     pub data: [u8; 0],
 }
 
+impl Sample {
+    /// Returns the instruction pointer, if requested with `PerfAttr::ip`.
+    pub fn ip(&self) -> Option<u64> {
+        self.field(perf_event_sample_format_PERF_SAMPLE_IP, self.ip)
+    }
+
+    /// Returns the timestamp of the sample, if requested with
+    /// `PerfAttr::time`.
+    pub fn time(&self) -> Option<u64> {
+        self.field(perf_event_sample_format_PERF_SAMPLE_TIME, self.time)
+    }
+
+    /// Returns the sampled address, if requested with `PerfAttr::addr`.
+    pub fn addr(&self) -> Option<u64> {
+        self.field(perf_event_sample_format_PERF_SAMPLE_ADDR, self.addr)
+    }
+
+    /// Returns the sampling period, if requested with `PerfAttr::period`.
+    pub fn period(&self) -> Option<u64> {
+        self.field(perf_event_sample_format_PERF_SAMPLE_PERIOD, self.period)
+    }
+
+    /// Returns the CPU the sample was generated on, if requested with
+    /// `SampleOptions::cpu`.
+    pub fn cpu(&self) -> Option<u32> {
+        self.field(perf_event_sample_format_PERF_SAMPLE_CPU, self.cpu)
+    }
+
+    /// Returns the process id of the task that generated the sample, if
+    /// requested with `SampleOptions::tid`.
+    pub fn pid(&self) -> Option<u32> {
+        self.field(perf_event_sample_format_PERF_SAMPLE_TID, self.pid)
+    }
+
+    /// Returns the thread id of the task that generated the sample, if
+    /// requested with `SampleOptions::tid`.
+    pub fn tid(&self) -> Option<u32> {
+        self.field(perf_event_sample_format_PERF_SAMPLE_TID, self.tid)
+    }
+
+    #[inline]
+    fn field<T>(&self, flag: perf_event_sample_format, value: T) -> Option<T> {
+        if self.sample_type & flag as u64 != 0 {
+            Some(value)
+        } else {
+            None
+        }
+    }
+}
+
+// Converts a PERF_RECORD_SAMPLE record to the layout of `Sample`.
+//
+// The fields of a sample record are laid out in a fixed order, each present
+// only if the corresponding bit is set in `sample_type`, so offsets are
+// computed while walking the record.
+fn normalize_sample(record: &[u8], sample_type: u64, out: &mut Vec<u8>) -> Option<()> {
+    let read_u32 = |off: usize| -> Option<u32> {
+        let bytes = record.get(off..off + 4)?;
+        Some(u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    };
+    let read_u64 = |off: usize| -> Option<u64> {
+        let bytes = record.get(off..off + 8)?;
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(bytes);
+        Some(u64::from_ne_bytes(buf))
+    };
+    let has = |flag: perf_event_sample_format| sample_type & flag as u64 != 0;
+
+    let mut off = mem::size_of::<perf_event_header>();
+    let (mut ip, mut time, mut addr, mut period) = (0, 0, 0, 0);
+    let (mut pid, mut tid, mut cpu) = (0, 0, 0);
+    if has(perf_event_sample_format_PERF_SAMPLE_IDENTIFIER) {
+        off += 8;
+    }
+    if has(perf_event_sample_format_PERF_SAMPLE_IP) {
+        ip = read_u64(off)?;
+        off += 8;
+    }
+    if has(perf_event_sample_format_PERF_SAMPLE_TID) {
+        pid = read_u32(off)?;
+        tid = read_u32(off + 4)?;
+        off += 8;
+    }
+    if has(perf_event_sample_format_PERF_SAMPLE_TIME) {
+        time = read_u64(off)?;
+        off += 8;
+    }
+    if has(perf_event_sample_format_PERF_SAMPLE_ADDR) {
+        addr = read_u64(off)?;
+        off += 8;
+    }
+    for flag in &[
+        perf_event_sample_format_PERF_SAMPLE_ID,
+        perf_event_sample_format_PERF_SAMPLE_STREAM_ID,
+    ] {
+        if has(*flag) {
+            off += 8;
+        }
+    }
+    if has(perf_event_sample_format_PERF_SAMPLE_CPU) {
+        cpu = read_u32(off)?;
+        off += 8;
+    }
+    if has(perf_event_sample_format_PERF_SAMPLE_PERIOD) {
+        period = read_u64(off)?;
+        off += 8;
+    }
+    // events are opened with a zero read_format, so a single value is read
+    if has(perf_event_sample_format_PERF_SAMPLE_READ) {
+        off += 8;
+    }
+    if has(perf_event_sample_format_PERF_SAMPLE_CALLCHAIN) {
+        let nr = read_u64(off)? as usize;
+        off += 8 + nr * 8;
+    }
+    let (size, data) = if has(perf_event_sample_format_PERF_SAMPLE_RAW) {
+        let size = read_u32(off)?;
+        off += 4;
+        (size, record.get(off..off + size as usize)?)
+    } else {
+        (0, &[][..])
+    };
+
+    out.clear();
+    out.extend_from_slice(&record[..mem::size_of::<perf_event_header>()]);
+    out.extend_from_slice(&sample_type.to_ne_bytes());
+    out.extend_from_slice(&ip.to_ne_bytes());
+    out.extend_from_slice(&time.to_ne_bytes());
+    out.extend_from_slice(&addr.to_ne_bytes());
+    out.extend_from_slice(&period.to_ne_bytes());
+    out.extend_from_slice(&pid.to_ne_bytes());
+    out.extend_from_slice(&tid.to_ne_bytes());
+    out.extend_from_slice(&cpu.to_ne_bytes());
+    out.extend_from_slice(&size.to_ne_bytes());
+    out.extend_from_slice(data);
+    Some(())
+}
+
+// The kernel writes records before storing `data_head` with release
+// semantics, so it has to be loaded with acquire semantics for the records to
+// be visible. Records must be read before `data_tail` is stored, with release
+// semantics, since the kernel then reuses their space. Without this, weakly
+// ordered architectures such as aarch64 can read stale records.
+unsafe fn load_data_head(header: *mut perf_event_mmap_page) -> u64 {
+    let head = &(*header).data_head as *const u64 as *const AtomicU64;
+    (*head).load(Ordering::Acquire)
+}
+
+unsafe fn store_data_tail(header: *mut perf_event_mmap_page, tail: u64) {
+    let tail_ptr = &mut (*header).data_tail as *mut u64 as *const AtomicU64;
+    (*tail_ptr).store(tail, Ordering::Release)
+}
+
+// Copies `len` bytes starting at `offset` out of the ring, wrapping around
+// its end.
+fn copy_from_ring(ring: &[u8], offset: usize, len: usize, out: &mut Vec<u8>) {
+    let first = len.min(ring.len() - offset);
+    out.extend_from_slice(&ring[offset..offset + first]);
+    out.extend_from_slice(&ring[..len - first]);
+}
+
+// Copies the record at `tail` out of the ring into `out` and returns its size.
+//
+// The header is copied first, since it can itself wrap around the end of the
+// ring, and only then is its size trusted.
+fn read_record(ring: &[u8], tail: u64, out: &mut Vec<u8>) -> Option<usize> {
+    let start = (tail % ring.len() as u64) as usize;
+    let header_len = mem::size_of::<perf_event_header>();
+    out.clear();
+    copy_from_ring(ring, start, header_len, out);
+    let header = unsafe { (out.as_ptr() as *const perf_event_header).read_unaligned() };
+    let size = header.size as usize;
+    if size < header_len || size > ring.len() {
+        return None;
+    }
+
+    out.clear();
+    copy_from_ring(ring, start, size, out);
+    Some(size)
+}
+
 #[repr(C)]
 pub struct LostSamples {
     header: perf_event_header,
@@ -108,21 +756,110 @@ pub struct PerfMap {
     page_cnt: usize,
     page_size: usize,
     mmap_size: usize,
+    sample_type: u64,
     buf: RefCell<Vec<u8>>,
+    sample_buf: RefCell<Vec<u8>>,
+    cpu: CpuId,
     pub fd: RawFd,
 }
 
 impl PerfMap {
+    /// Opens a perf buffer on `cpu` and stores it in the perf event array
+    /// `map`, so that eBPF programs running on `cpu` can write events to it.
+    ///
+    /// `pid`, `cpu`, `group` and `flags` are passed to `perf_event_open`.
+    /// `group` is `-1` for a buffer that is not part of a group, or the fd of
+    /// the group leader, see `PerfGroup`.
+    ///
+    /// `page_cnt` must be a power of two, otherwise this fails with
+    /// `ErrorKind::InvalidInput`.
+    ///
+    /// `PerfMapBuilder` sets the same options by name.
     pub fn bind(
         map: &mut Map,
         pid: i32,
+        cpu: i32,
+        page_cnt: usize,
+        group: RawFd,
+        flags: u32,
+    ) -> Result<PerfMap> {
+        PerfMapBuilder::new()
+            .pid(pid)
+            .cpu(cpu)
+            .pages(page_cnt)
+            .group(group)
+            .flags(flags)
+            .bind(map)
+    }
+
+    /// Binds a perf buffer of `page_cnt` pages for every online CPU.
+    pub fn bind_all(map: &mut Map, page_cnt: usize) -> Result<Vec<PerfMap>> {
+        PerfMap::bind_all_with(map, |_| page_cnt)
+    }
+
+    /// Binds a perf buffer for every online CPU, sized by `page_cnt`.
+    ///
+    /// `page_cnt` is called with each CPU id and returns the number of pages
+    /// of the buffer for that CPU, which must be a power of two. This allows
+    /// giving more space to CPUs that handle busy interrupts.
+    pub fn bind_all_with<F>(map: &mut Map, mut page_cnt: F) -> Result<Vec<PerfMap>>
+    where
+        F: FnMut(CpuId) -> usize,
+    {
+        let mut perf_maps = Vec::new();
+        for cpu in cpus::get_online()? {
+            perf_maps.push(PerfMap::bind(map, -1, cpu, page_cnt(cpu), -1, 0)?);
+        }
+
+        Ok(perf_maps)
+    }
+
+    /// Same as `bind`, additionally recording the fields requested by
+    /// `options` in every sample.
+    pub fn bind_with_options(
+        map: &mut Map,
+        pid: i32,
+        cpu: i32,
+        page_cnt: usize,
+        group: RawFd,
+        flags: u32,
+        options: SampleOptions,
+    ) -> Result<PerfMap> {
+        PerfMap::bind_with_attr(map, pid, cpu, page_cnt, group, flags, &options.into())
+    }
+
+    /// Same as `bind`, opening the perf event described by `attr`.
+    ///
+    /// Samples are parsed according to the `sample_type` built by `attr`.
+    pub fn bind_with_attr(
+        map: &mut Map,
+        pid: i32,
+        cpu: i32,
+        page_cnt: usize,
+        group: RawFd,
+        flags: u32,
+        attr: &PerfAttr,
+    ) -> Result<PerfMap> {
+        check_perf_event_array(map, cpu)?;
+        check_group_leader(group)?;
+        PerfMap::open(map.fd, pid, cpu, page_cnt, group, flags, attr)
+    }
+
+    // Opens the perf buffer and stores it in the perf event array `map_fd`,
+    // which has to be checked by the caller.
+    pub(crate) fn open(
+        map_fd: RawFd,
+        pid: i32,
         mut cpu: i32,
         page_cnt: usize,
         group: RawFd,
         flags: u32,
+        attr: &PerfAttr,
     ) -> Result<PerfMap> {
+        check_page_cnt(page_cnt)?;
+        let sample_type = attr.sample_type();
         unsafe {
-            let mut fd = open_perf_buffer(pid, cpu, group, flags)?;
+            let mut fd = open_perf_buffer(pid, cpu, group, flags, attr)?;
             let page_size = sysconf(_SC_PAGESIZE) as usize;
             let mmap_size = page_size * (page_cnt + 1);
             let base_ptr = mmap(
@@ -135,78 +872,199 @@ impl PerfMap {
             );
 
             if base_ptr == MAP_FAILED {
-                return Err(LoadError::IO(io::Error::last_os_error()));
+                let err = io::Error::last_os_error();
+                close(fd);
+                return Err(LoadError::IO(err));
             }
 
+            // from here on, dropping `perf_map` unmaps the buffer and closes
+            // the fd if binding fails
+            let perf_map = PerfMap {
+                base_ptr: AtomicPtr::new(base_ptr as *mut perf_event_mmap_page),
+                buf: RefCell::new(vec![]),
+                sample_buf: RefCell::new(vec![]),
+                page_cnt,
+                page_size,
+                mmap_size,
+                sample_type,
+                cpu,
+                fd,
+            };
+
             if ioctl(fd, PERF_EVENT_IOC_ENABLE, 0) != 0 {
                 return Err(LoadError::IO(io::Error::last_os_error()));
             }
 
-            map.set(
+            if bpf_sys::bpf_update_elem(
+                map_fd,
                 &mut cpu as *mut i32 as VoidPtr,
                 &mut fd as *mut i32 as VoidPtr,
-            );
+                0,
+            ) < 0
+            {
+                return Err(LoadError::IO(io::Error::last_os_error()));
+            }
 
-            Ok(PerfMap {
-                base_ptr: AtomicPtr::new(base_ptr as *mut perf_event_mmap_page),
-                buf: RefCell::new(vec![]),
-                page_cnt,
-                page_size,
-                mmap_size,
-                fd,
-            })
+            Ok(perf_map)
         }
     }
 
-    pub fn read(&self) -> Option<Event<'_>> {
+    /// Stops the kernel from writing events to the buffer.
+    ///
+    /// The buffer stays mapped, so events written before pausing can still
+    /// be read. Events emitted by programs while paused are dropped.
+    pub fn pause(&self) -> Result<()> {
+        self.perf_ioctl(PERF_EVENT_IOC_DISABLE, 0)
+    }
+
+    /// Resumes writing events to the buffer after `pause`.
+    pub fn resume(&self) -> Result<()> {
+        self.perf_ioctl(PERF_EVENT_IOC_ENABLE, 0)
+    }
+
+    /// Enables the buffer for `count` more events, after which it's disabled
+    /// again until the next call to `refresh` or `resume`.
+    ///
+    /// This lets consumers rate limit the events they receive, eg. by
+    /// re-arming the buffer only once the previous event was processed.
+    pub fn refresh(&self, count: u32) -> Result<()> {
+        self.perf_ioctl(PERF_EVENT_IOC_REFRESH, count as libc::c_int)
+    }
+
+    fn perf_ioctl(&self, request: RequestType, arg: libc::c_int) -> Result<()> {
+        if unsafe { ioctl(self.fd, request, arg) } != 0 {
+            return Err(LoadError::IO(io::Error::last_os_error()));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the CPU the buffer was bound on.
+    pub fn cpu(&self) -> CpuId {
+        self.cpu
+    }
+
+    /// Returns the size of the ring buffer, in bytes.
+    pub fn capacity(&self) -> usize {
+        self.page_cnt * self.page_size
+    }
+
+    /// Returns the number of bytes written by the kernel and not read yet.
+    ///
+    /// Samples are lost once the lag reaches `capacity`, so monitoring the
+    /// ratio between the two detects consumers falling behind, and tells
+    /// whether the buffer is sized right for the event rate.
+    pub fn lag(&self) -> usize {
         unsafe {
             let header = self.base_ptr.load(Ordering::SeqCst);
-            let data_head = (*header).data_head;
-            let data_tail = (*header).data_tail;
-            let raw_size = (self.page_cnt * self.page_size) as u64;
-            let base = (header as *const u8).add(self.page_size);
+            let data_head = load_data_head(header);
+            let data_tail = ptr::read_volatile(&(*header).data_tail);
+            data_head.wrapping_sub(data_tail) as usize
+        }
+    }
 
-            if data_tail == data_head {
-                return None;
+    /// Reads all the available events, calling `on_sample` with the raw data
+    /// of each sample and `on_lost` with the number of samples that were
+    /// lost, along with the CPU of the buffer.
+    ///
+    /// This mirrors the callbacks of bcc's `open_perf_buffer`. Returns the
+    /// number of events read.
+    pub fn consume<S, L>(&self, mut on_sample: S, mut on_lost: L) -> usize
+    where
+        S: FnMut(CpuId, &[u8]),
+        L: FnMut(CpuId, u64),
+    {
+        let mut count = 0;
+        while let Some(event) = self.read() {
+            match event {
+                Event::Sample(sample) => {
+                    let data = unsafe {
+                        slice::from_raw_parts(sample.data.as_ptr(), sample.size as usize)
+                    };
+                    on_sample(self.cpu, data);
+                }
+                Event::Lost(lost) => on_lost(self.cpu, lost.count),
             }
+            count += 1;
+        }
 
-            let start = (data_tail % raw_size) as usize;
-            let event = base.add(start) as *const perf_event_header;
-            let end = ((data_tail + (*event).size as u64) % raw_size) as usize;
-
-            let mut buf = self.buf.borrow_mut();
-            buf.clear();
-
-            if end < start {
-                let len = (raw_size as usize - start) as usize;
-                let ptr = base.add(start);
-                buf.extend_from_slice(slice::from_raw_parts(ptr, len));
-
-                let len = (*event).size as usize - len;
-                let ptr = base;
-                buf.extend_from_slice(slice::from_raw_parts(ptr, len));
-            } else {
-                let ptr = base.add(start);
-                let len = (*event).size as usize;
-                buf.extend_from_slice(slice::from_raw_parts(ptr, len));
-            }
+        count
+    }
 
-            atomic::fence(Ordering::SeqCst);
-            (*header).data_tail += (*event).size as u64;
+    /// Reads the next event, returning `None` once the buffer is empty.
+    ///
+    /// Malformed samples and records of other types are skipped.
+    pub fn read(&self) -> Option<Event<'_>> {
+        loop {
+            unsafe {
+                let header = self.base_ptr.load(Ordering::SeqCst);
+                let data_head = load_data_head(header);
+                let data_tail = (*header).data_tail;
+                let raw_size = (self.page_cnt * self.page_size) as u64;
+                let base = (header as *const u8).add(self.page_size);
 
-            match (*event).type_ {
-                perf_event_type_PERF_RECORD_SAMPLE => {
-                    Some(Event::Sample(&*(buf.as_ptr() as *const Sample)))
+                if data_tail == data_head {
+                    return None;
                 }
-                perf_event_type_PERF_RECORD_LOST => {
-                    Some(Event::Lost(&*(buf.as_ptr() as *const LostSamples)))
+
+                let ring = slice::from_raw_parts(base, raw_size as usize);
+                let mut buf = self.buf.borrow_mut();
+                let size = match read_record(ring, data_tail, &mut buf) {
+                    Some(size) => size,
+                    None => {
+                        // the ring is corrupt, skip everything that was written
+                        store_data_tail(header, data_head);
+                        return None;
+                    }
+                };
+
+                // the record was copied, the kernel can reuse its space
+                store_data_tail(header, data_tail + size as u64);
+
+                let event = buf.as_ptr() as *const perf_event_header;
+                match (*event).type_ {
+                    perf_event_type_PERF_RECORD_SAMPLE => {
+                        let mut sample = self.sample_buf.borrow_mut();
+                        if normalize_sample(&buf, self.sample_type, &mut sample).is_some() {
+                            return Some(Event::Sample(&*(sample.as_ptr() as *const Sample)));
+                        }
+                    }
+                    perf_event_type_PERF_RECORD_LOST => {
+                        return Some(Event::Lost(&*(buf.as_ptr() as *const LostSamples)));
+                    }
+                    _ => {}
                 }
-                _ => None,
             }
         }
     }
 }
 
+impl Program {
+    /// Attaches the loaded `perf_event` program to the perf event described
+    /// by `attr`, eg. `PerfAttr::hardware(PERF_COUNT_HW_CPU_CYCLES)`.
+    ///
+    /// The program runs every `attr.sample_period` occurrences of the event.
+    /// `pid` and `cpu` are passed to `perf_event_open`, `pid` being `-1` to
+    /// sample all the processes on `cpu`. Returns the fd of the perf event.
+    pub fn attach_perf_event(&mut self, attr: &PerfAttr, pid: i32, cpu: i32) -> Result<RawFd> {
+        let fd = self.fd.ok_or(LoadError::BPF)?;
+        unsafe {
+            let pfd = open_perf_buffer(pid, cpu, -1, 0, attr)?;
+            if ioctl(pfd, PERF_EVENT_IOC_SET_BPF, fd) != 0
+                || ioctl(pfd, PERF_EVENT_IOC_ENABLE, 0) != 0
+            {
+                let err = io::Error::last_os_error();
+                close(pfd);
+                return Err(LoadError::IO(err));
+            }
+            // closing the event detaches the program
+            self.attachments.push(Attachment::Tracepoint(pfd));
+
+            Ok(pfd)
+        }
+    }
+}
+
 impl Drop for PerfMap {
     fn drop(&mut self) {
         unsafe {
@@ -219,3 +1077,168 @@ impl Drop for PerfMap {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn record(fields: &[&[u8]]) -> Vec<u8> {
+        let size: usize =
+            mem::size_of::<perf_event_header>() + fields.iter().map(|f| f.len()).sum::<usize>();
+        let mut record = Vec::new();
+        record.extend_from_slice(&perf_event_type_PERF_RECORD_SAMPLE.to_ne_bytes());
+        record.extend_from_slice(&0u16.to_ne_bytes());
+        record.extend_from_slice(&(size as u16).to_ne_bytes());
+        for field in fields {
+            record.extend_from_slice(field);
+        }
+        record
+    }
+
+    fn sample(buf: &[u8]) -> &Sample {
+        unsafe { &*(buf.as_ptr() as *const Sample) }
+    }
+
+    #[test]
+    fn test_normalize_raw_sample() {
+        let options = SampleOptions::default();
+        let rec = record(&[&4u32.to_ne_bytes(), &[1, 2, 3, 4]]);
+        let mut buf = Vec::new();
+        normalize_sample(&rec, PerfAttr::from(options).sample_type(), &mut buf).unwrap();
+
+        let sample = sample(&buf);
+        assert_eq!(sample.size, 4);
+        assert_eq!(sample.cpu(), None);
+        assert_eq!(sample.pid(), None);
+        let data = unsafe { slice::from_raw_parts(sample.data.as_ptr(), sample.size as usize) };
+        assert_eq!(data, &[1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_normalize_sample_with_cpu_and_tid() {
+        let options = SampleOptions {
+            cpu: true,
+            tid: true,
+        };
+        let rec = record(&[
+            &42u32.to_ne_bytes(),
+            &43u32.to_ne_bytes(),
+            &3u32.to_ne_bytes(),
+            &0u32.to_ne_bytes(),
+            &2u32.to_ne_bytes(),
+            &[5, 6],
+        ]);
+        let mut buf = Vec::new();
+        normalize_sample(&rec, PerfAttr::from(options).sample_type(), &mut buf).unwrap();
+
+        let sample = sample(&buf);
+        assert_eq!(sample.pid(), Some(42));
+        assert_eq!(sample.tid(), Some(43));
+        assert_eq!(sample.cpu(), Some(3));
+        assert_eq!(sample.size, 2);
+        let data = unsafe { slice::from_raw_parts(sample.data.as_ptr(), sample.size as usize) };
+        assert_eq!(data, &[5, 6]);
+    }
+
+    #[test]
+    fn test_normalize_truncated_sample() {
+        let rec = record(&[&8u32.to_ne_bytes(), &[1, 2]]);
+        let mut buf = Vec::new();
+        assert!(normalize_sample(&rec, PerfAttr::new().sample_type(), &mut buf).is_none());
+    }
+
+    #[test]
+    fn test_hardware_attr() {
+        let attr = PerfAttr::hardware(perf_hw_id_PERF_COUNT_HW_CACHE_MISSES as u64)
+            .ip()
+            .sample_period(10_000)
+            .to_attr();
+        assert_eq!(attr.type_, perf_type_id_PERF_TYPE_HARDWARE);
+        assert_eq!(attr.config, perf_hw_id_PERF_COUNT_HW_CACHE_MISSES as u64);
+        assert_eq!(
+            attr.sample_type,
+            perf_event_sample_format_PERF_SAMPLE_IP as u64
+        );
+        assert_eq!(unsafe { attr.__bindgen_anon_1.sample_period }, 10_000);
+    }
+
+    #[test]
+    fn test_check_group_leader() {
+        assert!(check_group_leader(-1).is_ok());
+        assert!(check_group_leader(-2).is_err());
+        let file = std::fs::File::open("/dev/null").unwrap();
+        assert!(check_group_leader(std::os::unix::io::AsRawFd::as_raw_fd(&file)).is_err());
+    }
+
+    #[test]
+    fn test_read_record_straddling_header() {
+        let rec = record(&[&4u32.to_ne_bytes(), &[1, 2, 3, 4]]);
+        // the header starts 4 bytes before the end of the ring
+        let mut ring = vec![0u8; 32];
+        for (i, b) in rec.iter().enumerate() {
+            ring[(28 + i) % 32] = *b;
+        }
+
+        let mut buf = Vec::new();
+        // the tail keeps growing past the ring size
+        assert_eq!(read_record(&ring, 64 + 28, &mut buf), Some(rec.len()));
+        assert_eq!(buf, rec);
+    }
+
+    #[test]
+    fn test_read_record_corrupt_size() {
+        let mut ring = vec![0u8; 32];
+        ring[6..8].copy_from_slice(&64u16.to_ne_bytes());
+        assert_eq!(read_record(&ring, 0, &mut Vec::new()), None);
+        ring[6..8].copy_from_slice(&2u16.to_ne_bytes());
+        assert_eq!(read_record(&ring, 0, &mut Vec::new()), None);
+    }
+
+    #[test]
+    fn test_normalize_hardware_sample() {
+        let attr = PerfAttr::new().no_raw().ip().time().period();
+        let rec = record(&[
+            &0xffff_1234u64.to_ne_bytes(),
+            &99u64.to_ne_bytes(),
+            &100_000u64.to_ne_bytes(),
+        ]);
+        let mut buf = Vec::new();
+        normalize_sample(&rec, attr.sample_type(), &mut buf).unwrap();
+
+        let sample = sample(&buf);
+        assert_eq!(sample.ip(), Some(0xffff_1234));
+        assert_eq!(sample.time(), Some(99));
+        assert_eq!(sample.period(), Some(100_000));
+        assert_eq!(sample.pid(), None);
+        assert_eq!(sample.size, 0);
+    }
+
+    #[test]
+    fn test_builder_attr() {
+        let builder = PerfMapBuilder::new().sample_time().watermark(4096);
+        assert_eq!(
+            builder.attr.sample_type(),
+            (perf_event_sample_format_PERF_SAMPLE_RAW | perf_event_sample_format_PERF_SAMPLE_TIME)
+                as u64
+        );
+        let attr = builder.attr.to_attr();
+        assert_eq!(attr.watermark(), 1);
+        assert_eq!(unsafe { attr.__bindgen_anon_2.wakeup_watermark }, 4096);
+
+        let attr = PerfMapBuilder::new().attr.to_attr();
+        assert_eq!(attr.watermark(), 0);
+        assert_eq!(unsafe { attr.__bindgen_anon_2.wakeup_events }, 1);
+    }
+
+    #[test]
+    fn test_check_page_cnt() {
+        assert!(check_page_cnt(1).is_ok());
+        assert!(check_page_cnt(16).is_ok());
+        for &page_cnt in &[0, 3, 24] {
+            match check_page_cnt(page_cnt) {
+                Err(LoadError::IO(e)) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+                _ => panic!("{} pages should be rejected", page_cnt),
+            }
+        }
+    }
+}
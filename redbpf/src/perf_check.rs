@@ -0,0 +1,119 @@
+//! Checking that perf event arrays have an entry for every CPU.
+//!
+//! Probes output perf events to the entry of the CPU they run on. When a
+//! `PerfMap` is declared with fewer entries than there are CPUs, the events
+//! of the higher CPUs are silently dropped. `Module::perf_array_size_mismatches`
+//! returns such maps, and `Module::parse` logs a warning for each of them when
+//! the `log` feature is enabled.
+use std::fmt::{self, Display};
+
+use crate::cpus;
+use crate::{LoadError, Map, Module, Result};
+
+/// A perf event array with fewer entries than CPUs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PerfArraySizeMismatch {
+    /// The name of the map.
+    pub map: String,
+    /// The number of entries of the map.
+    pub max_entries: u32,
+    /// The number of entries needed to cover all the possible CPUs.
+    pub needed_entries: u32,
+}
+
+impl Display for PerfArraySizeMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "perf event array `{}' has {} entries but the system has {} possible CPUs, \
+             events output on CPUs {} and above are dropped",
+            self.map, self.max_entries, self.needed_entries, self.max_entries
+        )
+    }
+}
+
+// Returns the number of entries needed for the CPU ids `cpus`: CPU ids can
+// be sparse, and index perf event arrays.
+fn needed_entries(cpus: &[cpus::CpuId]) -> u32 {
+    cpus.iter().max().map_or(0, |cpu| *cpu as u32 + 1)
+}
+
+fn check_size(map: &Map, needed_entries: u32) -> Option<PerfArraySizeMismatch> {
+    if map.kind != bpf_sys::bpf_map_type_BPF_MAP_TYPE_PERF_EVENT_ARRAY
+        || map.config.max_entries >= needed_entries
+    {
+        return None;
+    }
+
+    Some(PerfArraySizeMismatch {
+        map: map.name.clone(),
+        max_entries: map.config.max_entries,
+        needed_entries,
+    })
+}
+
+impl Module {
+    /// Returns the perf event arrays of the module with fewer entries than
+    /// the possible CPUs of the system.
+    ///
+    /// Call it at startup to fail early instead of missing the events of
+    /// some CPUs.
+    pub fn perf_array_size_mismatches(&self) -> Result<Vec<PerfArraySizeMismatch>> {
+        let needed = needed_entries(&cpus::get_possible().map_err(LoadError::IO)?);
+        Ok(self
+            .maps
+            .iter()
+            .filter_map(|map| check_size(map, needed))
+            .collect())
+    }
+
+    // Logs a warning for each undersized perf event array.
+    #[cfg(feature = "log")]
+    pub(crate) fn warn_perf_array_sizes(&self) {
+        if let Ok(mismatches) = self.perf_array_size_mismatches() {
+            for mismatch in mismatches {
+                log::warn!("{}", mismatch);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bpf_sys::bpf_map_def;
+    use std::mem;
+
+    fn map(kind: u32, max_entries: u32) -> Map {
+        let mut config: bpf_map_def = unsafe { mem::zeroed() };
+        config.type_ = kind;
+        config.max_entries = max_entries;
+        Map {
+            name: "events".to_string(),
+            kind,
+            fd: -1,
+            config,
+            numa_node: None,
+            value_btf: None,
+        }
+    }
+
+    #[test]
+    fn test_check_size() {
+        assert_eq!(needed_entries(&[0, 1, 2, 3]), 4);
+        assert_eq!(needed_entries(&[0, 8]), 9);
+
+        let perf = bpf_sys::bpf_map_type_BPF_MAP_TYPE_PERF_EVENT_ARRAY;
+        assert_eq!(check_size(&map(perf, 4), 4), None);
+        assert_eq!(
+            check_size(&map(perf, 2), 4),
+            Some(PerfArraySizeMismatch {
+                map: "events".to_string(),
+                max_entries: 2,
+                needed_entries: 4,
+            })
+        );
+        let hash = bpf_sys::bpf_map_type_BPF_MAP_TYPE_HASH;
+        assert_eq!(check_size(&map(hash, 2), 4), None);
+    }
+}
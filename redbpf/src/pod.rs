@@ -0,0 +1,70 @@
+//! Types that can be read from raw map memory.
+//!
+//! Map keys and values are copied from the kernel as raw bytes. Only types
+//! for which every bit pattern is a valid value can be filled this way:
+//! `bool`, enums, references and `NonZero*` integers can't.
+use std::mem;
+use std::ptr;
+
+/// Types for which any bit pattern of their size is a valid value.
+///
+/// Implemented for the integer and floating point primitives and for arrays
+/// of `Pod` types. Implement it for the `#[repr(C)]` structs used as map keys
+/// and values, eg. generated by `cargo bpf bindgen`:
+///
+/// ```rust
+/// use redbpf::Pod;
+///
+/// #[repr(C)]
+/// #[derive(Clone, Copy)]
+/// struct Conn {
+///     saddr: u32,
+///     daddr: u32,
+///     ports: [u16; 2],
+/// }
+///
+/// unsafe impl Pod for Conn {}
+/// ```
+///
+/// # Safety
+///
+/// Every bit pattern of `size_of::<Self>()` bytes, padding included, must be
+/// a valid value of the type, so all its fields must be `Pod` themselves.
+pub unsafe trait Pod: Copy {
+    /// Reads a value from the start of `data`, eg. the data of a perf event
+    /// sample, which doesn't have to be aligned.
+    ///
+    /// Returns `None` if `data` is too short.
+    fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() < mem::size_of::<Self>() {
+            return None;
+        }
+        Some(unsafe { ptr::read_unaligned(data.as_ptr() as *const Self) })
+    }
+}
+
+macro_rules! impl_pod {
+    ($($ty:ty),*) => {
+        $(unsafe impl Pod for $ty {})*
+    };
+}
+
+impl_pod!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64);
+
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_from_bytes() {
+        let data = [1u8, 0, 0, 0, 2, 0, 0, 0, 3];
+        assert_eq!(
+            u32::from_bytes(&data[1..]),
+            Some(u32::from_ne_bytes([0, 0, 0, 2]))
+        );
+        assert_eq!(<[u8; 2]>::from_bytes(&data[7..]), Some([0, 3]));
+        assert_eq!(u64::from_bytes(&data[2..]), None);
+    }
+}
@@ -0,0 +1,164 @@
+//! Waiting for events on all the perf event arrays of a module.
+//!
+//! Agents consuming several perf event arrays, eg. one per event type,
+//! register a handler for each map with `Module::on_events`, then run a
+//! single loop calling `Module::poll_all`:
+//!
+//! ```rust,no_run
+//! use redbpf::Module;
+//! use std::time::Duration;
+//!
+//! let code = std::fs::read("bpf.elf").unwrap();
+//! let mut module = Module::parse(&code).unwrap();
+//! module.load().unwrap();
+//! module
+//!     .on_events("connections", 16, |cpu, event| println!("{}: {:?}", cpu, event))
+//!     .unwrap();
+//! module
+//!     .on_events("dns_queries", 16, |cpu, event| println!("{}: {:?}", cpu, event))
+//!     .unwrap();
+//! loop {
+//!     module.poll_all(Some(Duration::from_millis(100))).unwrap();
+//! }
+//! ```
+//!
+//! Only perf event arrays can be polled, as redbpf has no ring buffer
+//! (`BPF_MAP_TYPE_RINGBUF`) support.
+use std::io;
+use std::os::unix::io::RawFd;
+use std::time::Duration;
+
+use crate::cpus::CpuId;
+use crate::error::{LoadError, Result};
+use crate::{EventStream, Module, OwnedEvent};
+
+const MAX_EPOLL_EVENTS: usize = 16;
+
+type Callback = Box<dyn FnMut(CpuId, OwnedEvent) + Send>;
+
+struct Handler {
+    map: String,
+    stream: EventStream,
+    callback: Callback,
+}
+
+// The event streams of the maps registered with `Module::on_events`, polled
+// together through an epoll instance watching the epoll fd of each stream.
+pub(crate) struct Poller {
+    epoll: RawFd,
+    handlers: Vec<Handler>,
+}
+
+impl Default for Poller {
+    fn default() -> Poller {
+        Poller {
+            epoll: -1,
+            handlers: Vec::new(),
+        }
+    }
+}
+
+impl Poller {
+    fn add(&mut self, handler: Handler) -> Result<()> {
+        if self.epoll < 0 {
+            self.epoll = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+            if self.epoll < 0 {
+                return Err(LoadError::IO(io::Error::last_os_error()));
+            }
+        }
+        let mut event = libc::epoll_event {
+            events: libc::EPOLLIN as u32,
+            u64: self.handlers.len() as u64,
+        };
+        let fd = handler.stream.epoll_fd();
+        if unsafe { libc::epoll_ctl(self.epoll, libc::EPOLL_CTL_ADD, fd, &mut event) } < 0 {
+            return Err(LoadError::IO(io::Error::last_os_error()));
+        }
+        self.handlers.push(handler);
+
+        Ok(())
+    }
+
+    fn poll(&mut self, timeout: Option<Duration>) -> Result<usize> {
+        if self.handlers.is_empty() {
+            return Ok(0);
+        }
+        let timeout = timeout.map_or(-1, |t| t.as_millis().min(std::i32::MAX as u128) as i32);
+        let mut events = [libc::epoll_event { events: 0, u64: 0 }; MAX_EPOLL_EVENTS];
+        let n = unsafe {
+            libc::epoll_wait(
+                self.epoll,
+                events.as_mut_ptr(),
+                MAX_EPOLL_EVENTS as i32,
+                timeout,
+            )
+        };
+        if n < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                return Ok(0);
+            }
+            return Err(LoadError::IO(e));
+        }
+
+        let mut count = 0;
+        for event in &events[..n as usize] {
+            let Handler {
+                stream, callback, ..
+            } = &mut self.handlers[event.u64 as usize];
+            for (cpu, event) in stream.drain_ready()? {
+                callback(cpu, event);
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+}
+
+impl Drop for Poller {
+    fn drop(&mut self) {
+        if self.epoll >= 0 {
+            unsafe { libc::close(self.epoll) };
+        }
+    }
+}
+
+impl Module {
+    /// Binds a perf buffer of `page_cnt` pages to the perf event array `map`
+    /// on every online CPU, and registers `handler` to be called by
+    /// `poll_all` with its events.
+    ///
+    /// `map` must be a perf event array, ring buffers are not supported.
+    pub fn on_events<F>(&mut self, map: &str, page_cnt: usize, handler: F) -> Result<()>
+    where
+        F: FnMut(CpuId, OwnedEvent) + Send + 'static,
+    {
+        if self.poller.handlers.iter().any(|h| h.map == map) {
+            return Err(LoadError::InvalidMap(format!(
+                "map `{}' already has an event handler",
+                map
+            )));
+        }
+        let stream = {
+            let map = self
+                .map(map)
+                .ok_or_else(|| LoadError::MapNotFound(map.to_string()))?;
+            EventStream::new(map, page_cnt)?
+        };
+        self.poller.add(Handler {
+            map: map.to_string(),
+            stream,
+            callback: Box::new(handler),
+        })
+    }
+
+    /// Waits up to `timeout`, or until events arrive if `None`, for events on
+    /// the maps registered with `on_events`, and calls their handlers.
+    ///
+    /// Returns the number of events handled, which is 0 on timeout or if no
+    /// handler is registered. CPU hotplug is checked when a map gets events.
+    pub fn poll_all(&mut self, timeout: Option<Duration>) -> Result<usize> {
+        self.poller.poll(timeout)
+    }
+}
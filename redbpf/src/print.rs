@@ -0,0 +1,120 @@
+//! Routing libbpf's diagnostics.
+//!
+//! libbpf reports why it fails to load or attach things through a print
+//! callback. `set_print_callback` installs a Rust callback receiving the
+//! messages:
+//!
+//! ```rust
+//! use redbpf::{set_print_callback, PrintLevel};
+//!
+//! set_print_callback(PrintLevel::Info, |level, msg| eprintln!("libbpf {:?}: {}", level, msg));
+//! ```
+//!
+//! With the `log` feature, `forward_to_log` forwards the messages to the
+//! `log` crate instead.
+use std::ffi::CStr;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+
+use lazy_static::lazy_static;
+use libc::{c_char, c_int, c_void};
+
+/// The level of a libbpf message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PrintLevel {
+    Warn,
+    Info,
+    Debug,
+}
+
+impl PrintLevel {
+    fn from_libbpf(level: bpf_sys::libbpf_print_level) -> Option<PrintLevel> {
+        match level {
+            bpf_sys::libbpf_print_level_LIBBPF_WARN => Some(PrintLevel::Warn),
+            bpf_sys::libbpf_print_level_LIBBPF_INFO => Some(PrintLevel::Info),
+            bpf_sys::libbpf_print_level_LIBBPF_DEBUG => Some(PrintLevel::Debug),
+            _ => None,
+        }
+    }
+}
+
+type PrintCallback = Arc<dyn Fn(PrintLevel, &str) + Send + Sync>;
+
+lazy_static! {
+    static ref CALLBACK: Mutex<Option<(PrintLevel, PrintCallback)>> = Mutex::new(None);
+}
+
+// Messages longer than this are truncated.
+const MAX_MESSAGE_LEN: usize = 1024;
+
+extern "C" {
+    fn vsnprintf(buf: *mut c_char, size: usize, format: *const c_char, ap: *mut c_void) -> c_int;
+}
+
+unsafe extern "C" fn print(
+    level: bpf_sys::libbpf_print_level,
+    format: *const c_char,
+    ap: *mut c_void,
+) -> c_int {
+    let level = match PrintLevel::from_libbpf(level) {
+        Some(level) => level,
+        None => return 0,
+    };
+    // the callback is called without holding the lock, so that it can set
+    // another callback
+    let callback = CALLBACK.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    let (max_level, callback) = match callback {
+        Some(callback) => callback,
+        None => return 0,
+    };
+    if level > max_level {
+        return 0;
+    }
+
+    let mut buf = [0 as c_char; MAX_MESSAGE_LEN];
+    let len = vsnprintf(buf.as_mut_ptr(), buf.len(), format, ap);
+    let msg = CStr::from_ptr(buf.as_ptr()).to_string_lossy();
+    // unwinding into libbpf's C frames is undefined behavior
+    let _ = panic::catch_unwind(AssertUnwindSafe(|| callback(level, msg.trim_end())));
+    len
+}
+
+/// Calls `callback` with the libbpf messages up to `level`, eg. warnings and
+/// informational messages for `PrintLevel::Info`.
+///
+/// The callback replaces the previous one, if any. Messages have their
+/// trailing newline removed. Panics of the callback are caught and the
+/// message is dropped, as they can't unwind through libbpf.
+pub fn set_print_callback<F>(level: PrintLevel, callback: F)
+where
+    F: Fn(PrintLevel, &str) + Send + Sync + 'static,
+{
+    *CALLBACK.lock().unwrap_or_else(|e| e.into_inner()) = Some((level, Arc::new(callback)));
+    unsafe { bpf_sys::libbpf_set_print(Some(print)) };
+}
+
+/// Forwards the libbpf messages to the `log` crate, with the matching log
+/// level.
+#[cfg(feature = "log")]
+pub fn forward_to_log() {
+    set_print_callback(PrintLevel::Debug, |level, msg| match level {
+        PrintLevel::Warn => log::warn!(target: "libbpf", "{}", msg),
+        PrintLevel::Info => log::info!(target: "libbpf", "{}", msg),
+        PrintLevel::Debug => log::debug!(target: "libbpf", "{}", msg),
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_print_level() {
+        assert_eq!(
+            PrintLevel::from_libbpf(bpf_sys::libbpf_print_level_LIBBPF_INFO),
+            Some(PrintLevel::Info)
+        );
+        assert_eq!(PrintLevel::from_libbpf(42), None);
+        assert!(PrintLevel::Debug > PrintLevel::Warn);
+    }
+}
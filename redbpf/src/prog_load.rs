@@ -0,0 +1,220 @@
+//! Loading programs with `BPF_PROG_LOAD` flags.
+use std::ffi::CString;
+use std::io;
+use std::os::unix::io::RawFd;
+
+use crate::local_storage::load_btf;
+use crate::{sys, LoadError, Program, Result};
+
+const BPF_PROG_LOAD: u32 = 5;
+
+// Size of the buffer the verifier log is written to.
+const LOG_BUF_SIZE: usize = 64 * 65535;
+
+/// Makes the verifier enforce strict alignment of memory accesses.
+pub const BPF_F_STRICT_ALIGNMENT: u32 = 1 << 0;
+/// Makes the verifier accept unaligned memory accesses.
+pub const BPF_F_ANY_ALIGNMENT: u32 = 1 << 1;
+/// Randomizes the upper 32 bits of registers after 32 bit operations, to
+/// test programs.
+pub const BPF_F_TEST_RND_HI32: u32 = 1 << 2;
+/// Loads the program as sleepable, which lets it call helpers that may
+/// sleep, like `bpf_copy_from_user`. Requires Linux 5.10.
+pub const BPF_F_SLEEPABLE: u32 = 1 << 4;
+
+// Program load part of `union bpf_attr`.
+#[repr(C)]
+#[derive(Default)]
+struct ProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+    prog_name: [u8; 16],
+    prog_ifindex: u32,
+    expected_attach_type: u32,
+    prog_btf_fd: u32,
+    func_info_rec_size: u32,
+    func_info: u64,
+    func_info_cnt: u32,
+    line_info_rec_size: u32,
+    line_info: u64,
+    line_info_cnt: u32,
+}
+
+/// The func_info or line_info records of a program, from `.BTF.ext`.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ExtInfo {
+    /// Size of each record in bytes.
+    pub rec_size: u32,
+    /// The records, with instruction offsets in instructions as
+    /// `BPF_PROG_LOAD` expects them.
+    pub records: Vec<u8>,
+}
+
+impl ExtInfo {
+    fn count(&self) -> u32 {
+        if self.rec_size == 0 {
+            0
+        } else {
+            self.records.len() as u32 / self.rec_size
+        }
+    }
+
+    /// Appends the records of `other`, shifting their instruction offsets by
+    /// `insn_offset`, eg. those of the `.text` functions appended to a
+    /// program.
+    pub fn append(&mut self, other: &ExtInfo, insn_offset: u32) -> Result<()> {
+        if other.records.is_empty() {
+            return Ok(());
+        }
+        if self.records.is_empty() {
+            self.rec_size = other.rec_size;
+        } else if self.rec_size != other.rec_size {
+            return Err(LoadError::BTF(format!(
+                "mismatched record sizes {} and {}",
+                self.rec_size, other.rec_size
+            )));
+        }
+        for record in other.records.chunks(other.rec_size as usize) {
+            let insn_idx = u32::from_ne_bytes([record[0], record[1], record[2], record[3]]);
+            self.records
+                .extend_from_slice(&(insn_idx + insn_offset).to_ne_bytes());
+            self.records.extend_from_slice(&record[4..]);
+        }
+
+        Ok(())
+    }
+}
+
+/// The BTF of the object a program was parsed from and its debug info,
+/// which make the verifier log reference source lines.
+#[derive(Debug, Clone)]
+pub(crate) struct ProgramBtf {
+    pub btf: Vec<u8>,
+    pub func_info: ExtInfo,
+    pub line_info: ExtInfo,
+}
+
+impl ProgLoadAttr {
+    fn set_btf(&mut self, btf_fd: RawFd, info: &ProgramBtf) {
+        self.prog_btf_fd = btf_fd as u32;
+        self.func_info_rec_size = info.func_info.rec_size;
+        self.func_info = info.func_info.records.as_ptr() as u64;
+        self.func_info_cnt = info.func_info.count();
+        self.line_info_rec_size = info.line_info.rec_size;
+        self.line_info = info.line_info.records.as_ptr() as u64;
+        self.line_info_cnt = info.line_info.count();
+    }
+}
+
+fn prog_load(attr: &mut ProgLoadAttr) -> io::Result<RawFd> {
+    unsafe { sys::bpf(BPF_PROG_LOAD, attr) }
+}
+
+/// Returns `LoadError::Verifier` with the verifier `log` of the program
+/// `name`, or `LoadError::BPF` if the kernel didn't log anything.
+pub(crate) fn verifier_error(name: &str, log: String) -> LoadError {
+    if log.is_empty() {
+        LoadError::BPF
+    } else {
+        LoadError::Verifier(name.to_string(), log)
+    }
+}
+
+/// Returns the NUL terminated log written to `log_buf`.
+pub(crate) fn verifier_log(log_buf: &[u8]) -> String {
+    let end = log_buf
+        .iter()
+        .position(|c| *c == 0)
+        .unwrap_or(log_buf.len());
+    String::from_utf8_lossy(&log_buf[..end])
+        .trim_end()
+        .to_string()
+}
+
+impl Program {
+    /// Sets the attach type the program is loaded for.
+    ///
+    /// Some program types, eg. sleepable programs, must declare how they are
+    /// going to be attached when loaded. This is only used by
+    /// `load_with_flags`.
+    pub fn set_expected_attach_type(&mut self, attach_type: u32) {
+        self.expected_attach_type = attach_type;
+    }
+
+    /// Same as `load`, passing `flags` to `BPF_PROG_LOAD`.
+    ///
+    /// `flags` is a combination of the `BPF_F_*` load flags, eg.
+    /// `BPF_F_SLEEPABLE`.
+    ///
+    /// If the object was built with debug info, the program is loaded with
+    /// its BTF func info and line info, so that the verifier log references
+    /// source lines. The program is loaded without them if the kernel
+    /// rejects the BTF of the object.
+    ///
+    /// When the verifier rejects the program, the error is
+    /// `LoadError::Verifier` with the verifier log.
+    pub fn load_with_flags(
+        &mut self,
+        kernel_version: u32,
+        license: String,
+        flags: u32,
+    ) -> Result<RawFd> {
+        self.check_license(&license)?;
+        let clicense = CString::new(license)?;
+        let mut prog_name = [0u8; 16];
+        // the kernel limits names to 15 characters and a few symbols
+        for (dst, src) in prog_name[..15].iter_mut().zip(
+            self.name
+                .bytes()
+                .filter(|b| b.is_ascii_alphanumeric() || *b == b'_' || *b == b'.'),
+        ) {
+            *dst = src;
+        }
+
+        let mut attr = ProgLoadAttr {
+            prog_type: self.kind.to_prog_type()?,
+            insn_cnt: self.code.len() as u32,
+            insns: self.code.as_ptr() as u64,
+            license: clicense.as_ptr() as u64,
+            kern_version: kernel_version,
+            prog_flags: flags,
+            prog_name,
+            expected_attach_type: self.expected_attach_type,
+            ..Default::default()
+        };
+        let btf_fd = match &self.btf {
+            Some(info) => load_btf(&info.btf).ok().map(|fd| {
+                attr.set_btf(fd, info);
+                fd
+            }),
+            None => None,
+        };
+
+        let mut fd = prog_load(&mut attr);
+        let mut log = String::new();
+        if fd.is_err() {
+            // load again to get the verifier log
+            let mut log_buf = vec![0u8; LOG_BUF_SIZE];
+            attr.log_level = 1;
+            attr.log_size = log_buf.len() as u32;
+            attr.log_buf = log_buf.as_mut_ptr() as u64;
+            fd = prog_load(&mut attr);
+            log = verifier_log(&log_buf);
+        }
+        if let Some(btf_fd) = btf_fd {
+            // the program keeps a reference to the BTF
+            unsafe { libc::close(btf_fd) };
+        }
+        let fd = fd.map_err(|_| verifier_error(&self.name, log))?;
+
+        self.fd = Some(fd);
+        Ok(fd)
+    }
+}
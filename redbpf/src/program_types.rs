@@ -0,0 +1,179 @@
+//! Registering additional program types.
+//!
+//! The program types supported out of the box are listed in `ProgramKind`.
+//! Other types can be registered at runtime by mapping an ELF section prefix
+//! to a kernel program type, and optionally to functions that attach and
+//! detach the programs. Programs in sections with a registered prefix are
+//! then loaded as `ProgramKind::Custom`, and attached with
+//! `Program::attach_custom`.
+//!
+//! `flow_dissector` programs are provided as a built in registered type.
+use std::os::unix::io::RawFd;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+
+use crate::{sys, LoadError, Program, ProgramKind, Result};
+
+/// Attaches or detaches the program `prog_fd` to or from `target`.
+pub type AttachFn = fn(prog_fd: RawFd, target: &str) -> Result<()>;
+
+/// A program type registered with `register_program_type`.
+#[derive(Debug, Clone, Copy)]
+pub struct ProgramType {
+    /// The ELF section prefix, eg. `flow_dissector` for
+    /// `flow_dissector/name`.
+    pub section: &'static str,
+    /// The kernel program type.
+    pub prog_type: bpf_sys::bpf_prog_type,
+    pub attach: Option<AttachFn>,
+    pub detach: Option<AttachFn>,
+}
+
+lazy_static! {
+    static ref REGISTRY: Mutex<Vec<ProgramType>> = Mutex::new(builtin_types());
+}
+
+fn builtin_types() -> Vec<ProgramType> {
+    vec![flow_dissector::PROGRAM_TYPE]
+}
+
+/// Registers a program type, replacing any type registered with the same
+/// section prefix.
+///
+/// Types built into `ProgramKind` take precedence over registered types.
+pub fn register_program_type(program_type: ProgramType) {
+    let mut registry = REGISTRY.lock().unwrap();
+    registry.retain(|t| t.section != program_type.section);
+    registry.push(program_type);
+}
+
+pub(crate) fn find_by_section(section: &str) -> Option<ProgramType> {
+    let registry = REGISTRY.lock().unwrap();
+    registry.iter().find(|t| t.section == section).cloned()
+}
+
+pub(crate) fn find_by_prog_type(prog_type: bpf_sys::bpf_prog_type) -> Option<ProgramType> {
+    let registry = REGISTRY.lock().unwrap();
+    registry.iter().find(|t| t.prog_type == prog_type).cloned()
+}
+
+// Program attach part of `union bpf_attr`.
+#[repr(C)]
+#[derive(Default)]
+pub(crate) struct ProgAttachAttr {
+    pub target_fd: u32,
+    pub attach_bpf_fd: u32,
+    pub attach_type: u32,
+    pub attach_flags: u32,
+}
+
+pub(crate) const BPF_PROG_ATTACH: u32 = 8;
+pub(crate) const BPF_PROG_DETACH: u32 = 9;
+
+pub(crate) fn prog_attach_syscall(cmd: u32, attr: &mut ProgAttachAttr) -> Result<()> {
+    unsafe { sys::bpf(cmd, attr) }?;
+
+    Ok(())
+}
+
+mod flow_dissector {
+    use std::fs::File;
+    use std::os::unix::io::{AsRawFd, RawFd};
+
+    use super::*;
+
+    const BPF_FLOW_DISSECTOR: u32 = 17;
+
+    pub const PROGRAM_TYPE: ProgramType = ProgramType {
+        section: "flow_dissector",
+        prog_type: bpf_sys::bpf_prog_type_BPF_PROG_TYPE_FLOW_DISSECTOR,
+        attach: Some(attach),
+        detach: Some(detach),
+    };
+
+    // `target` is the network namespace, eg. `/proc/self/ns/net`
+    fn attach(prog_fd: RawFd, target: &str) -> Result<()> {
+        let netns = File::open(target)?;
+        let mut attr = ProgAttachAttr {
+            target_fd: netns.as_raw_fd() as u32,
+            attach_bpf_fd: prog_fd as u32,
+            attach_type: BPF_FLOW_DISSECTOR,
+            ..Default::default()
+        };
+        prog_attach_syscall(BPF_PROG_ATTACH, &mut attr)
+    }
+
+    fn detach(prog_fd: RawFd, target: &str) -> Result<()> {
+        let netns = File::open(target)?;
+        let mut attr = ProgAttachAttr {
+            target_fd: netns.as_raw_fd() as u32,
+            attach_bpf_fd: prog_fd as u32,
+            attach_type: BPF_FLOW_DISSECTOR,
+            ..Default::default()
+        };
+        prog_attach_syscall(BPF_PROG_DETACH, &mut attr)
+    }
+}
+
+impl Program {
+    /// Attaches a program of a registered type to `target`, whose meaning
+    /// depends on the type.
+    ///
+    /// The program is detached with the detach function of the type when
+    /// it's dropped.
+    pub fn attach_custom(&mut self, target: &str) -> Result<()> {
+        let section = match self.kind {
+            ProgramKind::Custom(section) => section,
+            ref kind => {
+                return Err(LoadError::NotSupported(format!(
+                    "attach_custom on {:?} programs",
+                    kind
+                )))
+            }
+        };
+        let program_type = find_by_section(section)
+            .ok_or_else(|| LoadError::Section(section.to_string()))?;
+        let attach = program_type.attach.ok_or_else(|| {
+            LoadError::NotSupported(format!("attaching {} programs", section))
+        })?;
+        let fd = self.fd.ok_or(LoadError::BPF)?;
+        attach(fd, target)?;
+        self.attachments.push(crate::Attachment::Custom {
+            prog_fd: fd,
+            target: target.to_string(),
+            detach: program_type.detach,
+        });
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_register_program_type() {
+        register_program_type(ProgramType {
+            section: "test_lirc",
+            prog_type: bpf_sys::bpf_prog_type_BPF_PROG_TYPE_LIRC_MODE2,
+            attach: None,
+            detach: None,
+        });
+        let kind = ProgramKind::from_section("test_lirc").unwrap();
+        assert_eq!(kind, ProgramKind::Custom("test_lirc"));
+        assert_eq!(
+            kind.to_prog_type().unwrap(),
+            bpf_sys::bpf_prog_type_BPF_PROG_TYPE_LIRC_MODE2
+        );
+        // built in types can't be overridden
+        register_program_type(ProgramType {
+            section: "xdp",
+            prog_type: bpf_sys::bpf_prog_type_BPF_PROG_TYPE_LIRC_MODE2,
+            attach: None,
+            detach: None,
+        });
+        assert_eq!(ProgramKind::from_section("xdp").unwrap(), ProgramKind::XDP);
+    }
+}
@@ -0,0 +1,168 @@
+//! Reading and adjusting the buckets of `redbpf_probes::maps::RateLimiter`
+//! maps.
+//!
+//! ```rust
+//! use redbpf::{Module, RateLimiter};
+//!
+//! let code = std::fs::read("bpf.elf").unwrap();
+//! let module = Module::parse(&code).unwrap();
+//! let limits = RateLimiter::<u32>::new(module.map("limits").unwrap()).unwrap();
+//! // let 10.0.0.1 through at 10000 packets per second
+//! limits.set_limits(u32::from_be_bytes([10, 0, 0, 1]).to_be(), 10_000, 1000).unwrap();
+//! ```
+use std::io;
+use std::marker::PhantomData;
+
+use crate::cpus;
+use crate::{LoadError, Map, Result, VoidPtr};
+
+const NSEC_PER_SEC: u64 = 1_000_000_000;
+
+/// The state of the bucket of a key on a CPU, see `RateLimiter::buckets`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TokenBucket {
+    /// The available tokens, in billionths of a token.
+    pub tokens: u64,
+    /// The time of the last refill, in nanoseconds since boot.
+    pub last_ns: u64,
+    /// The number of tokens added per second.
+    pub rate: u64,
+    /// The maximum number of tokens, 0 if the bucket was never used on the
+    /// CPU.
+    pub burst: u64,
+}
+
+impl TokenBucket {
+    /// Returns the number of whole tokens available at the last refill.
+    pub fn available(&self) -> u64 {
+        self.tokens / NSEC_PER_SEC
+    }
+
+    fn set_limits(&mut self, rate: u64, burst: u64) {
+        let max = burst * NSEC_PER_SEC;
+        // uninitialized buckets start full
+        self.tokens = if self.burst == 0 {
+            max
+        } else {
+            self.tokens.min(max)
+        };
+        self.rate = rate;
+        self.burst = burst;
+    }
+}
+
+/// User space view of a rate limiter map keyed by `K`.
+///
+/// The map holds a bucket per key and per CPU, see the probe side
+/// `RateLimiter` for the caveats of per-CPU buckets.
+pub struct RateLimiter<'a, K> {
+    map: &'a Map,
+    _k: PhantomData<K>,
+}
+
+impl<'a, K: Copy> RateLimiter<'a, K> {
+    /// Checks that `map` is a rate limiter map with `K` keys.
+    pub fn new(map: &'a Map) -> Result<RateLimiter<'a, K>> {
+        if map.kind != bpf_sys::bpf_map_type_BPF_MAP_TYPE_LRU_PERCPU_HASH {
+            return Err(LoadError::InvalidMap(format!(
+                "map `{}' is not a rate limiter (type {})",
+                map.name, map.kind
+            )));
+        }
+        map.assert_layout::<K, TokenBucket>()?;
+
+        Ok(RateLimiter {
+            map,
+            _k: PhantomData,
+        })
+    }
+
+    /// Returns the buckets of `key` on every possible CPU, or `None` if the
+    /// key has no bucket.
+    pub fn buckets(&self, mut key: K) -> Result<Option<Vec<TokenBucket>>> {
+        let mut buckets = vec![TokenBucket::default(); cpus::get_possible()?.len()];
+        let ret = unsafe {
+            bpf_sys::bpf_lookup_elem(
+                self.map.fd,
+                &mut key as *mut K as VoidPtr,
+                buckets.as_mut_ptr() as VoidPtr,
+            )
+        };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::ENOENT) {
+                return Ok(None);
+            }
+            return Err(LoadError::IO(e));
+        }
+
+        Ok(Some(buckets))
+    }
+
+    /// Returns the number of tokens available for `key` on all the CPUs.
+    pub fn available(&self, key: K) -> Result<u64> {
+        let buckets = self.buckets(key)?.unwrap_or_default();
+        Ok(buckets.iter().map(TokenBucket::available).sum())
+    }
+
+    /// Sets the rate and burst of `key` on every CPU, creating full buckets
+    /// if the key has none.
+    ///
+    /// Buckets with more tokens than the new burst are capped.
+    pub fn set_limits(&self, mut key: K, rate: u64, burst: u64) -> Result<()> {
+        let mut buckets = match self.buckets(key)? {
+            Some(buckets) => buckets,
+            None => vec![TokenBucket::default(); cpus::get_possible()?.len()],
+        };
+        for bucket in buckets.iter_mut() {
+            bucket.set_limits(rate, burst);
+        }
+        let ret = unsafe {
+            bpf_sys::bpf_update_elem(
+                self.map.fd,
+                &mut key as *mut K as VoidPtr,
+                buckets.as_mut_ptr() as VoidPtr,
+                0,
+            )
+        };
+        if ret < 0 {
+            return Err(LoadError::IO(io::Error::last_os_error()));
+        }
+
+        Ok(())
+    }
+
+    /// Removes the buckets of `key`, which then gets the default rate and
+    /// burst of the probe and a full bucket.
+    pub fn reset(&self, mut key: K) -> Result<()> {
+        let ret = unsafe { bpf_sys::bpf_delete_elem(self.map.fd, &mut key as *mut K as VoidPtr) };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() != Some(libc::ENOENT) {
+                return Err(LoadError::IO(e));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_set_limits() {
+        let mut bucket = TokenBucket::default();
+        bucket.set_limits(100, 10);
+        assert_eq!(bucket.available(), 10);
+        assert_eq!((bucket.rate, bucket.burst), (100, 10));
+
+        bucket.set_limits(100, 5);
+        assert_eq!(bucket.available(), 5);
+        bucket.tokens = 2 * NSEC_PER_SEC;
+        bucket.set_limits(200, 20);
+        assert_eq!(bucket.available(), 2);
+    }
+}
@@ -0,0 +1,127 @@
+//! Populating maps before programs run.
+//!
+//! Maps often need seed data, eg. an allowlist, before programs use them.
+//! Inserting it after attaching the programs races with them, so the seed
+//! data is best inserted between `Module::parse`, which creates the maps,
+//! and attaching the programs:
+//!
+//! ```rust
+//! use redbpf::Module;
+//!
+//! let code = std::fs::read("bpf.elf").unwrap();
+//! let mut module = Module::parse(&code).unwrap();
+//! let allowed: Vec<([u8; 4], [u8; 1])> = vec![([10, 0, 0, 1], [1]), ([10, 0, 0, 2], [1])];
+//! module.seed_map("allowlist", allowed).unwrap();
+//! module.load().unwrap();
+//! ```
+use std::io;
+
+use crate::dump::is_percpu;
+use crate::{LoadError, Map, Module, Result, VoidPtr};
+
+// Checks that all the entries have the key and value sizes of the map.
+fn check_entry_sizes<K, V>(
+    name: &str,
+    key_size: usize,
+    value_size: usize,
+    entries: &[(K, V)],
+) -> Result<()>
+where
+    K: AsRef<[u8]>,
+    V: AsRef<[u8]>,
+{
+    for (i, (key, value)) in entries.iter().enumerate() {
+        let (key, value) = (key.as_ref(), value.as_ref());
+        if key.len() != key_size || value.len() != value_size {
+            return Err(LoadError::InvalidMap(format!(
+                "entry {} has a {} bytes key and a {} bytes value, map `{}' has {} bytes keys \
+                 and {} bytes values",
+                i,
+                key.len(),
+                value.len(),
+                name,
+                key_size,
+                value_size
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+impl Map {
+    /// Inserts `entries`, pairs of raw keys and values, in the map.
+    ///
+    /// The sizes of all the keys and values are checked against the map
+    /// definition before anything is inserted. Existing entries with the
+    /// same keys are overwritten. Returns the number of entries inserted.
+    /// Per-CPU maps are not supported.
+    pub fn seed<I, K, V>(&self, entries: I) -> Result<usize>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        if is_percpu(self.config.type_) {
+            return Err(LoadError::NotSupported(format!(
+                "seeding per-CPU map `{}'",
+                self.name
+            )));
+        }
+        let entries: Vec<(K, V)> = entries.into_iter().collect();
+        check_entry_sizes(
+            &self.name,
+            self.config.key_size as usize,
+            self.config.value_size as usize,
+            &entries,
+        )?;
+
+        for (key, value) in entries.iter() {
+            let ret = unsafe {
+                bpf_sys::bpf_update_elem(
+                    self.fd,
+                    key.as_ref().as_ptr() as VoidPtr,
+                    value.as_ref().as_ptr() as VoidPtr,
+                    0,
+                )
+            };
+            if ret < 0 {
+                return Err(LoadError::IO(io::Error::last_os_error()));
+            }
+        }
+
+        Ok(entries.len())
+    }
+}
+
+impl Module {
+    /// Inserts `entries` in the map `name`, see `Map::seed`.
+    ///
+    /// Call it before attaching the programs, so that they never see the
+    /// map without its seed data.
+    pub fn seed_map<I, K, V>(&self, name: &str, entries: I) -> Result<usize>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        self.map(name)
+            .ok_or_else(|| LoadError::MapNotFound(name.to_string()))?
+            .seed(entries)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_check_entry_sizes() {
+        let entries = vec![([1u8, 2, 3, 4], [1u8]), ([5, 6, 7, 8], [0])];
+        assert!(check_entry_sizes("allowlist", 4, 1, &entries).is_ok());
+        assert!(check_entry_sizes("allowlist", 4, 2, &entries).is_err());
+
+        let entries = vec![(vec![1u8, 2, 3, 4], vec![1u8]), (vec![5, 6], vec![0])];
+        assert!(check_entry_sizes("allowlist", 4, 1, &entries).is_err());
+    }
+}
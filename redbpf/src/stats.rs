@@ -0,0 +1,147 @@
+//! Program run time statistics.
+//!
+//! The kernel counts how many times each program ran and how long it took,
+//! but only while statistics are enabled, as they slow down every run. They
+//! can be enabled system wide with the `kernel.bpf_stats_enabled` sysctl, or
+//! for as long as a `StatsGuard` returned by `enable_stats` is alive:
+//!
+//! ```rust
+//! use redbpf::{enable_stats, Module};
+//!
+//! let code = std::fs::read("bpf.elf").unwrap();
+//! let mut module = Module::parse(&code).unwrap();
+//! let prog = module.program_mut("block_port_80").unwrap();
+//! prog.load(module.version, module.license.clone()).unwrap();
+//! prog.attach_xdp("eth0").unwrap();
+//!
+//! let _stats = enable_stats().unwrap();
+//! std::thread::sleep(std::time::Duration::from_secs(1));
+//! let stats = prog.stats().unwrap();
+//! println!("{} runs, {}ns", stats.run_cnt, stats.run_time_ns);
+//! ```
+//!
+//! Enabling statistics requires `CAP_SYS_ADMIN`.
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+
+use crate::{sys, LoadError, Program, Result, VoidPtr};
+
+const BPF_ENABLE_STATS: u32 = 32;
+const BPF_STATS_RUN_TIME: u32 = 0;
+
+#[repr(C)]
+struct EnableStatsAttr {
+    type_: u32,
+}
+
+// Prefix of `struct bpf_prog_info` up to the statistics, which were added in
+// Linux 5.1. The fields before them are not needed here.
+#[repr(C)]
+struct ProgStatsInfo {
+    _head: [u64; 24],
+    run_time_ns: u64,
+    run_cnt: u64,
+}
+
+/// Run time statistics of a loaded program.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ProgStats {
+    /// The number of times the program ran.
+    pub run_cnt: u64,
+    /// The cumulative time spent running the program, in nanoseconds.
+    pub run_time_ns: u64,
+}
+
+impl ProgStats {
+    /// Returns the average duration of a run in nanoseconds, if the program
+    /// ran at all.
+    pub fn avg_run_time_ns(&self) -> Option<u64> {
+        self.run_time_ns.checked_div(self.run_cnt)
+    }
+}
+
+/// Keeps run time statistics enabled until it is dropped.
+pub struct StatsGuard {
+    fd: RawFd,
+}
+
+impl Drop for StatsGuard {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/// Enables run time statistics for all programs with `BPF_ENABLE_STATS`.
+///
+/// Statistics stay enabled until the returned guard is dropped. On kernels
+/// older than 5.8 this returns `LoadError::NotSupported`, and the
+/// `kernel.bpf_stats_enabled` sysctl has to be used instead.
+pub fn enable_stats() -> Result<StatsGuard> {
+    let mut attr = EnableStatsAttr {
+        type_: BPF_STATS_RUN_TIME,
+    };
+    let fd = unsafe { sys::bpf(BPF_ENABLE_STATS, &mut attr) }.map_err(|e| {
+        match e.raw_os_error() {
+            // unknown command
+            Some(libc::EINVAL) => {
+                LoadError::NotSupported("BPF_ENABLE_STATS requires Linux 5.8".to_string())
+            }
+            _ => LoadError::IO(e),
+        }
+    })?;
+
+    Ok(StatsGuard { fd })
+}
+
+pub(crate) fn prog_stats(fd: RawFd) -> Result<ProgStats> {
+    let mut info = unsafe { mem::zeroed::<ProgStatsInfo>() };
+    let mut len = mem::size_of::<ProgStatsInfo>() as u32;
+    let ret = unsafe {
+        bpf_sys::bpf_obj_get_info(fd, &mut info as *mut ProgStatsInfo as VoidPtr, &mut len)
+    };
+    if ret < 0 {
+        return Err(LoadError::IO(io::Error::last_os_error()));
+    }
+    // the kernel truncates the info to the size it knows about
+    if (len as usize) < mem::size_of::<ProgStatsInfo>() {
+        return Err(LoadError::NotSupported(
+            "program statistics require Linux 5.1".to_string(),
+        ));
+    }
+
+    Ok(ProgStats {
+        run_cnt: info.run_cnt,
+        run_time_ns: info.run_time_ns,
+    })
+}
+
+impl Program {
+    /// Returns the run time statistics of the loaded program.
+    ///
+    /// The counters only increase while statistics are enabled, see
+    /// `enable_stats`.
+    pub fn stats(&self) -> Result<ProgStats> {
+        let fd = self.fd.ok_or(LoadError::BPF)?;
+        prog_stats(fd)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_avg_run_time() {
+        let stats = ProgStats {
+            run_cnt: 4,
+            run_time_ns: 1000,
+        };
+        assert_eq!(stats.avg_run_time_ns(), Some(250));
+        let stats = ProgStats {
+            run_cnt: 0,
+            run_time_ns: 0,
+        };
+        assert_eq!(stats.avg_run_time_ns(), None);
+    }
+}
@@ -0,0 +1,110 @@
+//! Relocation of the references to `.text` functions.
+//!
+//! Functions that are not inlined, eg. the callbacks of
+//! `bpf_timer_set_callback` and the targets of BPF-to-BPF calls, are
+//! compiled to the `.text` section. The kernel expects them to be part of the
+//! program using them, so `.text` is appended to the programs referencing it
+//! and the relocated `call` and `ld_imm64` instructions are patched with the
+//! offset of the function relative to the next instruction.
+use bpf_sys::bpf_insn;
+use goblin::elf::Sym;
+use std::mem;
+
+use crate::{LoadError, Program, Result};
+
+const BPF_LD_IMM64: u8 = 0x18;
+const BPF_CALL: u8 = 0x85;
+const BPF_PSEUDO_CALL: u8 = 1;
+const BPF_PSEUDO_FUNC: u8 = 4;
+
+impl Program {
+    /// Appends the `.text` functions to the program, returning the index of
+    /// the first appended instruction.
+    pub(crate) fn append_text(&mut self, text: &[bpf_insn]) -> usize {
+        let start = self.code.len();
+        self.code.extend_from_slice(text);
+        self.code_bytes = (self.code.len() * mem::size_of::<bpf_insn>()) as i32;
+
+        start
+    }
+}
+
+/// Patches the instruction at `insn_idx` to reference the `.text` function
+/// of `sym`, the `.text` functions starting at `text_start` in `code`.
+///
+/// `call` instructions keep their `BPF_PSEUDO_CALL` source register while
+/// `ld_imm64` ones, which load the address of a callback, are marked
+/// `BPF_PSEUDO_FUNC`.
+pub(crate) fn relocate(
+    code: &mut [bpf_insn],
+    insn_idx: usize,
+    sym: &Sym,
+    text_start: usize,
+) -> Result<()> {
+    let insn_size = mem::size_of::<bpf_insn>() as i64;
+    let insn = code.get_mut(insn_idx).ok_or(LoadError::Reloc)?;
+    let sym_idx = sym.st_value as i64 / insn_size;
+    // the index of the function in .text, the immediate holding the addend
+    // of section symbols
+    let func_idx = match insn.code {
+        BPF_LD_IMM64 => sym_idx + insn.imm as i64 / insn_size,
+        BPF_CALL if insn.src_reg() == BPF_PSEUDO_CALL => sym_idx + insn.imm as i64 + 1,
+        _ => return Err(LoadError::Reloc),
+    };
+    if insn.code == BPF_LD_IMM64 {
+        insn.set_src_reg(BPF_PSEUDO_FUNC);
+    }
+    insn.imm = (text_start as i64 + func_idx - insn_idx as i64 - 1) as i32;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn insn(code: u8, src_reg: u8, imm: i32) -> bpf_insn {
+        let mut insn: bpf_insn = unsafe { mem::zeroed() };
+        insn.code = code;
+        insn.set_src_reg(src_reg);
+        insn.imm = imm;
+        insn
+    }
+
+    fn func(st_value: u64) -> Sym {
+        Sym {
+            st_value,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_relocate_ld_imm64() {
+        let mut code = vec![insn(BPF_LD_IMM64, 0, 0), insn(0, 0, 0), insn(0x95, 0, 0)];
+        // the callback is the second function of .text, appended at 3
+        relocate(&mut code, 0, &func(16), 3).unwrap();
+        assert_eq!(code[0].src_reg(), BPF_PSEUDO_FUNC);
+        assert_eq!(code[0].imm, 3 + 2 - 0 - 1);
+    }
+
+    #[test]
+    fn test_relocate_section_symbol() {
+        let mut code = vec![insn(0x95, 0, 0), insn(BPF_LD_IMM64, 0, 8), insn(0, 0, 0)];
+        relocate(&mut code, 1, &func(0), 3).unwrap();
+        assert_eq!(code[1].imm, 3 + 1 - 1 - 1);
+    }
+
+    #[test]
+    fn test_relocate_call() {
+        let mut code = vec![insn(BPF_CALL, BPF_PSEUDO_CALL, -1), insn(0x95, 0, 0)];
+        relocate(&mut code, 0, &func(8), 2).unwrap();
+        assert_eq!(code[0].src_reg(), BPF_PSEUDO_CALL);
+        assert_eq!(code[0].imm, 2 + 1 - 0 - 1);
+    }
+
+    #[test]
+    fn test_relocate_helper_call() {
+        let mut code = vec![insn(BPF_CALL, 0, 1)];
+        assert!(relocate(&mut code, 0, &func(0), 1).is_err());
+    }
+}
@@ -0,0 +1,127 @@
+//! Kernel symbol lookups.
+//!
+//! Attaching a kprobe to a function that doesn't exist, or that can't be
+//! traced, fails with an opaque error. The helpers in this module check a
+//! symbol against `/proc/kallsyms` and the kprobes blacklist so that a
+//! meaningful error can be reported instead.
+use std::fs;
+
+use crate::{LoadError, Result};
+
+const KALLSYMS: &str = "/proc/kallsyms";
+const KPROBES_BLACKLIST: &str = "/sys/kernel/debug/kprobes/blacklist";
+
+/// Checks that `name` is a kernel symbol that kprobes can be attached to.
+///
+/// Returns `LoadError::SymbolNotFound` if the symbol is not present in
+/// `/proc/kallsyms`, and `LoadError::SymbolNotTraceable` if the symbol is
+/// in the kprobes blacklist.
+///
+/// `available_filter_functions` is not checked: it only lists the functions
+/// ftrace can trace, and kprobes can also be attached to `notrace` functions.
+///
+/// Checks against files that can't be read, eg. because `debugfs` is not
+/// mounted, are skipped.
+pub fn check_kprobe_symbol(name: &str) -> Result<()> {
+    if let Ok(kallsyms) = fs::read_to_string(KALLSYMS) {
+        if !kallsyms_contains(&kallsyms, name) {
+            return Err(LoadError::SymbolNotFound(name.to_string()));
+        }
+    }
+
+    if let Ok(blacklist) = fs::read_to_string(KPROBES_BLACKLIST) {
+        if blacklist_contains(&blacklist, name) {
+            return Err(LoadError::SymbolNotTraceable(name.to_string()));
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the size of the kernel function `name` in bytes.
+///
+/// The size is computed from the address of the next symbol in
+/// `/proc/kallsyms`. Returns `None` if the addresses are hidden, which is
+/// the case unless the process has `CAP_SYSLOG`, or if `/proc/kallsyms`
+/// can't be read, eg. in some containers.
+pub fn kernel_function_size(name: &str) -> Result<Option<u64>> {
+    let kallsyms = match fs::read_to_string(KALLSYMS) {
+        Ok(kallsyms) => kallsyms,
+        Err(_) => return Ok(None),
+    };
+    function_size(&kallsyms, name).ok_or_else(|| LoadError::SymbolNotFound(name.to_string()))
+}
+
+fn function_size(kallsyms: &str, name: &str) -> Option<Option<u64>> {
+    let mut addrs: Vec<u64> = Vec::new();
+    let mut start = None;
+    for (addr, sym) in kallsyms.lines().filter_map(parse_kallsyms_line) {
+        if sym == name && start.is_none() {
+            start = Some(addr);
+        }
+        addrs.push(addr);
+    }
+    let start = start?;
+    if start == 0 {
+        return Some(None);
+    }
+
+    Some(addrs.iter().filter(|a| **a > start).min().map(|end| end - start))
+}
+
+// ffffffff81000000 T _stext
+// ffffffffc0a3f000 t foo_init	[foo]
+fn parse_kallsyms_line(line: &str) -> Option<(u64, &str)> {
+    let mut parts = line.split_whitespace();
+    let addr = u64::from_str_radix(parts.next()?, 16).ok()?;
+    let _kind = parts.next()?;
+    let name = parts.next()?;
+    Some((addr, name))
+}
+
+fn kallsyms_contains(kallsyms: &str, name: &str) -> bool {
+    kallsyms
+        .lines()
+        .filter_map(parse_kallsyms_line)
+        .any(|(_, sym)| sym == name)
+}
+
+// 0xffffffff81000000-0xffffffff81000010	foo
+fn blacklist_contains(blacklist: &str, name: &str) -> bool {
+    blacklist
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .any(|sym| sym == name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_kallsyms() {
+        let kallsyms = "ffffffff81000000 T _stext\n\
+                        ffffffff81001000 T __x64_sys_clone\n\
+                        ffffffffc0a3f000 t foo_init\t[foo]\n";
+        assert!(kallsyms_contains(kallsyms, "__x64_sys_clone"));
+        assert!(kallsyms_contains(kallsyms, "foo_init"));
+        assert!(!kallsyms_contains(kallsyms, "__x64_sys_clone3"));
+        assert_eq!(
+            parse_kallsyms_line("ffffffff81001000 T __x64_sys_clone"),
+            Some((0xffff_ffff_8100_1000, "__x64_sys_clone"))
+        );
+        assert_eq!(function_size(kallsyms, "_stext"), Some(Some(0x1000)));
+        assert_eq!(function_size(kallsyms, "foo_init"), Some(None));
+        assert_eq!(function_size(kallsyms, "bar"), None);
+
+        let hidden = "0000000000000000 T _stext\n0000000000000000 T do_fork\n";
+        assert_eq!(function_size(hidden, "do_fork"), Some(None));
+    }
+
+    #[test]
+    fn test_tracing_lists() {
+        let blacklist = "0xffffffff81000000-0xffffffff81000010\tdo_int3\n";
+        assert!(blacklist_contains(blacklist, "do_int3"));
+        assert!(!blacklist_contains(blacklist, "do_fork"));
+    }
+}
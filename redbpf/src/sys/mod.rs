@@ -1 +1,24 @@
 pub mod perf;
+
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+
+/// Calls the `bpf` syscall with the command `cmd`, `attr` being the part of
+/// `union bpf_attr` the command reads and writes.
+///
+/// Returns the non negative result of the command, eg. the fd of a created
+/// object.
+///
+/// # Safety
+///
+/// `attr` must have the layout the kernel expects for `cmd`, and the
+/// pointers it holds must be valid for the sizes it gives.
+pub(crate) unsafe fn bpf<T>(cmd: u32, attr: &mut T) -> io::Result<RawFd> {
+    let ret = libc::syscall(libc::SYS_bpf, cmd, attr as *mut T, mem::size_of::<T>());
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret as RawFd)
+    }
+}
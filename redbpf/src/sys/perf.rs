@@ -10,6 +10,8 @@ pub const PERF_EVENT_IOC_ENABLE: libc::c_ulong = 9216;
 #[cfg(target_env = "gnu")]
 pub const PERF_EVENT_IOC_DISABLE: libc::c_ulong = 9217;
 #[cfg(target_env = "gnu")]
+pub const PERF_EVENT_IOC_REFRESH: libc::c_ulong = 9218;
+#[cfg(target_env = "gnu")]
 pub const PERF_EVENT_IOC_SET_BPF: libc::c_ulong = 0x40042408;
 
 #[cfg(target_env = "musl")]
@@ -17,6 +19,8 @@ pub const PERF_EVENT_IOC_ENABLE: i32 = 9216;
 #[cfg(target_env = "musl")]
 pub const PERF_EVENT_IOC_DISABLE: i32 = 9217;
 #[cfg(target_env = "musl")]
+pub const PERF_EVENT_IOC_REFRESH: i32 = 9218;
+#[cfg(target_env = "musl")]
 pub const PERF_EVENT_IOC_SET_BPF: i32 = 0x40042408;
 
 #[repr(C)]
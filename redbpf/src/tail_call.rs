@@ -0,0 +1,154 @@
+//! Populating program arrays for tail calls.
+//!
+//! Probes declare the programs stored in their `ProgramArray` maps with the
+//! `tail_call!` macro of `redbpf-macros`, eg. `tail_call!("dispatch", 0,
+//! "parse_ipv4")`. The declarations end up in `Module::tail_calls`, and
+//! `Module::load` stores the programs in the maps once they are all loaded:
+//!
+//! ```rust
+//! use redbpf::Module;
+//!
+//! let code = std::fs::read("bpf.elf").unwrap();
+//! let mut module = Module::parse(&code).unwrap();
+//! module.load().unwrap();
+//! ```
+//!
+//! Tail calls can also be declared from user space with
+//! `Module::add_tail_call`, or set directly with `Map::set_prog`.
+use std::io;
+use std::str;
+
+use crate::error::{LoadError, Result};
+use crate::{Map, Module, Program, VoidPtr};
+
+/// A program stored in a `BPF_MAP_TYPE_PROG_ARRAY` map.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TailCall {
+    /// The name of the program array.
+    pub map: String,
+    /// The index of the program in the array.
+    pub index: u32,
+    /// The name of the program.
+    pub program: String,
+}
+
+// Parses the `tail_calls` section, made of NUL terminated `map/index/program`
+// entries.
+pub(crate) fn parse_tail_calls(section: &[u8]) -> Result<Vec<TailCall>> {
+    section
+        .split(|b| *b == 0)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let invalid = || LoadError::Section(format!("tail_calls: invalid entry {:?}", entry));
+            let entry = str::from_utf8(entry).map_err(|_| invalid())?;
+            let mut parts = entry.splitn(3, '/');
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some(map), Some(index), Some(program)) => Ok(TailCall {
+                    map: map.to_string(),
+                    index: index.parse().map_err(|_| invalid())?,
+                    program: program.to_string(),
+                }),
+                _ => Err(invalid()),
+            }
+        })
+        .collect()
+}
+
+impl Map {
+    /// Stores `program` at `index` of a `BPF_MAP_TYPE_PROG_ARRAY` map.
+    ///
+    /// The program must be loaded, and be of the same type as the programs
+    /// tail calling into it.
+    pub fn set_prog(&self, mut index: u32, program: &Program) -> Result<()> {
+        if self.kind != bpf_sys::bpf_map_type_BPF_MAP_TYPE_PROG_ARRAY {
+            return Err(LoadError::InvalidMap(format!(
+                "map `{}' is not a program array (type {})",
+                self.name, self.kind
+            )));
+        }
+        let mut fd = program
+            .fd
+            .ok_or_else(|| LoadError::ProgramNotLoaded(program.name.clone()))?;
+        let ret = unsafe {
+            bpf_sys::bpf_update_elem(
+                self.fd,
+                &mut index as *mut u32 as VoidPtr,
+                &mut fd as *mut i32 as VoidPtr,
+                0,
+            )
+        };
+        if ret < 0 {
+            return Err(LoadError::IO(io::Error::last_os_error()));
+        }
+
+        Ok(())
+    }
+}
+
+impl Module {
+    /// Declares that `program` must be stored at `index` of the program array
+    /// `map` when the module is loaded with `Module::load`.
+    pub fn add_tail_call(&mut self, map: &str, index: u32, program: &str) -> &mut Self {
+        self.tail_calls.push(TailCall {
+            map: map.to_string(),
+            index,
+            program: program.to_string(),
+        });
+        self
+    }
+
+    /// Loads all the programs, then stores the programs of `tail_calls` in
+    /// their program arrays.
+    pub fn load(&mut self) -> Result<()> {
+        for prog in self.programs.iter_mut().filter(|p| !p.is_loaded()) {
+            prog.load(self.version, self.license.clone())?;
+        }
+        self.populate_tail_calls()
+    }
+
+    /// Stores the programs of `tail_calls` in their program arrays.
+    ///
+    /// This is done by `Module::load`, call it directly when loading the
+    /// programs individually, eg. with custom flags. All the programs of
+    /// `tail_calls` must be loaded.
+    pub fn populate_tail_calls(&self) -> Result<()> {
+        for call in self.tail_calls.iter() {
+            let map = self
+                .map(&call.map)
+                .ok_or_else(|| LoadError::MapNotFound(call.map.clone()))?;
+            let program = self
+                .program(&call.program)
+                .ok_or_else(|| LoadError::ProgramNotFound(call.program.clone()))?;
+            map.set_prog(call.index, program)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_tail_calls() {
+        let calls = parse_tail_calls(b"dispatch/0/parse_ipv4\0dispatch/12/parse_ipv6\0\0").unwrap();
+        assert_eq!(
+            calls,
+            vec![
+                TailCall {
+                    map: "dispatch".to_string(),
+                    index: 0,
+                    program: "parse_ipv4".to_string(),
+                },
+                TailCall {
+                    map: "dispatch".to_string(),
+                    index: 12,
+                    program: "parse_ipv6".to_string(),
+                },
+            ]
+        );
+        assert!(parse_tail_calls(b"dispatch/parse_ipv4\0").is_err());
+        assert!(parse_tail_calls(b"dispatch/x/parse_ipv4\0").is_err());
+    }
+}
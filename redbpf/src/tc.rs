@@ -0,0 +1,44 @@
+//! Managing the traffic control (`tc`) classifiers of interfaces.
+//!
+//! Classifiers are attached to the clsact qdisc of an interface, usually
+//! with the `tc` tool, and stay attached after the process that loaded them
+//! exits. `detach` removes them, eg. to clean up after a crashed process:
+//!
+//! ```no_run
+//! use redbpf::tc;
+//!
+//! tc::detach("eth0").unwrap();
+//! ```
+use crate::{if_nametoindex, netlink, LoadError, Result};
+
+/// Detaches the BPF classifiers attached to the ingress and egress hooks of
+/// `iface`, whether they were attached by this process or not.
+///
+/// Filters of other kinds and the qdisc itself are left alone. Does nothing
+/// if no BPF classifier is attached, or if the interface has no clsact
+/// qdisc.
+pub fn detach(iface: &str) -> Result<()> {
+    let ifindex = if_nametoindex(iface)?;
+    let iface_err = |e| LoadError::Interface(iface.to_string(), e);
+    for parent in &[netlink::TC_H_INGRESS, netlink::TC_H_EGRESS] {
+        let infos = match netlink::bpf_filters(ifindex, *parent) {
+            Ok(infos) => infos,
+            Err(ref e) if is_missing(e) => continue,
+            Err(e) => return Err(iface_err(e)),
+        };
+        for info in infos {
+            match netlink::del_bpf_filters(ifindex, *parent, info) {
+                // removed concurrently
+                Err(ref e) if is_missing(e) => {}
+                res => res.map_err(iface_err)?,
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Depending on the kernel, a missing qdisc or filter is ENOENT or EINVAL.
+fn is_missing(e: &std::io::Error) -> bool {
+    [Some(libc::ENOENT), Some(libc::EINVAL)].contains(&e.raw_os_error())
+}
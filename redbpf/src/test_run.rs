@@ -0,0 +1,169 @@
+//! Running programs on synthetic input with `BPF_PROG_TEST_RUN`.
+//!
+//! This makes it possible to unit test XDP and socket programs without
+//! attaching them to a live interface:
+//!
+//! ```rust
+//! use redbpf::{Module, XdpAction};
+//!
+//! let code = std::fs::read("bpf.elf").unwrap();
+//! let mut module = Module::parse(&code).unwrap();
+//! let prog = module.program_mut("block_port_80").unwrap();
+//! prog.load(module.version, module.license.clone()).unwrap();
+//!
+//! let packet = [0u8; 64];
+//! let result = prog.test_run(&packet, 1).unwrap();
+//! assert_eq!(result.xdp_action(), Some(XdpAction::Pass));
+//! ```
+use std::os::unix::io::RawFd;
+use std::time::Duration;
+
+use crate::{sys, LoadError, Result};
+
+// Test run part of `union bpf_attr`. The kernel zero-extends shorter attrs.
+#[repr(C)]
+#[derive(Default)]
+struct TestRunAttr {
+    prog_fd: u32,
+    retval: u32,
+    data_size_in: u32,
+    data_size_out: u32,
+    data_in: u64,
+    data_out: u64,
+    repeat: u32,
+    duration: u32,
+}
+
+/// Actions returned by XDP programs.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum XdpAction {
+    Aborted,
+    Drop,
+    Pass,
+    Tx,
+    Redirect,
+}
+
+impl XdpAction {
+    pub fn from_retval(retval: u32) -> Option<XdpAction> {
+        use XdpAction::*;
+        match retval {
+            0 => Some(Aborted),
+            1 => Some(Drop),
+            2 => Some(Pass),
+            3 => Some(Tx),
+            4 => Some(Redirect),
+            _ => None,
+        }
+    }
+}
+
+/// The result of running a program with `Program::test_run`.
+#[derive(Debug)]
+pub struct TestRun {
+    /// The value returned by the program.
+    pub retval: u32,
+    /// The packet data after the program ran.
+    pub data: Vec<u8>,
+    /// The average duration of a single run.
+    pub duration: Duration,
+}
+
+impl TestRun {
+    /// Returns the action returned by an XDP program.
+    pub fn xdp_action(&self) -> Option<XdpAction> {
+        XdpAction::from_retval(self.retval)
+    }
+}
+
+fn test_run_attr(fd: RawFd, data: &[u8], out: &mut [u8], repeat: u32) -> TestRunAttr {
+    TestRunAttr {
+        prog_fd: fd as u32,
+        data_size_in: data.len() as u32,
+        data_size_out: out.len() as u32,
+        data_in: data.as_ptr() as u64,
+        data_out: out.as_mut_ptr() as u64,
+        repeat,
+        ..Default::default()
+    }
+}
+
+// Builds the result from the attr filled in by the kernel.
+fn test_run_result(attr: &TestRunAttr, mut out: Vec<u8>) -> TestRun {
+    out.truncate(attr.data_size_out as usize);
+    TestRun {
+        retval: attr.retval,
+        data: out,
+        duration: Duration::from_nanos(attr.duration as u64),
+    }
+}
+
+pub(crate) fn test_run(fd: RawFd, data: &[u8], repeat: u32) -> Result<TestRun> {
+    // leave room for programs that grow the packet
+    let mut out = vec![0u8; data.len() + 4096];
+    let mut attr = test_run_attr(fd, data, &mut out, repeat);
+
+    unsafe { sys::bpf(bpf_sys::bpf_cmd_BPF_PROG_TEST_RUN, &mut attr) }?;
+
+    Ok(test_run_result(&attr, out))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::mem;
+
+    #[test]
+    fn test_attr_layout() {
+        // offsets of the test fields in `union bpf_attr`
+        let attr = TestRunAttr::default();
+        let base = &attr as *const _ as usize;
+        assert_eq!(&attr.retval as *const _ as usize - base, 4);
+        assert_eq!(&attr.data_size_in as *const _ as usize - base, 8);
+        assert_eq!(&attr.data_size_out as *const _ as usize - base, 12);
+        assert_eq!(&attr.data_in as *const _ as usize - base, 16);
+        assert_eq!(&attr.data_out as *const _ as usize - base, 24);
+        assert_eq!(&attr.repeat as *const _ as usize - base, 32);
+        assert_eq!(&attr.duration as *const _ as usize - base, 36);
+        assert_eq!(mem::size_of::<TestRunAttr>(), 40);
+    }
+
+    #[test]
+    fn test_run_attr_buffers() {
+        let data = [1u8, 2, 3];
+        let mut out = vec![0u8; 16];
+        let attr = test_run_attr(7, &data, &mut out, 10);
+        assert_eq!(attr.prog_fd, 7);
+        assert_eq!(attr.data_size_in, 3);
+        assert_eq!(attr.data_in, data.as_ptr() as u64);
+        assert_eq!(attr.data_size_out, 16);
+        assert_eq!(attr.data_out, out.as_ptr() as u64);
+        assert_eq!(attr.repeat, 10);
+        assert_eq!(attr.retval, 0);
+        assert_eq!(attr.duration, 0);
+    }
+
+    #[test]
+    fn test_run_result_parsing() {
+        let attr = TestRunAttr {
+            retval: 2,
+            data_size_out: 3,
+            duration: 1500,
+            ..Default::default()
+        };
+        let result = test_run_result(&attr, vec![1, 2, 3, 0, 0, 0]);
+        assert_eq!(result.retval, 2);
+        assert_eq!(result.data, vec![1, 2, 3]);
+        assert_eq!(result.duration, Duration::from_nanos(1500));
+        assert_eq!(result.xdp_action(), Some(XdpAction::Pass));
+    }
+
+    #[test]
+    fn test_xdp_action_from_retval() {
+        assert_eq!(XdpAction::from_retval(0), Some(XdpAction::Aborted));
+        assert_eq!(XdpAction::from_retval(1), Some(XdpAction::Drop));
+        assert_eq!(XdpAction::from_retval(3), Some(XdpAction::Tx));
+        assert_eq!(XdpAction::from_retval(4), Some(XdpAction::Redirect));
+        assert_eq!(XdpAction::from_retval(5), None);
+    }
+}
@@ -0,0 +1,410 @@
+//! Attaching uprobes to the functions of user space binaries.
+//!
+//! A uprobe can trace every process running a binary or a library, or a
+//! single process:
+//!
+//! ```rust
+//! use redbpf::Module;
+//! use std::path::Path;
+//!
+//! let code = std::fs::read("bpf.elf").unwrap();
+//! let mut module = Module::parse(&code).unwrap();
+//! let prog = module.program_mut("readline").unwrap();
+//! prog.load(module.version, module.license.clone()).unwrap();
+//! // every bash process
+//! prog.attach_uprobe(Path::new("/bin/bash"), "readline", None)
+//!     .unwrap();
+//! // only the process 1234, through the libreadline it has mapped
+//! prog.attach_uprobe(Path::new("libreadline"), "readline", Some(1234))
+//!     .unwrap();
+//! ```
+//!
+//! Uprobes are placed at a file offset, so the load address of position
+//! independent executables and libraries doesn't matter when attaching.
+//! `resolve_symbol` gives the file offset of a symbol, and `runtime_address`
+//! its address in a running process.
+use std::ffi::CString;
+use std::fs;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+
+use goblin::elf::program_header::PT_LOAD;
+use goblin::elf::section_header::SHN_UNDEF;
+use goblin::elf::sym::STB_WEAK;
+use goblin::elf::Elf;
+
+use crate::{Attachment, LoadError, Program, ProgramKind, Result};
+
+// A file backed mapping of /proc/<pid>/maps.
+#[derive(Debug, PartialEq, Eq)]
+struct Mapping<'a> {
+    start: u64,
+    end: u64,
+    offset: u64,
+    path: &'a str,
+}
+
+fn parse_maps_line(line: &str) -> Option<Mapping<'_>> {
+    let mut fields = line.split_whitespace();
+    let mut range = fields.next()?.splitn(2, '-');
+    let start = u64::from_str_radix(range.next()?, 16).ok()?;
+    let end = u64::from_str_radix(range.next()?, 16).ok()?;
+    let _perms = fields.next()?;
+    let offset = u64::from_str_radix(fields.next()?, 16).ok()?;
+    let _dev = fields.next()?;
+    let _inode = fields.next()?;
+    let path = fields.next()?;
+    if !path.starts_with('/') {
+        return None;
+    }
+
+    Some(Mapping {
+        start,
+        end,
+        offset,
+        path,
+    })
+}
+
+// Whether the library name `name`, eg. `libc`, designates the file `path`,
+// eg. `/usr/lib/libc.so.6` or `/lib/libc-2.31.so`.
+fn is_library(path: &str, name: &str) -> bool {
+    let file = path.rsplit('/').next().unwrap_or(path);
+    file == name
+        || (file.starts_with(name)
+            && (file[name.len()..].starts_with('.') || file[name.len()..].starts_with('-')))
+}
+
+// Returns the path, as seen by the process, of the file mapped by `maps`
+// that `binary` designates. Bare names are matched against the libraries.
+fn find_mapped<'a>(maps: &'a str, binary: &Path) -> Option<&'a str> {
+    let binary = binary.to_str()?;
+    maps.lines().filter_map(parse_maps_line).find_map(|m| {
+        let found = if binary.contains('/') {
+            m.path == binary
+        } else {
+            is_library(m.path, binary)
+        };
+        if found {
+            Some(m.path)
+        } else {
+            None
+        }
+    })
+}
+
+// Returns the address `file_offset` of the file `path` is mapped at.
+fn mapped_address(maps: &str, path: &str, file_offset: u64) -> Option<u64> {
+    maps.lines()
+        .filter_map(parse_maps_line)
+        .find(|m| {
+            m.path == path && m.offset <= file_offset && file_offset < m.offset + (m.end - m.start)
+        })
+        .map(|m| m.start + file_offset - m.offset)
+}
+
+// Converts the virtual address `vaddr` to an offset in the file, using the
+// loadable segment that contains it. For executables that are not position
+// independent the two differ by the fixed load address.
+fn vaddr_to_offset(elf: &Elf<'_>, vaddr: u64) -> Option<u64> {
+    elf.program_headers
+        .iter()
+        .find(|ph| ph.p_type == PT_LOAD && ph.p_vaddr <= vaddr && vaddr < ph.p_vaddr + ph.p_filesz)
+        .map(|ph| vaddr - ph.p_vaddr + ph.p_offset)
+}
+
+// Splits the version from symbol names like `memcpy@@GLIBC_2.14`, which
+// is the default version of `memcpy`, or `memcpy@GLIBC_2.2.5`.
+fn split_version(name: &str) -> (&str, Option<&str>, bool) {
+    match name.find('@') {
+        Some(i) if name[i + 1..].starts_with('@') => (&name[..i], Some(&name[i + 2..]), true),
+        Some(i) => (&name[..i], Some(&name[i + 1..]), false),
+        None => (name, None, false),
+    }
+}
+
+// Ranks how well the symbol `name` of a binary matches the requested
+// `symbol`, `None` if it doesn't match. Versioned names only appear in
+// `.symtab`, `.dynsym` names lack their version, so they don't match a
+// requested version.
+fn match_rank(name: &str, symbol: &str) -> Option<u8> {
+    let (name, name_version, default) = split_version(name);
+    let (symbol, version, _) = split_version(symbol);
+    if name != symbol {
+        return None;
+    }
+    match (version, name_version) {
+        (None, None) => Some(2),
+        (None, Some(_)) if default => Some(1),
+        (Some(version), Some(name_version)) if version == name_version => Some(2),
+        _ => None,
+    }
+}
+
+// Whether the version requested by `symbol` can't be verified, because the
+// `(name, weak, value)` symbols only have unversioned names for it.
+fn unverifiable_version<'a, I>(symbols: I, symbol: &str) -> bool
+where
+    I: Iterator<Item = (&'a str, bool, u64)>,
+{
+    let (symbol, version, _) = split_version(symbol);
+    if version.is_none() {
+        return false;
+    }
+    let (mut unversioned, mut versioned) = (false, false);
+    for (name, _, _) in symbols {
+        match split_version(name) {
+            (name, None, _) if name == symbol => unversioned = true,
+            (name, Some(_), _) if name == symbol => versioned = true,
+            _ => {}
+        }
+    }
+
+    unversioned && !versioned
+}
+
+// Returns the value of the best definition of `symbol` among `(name, weak,
+// value)` symbols: the best matching name, then global over weak.
+fn best_definition<'a, I>(symbols: I, symbol: &str) -> Option<u64>
+where
+    I: Iterator<Item = (&'a str, bool, u64)>,
+{
+    let mut best: Option<((u8, bool), u64)> = None;
+    for (name, weak, value) in symbols {
+        let rank = match match_rank(name, symbol) {
+            Some(rank) => (rank, !weak),
+            None => continue,
+        };
+        if best.map_or(true, |(best_rank, _)| rank > best_rank) {
+            best = Some((rank, value));
+        }
+    }
+
+    best.map(|(_, value)| value)
+}
+
+/// Returns the file offset of `symbol` in the ELF file `binary`, as expected
+/// by uprobes.
+///
+/// Symbols are looked up in `.symtab`, then in `.dynsym` for stripped
+/// binaries. Global definitions are preferred to weak ones. `symbol` can
+/// name a version, eg. `memcpy@GLIBC_2.2.5`, and otherwise matches the
+/// default version. Versions are read from the symbol names of `.symtab`,
+/// so requesting a version of a binary without them fails with
+/// `LoadError::NotSupported`.
+pub fn resolve_symbol(binary: &Path, symbol: &str) -> Result<u64> {
+    let not_found =
+        || LoadError::BinarySymbolNotFound(binary.display().to_string(), symbol.to_string());
+    let code = fs::read(binary)?;
+    let elf = Elf::parse(&code)?;
+    let syms = elf.syms.iter().map(|sym| (sym, &elf.strtab));
+    let dynsyms = elf.dynsyms.iter().map(|sym| (sym, &elf.dynstrtab));
+    let defined: Vec<_> = syms
+        .chain(dynsyms)
+        // undefined symbols are imports from other binaries
+        .filter(|(sym, _)| sym.st_shndx != SHN_UNDEF as usize)
+        .filter_map(|(sym, strtab)| {
+            let name = strtab.get_unsafe(sym.st_name)?;
+            Some((name, sym.st_bind() == STB_WEAK, sym.st_value))
+        })
+        .collect();
+    let vaddr = match best_definition(defined.iter().cloned(), symbol) {
+        Some(vaddr) => vaddr,
+        None if unverifiable_version(defined.iter().cloned(), symbol) => {
+            return Err(LoadError::NotSupported(format!(
+                "checking the version of `{}' in `{}', which has no versioned symbol names",
+                symbol,
+                binary.display()
+            )))
+        }
+        None => return Err(not_found()),
+    };
+
+    vaddr_to_offset(&elf, vaddr).ok_or_else(not_found)
+}
+
+// Returns the path of `binary` as seen from this process, and as seen by
+// `pid` if given. The process may run in another mount namespace, eg. in a
+// container, so its files are reached through `/proc/<pid>/root`.
+fn resolve_binary(binary: &Path, pid: Option<i32>) -> Result<(PathBuf, String)> {
+    let pid = match pid {
+        Some(pid) => pid,
+        None if binary.is_relative() && binary.components().count() == 1 => {
+            return Err(LoadError::NotSupported(format!(
+                "attaching to library `{}' without a pid, use its path instead",
+                binary.display()
+            )))
+        }
+        None => return Ok((binary.to_path_buf(), binary.display().to_string())),
+    };
+    let maps = fs::read_to_string(format!("/proc/{}/maps", pid))?;
+    let path = find_mapped(&maps, binary)
+        .ok_or_else(|| LoadError::BinaryNotMapped(binary.display().to_string(), pid))?;
+
+    Ok((
+        PathBuf::from(format!("/proc/{}/root{}", pid, path)),
+        path.to_string(),
+    ))
+}
+
+/// Returns the address of `symbol` of `binary` in the address space of the
+/// process `pid`.
+///
+/// `binary` is either the path of the executable or library as seen by the
+/// process, or a library name like `libc` that is looked up in the files
+/// mapped by the process. The address accounts for the load address of
+/// position independent binaries.
+pub fn runtime_address(binary: &Path, symbol: &str, pid: i32) -> Result<u64> {
+    let (file, path) = resolve_binary(binary, Some(pid))?;
+    let offset = resolve_symbol(&file, symbol)?;
+    let maps = fs::read_to_string(format!("/proc/{}/maps", pid))?;
+
+    mapped_address(&maps, &path, offset).ok_or_else(|| LoadError::BinaryNotMapped(path, pid))
+}
+
+// Event names can only contain alphanumeric characters and underscores.
+fn event_name(kind: &ProgramKind, path: &str, offset: u64, pid: Option<i32>) -> String {
+    let prefix = match kind {
+        ProgramKind::Uretprobe => "r",
+        _ => "p",
+    };
+    let path: String = path
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    match pid {
+        Some(pid) => format!("{}_{}_0x{:x}_{}", prefix, path, offset, pid),
+        None => format!("{}_{}_0x{:x}", prefix, path, offset),
+    }
+}
+
+impl Program {
+    /// Attaches the uprobe or uretprobe to `symbol` of `binary`.
+    ///
+    /// Without `pid`, the probe fires in every process running `binary`,
+    /// including the processes started after attaching. With `pid`, it only
+    /// fires in that process: `binary` is then the path as seen by the
+    /// process, which may run in another mount namespace, or a library name
+    /// like `libc` that is looked up in the files mapped by the process.
+    /// The binary has to be mapped by the process.
+    pub fn attach_uprobe(
+        &mut self,
+        binary: &Path,
+        symbol: &str,
+        pid: Option<i32>,
+    ) -> Result<RawFd> {
+        if self.kind != ProgramKind::Uprobe && self.kind != ProgramKind::Uretprobe {
+            return Err(LoadError::NotSupported(format!(
+                "attaching {:?} program `{}' as a uprobe",
+                self.kind, self.name
+            )));
+        }
+        let fd = self.fd.ok_or(LoadError::BPF)?;
+        let (file, path) = resolve_binary(binary, pid)?;
+        let offset = resolve_symbol(&file, symbol)?;
+
+        let ev_name = event_name(&self.kind, &path, offset, pid);
+        let cev_name = CString::new(ev_name.clone())?;
+        let cfile = CString::new(file.to_string_lossy().into_owned())?;
+        let pfd = unsafe {
+            bpf_sys::bpf_attach_uprobe(
+                fd,
+                self.kind.to_attach_type(),
+                cev_name.as_ptr(),
+                cfile.as_ptr(),
+                offset,
+                pid.unwrap_or(-1),
+            )
+        };
+        if pfd < 0 {
+            return Err(LoadError::BPF);
+        }
+        self.attachments.push(Attachment::Uprobe { pfd, ev_name });
+
+        Ok(pfd)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MAPS: &str = "\
+5581a5e4c000-5581a5e7b000 r--p 00000000 fd:01 1835033                    /usr/bin/bash
+5581a5e7b000-5581a5f3a000 r-xp 0002f000 fd:01 1835033                    /usr/bin/bash
+5581a6f8f000-5581a70f2000 rw-p 00000000 00:00 0                          [heap]
+7f2b1c000000-7f2b1c028000 r--p 00000000 fd:01 1843423                    /usr/lib/x86_64-linux-gnu/libc.so.6
+7f2b1c028000-7f2b1c1bd000 r-xp 00028000 fd:01 1843423                    /usr/lib/x86_64-linux-gnu/libc.so.6
+7ffd4a5d4000-7ffd4a5f5000 rw-p 00000000 00:00 0                          [stack]
+";
+
+    #[test]
+    fn test_find_mapped() {
+        assert_eq!(
+            find_mapped(MAPS, Path::new("/usr/bin/bash")),
+            Some("/usr/bin/bash")
+        );
+        assert_eq!(
+            find_mapped(MAPS, Path::new("libc")),
+            Some("/usr/lib/x86_64-linux-gnu/libc.so.6")
+        );
+        assert_eq!(find_mapped(MAPS, Path::new("libcrypto")), None);
+        assert_eq!(find_mapped(MAPS, Path::new("/bin/bash")), None);
+    }
+
+    #[test]
+    fn test_mapped_address() {
+        assert_eq!(
+            mapped_address(MAPS, "/usr/bin/bash", 0x30000),
+            Some(0x5581a5e7c000)
+        );
+        assert_eq!(
+            mapped_address(MAPS, "/usr/lib/x86_64-linux-gnu/libc.so.6", 0x100),
+            Some(0x7f2b1c000100)
+        );
+        assert_eq!(mapped_address(MAPS, "/usr/bin/bash", 0x200000), None);
+    }
+
+    #[test]
+    fn test_best_definition() {
+        let symbols = vec![
+            ("memcpy@GLIBC_2.2.5", false, 1),
+            ("memcpy@@GLIBC_2.14", false, 2),
+            ("malloc", true, 3),
+            ("malloc", false, 4),
+            ("free", false, 5),
+        ];
+        let find = |symbol| best_definition(symbols.iter().cloned(), symbol);
+        assert_eq!(find("memcpy"), Some(2));
+        assert_eq!(find("memcpy@GLIBC_2.2.5"), Some(1));
+        assert_eq!(find("memcpy@GLIBC_2.99"), None);
+        assert_eq!(find("malloc"), Some(4));
+        // .dynsym names have no version to check
+        assert_eq!(find("free@GLIBC_2.2.5"), None);
+        assert_eq!(find("fre"), None);
+
+        let unverifiable = |symbol| unverifiable_version(symbols.iter().cloned(), symbol);
+        assert!(unverifiable("free@GLIBC_2.2.5"));
+        assert!(!unverifiable("free"));
+        assert!(!unverifiable("memcpy@GLIBC_2.99"));
+        assert!(!unverifiable("fre@GLIBC_2.2.5"));
+        // .symtab has the versions of the .dynsym names
+        let symbols = vec![("memcpy@@GLIBC_2.14", false, 2), ("memcpy", false, 2)];
+        assert!(!unverifiable_version(
+            symbols.iter().cloned(),
+            "memcpy@GLIBC_2.99"
+        ));
+    }
+
+    #[test]
+    fn test_event_name() {
+        assert_eq!(
+            event_name(&ProgramKind::Uprobe, "/usr/bin/bash", 0x30, None),
+            "p__usr_bin_bash_0x30"
+        );
+        assert_eq!(
+            event_name(&ProgramKind::Uretprobe, "/usr/bin/bash", 0x30, Some(42)),
+            "r__usr_bin_bash_0x30_42"
+        );
+    }
+}
@@ -0,0 +1,234 @@
+//! Creation of maps whose values hold a `bpf_spin_lock` or a `bpf_timer`.
+//!
+//! The kernel only lets programs use the locks and timers embedded in map
+//! values if the map was created with the BTF of its value, which tells
+//! where they are. Probes compiled from Rust don't carry BTF, so maps created
+//! with `HashMap::with_value_btf` store their offsets after the map
+//! definition, and a minimal BTF blob describing the value is loaded instead.
+use std::mem;
+use std::os::unix::io::RawFd;
+
+use bpf_sys::bpf_map_def;
+
+use crate::btf::consts::{BTF_INT_SIGNED, BTF_KIND_ARRAY, BTF_KIND_INT, BTF_KIND_STRUCT};
+use crate::local_storage::{
+    btf_blob, create_with_btf, load_btf, map_name, push_u32s, MapCreateAttr,
+};
+use crate::{numa, Result};
+
+// ids of the types in the generated BTF, 0 being void
+const INT_TYPE_ID: u32 = 1;
+const BYTE_TYPE_ID: u32 = 2;
+const KEY_ARRAY_TYPE_ID: u32 = 3;
+const SPIN_LOCK_TYPE_ID: u32 = 4;
+const TIMER_TYPE_ID: u32 = 5;
+const VALUE_TYPE_ID: u32 = 6;
+
+// offsets of the names in the string section
+const INT_NAME: u32 = 1;
+const BYTE_NAME: u32 = 5;
+const SPIN_LOCK_NAME: u32 = 19;
+const VAL_NAME: u32 = 33;
+const LOCK_NAME: u32 = 37;
+const TIMER_STRUCT_NAME: u32 = 42;
+const TIMER_NAME: u32 = 52;
+const STRINGS: &[u8] = b"\0int\0unsigned char\0bpf_spin_lock\0val\0lock\0bpf_timer\0timer\0";
+
+// The size of `struct bpf_timer`.
+const TIMER_SIZE: u32 = 16;
+
+// Marks a field the value doesn't have.
+const NO_FIELD: u32 = std::u32::MAX;
+
+/// The offsets of the special fields of map values, stored after the map
+/// definition by `HashMap::with_value_btf`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ValueBtf {
+    pub spin_lock_offset: u32,
+    pub timer_offset: u32,
+}
+
+impl ValueBtf {
+    /// Parses the field offsets following the map definition in the
+    /// `maps/name` section `section`, `None` if the value has no special
+    /// field.
+    pub(crate) fn parse(section: &[u8]) -> Option<ValueBtf> {
+        let section = section.get(mem::size_of::<bpf_map_def>()..)?;
+        if section.len() < mem::size_of::<ValueBtf>() {
+            return None;
+        }
+        let u32_at = |off: usize| {
+            u32::from_ne_bytes([
+                section[off],
+                section[off + 1],
+                section[off + 2],
+                section[off + 3],
+            ])
+        };
+        let value_btf = ValueBtf {
+            spin_lock_offset: u32_at(0),
+            timer_offset: u32_at(4),
+        };
+        if value_btf.spin_lock_offset == NO_FIELD && value_btf.timer_offset == NO_FIELD {
+            return None;
+        }
+
+        Some(value_btf)
+    }
+}
+
+// Builds BTF with `int` (1), `unsigned char` (2), `unsigned char[key_size]`
+// (3), `struct bpf_spin_lock` (4), `struct bpf_timer` (5) and the value struct
+// (6) types. The value struct only describes the lock and the timer, the rest
+// of the value is left as padding.
+fn value_btf(config: &bpf_map_def, fields: &ValueBtf) -> Vec<u8> {
+    let mut types = Vec::new();
+    // name_off, info, size, then the INT encoding
+    push_u32s(
+        &mut types,
+        &[INT_NAME, BTF_KIND_INT << 24, 4, BTF_INT_SIGNED << 24 | 32],
+    );
+    push_u32s(&mut types, &[BYTE_NAME, BTF_KIND_INT << 24, 1, 8]);
+    // name_off, info, unused, then the element type, index type and length
+    push_u32s(
+        &mut types,
+        &[
+            0,
+            BTF_KIND_ARRAY << 24,
+            0,
+            BYTE_TYPE_ID,
+            INT_TYPE_ID,
+            config.key_size,
+        ],
+    );
+    // name_off, info, size, then name_off, type and bit offset of the members
+    push_u32s(&mut types, &[SPIN_LOCK_NAME, BTF_KIND_STRUCT << 24 | 1, 4]);
+    push_u32s(&mut types, &[VAL_NAME, INT_TYPE_ID, 0]);
+    // the kernel only checks the name and size of `struct bpf_timer`
+    push_u32s(
+        &mut types,
+        &[TIMER_STRUCT_NAME, BTF_KIND_STRUCT << 24, TIMER_SIZE],
+    );
+
+    // members must be sorted by offset
+    let mut members = vec![
+        (fields.spin_lock_offset, LOCK_NAME, SPIN_LOCK_TYPE_ID),
+        (fields.timer_offset, TIMER_NAME, TIMER_TYPE_ID),
+    ];
+    members.retain(|(offset, _, _)| *offset != NO_FIELD);
+    members.sort();
+    push_u32s(
+        &mut types,
+        &[
+            0,
+            BTF_KIND_STRUCT << 24 | members.len() as u32,
+            config.value_size,
+        ],
+    );
+    for (offset, name, type_id) in members {
+        push_u32s(&mut types, &[name, type_id, offset * 8]);
+    }
+
+    btf_blob(&types, STRINGS)
+}
+
+// Array maps require an `int` key, other maps are fine with a byte array.
+fn key_type_id(config: &bpf_map_def) -> u32 {
+    if config.key_size == 4 {
+        INT_TYPE_ID
+    } else {
+        KEY_ARRAY_TYPE_ID
+    }
+}
+
+/// Creates a map whose value is described by `fields`, on the NUMA node
+/// `numa_node` if given.
+pub(crate) fn create(
+    name: &str,
+    config: &bpf_map_def,
+    fields: &ValueBtf,
+    numa_node: Option<u32>,
+) -> Result<RawFd> {
+    let mut attr = match numa_node {
+        Some(node) => {
+            numa::check_node(name, node)?;
+            numa::create_attr(name, config, node)?
+        }
+        None => MapCreateAttr {
+            map_type: config.type_,
+            key_size: config.key_size,
+            value_size: config.value_size,
+            max_entries: config.max_entries,
+            map_flags: config.map_flags,
+            map_name: map_name(name)?,
+            ..Default::default()
+        },
+    };
+    attr.btf_key_type_id = key_type_id(config);
+    attr.btf_value_type_id = VALUE_TYPE_ID;
+    let btf_fd = load_btf(&value_btf(config, fields))?;
+
+    create_with_btf(attr, btf_fd)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let def = vec![0u8; mem::size_of::<bpf_map_def>()];
+        assert_eq!(ValueBtf::parse(&def), None);
+        let mut section = def.clone();
+        section.extend_from_slice(&NO_FIELD.to_ne_bytes());
+        section.extend_from_slice(&NO_FIELD.to_ne_bytes());
+        assert_eq!(ValueBtf::parse(&section), None);
+        let mut section = def;
+        section.extend_from_slice(&NO_FIELD.to_ne_bytes());
+        section.extend_from_slice(&8u32.to_ne_bytes());
+        assert_eq!(
+            ValueBtf::parse(&section),
+            Some(ValueBtf {
+                spin_lock_offset: NO_FIELD,
+                timer_offset: 8,
+            })
+        );
+    }
+
+    #[test]
+    fn test_value_btf() {
+        let mut config: bpf_map_def = unsafe { mem::zeroed() };
+        config.key_size = 8;
+        config.value_size = 32;
+        let btf = value_btf(
+            &config,
+            &ValueBtf {
+                spin_lock_offset: 24,
+                timer_offset: 8,
+            },
+        );
+        let u32_at = |off: usize| {
+            let mut buf = [0u8; 4];
+            buf.copy_from_slice(&btf[off..off + 4]);
+            u32::from_ne_bytes(buf)
+        };
+        let type_len = u32_at(12) as usize;
+        assert_eq!(btf.len(), 24 + type_len + STRINGS.len());
+        // the value struct is last, with its size and the timer and lock
+        // members sorted by offset
+        assert_eq!(u32_at(24 + type_len - 28), 32);
+        assert_eq!(u32_at(24 + type_len - 20), TIMER_TYPE_ID);
+        assert_eq!(u32_at(24 + type_len - 16), 64);
+        assert_eq!(u32_at(24 + type_len - 8), SPIN_LOCK_TYPE_ID);
+        assert_eq!(u32_at(24 + type_len - 4), 192);
+        assert_eq!(
+            &STRINGS[SPIN_LOCK_NAME as usize..][..14],
+            b"bpf_spin_lock\0"
+        );
+        assert_eq!(&STRINGS[LOCK_NAME as usize..][..5], b"lock\0");
+        assert_eq!(&STRINGS[TIMER_STRUCT_NAME as usize..][..10], b"bpf_timer\0");
+        assert_eq!(&STRINGS[TIMER_NAME as usize..], b"timer\0");
+        assert_eq!(key_type_id(&config), KEY_ARRAY_TYPE_ID);
+    }
+}
@@ -0,0 +1,235 @@
+//! Watching maps for changes.
+//!
+//! The kernel doesn't notify user space when map entries change, so the map
+//! is polled: every `interval` all the entries are read with
+//! `Map::lookup_batch` and compared with the previous snapshot. Changes made
+//! and reverted between two polls are not seen, and every poll reads the
+//! whole map, so the interval should be chosen according to the map size.
+//!
+//! With the `async` feature, `Map::watch_stream` returns a `Stream` of the
+//! changes that waits for the next poll with a tokio timer. `Map::watch`
+//! returns a blocking iterator instead, which sleeps the calling thread
+//! between polls:
+//!
+//! ```rust
+//! use redbpf::{MapChange, Module};
+//! use std::time::Duration;
+//!
+//! let code = std::fs::read("bpf.elf").unwrap();
+//! let module = Module::parse(&code).unwrap();
+//! let map = module.map("blocked").unwrap();
+//!
+//! for changes in map.watch::<u32, u64>(Duration::from_secs(1)).unwrap() {
+//!     for change in changes.unwrap() {
+//!         match change {
+//!             MapChange::Added(key, _) => println!("blocked {}", key),
+//!             MapChange::Removed(key, _) => println!("unblocked {}", key),
+//!             MapChange::Changed { .. } => (),
+//!         }
+//!     }
+//! }
+//! ```
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::thread;
+use std::time::Duration;
+
+use crate::{Map, Pod, Result};
+
+/// A change between two snapshots of a map.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MapChange<K, V> {
+    Added(K, V),
+    Removed(K, V),
+    Changed { key: K, old: V, new: V },
+}
+
+/// Iterator over the changes of a map, returned by `Map::watch`.
+///
+/// Each item holds the changes found by one poll. Polls that find no change
+/// are not returned, so `next` blocks until the map changes. The iteration
+/// never ends on its own.
+pub struct MapWatch<'a, K, V> {
+    map: &'a Map,
+    interval: Duration,
+    snapshot: HashMap<K, V>,
+}
+
+impl<'a, K, V> Iterator for MapWatch<'a, K, V>
+where
+    K: Pod + Eq + Hash,
+    V: Pod + PartialEq,
+{
+    type Item = Result<Vec<MapChange<K, V>>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            thread::sleep(self.interval);
+            let snapshot = match snapshot(self.map) {
+                Ok(snapshot) => snapshot,
+                Err(e) => return Some(Err(e)),
+            };
+            let changes = diff(&self.snapshot, &snapshot);
+            self.snapshot = snapshot;
+            if !changes.is_empty() {
+                return Some(Ok(changes));
+            }
+        }
+    }
+}
+
+fn snapshot<K, V>(map: &Map) -> Result<HashMap<K, V>>
+where
+    K: Pod + Eq + Hash,
+    V: Pod,
+{
+    Ok(map.lookup_batch()?.into_iter().collect())
+}
+
+fn diff<K, V>(old: &HashMap<K, V>, new: &HashMap<K, V>) -> Vec<MapChange<K, V>>
+where
+    K: Copy + Eq + Hash,
+    V: Copy + PartialEq,
+{
+    let mut changes = Vec::new();
+    for (key, value) in new.iter() {
+        match old.get(key) {
+            None => changes.push(MapChange::Added(*key, *value)),
+            Some(old) if old != value => changes.push(MapChange::Changed {
+                key: *key,
+                old: *old,
+                new: *value,
+            }),
+            Some(_) => (),
+        }
+    }
+    for (key, value) in old.iter() {
+        if !new.contains_key(key) {
+            changes.push(MapChange::Removed(*key, *value));
+        }
+    }
+
+    changes
+}
+
+impl Map {
+    /// Polls the map every `interval` and returns the changes as an iterator.
+    ///
+    /// `K` and `V` must have the same size as the keys and values of the map.
+    /// Per-CPU maps are not supported.
+    ///
+    /// The iterator blocks the calling thread, sleeping between polls. With
+    /// the `async` feature, use `watch_stream` from async code.
+    ///
+    /// This is poll based, not event driven: every poll reads the whole map
+    /// with `lookup_batch`, and changes made and reverted between two polls
+    /// are not reported. The first snapshot is taken before returning, so
+    /// only changes made after calling `watch` are reported.
+    pub fn watch<K, V>(&self, interval: Duration) -> Result<MapWatch<K, V>>
+    where
+        K: Pod + Eq + Hash,
+        V: Pod + PartialEq,
+    {
+        Ok(MapWatch {
+            map: self,
+            interval,
+            snapshot: snapshot(self)?,
+        })
+    }
+}
+
+#[cfg(feature = "async")]
+mod stream {
+    use futures::{try_ready, Async, Poll, Stream};
+    use std::collections::HashMap;
+    use std::hash::Hash;
+    use std::io;
+    use std::time::{Duration, Instant};
+    use tokio::timer::Interval;
+
+    use super::{diff, snapshot, MapChange};
+    use crate::{LoadError, Map, Pod, Result};
+
+    /// `Stream` of the changes of a map, returned by `Map::watch_stream`.
+    ///
+    /// Each item holds the changes found by one poll. Polls that find no
+    /// change are not returned. The stream never ends on its own.
+    pub struct MapWatchStream<'a, K, V> {
+        map: &'a Map,
+        interval: Interval,
+        snapshot: HashMap<K, V>,
+    }
+
+    impl<'a, K, V> Stream for MapWatchStream<'a, K, V>
+    where
+        K: Pod + Eq + Hash,
+        V: Pod + PartialEq,
+    {
+        type Item = Vec<MapChange<K, V>>;
+        type Error = LoadError;
+
+        fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+            loop {
+                try_ready!(self
+                    .interval
+                    .poll()
+                    .map_err(|e| LoadError::IO(io::Error::new(io::ErrorKind::Other, e))));
+                let snapshot = snapshot(self.map)?;
+                let changes = diff(&self.snapshot, &snapshot);
+                self.snapshot = snapshot;
+                if !changes.is_empty() {
+                    return Ok(Async::Ready(Some(changes)));
+                }
+            }
+        }
+    }
+
+    impl Map {
+        /// Same as `watch`, returning a `Stream` that waits for the next poll
+        /// with the timer of the tokio runtime instead of sleeping.
+        pub fn watch_stream<K, V>(&self, interval: Duration) -> Result<MapWatchStream<K, V>>
+        where
+            K: Pod + Eq + Hash,
+            V: Pod + PartialEq,
+        {
+            Ok(MapWatchStream {
+                map: self,
+                interval: Interval::new(Instant::now() + interval, interval),
+                snapshot: snapshot(self)?,
+            })
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+pub use stream::MapWatchStream;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_diff() {
+        let old: HashMap<u32, u64> = vec![(1, 10), (2, 20), (3, 30)].into_iter().collect();
+        let new: HashMap<u32, u64> = vec![(1, 10), (2, 21), (4, 40)].into_iter().collect();
+
+        let mut changes = diff(&old, &new);
+        changes.sort_by_key(|c| match c {
+            MapChange::Added(k, _) | MapChange::Removed(k, _) => *k,
+            MapChange::Changed { key, .. } => *key,
+        });
+        assert_eq!(
+            changes,
+            vec![
+                MapChange::Changed {
+                    key: 2,
+                    old: 20,
+                    new: 21
+                },
+                MapChange::Removed(3, 30),
+                MapChange::Added(4, 40),
+            ]
+        );
+        assert!(diff(&new, &new).is_empty());
+    }
+}
@@ -0,0 +1,320 @@
+//! Attaching XDP programs to several interfaces.
+//!
+//! A single loaded XDP program can run on any number of interfaces, eg. on
+//! hosts with several data-plane NICs:
+//!
+//! ```rust
+//! use redbpf::{xdp, Module};
+//!
+//! let code = std::fs::read("bpf.elf").unwrap();
+//! let mut module = Module::parse(&code).unwrap();
+//! let prog = module.program_mut("block_port_80").unwrap();
+//! prog.load(module.version, module.license.clone()).unwrap();
+//!
+//! let attached = prog
+//!     .attach_xdp_multi(&["eth0", "eth1"], xdp::XDP_FLAGS_SKB_MODE)
+//!     .unwrap();
+//! // the program is detached from eth0 and eth1 when `attached` is dropped
+//! ```
+//!
+//! `query` tells which programs are attached to an interface, whether they
+//! were attached by this process or not, and `detach` removes them, eg. to
+//! clean up after a crashed process.
+use std::os::unix::io::RawFd;
+
+use crate::{if_nametoindex, netlink, Attachment, LoadError, Program, Result};
+
+/// Only attach if no program is attached to the interface already.
+pub const XDP_FLAGS_UPDATE_IF_NOEXIST: u32 = 1 << 0;
+/// Attach in generic mode, which works with any driver.
+pub const XDP_FLAGS_SKB_MODE: u32 = 1 << 1;
+/// Attach in native driver mode.
+pub const XDP_FLAGS_DRV_MODE: u32 = 1 << 2;
+/// Offload the program to the NIC.
+pub const XDP_FLAGS_HW_MODE: u32 = 1 << 3;
+/// Only replace the program attached to the interface if it is the expected
+/// one. Requires Linux 5.7 or newer.
+pub const XDP_FLAGS_REPLACE: u32 = 1 << 4;
+
+pub(crate) const XDP_FLAGS_MODES: u32 = XDP_FLAGS_SKB_MODE | XDP_FLAGS_DRV_MODE | XDP_FLAGS_HW_MODE;
+
+/// Attaches the XDP program `fd` to `iface`, or detaches the current one if
+/// `fd` is -1.
+pub(crate) fn attach(iface: &str, fd: RawFd, flags: u32) -> Result<()> {
+    let ifindex = if_nametoindex(iface)?;
+    netlink::set_xdp(ifindex, fd, flags, None)
+        .map_err(|e| LoadError::Interface(iface.to_string(), e))
+}
+
+/// The mode an XDP program is attached in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XdpMode {
+    /// Generic mode, run by the kernel network stack (`XDP_FLAGS_SKB_MODE`).
+    Generic,
+    /// Native mode, run by the driver (`XDP_FLAGS_DRV_MODE`).
+    Driver,
+    /// Offloaded mode, run by the NIC (`XDP_FLAGS_HW_MODE`).
+    Offloaded,
+}
+
+impl XdpMode {
+    fn flags(self) -> u32 {
+        match self {
+            XdpMode::Generic => XDP_FLAGS_SKB_MODE,
+            XdpMode::Driver => XDP_FLAGS_DRV_MODE,
+            XdpMode::Offloaded => XDP_FLAGS_HW_MODE,
+        }
+    }
+}
+
+/// The XDP programs attached to an interface, see `query`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XdpAttachInfo {
+    programs: Vec<(XdpMode, u32)>,
+}
+
+impl XdpAttachInfo {
+    fn from_prog_ids(ids: netlink::XdpProgIds) -> Option<XdpAttachInfo> {
+        let programs: Vec<(XdpMode, u32)> = vec![
+            (XdpMode::Generic, ids.skb),
+            (XdpMode::Driver, ids.drv),
+            (XdpMode::Offloaded, ids.hw),
+        ]
+        .into_iter()
+        .filter_map(|(mode, id)| id.map(|id| (mode, id)))
+        .collect();
+        if programs.is_empty() {
+            return None;
+        }
+
+        Some(XdpAttachInfo { programs })
+    }
+
+    /// Returns the id of the attached program, or of the first one in
+    /// `programs` if several are attached.
+    pub fn prog_id(&self) -> u32 {
+        self.programs[0].1
+    }
+
+    /// Returns the mode of the attached program, or of the first one in
+    /// `programs` if several are attached.
+    pub fn mode(&self) -> XdpMode {
+        self.programs[0].0
+    }
+
+    /// Returns the ids of the attached programs with their mode.
+    ///
+    /// There is a single program unless one is offloaded to the NIC, in
+    /// which case another can be attached in driver or generic mode.
+    pub fn programs(&self) -> &[(XdpMode, u32)] {
+        &self.programs
+    }
+}
+
+/// Returns the XDP programs attached to `iface`, or `None` if there is none.
+///
+/// The program ids can be turned into file descriptors with
+/// `bpf_prog_get_fd_by_id`, eg. to replace the program with
+/// `Program::replace_xdp`.
+pub fn query(iface: &str) -> Result<Option<XdpAttachInfo>> {
+    let ifindex = if_nametoindex(iface)?;
+    let ids = netlink::get_xdp(ifindex).map_err(|e| LoadError::Interface(iface.to_string(), e))?;
+
+    Ok(XdpAttachInfo::from_prog_ids(ids))
+}
+
+/// Detaches the XDP programs attached to `iface` in any mode, whether they
+/// were attached by this process or not.
+///
+/// Does nothing if no program is attached.
+pub fn detach(iface: &str) -> Result<()> {
+    let ifindex = if_nametoindex(iface)?;
+    let ids = netlink::get_xdp(ifindex).map_err(|e| LoadError::Interface(iface.to_string(), e))?;
+    let info = match XdpAttachInfo::from_prog_ids(ids) {
+        Some(info) => info,
+        None => return Ok(()),
+    };
+    for (mode, _) in info.programs() {
+        // a program can only be detached with the mode it was attached with
+        netlink::set_xdp(ifindex, -1, mode.flags(), None)
+            .map_err(|e| LoadError::Interface(iface.to_string(), e))?;
+    }
+
+    Ok(())
+}
+
+/// An XDP program attached to several interfaces.
+///
+/// The program is detached from all the interfaces when this is dropped,
+/// even if the `Program` it was attached from is dropped first.
+pub struct XdpMultiAttachment {
+    interfaces: Vec<String>,
+    flags: u32,
+}
+
+impl XdpMultiAttachment {
+    /// Returns the interfaces the program is attached to.
+    pub fn interfaces(&self) -> &[String] {
+        &self.interfaces
+    }
+
+    /// Detaches the program from all the interfaces.
+    ///
+    /// All the interfaces are detached even if some of them fail, in which
+    /// case the first error is returned.
+    pub fn detach(mut self) -> Result<()> {
+        self.detach_all()
+    }
+
+    fn detach_all(&mut self) -> Result<()> {
+        // detaching has to use the same mode as attaching
+        let flags = self.flags & XDP_FLAGS_MODES;
+        let mut ret = Ok(());
+        for iface in self.interfaces.drain(..) {
+            let res = attach(&iface, -1, flags);
+            if ret.is_ok() {
+                ret = res;
+            }
+        }
+
+        ret
+    }
+}
+
+impl Drop for XdpMultiAttachment {
+    fn drop(&mut self) {
+        let _ = self.detach_all();
+    }
+}
+
+impl Program {
+    /// Attaches the loaded XDP program to each of `interfaces`.
+    ///
+    /// `flags` is a combination of the `XDP_FLAGS_*` constants. If attaching
+    /// to one of the interfaces fails, the program is detached from the
+    /// interfaces it was already attached to and the error is returned.
+    pub fn attach_xdp_multi(
+        &mut self,
+        interfaces: &[&str],
+        flags: u32,
+    ) -> Result<XdpMultiAttachment> {
+        let fd = self.fd.ok_or(LoadError::BPF)?;
+        let mut attached = XdpMultiAttachment {
+            interfaces: Vec::with_capacity(interfaces.len()),
+            flags,
+        };
+        for iface in interfaces {
+            // dropping `attached` rolls back the interfaces attached so far
+            attach(iface, fd, flags)?;
+            attached.interfaces.push(iface.to_string());
+        }
+
+        Ok(attached)
+    }
+
+    /// Atomically replaces the XDP program `old_fd` attached to `iface` in
+    /// `mode` with this loaded program.
+    ///
+    /// `mode` must be the mode the old program is attached in, as returned by
+    /// `query`. Unlike detaching the old program and attaching the new one,
+    /// no packet goes unprocessed during the swap. The replacement fails if
+    /// the program attached to `iface` is not `old_fd` anymore, eg. because
+    /// another process replaced it first.
+    ///
+    /// Requires Linux 5.7 for `XDP_FLAGS_REPLACE`, older kernels fail with
+    /// `EINVAL`. See `replace_xdp_or_reattach` to fall back to a non-atomic
+    /// replacement.
+    ///
+    /// This program is detached from `iface` when it is dropped. If the old
+    /// program was attached by a `Program` of this process, dropping it
+    /// detaches whatever program is attached to `iface`, so it must not be
+    /// dropped before this one.
+    pub fn replace_xdp(&mut self, iface: &str, old_fd: RawFd, mode: XdpMode) -> Result<()> {
+        self.replace_xdp_with(iface, old_fd, mode, false)
+    }
+
+    /// Replaces the XDP program `old_fd` like `replace_xdp`, falling back to
+    /// detaching the old program and attaching this one if the atomic
+    /// replacement fails with `EINVAL`.
+    ///
+    /// Kernels older than 5.7 reject `XDP_FLAGS_REPLACE` with `EINVAL`, but so
+    /// do newer kernels for other invalid arguments, so the fallback may also
+    /// run when `XDP_FLAGS_REPLACE` is supported. The old program is attached
+    /// again if attaching this one fails. A warning is logged when the `log`
+    /// feature is enabled.
+    pub fn replace_xdp_or_reattach(
+        &mut self,
+        iface: &str,
+        old_fd: RawFd,
+        mode: XdpMode,
+    ) -> Result<()> {
+        self.replace_xdp_with(iface, old_fd, mode, true)
+    }
+
+    fn replace_xdp_with(
+        &mut self,
+        iface: &str,
+        old_fd: RawFd,
+        mode: XdpMode,
+        reattach: bool,
+    ) -> Result<()> {
+        let fd = self.fd.ok_or(LoadError::BPF)?;
+        let ifindex = if_nametoindex(iface)?;
+        let flags = mode.flags();
+        match netlink::set_xdp(ifindex, fd, flags | XDP_FLAGS_REPLACE, Some(old_fd)) {
+            Ok(()) => {}
+            // unknown flag on older kernels
+            Err(ref e) if reattach && e.raw_os_error() == Some(libc::EINVAL) => {
+                #[cfg(feature = "log")]
+                log::warn!(
+                    "XDP_FLAGS_REPLACE failed, replacing the XDP program of {} non-atomically",
+                    iface
+                );
+                attach(iface, -1, flags)?;
+                if let Err(e) = attach(iface, fd, flags) {
+                    // don't leave the interface without a program
+                    let _ = attach(iface, old_fd, flags);
+                    return Err(e);
+                }
+            }
+            Err(e) => return Err(LoadError::Interface(iface.to_string(), e)),
+        }
+        self.attachments.push(Attachment::XDP {
+            iface: iface.to_string(),
+            netns: None,
+            flags,
+        });
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_attach_info() {
+        let ids = netlink::XdpProgIds::default();
+        assert_eq!(XdpAttachInfo::from_prog_ids(ids), None);
+
+        let ids = netlink::XdpProgIds {
+            skb: None,
+            drv: Some(12),
+            hw: None,
+        };
+        let info = XdpAttachInfo::from_prog_ids(ids).unwrap();
+        assert_eq!((info.mode(), info.prog_id()), (XdpMode::Driver, 12));
+
+        let ids = netlink::XdpProgIds {
+            skb: None,
+            drv: Some(12),
+            hw: Some(3),
+        };
+        let info = XdpAttachInfo::from_prog_ids(ids).unwrap();
+        assert_eq!(
+            info.programs(),
+            &[(XdpMode::Driver, 12), (XdpMode::Offloaded, 3)]
+        );
+    }
+}
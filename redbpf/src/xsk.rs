@@ -0,0 +1,420 @@
+//! AF_XDP sockets.
+//!
+//! AF_XDP sockets receive packets redirected by XDP programs directly in a
+//! memory area shared with the kernel, the UMEM, without going through the
+//! network stack. Packets are exchanged with the kernel through four rings:
+//! the fill ring hands free frames to the kernel for receiving, the rx ring
+//! returns the received packets, the tx ring submits packets to send, and the
+//! completion ring returns the frames of the sent packets.
+//!
+//! ```rust
+//! use redbpf::xsk::{XskConfig, XskSocket};
+//!
+//! let mut socket = XskSocket::new("eth0", 0, &XskConfig::default()).unwrap();
+//! loop {
+//!     socket.recv(64, |packet| println!("received {} bytes", packet.len()));
+//! }
+//! ```
+//!
+//! By default libbpf attaches an XDP program that redirects all the packets
+//! of the queue to the socket. To use your own program, set
+//! `inhibit_prog_load` and insert the socket in an `XskMap` of the program
+//! with `Map::set_xsk`, see `redbpf_probes::xdp::XskMap`.
+use std::ffi::CString;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::ptr::{self, null_mut};
+use std::slice;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use bpf_sys::xsk::{
+    xdp_desc, xsk_ring_cons, xsk_ring_prod, xsk_socket, xsk_socket__create, xsk_socket__delete,
+    xsk_socket__fd, xsk_socket_config, xsk_umem, xsk_umem__create, xsk_umem__delete,
+    xsk_umem_config,
+};
+
+use crate::{LoadError, Map, Result, VoidPtr};
+
+const XSK_LIBBPF_FLAGS_INHIBIT_PROG_LOAD: u32 = 1 << 0;
+
+/// Configuration of an `XskSocket`.
+#[derive(Debug, Clone)]
+pub struct XskConfig {
+    /// Size of the UMEM frames. Must be a power of two, usually the page
+    /// size.
+    pub frame_size: u32,
+    /// Number of frames in the UMEM.
+    pub frame_count: u32,
+    /// Number of entries of each ring. Must be a power of two.
+    pub ring_size: u32,
+    /// Flags used to attach the default XDP program, see `redbpf::xdp`.
+    pub xdp_flags: u32,
+    /// Flags passed to `bind`, eg. `XDP_ZEROCOPY`.
+    pub bind_flags: u16,
+    /// Don't attach the default libbpf XDP program.
+    pub inhibit_prog_load: bool,
+}
+
+impl Default for XskConfig {
+    fn default() -> Self {
+        XskConfig {
+            frame_size: 4096,
+            frame_count: 4096,
+            ring_size: 2048,
+            xdp_flags: 0,
+            bind_flags: 0,
+            inhibit_prog_load: false,
+        }
+    }
+}
+
+// The ring accessors are inline functions in xsk.h, so they are
+// reimplemented here with the same memory ordering.
+
+unsafe fn load_acquire(p: *mut u32) -> u32 {
+    (*(p as *const AtomicU32)).load(Ordering::Acquire)
+}
+
+unsafe fn store_release(p: *mut u32, value: u32) {
+    (*(p as *const AtomicU32)).store(value, Ordering::Release)
+}
+
+unsafe fn prod_reserve(r: &mut xsk_ring_prod, nb: u32) -> Option<u32> {
+    let mut free = r.cached_cons.wrapping_sub(r.cached_prod);
+    if free < nb {
+        r.cached_cons = load_acquire(r.consumer).wrapping_add(r.size);
+        free = r.cached_cons.wrapping_sub(r.cached_prod);
+    }
+    if free < nb {
+        return None;
+    }
+    let idx = r.cached_prod;
+    r.cached_prod = r.cached_prod.wrapping_add(nb);
+    Some(idx)
+}
+
+unsafe fn prod_submit(r: &mut xsk_ring_prod, nb: u32) {
+    store_release(r.producer, (*r.producer).wrapping_add(nb));
+}
+
+unsafe fn prod_entry<T>(r: &xsk_ring_prod, idx: u32) -> *mut T {
+    (r.ring as *mut T).add((idx & r.mask) as usize)
+}
+
+unsafe fn cons_peek(r: &mut xsk_ring_cons, nb: u32) -> (u32, u32) {
+    let mut entries = r.cached_prod.wrapping_sub(r.cached_cons);
+    if entries == 0 {
+        r.cached_prod = load_acquire(r.producer);
+        entries = r.cached_prod.wrapping_sub(r.cached_cons);
+    }
+    let n = entries.min(nb);
+    let idx = r.cached_cons;
+    r.cached_cons = r.cached_cons.wrapping_add(n);
+    (idx, n)
+}
+
+unsafe fn cons_release(r: &mut xsk_ring_cons, nb: u32) {
+    store_release(r.consumer, (*r.consumer).wrapping_add(nb));
+}
+
+unsafe fn cons_entry<T>(r: &xsk_ring_cons, idx: u32) -> *const T {
+    (r.ring as *const T).add((idx & r.mask) as usize)
+}
+
+fn libbpf_result(ret: i32) -> Result<()> {
+    if ret < 0 {
+        return Err(LoadError::IO(io::Error::from_raw_os_error(-ret)));
+    }
+
+    Ok(())
+}
+
+/// An AF_XDP socket bound to a queue of a network interface.
+///
+/// The socket owns its UMEM. Half of the frames are used to receive
+/// packets, and the other half to send them. The socket is closed and the
+/// UMEM released when dropped.
+pub struct XskSocket {
+    xsk: *mut xsk_socket,
+    umem: *mut xsk_umem,
+    area: *mut u8,
+    area_len: usize,
+    frame_size: u32,
+    // libbpf keeps pointers to the rings, so they must not move
+    fill: Box<xsk_ring_prod>,
+    comp: Box<xsk_ring_cons>,
+    rx: Box<xsk_ring_cons>,
+    tx: Box<xsk_ring_prod>,
+    free_frames: Vec<u64>,
+}
+
+// the socket exclusively owns the UMEM and the rings
+unsafe impl Send for XskSocket {}
+
+impl XskSocket {
+    /// Creates a socket receiving the packets of the queue `queue_id` of
+    /// `iface`.
+    pub fn new(iface: &str, queue_id: u32, config: &XskConfig) -> Result<XskSocket> {
+        let ciface = CString::new(iface)?;
+        let area_len = config.frame_size as usize * config.frame_count as usize;
+        let area = unsafe {
+            libc::mmap(
+                null_mut(),
+                area_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if area == libc::MAP_FAILED {
+            return Err(LoadError::IO(io::Error::last_os_error()));
+        }
+
+        let mut socket = XskSocket {
+            xsk: null_mut(),
+            umem: null_mut(),
+            area: area as *mut u8,
+            area_len,
+            frame_size: config.frame_size,
+            fill: Box::new(unsafe { std::mem::zeroed() }),
+            comp: Box::new(unsafe { std::mem::zeroed() }),
+            rx: Box::new(unsafe { std::mem::zeroed() }),
+            tx: Box::new(unsafe { std::mem::zeroed() }),
+            free_frames: Vec::new(),
+        };
+        // from here on, dropping `socket` releases whatever was created
+
+        let umem_config = xsk_umem_config {
+            fill_size: config.ring_size,
+            comp_size: config.ring_size,
+            frame_size: config.frame_size,
+            frame_headroom: 0,
+            flags: 0,
+        };
+        libbpf_result(unsafe {
+            xsk_umem__create(
+                &mut socket.umem,
+                area,
+                area_len as u64,
+                &mut *socket.fill,
+                &mut *socket.comp,
+                &umem_config,
+            )
+        })?;
+
+        let mut xsk_config: xsk_socket_config = unsafe { std::mem::zeroed() };
+        xsk_config.rx_size = config.ring_size;
+        xsk_config.tx_size = config.ring_size;
+        xsk_config.xdp_flags = config.xdp_flags;
+        xsk_config.bind_flags = config.bind_flags;
+        if config.inhibit_prog_load {
+            xsk_config.libbpf_flags = XSK_LIBBPF_FLAGS_INHIBIT_PROG_LOAD;
+        }
+        libbpf_result(unsafe {
+            xsk_socket__create(
+                &mut socket.xsk,
+                ciface.as_ptr(),
+                queue_id,
+                socket.umem,
+                &mut *socket.rx,
+                &mut *socket.tx,
+                &xsk_config,
+            )
+        })?;
+
+        let frames: Vec<u64> = (0..config.frame_count as u64)
+            .map(|i| i * config.frame_size as u64)
+            .collect();
+        let (rx_frames, tx_frames) = frames.split_at(frames.len() / 2);
+        socket.free_frames = tx_frames.to_vec();
+        socket.fill_frames(rx_frames);
+
+        Ok(socket)
+    }
+
+    /// Returns the file descriptor of the socket, to be inserted in an
+    /// `XskMap` or polled for incoming packets.
+    pub fn fd(&self) -> RawFd {
+        unsafe { xsk_socket__fd(self.xsk) }
+    }
+
+    // Hands frames to the kernel for receiving. Frames that don't fit in the
+    // fill ring are kept for sending.
+    fn fill_frames(&mut self, frames: &[u64]) {
+        let mut frames = frames;
+        while !frames.is_empty() {
+            let n = frames.len().min(self.fill.size as usize) as u32;
+            let idx = match unsafe { prod_reserve(&mut self.fill, n) } {
+                Some(idx) => idx,
+                None => {
+                    self.free_frames.extend_from_slice(frames);
+                    return;
+                }
+            };
+            for (i, addr) in frames[..n as usize].iter().enumerate() {
+                unsafe { *prod_entry::<u64>(&self.fill, idx.wrapping_add(i as u32)) = *addr };
+            }
+            unsafe { prod_submit(&mut self.fill, n) };
+            frames = &frames[n as usize..];
+        }
+    }
+
+    /// Calls `f` on each received packet, up to `max` packets.
+    ///
+    /// The packets are read in place from the UMEM, and their frames are
+    /// handed back to the kernel once `f` returns. Returns the number of
+    /// packets received, which is `0` if none are pending.
+    pub fn recv<F: FnMut(&mut [u8])>(&mut self, max: u32, mut f: F) -> usize {
+        let (idx, n) = unsafe { cons_peek(&mut self.rx, max) };
+        if n == 0 {
+            return 0;
+        }
+
+        let mut frames = Vec::with_capacity(n as usize);
+        for i in 0..n {
+            let desc: xdp_desc = unsafe { ptr::read(cons_entry(&self.rx, idx.wrapping_add(i))) };
+            let data = unsafe {
+                slice::from_raw_parts_mut(self.area.add(desc.addr as usize), desc.len as usize)
+            };
+            f(data);
+            // the address may include an offset within the frame
+            frames.push(desc.addr - desc.addr % self.frame_size as u64);
+        }
+        unsafe { cons_release(&mut self.rx, n) };
+        self.fill_frames(&frames);
+
+        n as usize
+    }
+
+    /// Queues `data` for sending.
+    ///
+    /// Fails with `WouldBlock` if no frame or tx ring entry is available,
+    /// until sent packets are completed.
+    pub fn send(&mut self, data: &[u8]) -> Result<()> {
+        if data.len() > self.frame_size as usize {
+            return Err(LoadError::IO(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "packet larger than the frame size",
+            )));
+        }
+
+        self.complete();
+        let addr = self
+            .free_frames
+            .pop()
+            .ok_or_else(|| LoadError::IO(io::ErrorKind::WouldBlock.into()))?;
+        let idx = match unsafe { prod_reserve(&mut self.tx, 1) } {
+            Some(idx) => idx,
+            None => {
+                self.free_frames.push(addr);
+                return Err(LoadError::IO(io::ErrorKind::WouldBlock.into()));
+            }
+        };
+        unsafe {
+            ptr::copy_nonoverlapping(data.as_ptr(), self.area.add(addr as usize), data.len());
+            let desc = prod_entry::<xdp_desc>(&self.tx, idx);
+            (*desc).addr = addr;
+            (*desc).len = data.len() as u32;
+            (*desc).options = 0;
+            prod_submit(&mut self.tx, 1);
+        }
+
+        self.kick()
+    }
+
+    // Wakes up the kernel to process the tx ring.
+    fn kick(&self) -> Result<()> {
+        let ret =
+            unsafe { libc::sendto(self.fd(), ptr::null(), 0, libc::MSG_DONTWAIT, ptr::null(), 0) };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            match e.raw_os_error() {
+                // the kernel is busy and will process the ring later
+                Some(libc::EAGAIN) | Some(libc::EBUSY) | Some(libc::ENOBUFS) => (),
+                _ => return Err(LoadError::IO(e)),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reclaims the frames of the packets sent so far. Returns the number of
+    /// completed packets.
+    pub fn complete(&mut self) -> usize {
+        let (idx, n) = unsafe { cons_peek(&mut self.comp, self.comp.size) };
+        for i in 0..n {
+            let addr = unsafe { *cons_entry::<u64>(&self.comp, idx.wrapping_add(i)) };
+            self.free_frames.push(addr);
+        }
+        if n > 0 {
+            unsafe { cons_release(&mut self.comp, n) };
+        }
+
+        n as usize
+    }
+}
+
+impl Drop for XskSocket {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.xsk.is_null() {
+                xsk_socket__delete(self.xsk);
+            }
+            if !self.umem.is_null() {
+                xsk_umem__delete(self.umem);
+            }
+            libc::munmap(self.area as *mut libc::c_void, self.area_len);
+        }
+    }
+}
+
+impl Map {
+    /// Inserts `socket` in a `BPF_MAP_TYPE_XSKMAP` map under `queue_id`.
+    ///
+    /// XDP programs can then redirect the packets received on the queue to
+    /// the socket with `XskMap::redirect(queue_id)`. The socket should be
+    /// bound to the same queue.
+    pub fn set_xsk(&self, queue_id: u32, socket: &XskSocket) -> Result<()> {
+        self.check_xsk_map()?;
+        let mut key = queue_id;
+        let mut fd = socket.fd();
+        let ret = unsafe {
+            bpf_sys::bpf_update_elem(
+                self.fd,
+                &mut key as *mut u32 as VoidPtr,
+                &mut fd as *mut RawFd as VoidPtr,
+                0,
+            )
+        };
+        if ret < 0 {
+            return Err(LoadError::IO(io::Error::last_os_error()));
+        }
+
+        Ok(())
+    }
+
+    /// Removes the socket stored under `queue_id` in a `BPF_MAP_TYPE_XSKMAP`
+    /// map. Packets redirected to the queue then get the fallback action of
+    /// the redirect.
+    pub fn remove_xsk(&self, queue_id: u32) -> Result<()> {
+        self.check_xsk_map()?;
+        let mut key = queue_id;
+        if unsafe { bpf_sys::bpf_delete_elem(self.fd, &mut key as *mut u32 as VoidPtr) } < 0 {
+            return Err(LoadError::IO(io::Error::last_os_error()));
+        }
+
+        Ok(())
+    }
+
+    fn check_xsk_map(&self) -> Result<()> {
+        if self.kind != bpf_sys::bpf_map_type_BPF_MAP_TYPE_XSKMAP {
+            return Err(LoadError::InvalidMap(format!(
+                "map `{}' is not an XSK map (type {})",
+                self.name, self.kind
+            )));
+        }
+
+        Ok(())
+    }
+}